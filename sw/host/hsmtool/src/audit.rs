@@ -0,0 +1,341 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An append-only, hash-chained audit log of hsmtool commands.
+//!
+//! The log is a JSON-lines file: each line is one `AuditRecord`.  Every record
+//! contains the hash of the previous record, and its own hash is the SHA256
+//! of the record serialized with an empty `hash` field.  Modifying, removing
+//! or reordering any record breaks the chain, which `AuditLog::verify`
+//! detects.  Appends hold an exclusive lock on the log so that concurrent
+//! invocations of hsmtool don't chain onto the same record.
+
+use anyhow::{Context, Result};
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use serde_annotate::{Annotate, ColorProfile};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::commands::exec::Exec;
+use crate::commands::{result_document, Dispatch};
+
+/// The `prev_hash` of the first record in a log.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("Record {0}: malformed record: {1}")]
+    Malformed(usize, String),
+    #[error("Record {0}: expected sequence number {1}, found {2}")]
+    Sequence(usize, u64, u64),
+    #[error("Record {0}: previous hash mismatch")]
+    ChainBroken(usize),
+    #[error("Record {0}: hash mismatch")]
+    HashMismatch(usize),
+}
+
+/// An object referenced by a command or its result.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ObjectRef {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A single entry in the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub timestamp: String,
+    pub profile: Option<String>,
+    pub token: Option<String>,
+    pub command: Value,
+    pub objects: Vec<ObjectRef>,
+    pub success: bool,
+    pub result: Value,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Computes the hash of this record.
+    pub fn compute_hash(&self) -> Result<String> {
+        let mut record = self.clone();
+        record.hash = String::new();
+        let bytes = serde_json::to_vec(&record)?;
+        Ok(hex::encode(Sha256::digest(bytes)))
+    }
+}
+
+/// Summary of a verified audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditSummary {
+    pub records: u64,
+    pub last_hash: String,
+}
+
+/// A handle to an audit log file.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditLog { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a record for a command to the log.
+    ///
+    /// The sequence number, previous hash and hash are computed from the
+    /// current contents of the log, which is locked until the record is
+    /// written.
+    pub fn append(
+        &self,
+        profile: Option<&str>,
+        token: Option<&str>,
+        command: Value,
+        success: bool,
+        result: Value,
+    ) -> Result<AuditRecord> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Opening audit log {:?}", self.path))?;
+        // The lock is released when `file` is closed.
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .with_context(|| format!("Locking audit log {:?}", self.path))?;
+        let (seq, prev_hash) = match read_records(&file)?.pop() {
+            Some(r) => (r.seq + 1, r.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        let mut objects = Vec::new();
+        collect_objects(&command, &mut objects);
+        collect_objects(&result, &mut objects);
+        objects.sort();
+        objects.dedup();
+
+        let mut record = AuditRecord {
+            seq,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            profile: profile.map(str::to_string),
+            token: token.map(str::to_string),
+            command,
+            objects,
+            success,
+            result,
+            prev_hash,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash()?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        file.sync_all()?;
+        Ok(record)
+    }
+
+    /// Appends a record describing the execution of `command` to the log.
+    pub fn record(
+        &self,
+        profile: Option<&str>,
+        token: Option<&str>,
+        command: &dyn Dispatch,
+        result: &Result<Box<dyn Annotate>>,
+    ) -> Result<AuditRecord> {
+        let doc = result_document(result)?;
+        let json = doc.to_json().color(ColorProfile::default()).to_string();
+        let mut command = serde_json::to_value(command)?;
+        // An `exec` command only names its script, which may change after it runs, so record
+        // what the script contained as well.
+        if command["command"] == "__exec__" {
+            let exec = serde_json::from_value::<Exec>(command.clone())?;
+            if let Value::Object(fields) = &mut command {
+                fields.extend(exec.audit_fields());
+            }
+        }
+        self.append(
+            profile,
+            token,
+            command,
+            result.is_ok(),
+            serde_json::from_str(&json)?,
+        )
+    }
+
+    /// Reads all records from the log.
+    pub fn records(&self) -> Result<Vec<AuditRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        read_records(File::open(&self.path)?)
+    }
+
+    /// Verifies the hash chain of the log.
+    pub fn verify(&self) -> Result<AuditSummary> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut count = 0u64;
+        for (n, record) in self.records()?.iter().enumerate() {
+            if record.seq != count {
+                return Err(AuditError::Sequence(n, count, record.seq).into());
+            }
+            if record.prev_hash != prev_hash {
+                return Err(AuditError::ChainBroken(n).into());
+            }
+            if record.compute_hash()? != record.hash {
+                return Err(AuditError::HashMismatch(n).into());
+            }
+            prev_hash = record.hash.clone();
+            count += 1;
+        }
+        Ok(AuditSummary {
+            records: count,
+            last_hash: prev_hash,
+        })
+    }
+}
+
+/// Reads all records from an audit log `file`.
+fn read_records(file: impl Read) -> Result<Vec<AuditRecord>> {
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<AuditRecord>(&line)
+            .map_err(|e| AuditError::Malformed(n, e.to_string()))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Collects the `id` and `label` fields of every JSON object in `value`.
+fn collect_objects(value: &Value, objects: &mut Vec<ObjectRef>) {
+    match value {
+        Value::Object(map) => {
+            let field = |name: &str| match map.get(name) {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            };
+            let object = ObjectRef {
+                id: field("id"),
+                label: field("label"),
+            };
+            if object.id.is_some() || object.label.is_some() {
+                objects.push(object);
+            }
+            map.values().for_each(|v| collect_objects(v, objects));
+        }
+        Value::Array(array) => array.iter().for_each(|v| collect_objects(v, objects)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write_log(log: &AuditLog) -> Result<()> {
+        log.append(
+            Some("ceremony"),
+            Some("token"),
+            json!({"command": "rsa-generate", "label": "root-key"}),
+            true,
+            json!({"success": true, "id": "0011", "label": "root-key"}),
+        )?;
+        log.append(
+            None,
+            Some("token"),
+            json!({"command": "object-list"}),
+            true,
+            json!({"objects": [{"id": "0011"}, {"label": "other"}]}),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = AuditLog::new(dir.path().join("audit.log"));
+        write_log(&log)?;
+
+        let records = log.records()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert_eq!(
+            records[0].objects,
+            vec![
+                ObjectRef {
+                    id: None,
+                    label: Some("root-key".into()),
+                },
+                ObjectRef {
+                    id: Some("0011".into()),
+                    label: Some("root-key".into()),
+                },
+            ]
+        );
+        let summary = log.verify()?;
+        assert_eq!(summary.records, 2);
+        assert_eq!(summary.last_hash, records[1].hash);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tamper() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = AuditLog::new(dir.path().join("audit.log"));
+        write_log(&log)?;
+
+        // Modifying a record invalidates its hash.
+        let text = std::fs::read_to_string(log.path())?;
+        std::fs::write(log.path(), text.replace("root-key", "evil-key"))?;
+        let err = log.verify().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<AuditError>(),
+            Some(AuditError::HashMismatch(0))
+        ));
+
+        // Removing a record breaks the chain.
+        let lines = text.lines().collect::<Vec<_>>();
+        std::fs::write(log.path(), format!("{}\n", lines[1]))?;
+        assert!(log.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_append() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("audit.log");
+        let threads = (0..4)
+            .map(|_| {
+                let log = AuditLog::new(&path);
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..10 {
+                        log.append(None, None, json!({}), true, json!({}))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!(AuditLog::new(&path).verify()?.records, 40);
+        Ok(())
+    }
+}
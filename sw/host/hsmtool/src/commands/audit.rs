@@ -0,0 +1,61 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use cryptoki::session::Session;
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::any::Any;
+use std::path::PathBuf;
+
+use crate::audit::AuditLog;
+use crate::commands::Dispatch;
+use crate::module::Module;
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+pub struct Verify {
+    /// The audit log to verify.
+    file: PathBuf,
+}
+
+#[typetag::serde(name = "audit-verify")]
+impl Dispatch for Verify {
+    fn run(
+        &self,
+        _context: &dyn Any,
+        _hsm: &Module,
+        _session: Option<&Session>,
+    ) -> Result<Box<dyn Annotate>> {
+        let summary = AuditLog::new(&self.file).verify()?;
+        Ok(Box::new(summary))
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Serialize, Deserialize)]
+pub enum Audit {
+    Verify(Verify),
+}
+
+#[typetag::serde(name = "__audit__")]
+impl Dispatch for Audit {
+    fn run(
+        &self,
+        context: &dyn Any,
+        hsm: &Module,
+        session: Option<&Session>,
+    ) -> Result<Box<dyn Annotate>> {
+        match self {
+            Audit::Verify(x) => x.run(context, hsm, session),
+        }
+    }
+
+    fn leaf(&self) -> &dyn Dispatch
+    where
+        Self: Sized,
+    {
+        match self {
+            Audit::Verify(x) => x.leaf(),
+        }
+    }
+}
@@ -6,6 +6,8 @@ use anyhow::Result;
 use cryptoki::session::Session;
 use serde::{Deserialize, Serialize};
 use serde_annotate::{Annotate, Document};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use std::any::Any;
 use std::path::PathBuf;
 use thiserror::Error;
@@ -18,6 +20,31 @@ pub struct Exec {
     file: PathBuf,
 }
 
+impl Exec {
+    pub fn new(file: PathBuf) -> Self {
+        Exec { file }
+    }
+
+    /// Describes the script for the audit log: the SHA256 of its contents and
+    /// the commands it contains, as far as the script can be read and parsed.
+    pub fn audit_fields(&self) -> Map<String, Value> {
+        let mut fields = Map::new();
+        let Ok(script) = std::fs::read_to_string(&self.file) else {
+            return fields;
+        };
+        fields.insert(
+            "sha256".into(),
+            hex::encode(Sha256::digest(script.as_bytes())).into(),
+        );
+        if let Ok(commands) = serde_annotate::from_str::<Vec<Box<dyn Dispatch>>>(&script) {
+            if let Ok(commands) = serde_json::to_value(commands) {
+                fields.insert("commands".into(), commands);
+            }
+        }
+        fields
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ExecResult {
     pub command: String,
@@ -6,13 +6,14 @@ use anyhow::Result;
 use atty::Stream;
 use cryptoki::session::Session;
 use serde::{Deserialize, Serialize};
-use serde_annotate::{Annotate, ColorProfile};
+use serde_annotate::{Annotate, ColorProfile, Document};
 use std::any::Any;
 
 use crate::module::Module;
 use crate::util::attribute::AttrData;
 
-mod exec;
+mod audit;
+pub mod exec;
 mod object;
 mod rsa;
 mod token;
@@ -36,6 +37,8 @@ pub trait Dispatch {
 
 #[derive(clap::Subcommand, Debug, Serialize, Deserialize)]
 pub enum Commands {
+    #[command(subcommand)]
+    Audit(audit::Audit),
     Exec(exec::Exec),
     #[command(subcommand)]
     Object(object::Object),
@@ -54,6 +57,7 @@ impl Dispatch for Commands {
         session: Option<&Session>,
     ) -> Result<Box<dyn Annotate>> {
         match self {
+            Commands::Audit(x) => x.run(context, hsm, session),
             Commands::Exec(x) => x.run(context, hsm, session),
             Commands::Object(x) => x.run(context, hsm, session),
             Commands::Rsa(x) => x.run(context, hsm, session),
@@ -66,6 +70,7 @@ impl Dispatch for Commands {
         Self: Sized,
     {
         match self {
+            Commands::Audit(x) => x.leaf(),
             Commands::Exec(x) => x.leaf(),
             Commands::Object(x) => x.leaf(),
             Commands::Rsa(x) => x.leaf(),
@@ -115,26 +120,28 @@ pub enum Format {
     Yaml,
 }
 
+/// Converts the result of a command into a document for display.
+pub fn result_document(result: &Result<Box<dyn Annotate>>) -> Result<Document> {
+    match result {
+        Ok(value) => Ok(serde_annotate::serialize(value.as_ref())?),
+        Err(e) => {
+            if let Some(exerr) = e.downcast_ref::<exec::ExecError>() {
+                Ok(exerr.result.clone())
+            } else {
+                let value = BasicResult::from_error(e);
+                Ok(serde_annotate::serialize(value.as_ref())?)
+            }
+        }
+    }
+}
+
 pub fn print_result(
     format: Format,
     color: Option<bool>,
     result: Result<Box<dyn Annotate>>,
 ) -> Result<()> {
-    let (doc, result) = match result {
-        Ok(value) => {
-            let doc = serde_annotate::serialize(value.as_ref())?;
-            (doc, Ok(()))
-        }
-        Err(e) => {
-            let doc = if let Some(exerr) = e.downcast_ref::<exec::ExecError>() {
-                exerr.result.clone()
-            } else {
-                let value = BasicResult::from_error(&e);
-                serde_annotate::serialize(value.as_ref())?
-            };
-            (doc, Err(e))
-        }
-    };
+    let doc = result_document(&result)?;
+    let result = result.map(|_| ());
 
     let profile = if atty::is(Stream::Stdout) && color.unwrap_or(true) {
         ColorProfile::basic()
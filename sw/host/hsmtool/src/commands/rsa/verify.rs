@@ -15,7 +15,7 @@ use crate::error::HsmError;
 use crate::module::Module;
 use crate::util::attribute::KeyType;
 use crate::util::helper;
-use crate::util::signing::SignData;
+use crate::util::signing::{SignData, SignHash, SignPadding};
 
 #[derive(clap::Args, Debug, Serialize, Deserialize)]
 pub struct Verify {
@@ -25,6 +25,19 @@ pub struct Verify {
     label: Option<String>,
     #[arg(short, long, value_enum, default_value = "sha256-hash")]
     format: SignData,
+    #[arg(long, value_enum, default_value = "pkcs1v15")]
+    #[serde(default)]
+    padding: SignPadding,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "sha256",
+        help = "Hash applied to plain-text data"
+    )]
+    #[serde(default)]
+    hash: SignHash,
+    #[arg(long, help = "PSS salt length in bytes (defaults to the hash length)")]
+    salt_len: Option<u64>,
     #[arg(
         short = 'r',
         long,
@@ -53,8 +66,10 @@ impl Dispatch for Verify {
         if self.little_endian {
             data.reverse();
         }
-        let data = self.format.prepare(&data)?;
-        let mechanism = self.format.mechanism()?;
+        let data = self.format.prepare(self.padding, self.hash, &data)?;
+        let mechanism = self
+            .format
+            .mechanism(self.padding, self.hash, self.salt_len)?;
         let mut signature = helper::read_file(&self.signature)?;
         if self.little_endian {
             signature.reverse();
@@ -8,6 +8,7 @@ use cryptoki::session::UserType;
 use log::LevelFilter;
 use std::path::PathBuf;
 
+use hsmtool::audit::AuditLog;
 use hsmtool::commands::{print_command, print_result, Commands, Dispatch, Format};
 use hsmtool::module::{self, Module};
 use hsmtool::profile::Profile;
@@ -65,6 +66,13 @@ struct Args {
     )]
     show_json: bool,
 
+    #[arg(
+        long,
+        env = "HSMTOOL_AUDIT_LOG",
+        help = "Append a hash-chained record of the command to this audit log"
+    )]
+    audit_log: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -88,11 +96,13 @@ fn main() -> Result<()> {
         return print_command(args.format, args.color, args.command.leaf());
     }
 
+    let mut token = args.token.clone();
     let session = if let Some(profile) = &args.profile {
         let profiles = Profile::load(&args.profiles)?;
         let profile = profiles
             .get(profile)
             .ok_or_else(|| anyhow!("Profile {profile:?} not found."))?;
+        token = Some(profile.token.clone());
         Some(hsm.connect(&profile.token, Some(profile.user), profile.pin.as_deref())?)
     } else if let Some(token) = &args.token {
         Some(hsm.connect(token, args.user, args.pin.as_deref())?)
@@ -101,5 +111,23 @@ fn main() -> Result<()> {
     };
 
     let result = args.command.run(&(), &hsm, session.as_ref());
-    print_result(args.format, args.color, result)
+    // Record the command before printing so the command's own error doesn't
+    // prevent the audit record from being written.
+    let audit = args
+        .audit_log
+        .as_ref()
+        .map(|path| {
+            AuditLog::new(path).record(
+                args.profile.as_deref(),
+                token.as_deref(),
+                args.command.leaf(),
+                &result,
+            )
+        })
+        .transpose();
+    let printed = print_result(args.format, args.color, result);
+    match (printed, audit.context("Writing the audit log")) {
+        (Err(e), Err(audit_err)) => Err(anyhow!("{e:#}\n{audit_err:#}")),
+        (printed, audit) => printed.and(audit.map(|_| ())),
+    }
 }
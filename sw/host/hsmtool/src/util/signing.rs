@@ -3,12 +3,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
-use cryptoki::mechanism::Mechanism;
+use cryptoki::mechanism::rsa::{PkcsMgfType, PkcsPssParams};
+use cryptoki::mechanism::{Mechanism, MechanismType};
+use cryptoki::types::Ulong;
 use rsa::pkcs1v15::Pkcs1v15Sign;
 use serde::{Deserialize, Serialize};
 use sha2::digest::const_oid::AssociatedOid;
 use sha2::digest::Digest;
-use sha2::Sha256;
+use sha2::{Sha256, Sha384, Sha512};
 
 use crate::error::HsmError;
 
@@ -16,37 +18,134 @@ use crate::error::HsmError;
 #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SignData {
     /// The data to be signed is plain text.
-    /// The data will be hashed and padded according to PKCS#1 v1.5.
+    /// The data will be hashed with the selected `SignHash` and padded.
     #[serde(alias = "plain-text")]
     PlainText,
     /// The data to be signed is a SHA-256 hash.
     /// The data will have the PKCS#1 v1.5 prefix and padding added.
     #[serde(alias = "sha256-hash")]
     Sha256Hash,
+    /// The data to be signed is a SHA-384 hash.
+    /// The data will have the PKCS#1 v1.5 prefix and padding added.
+    #[serde(alias = "sha384-hash")]
+    Sha384Hash,
+    /// The data to be signed is a SHA-512 hash.
+    /// The data will have the PKCS#1 v1.5 prefix and padding added.
+    #[serde(alias = "sha512-hash")]
+    Sha512Hash,
     /// The data is raw and will be passed directly to the signing functions.
     #[serde(alias = "raw")]
     Raw,
 }
 
-impl SignData {
-    /// Prepare `input` data for signing or verification.
-    pub fn prepare(&self, input: &[u8]) -> Result<Vec<u8>> {
+/// Specify the padding scheme used when signing or verifying.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignPadding {
+    /// PKCS#1 v1.5 padding.
+    #[default]
+    #[serde(alias = "pkcs1v15")]
+    Pkcs1v15,
+    /// RSASSA-PSS padding with MGF1 using the same hash as the data.
+    #[serde(alias = "pss")]
+    Pss,
+}
+
+/// Specify the hash algorithm applied to plain-text data.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignHash {
+    #[default]
+    #[serde(alias = "sha256")]
+    Sha256,
+    #[serde(alias = "sha384")]
+    Sha384,
+    #[serde(alias = "sha512")]
+    Sha512,
+}
+
+impl SignHash {
+    /// Compute the digest of `input`.
+    pub fn digest(&self, input: &[u8]) -> Vec<u8> {
         match self {
-            SignData::PlainText => Self::data_plain_text(input),
-            SignData::Sha256Hash => Self::data_hashed::<Sha256>(input),
-            SignData::Raw => Self::data_raw(input),
+            SignHash::Sha256 => Sha256::digest(input).to_vec(),
+            SignHash::Sha384 => Sha384::digest(input).to_vec(),
+            SignHash::Sha512 => Sha512::digest(input).to_vec(),
         }
     }
 
-    /// Return the `Mechanism` needed during signing or verification.
-    pub fn mechanism(&self) -> Result<Mechanism> {
+    /// The `SignData` describing a digest computed with this hash.
+    fn hashed(&self) -> SignData {
         match self {
-            SignData::PlainText => Ok(Mechanism::RsaPkcs),
-            SignData::Sha256Hash => Ok(Mechanism::RsaPkcs),
-            SignData::Raw => Err(HsmError::Unsupported(
+            SignHash::Sha256 => SignData::Sha256Hash,
+            SignHash::Sha384 => SignData::Sha384Hash,
+            SignHash::Sha512 => SignData::Sha512Hash,
+        }
+    }
+}
+
+impl SignData {
+    /// Prepare `input` data for signing or verification.  Plain text is
+    /// hashed with `hash`, which is otherwise ignored.
+    ///
+    /// For PKCS#1 v1.5 padding, hashed data is prefixed with the `DigestInfo`
+    /// header.  For PSS padding, the hash is passed to the HSM unmodified.
+    pub fn prepare(&self, padding: SignPadding, hash: SignHash, input: &[u8]) -> Result<Vec<u8>> {
+        match (self, padding) {
+            (SignData::PlainText, _) => hash.hashed().prepare(padding, hash, &hash.digest(input)),
+            (SignData::Sha256Hash, SignPadding::Pkcs1v15) => Self::data_hashed::<Sha256>(input),
+            (SignData::Sha384Hash, SignPadding::Pkcs1v15) => Self::data_hashed::<Sha384>(input),
+            (SignData::Sha512Hash, SignPadding::Pkcs1v15) => Self::data_hashed::<Sha512>(input),
+            (SignData::Sha256Hash, SignPadding::Pss) => Self::data_pss::<Sha256>(input),
+            (SignData::Sha384Hash, SignPadding::Pss) => Self::data_pss::<Sha384>(input),
+            (SignData::Sha512Hash, SignPadding::Pss) => Self::data_pss::<Sha512>(input),
+            (SignData::Raw, SignPadding::Pkcs1v15) => Self::data_raw(input),
+            (SignData::Raw, SignPadding::Pss) => {
+                Err(HsmError::Unsupported("PSS padding of raw data".into()).into())
+            }
+        }
+    }
+
+    /// Return the `Mechanism` needed during signing or verification.  Plain
+    /// text is hashed with `hash`, which is otherwise ignored.
+    ///
+    /// For PSS padding, `salt_len` defaults to the length of the hash.
+    pub fn mechanism(
+        &self,
+        padding: SignPadding,
+        hash: SignHash,
+        salt_len: Option<u64>,
+    ) -> Result<Mechanism> {
+        match (self, padding) {
+            (SignData::PlainText, _) => hash.hashed().mechanism(padding, hash, salt_len),
+            (SignData::Raw, _) => Err(HsmError::Unsupported(
                 "rust-cryptoki Mechanism doesn't include RSA_X_509".into(),
             )
             .into()),
+            (_, SignPadding::Pkcs1v15) => Ok(Mechanism::RsaPkcs),
+            (_, SignPadding::Pss) => {
+                let (hash_alg, mgf, hash_len) = match self {
+                    SignData::Sha256Hash => (
+                        MechanismType::SHA256,
+                        PkcsMgfType::MGF1_SHA256,
+                        <Sha256 as Digest>::output_size(),
+                    ),
+                    SignData::Sha384Hash => (
+                        MechanismType::SHA384,
+                        PkcsMgfType::MGF1_SHA384,
+                        <Sha384 as Digest>::output_size(),
+                    ),
+                    SignData::Sha512Hash => (
+                        MechanismType::SHA512,
+                        PkcsMgfType::MGF1_SHA512,
+                        <Sha512 as Digest>::output_size(),
+                    ),
+                    SignData::PlainText | SignData::Raw => unreachable!(),
+                };
+                Ok(Mechanism::RsaPkcsPss(PkcsPssParams {
+                    hash_alg,
+                    mgf,
+                    s_len: Ulong::from(salt_len.unwrap_or(hash_len as u64)),
+                }))
+            }
         }
     }
 
@@ -71,9 +170,12 @@ impl SignData {
         Ok(result)
     }
 
-    fn data_plain_text(input: &[u8]) -> Result<Vec<u8>> {
-        let hash = Sha256::digest(input);
-        Self::data_hashed::<Sha256>(&hash)
+    fn data_pss<D: Digest>(input: &[u8]) -> Result<Vec<u8>> {
+        let hash_len = <D as Digest>::output_size();
+        if hash_len != input.len() {
+            return Err(HsmError::HashSizeError(hash_len, input.len()).into());
+        }
+        Ok(input.to_vec())
     }
 }
 
@@ -83,31 +185,121 @@ mod tests {
 
     #[test]
     fn test_raw() -> Result<()> {
-        let result = SignData::Raw.prepare(b"abc123")?;
+        let result = SignData::Raw.prepare(SignPadding::Pkcs1v15, SignHash::Sha256, b"abc123")?;
         assert_eq!(result, b"abc123");
         Ok(())
     }
 
     #[test]
     fn test_plain_text() -> Result<()> {
-        let result =
-            SignData::PlainText.prepare(b"The quick brown fox jumped over the lazy dog")?;
+        let result = SignData::PlainText.prepare(
+            SignPadding::Pkcs1v15,
+            SignHash::Sha256,
+            b"The quick brown fox jumped over the lazy dog",
+        )?;
         assert_eq!(hex::encode(result),
             "3031300d0609608648016503040201050004207d38b5cd25a2baf85ad3bb5b9311383e671a8a142eb302b324d4a5fba8748c69"
         );
         Ok(())
     }
 
+    #[test]
+    fn test_plain_text_sha512() -> Result<()> {
+        let result =
+            SignData::PlainText.prepare(SignPadding::Pkcs1v15, SignHash::Sha512, b"abc")?;
+        let expected = SignData::Sha512Hash.prepare(
+            SignPadding::Pkcs1v15,
+            SignHash::Sha256,
+            &Sha512::digest(b"abc"),
+        )?;
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
     #[test]
     fn test_hashed() -> Result<()> {
         let input =
             hex::decode("7d38b5cd25a2baf85ad3bb5b9311383e671a8a142eb302b324d4a5fba8748c69")?;
-        let result = SignData::Sha256Hash.prepare(&input)?;
+        let result =
+            SignData::Sha256Hash.prepare(SignPadding::Pkcs1v15, SignHash::Sha256, &input)?;
         assert_eq!(hex::encode(result),
             "3031300d0609608648016503040201050004207d38b5cd25a2baf85ad3bb5b9311383e671a8a142eb302b324d4a5fba8748c69"
         );
 
-        assert!(SignData::Sha256Hash.prepare(b"").is_err());
+        assert!(SignData::Sha256Hash
+            .prepare(SignPadding::Pkcs1v15, SignHash::Sha256, b"")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashed_sha384() -> Result<()> {
+        let input = Sha384::digest(b"abc");
+        let result =
+            SignData::Sha384Hash.prepare(SignPadding::Pkcs1v15, SignHash::Sha256, &input)?;
+        assert_eq!(
+            hex::encode(&result[..19]),
+            "3041300d060960864801650304020205000430"
+        );
+        assert_eq!(&result[19..], input.as_slice());
+        assert!(SignData::Sha384Hash
+            .prepare(
+                SignPadding::Pkcs1v15,
+                SignHash::Sha256,
+                &Sha256::digest(b"abc")
+            )
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashed_sha512() -> Result<()> {
+        let input = Sha512::digest(b"abc");
+        let result =
+            SignData::Sha512Hash.prepare(SignPadding::Pkcs1v15, SignHash::Sha256, &input)?;
+        assert_eq!(
+            hex::encode(&result[..19]),
+            "3051300d060960864801650304020305000440"
+        );
+        assert_eq!(&result[19..], input.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pss() -> Result<()> {
+        let input = Sha384::digest(b"abc");
+        let result = SignData::Sha384Hash.prepare(SignPadding::Pss, SignHash::Sha256, &input)?;
+        assert_eq!(result, input.as_slice());
+        assert!(SignData::Sha384Hash
+            .prepare(SignPadding::Pss, SignHash::Sha256, &Sha256::digest(b"abc"))
+            .is_err());
+
+        let result = SignData::PlainText.prepare(SignPadding::Pss, SignHash::Sha256, b"abc")?;
+        assert_eq!(result, Sha256::digest(b"abc").as_slice());
+        let result = SignData::PlainText.prepare(SignPadding::Pss, SignHash::Sha384, b"abc")?;
+        assert_eq!(result, Sha384::digest(b"abc").as_slice());
+        assert!(SignData::Raw
+            .prepare(SignPadding::Pss, SignHash::Sha256, b"abc")
+            .is_err());
+
+        match SignData::Sha512Hash.mechanism(SignPadding::Pss, SignHash::Sha256, None)? {
+            Mechanism::RsaPkcsPss(params) => {
+                assert_eq!(params.hash_alg, MechanismType::SHA512);
+                assert_eq!(*params.s_len, 64);
+            }
+            m => panic!("Unexpected mechanism {m:?}"),
+        }
+        match SignData::PlainText.mechanism(SignPadding::Pss, SignHash::Sha512, None)? {
+            Mechanism::RsaPkcsPss(params) => {
+                assert_eq!(params.hash_alg, MechanismType::SHA512);
+                assert_eq!(*params.s_len, 64);
+            }
+            m => panic!("Unexpected mechanism {m:?}"),
+        }
+        match SignData::Sha256Hash.mechanism(SignPadding::Pss, SignHash::Sha256, Some(0))? {
+            Mechanism::RsaPkcsPss(params) => assert_eq!(*params.s_len, 0),
+            m => panic!("Unexpected mechanism {m:?}"),
+        }
         Ok(())
     }
 }
@@ -0,0 +1,222 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Integration tests for the `hsmtool` commands.
+//!
+//! Each `testdata/<name>.hjson` file is a command script as accepted by
+//! `hsmtool exec`.  The test creates a fresh SoftHSM2 token for every script,
+//! runs the script through `commands::exec::Exec` and compares the resulting
+//! `Annotate` document against `testdata/<name>.snapshot.json`.
+//!
+//! The snapshot comparison is a subset match: every key present in a snapshot
+//! object must be present and equal in the result, but the result may contain
+//! additional keys.  This keeps the snapshots stable across SoftHSM2 versions
+//! which report slightly different sets of attributes for the same object.
+//! Arrays must have the same length and are matched without regard to order,
+//! since PKCS#11 makes no guarantees about the order of `find_objects`.
+//! A snapshot value of the form `{"$contains": "text"}` matches any string
+//! containing `text`, which is used to check error messages.
+//!
+//! Scripts may use `${TMPDIR}` to refer to a per-script scratch directory.
+//! If `testdata/<name>.outputs.json` exists, it maps files the script writes
+//! to `${TMPDIR}` to the `testdata` files whose contents they must equal.
+
+use anyhow::{anyhow, bail, Context, Result};
+use cryptoki::session::UserType;
+use serde_annotate::ColorProfile;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use hsmtool::commands::exec::{Exec, ExecError};
+use hsmtool::commands::Dispatch;
+use hsmtool::module::Module;
+
+const TESTDATA: &str = "sw/host/hsmtool/tests/testdata";
+const SO_PIN: &str = "officer_pin";
+const USER_PIN: &str = "123456";
+
+const SOFTHSM2_CONF: &str = r#"# SoftHSM v2 configuration file
+directories.tokendir = {tokendir}
+objectstore.backend = file
+log.level = WARNING
+slots.removable = false
+slots.mechanisms = ALL
+library.reset_on_fork = true
+"#;
+
+struct TestCase {
+    token: String,
+    script: PathBuf,
+    snapshot: PathBuf,
+    outputs: PathBuf,
+}
+
+impl TestCase {
+    fn discover() -> Result<Vec<TestCase>> {
+        let mut cases = Vec::new();
+        for entry in std::fs::read_dir(TESTDATA).context(format!("Reading {TESTDATA}"))? {
+            let script = entry?.path();
+            if script.extension().and_then(|e| e.to_str()) != Some("hjson") {
+                continue;
+            }
+            let token = script
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .ok_or_else(|| anyhow!("Bad script name {script:?}"))?
+                .to_string();
+            let snapshot = script.with_extension("snapshot.json");
+            let outputs = script.with_extension("outputs.json");
+            cases.push(TestCase {
+                token,
+                script,
+                snapshot,
+                outputs,
+            });
+        }
+        cases.sort_by(|a, b| a.token.cmp(&b.token));
+        Ok(cases)
+    }
+}
+
+/// Creates a SoftHSM2 configuration in `dir` and initializes one token per test case.
+fn init_softhsm(dir: &Path, cases: &[TestCase]) -> Result<()> {
+    let tokendir = dir.join("tokens");
+    std::fs::create_dir(&tokendir)?;
+    let conf = dir.join("softhsm2.conf");
+    std::fs::write(
+        &conf,
+        SOFTHSM2_CONF.replace("{tokendir}", &tokendir.to_string_lossy()),
+    )?;
+    // SoftHSM2 reads its configuration when the module is initialized, so
+    // this must happen before `Module::initialize`.
+    std::env::set_var("SOFTHSM2_CONF", &conf);
+
+    let util = std::env::var("SOFTHSM2_UTIL").context("SOFTHSM2_UTIL must be set")?;
+    for case in cases {
+        let status = Command::new(&util)
+            .args(["--init-token", "--free", "--label", &case.token])
+            .args(["--so-pin", SO_PIN, "--pin", USER_PIN])
+            .status()
+            .context(format!("Running {util}"))?;
+        if !status.success() {
+            bail!("Failed to initialize token {:?}: {status}", case.token);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the script of `case` and returns the result as a JSON value.
+fn run_script(hsm: &Module, case: &TestCase, tmpdir: &Path) -> Result<Value> {
+    let script =
+        std::fs::read_to_string(&case.script)?.replace("${TMPDIR}", &tmpdir.to_string_lossy());
+    let file = tmpdir.join("script.hjson");
+    std::fs::write(&file, script)?;
+
+    let session = hsm.connect(&case.token, Some(UserType::User), Some(USER_PIN))?;
+    let exec = Exec::new(file);
+    let doc = match exec.run(&(), hsm, Some(&session)) {
+        Ok(result) => serde_annotate::serialize(result.as_ref())?,
+        Err(e) => match e.downcast::<ExecError>() {
+            Ok(exerr) => exerr.result,
+            Err(e) => return Err(e),
+        },
+    };
+    let json = doc.to_json().color(ColorProfile::default()).to_string();
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Returns `Ok(())` if `actual` contains everything in `expected`.
+fn subset_match(expected: &Value, actual: &Value, path: &str) -> Result<()> {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => {
+            for (k, ev) in e {
+                let av = a
+                    .get(k)
+                    .ok_or_else(|| anyhow!("{path}: missing key {k:?}"))?;
+                subset_match(ev, av, &format!("{path}.{k}"))?;
+            }
+            Ok(())
+        }
+        (Value::Object(e), Value::String(a)) if e.len() == 1 && e.contains_key("$contains") => {
+            match &e["$contains"] {
+                Value::String(text) if a.contains(text.as_str()) => Ok(()),
+                text => bail!("{path}: expected a string containing {text}, found {a:?}"),
+            }
+        }
+        (Value::Array(e), Value::Array(a)) => {
+            if e.len() != a.len() {
+                bail!("{path}: expected {} elements, found {}", e.len(), a.len());
+            }
+            let mut used = vec![false; a.len()];
+            for (i, ev) in e.iter().enumerate() {
+                let found = a.iter().enumerate().find(|(j, av)| {
+                    !used[*j] && subset_match(ev, av, &format!("{path}[{i}]")).is_ok()
+                });
+                match found {
+                    Some((j, _)) => used[j] = true,
+                    None => bail!("{path}[{i}]: no element matches {ev}"),
+                }
+            }
+            Ok(())
+        }
+        (e, a) if e == a => Ok(()),
+        (e, a) => bail!("{path}: expected {e}, found {a}"),
+    }
+}
+
+/// Compares the files written by the script of `case` to their expected contents.
+fn check_outputs(case: &TestCase, tmpdir: &Path) -> Result<()> {
+    if !case.outputs.exists() {
+        return Ok(());
+    }
+    let outputs = std::fs::read_to_string(&case.outputs)?;
+    let outputs = serde_json::from_str::<BTreeMap<String, String>>(&outputs)?;
+    for (output, reference) in outputs {
+        let actual = std::fs::read(tmpdir.join(&output)).context(format!("Reading {output}"))?;
+        let expected = std::fs::read(Path::new(TESTDATA).join(&reference))
+            .context(format!("Reading {reference}"))?;
+        if actual != expected {
+            bail!("{output}: contents differ from {reference}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_exec_scripts() -> Result<()> {
+    let cases = TestCase::discover()?;
+    assert!(!cases.is_empty(), "No test scripts found in {TESTDATA}");
+
+    let dir = tempfile::tempdir()?;
+    init_softhsm(dir.path(), &cases)?;
+    let module = std::env::var("HSMTOOL_MODULE").context("HSMTOOL_MODULE must be set")?;
+    let hsm = Module::initialize(&module)?;
+
+    let mut failures = 0;
+    for case in cases.iter() {
+        let tmpdir = dir.path().join(&case.token);
+        std::fs::create_dir(&tmpdir)?;
+        let actual =
+            run_script(&hsm, case, &tmpdir).context(format!("Running script {:?}", case.script))?;
+        let snapshot = std::fs::read_to_string(&case.snapshot)
+            .context(format!("Reading snapshot {:?}", case.snapshot))?;
+        let expected = serde_json::from_str::<Value>(&snapshot)?;
+        let result =
+            subset_match(&expected, &actual, "$").and_then(|_| check_outputs(case, &tmpdir));
+        if let Err(e) = result {
+            failures += 1;
+            eprintln!("FAIL {:?}: {e}", case.script);
+            eprintln!("Actual result:\n{}", serde_json::to_string_pretty(&actual)?);
+        } else {
+            eprintln!("PASS {:?}", case.script);
+        }
+    }
+    assert_eq!(
+        failures, 0,
+        "{failures} script(s) did not match their snapshots"
+    );
+    Ok(())
+}
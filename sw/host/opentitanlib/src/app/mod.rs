@@ -42,6 +42,9 @@ impl ProgressIndicator for NoProgressBar {
 /// underway.
 pub struct StagedProgressBar {
     pub current_progress_bar: Rc<RefCell<Option<indicatif::ProgressBar>>>,
+    /// Set when drawing one of several concurrent bars (e.g. in a `MultiProgress`), in which
+    /// case each stage reuses the same bar, labeled with this prefix.
+    prefix: Option<String>,
 }
 
 impl Default for StagedProgressBar {
@@ -55,9 +58,32 @@ impl StagedProgressBar {
     const STAGE_TEMPLATE: &str =
         "{msg}: [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta})";
 
+    const PREFIX_TEMPLATE: &str =
+        "{prefix} {msg}: [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta})";
+
     pub fn new() -> Self {
         Self {
             current_progress_bar: Rc::new(RefCell::new(None)),
+            prefix: None,
+        }
+    }
+
+    /// Creates a progress indicator which draws all stages on the given `bar`, labeled with
+    /// `prefix`.  The bar is left unfinished between stages, so that it can be part of a
+    /// `MultiProgress`; call `finish_with_message()` once the operation is complete.
+    pub fn with_bar(bar: ProgressBar, prefix: &str) -> Self {
+        bar.set_style(ProgressStyle::default_bar().template(Self::PREFIX_TEMPLATE));
+        bar.set_prefix(prefix.to_string());
+        Self {
+            current_progress_bar: Rc::new(RefCell::new(Some(bar))),
+            prefix: Some(prefix.to_string()),
+        }
+    }
+
+    /// Finishes the current bar, replacing its stage name with `msg`.
+    pub fn finish_with_message(&self, msg: &str) {
+        if let Some(bar) = self.current_progress_bar.borrow().as_ref() {
+            bar.finish_with_message(msg.to_string());
         }
     }
 
@@ -78,6 +104,14 @@ impl StagedProgressBar {
 
 impl ProgressIndicator for StagedProgressBar {
     fn new_stage(&self, name: &str, total: usize) {
+        if self.prefix.is_some() {
+            let bar = self.current_progress_bar.borrow();
+            let bar = bar.as_ref().unwrap();
+            bar.reset();
+            bar.set_length(total as u64);
+            bar.set_message(name.to_string());
+            return;
+        }
         let progress = ProgressBar::new(total as u64);
         if name.is_empty() {
             progress.set_style(ProgressStyle::default_bar().template(Self::DEFAULT_TEMPLATE));
@@ -92,7 +126,7 @@ impl ProgressIndicator for StagedProgressBar {
     fn progress(&self, pos: usize) {
         let bar = self.current_progress_bar.borrow();
         let bar = bar.as_ref().unwrap();
-        if pos as u64 == bar.length() {
+        if pos as u64 == bar.length() && self.prefix.is_none() {
             bar.finish();
             return;
         }
@@ -111,6 +111,25 @@ pub fn create(args: &BackendOpts) -> Result<TransportWrapper> {
     env.build(backend)
 }
 
+/// Opens the Dediprog programmer with USB serial number `serial`, otherwise selected and
+/// configured according to [`BackendOpts`] like the `dediprog` interface, and applies the
+/// default pin configuration (e.g. `VCC`).  This allows driving several programmers at once,
+/// each from its own thread.
+pub fn create_dediprog(args: &BackendOpts, serial: &str) -> Result<TransportWrapper> {
+    let mut env = TransportWrapperBuilder::new("dediprog".to_string());
+    for conf_file in &args.conf {
+        process_config_file(&mut env, conf_file.as_ref())?
+    }
+    if args.conf.is_empty() {
+        process_config_file(&mut env, Path::new("/__builtin__/dediprog.json"))?;
+    }
+    let dediprog: Box<dyn Transport> =
+        Box::new(Dediprog::new(args.usb_vid, args.usb_pid, Some(serial))?);
+    let transport = env.build(dediprog)?;
+    transport.apply_default_configuration()?;
+    Ok(transport)
+}
+
 pub fn create_empty_transport() -> Result<Box<dyn Transport>> {
     Ok(Box::new(EmptyTransport))
 }
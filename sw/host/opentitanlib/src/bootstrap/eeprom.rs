@@ -3,23 +3,34 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
+use std::time::Instant;
 
 use crate::app::TransportWrapper;
-use crate::bootstrap::{Bootstrap, UpdateProtocol};
+use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol, UpdateStats};
 use crate::spiflash::SpiFlash;
 use crate::transport::{Capability, ProgressIndicator};
 
 /// Implements the SPI EEPROM bootstrap protocol.
-pub struct Eeprom;
+pub struct Eeprom {
+    incremental: bool,
+    verify: bool,
+}
 
 impl Eeprom {
-    /// Creates a new `Eeprom` protocol updater.
-    pub fn new() -> Self {
-        Eeprom
+    /// Creates a new `Eeprom` protocol updater from `options`.
+    pub fn new(options: &BootstrapOptions) -> Self {
+        Eeprom {
+            incremental: options.incremental,
+            verify: options.verify,
+        }
     }
 }
 
 impl UpdateProtocol for Eeprom {
+    fn supports_verify(&self) -> bool {
+        true
+    }
+
     fn verify_capabilities(
         &self,
         _container: &Bootstrap,
@@ -43,12 +54,30 @@ impl UpdateProtocol for Eeprom {
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<UpdateStats> {
         let spi = container.spi_params.create(transport, "BOOTSTRAP")?;
         let flash = SpiFlash::from_spi(&*spi)?;
-        flash.chip_erase(&*spi)?;
-        flash.program_with_progress(&*spi, 0, payload, progress)?;
+        let page_size = flash.program_size as usize;
+        let mut stats = UpdateStats {
+            frames: (payload.len() + page_size - 1) / page_size,
+            ..Default::default()
+        };
+        if self.incremental {
+            let incremental =
+                flash.program_incremental_with_progress(&*spi, 0, payload, progress)?;
+            log::info!("Incremental program: {:?}", incremental);
+            stats.frames = incremental.pages_programmed;
+        } else {
+            flash.chip_erase(&*spi)?;
+            flash.program_with_progress(&*spi, 0, payload, progress)?;
+        }
+        if self.verify {
+            let start = Instant::now();
+            flash.verify_with_progress(&*spi, 0, payload, progress)?;
+            stats.verify_time = Some(start.elapsed());
+            stats.verified = true;
+        }
         SpiFlash::chip_reset(&*spi)?;
-        Ok(())
+        Ok(stats)
     }
 }
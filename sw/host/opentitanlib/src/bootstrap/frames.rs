@@ -0,0 +1,350 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::bootstrap::{BootstrapError, BootstrapProtocol};
+use crate::impl_serializable_error;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum TraceError {
+    #[error("Trace line {0}: {1}")]
+    Parse(usize, String),
+    #[error("Transaction {0} does not match any frame")]
+    UnknownFrame(usize),
+    #[error("Transaction {0} sends frame {1} before frame {2}")]
+    OutOfOrder(usize, usize, usize),
+    #[error("Trace ends after {0} of {1} frames")]
+    Incomplete(usize, usize),
+    #[error("Frame {0} was never acknowledged")]
+    Unacknowledged(usize),
+}
+impl_serializable_error!(TraceError);
+
+/// A frame as sent to the target by a bootstrap protocol.
+#[derive(Clone, Debug)]
+pub struct ExpectedFrame {
+    /// Frame number, as it appears in the frame header.
+    pub frame_num: u32,
+    /// Flash offset, as it appears in the frame header.
+    pub flash_offset: u32,
+    /// The complete frame, as sent to the target.
+    pub data: Vec<u8>,
+    /// The response by which the target acknowledges receipt of the frame, during a later
+    /// transaction.  `None` if the target does not acknowledge this frame.
+    pub ack: Option<Vec<u8>>,
+    /// Whether the target erases flash after receiving this frame.
+    pub erase: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    frame_num: u32,
+    flash_offset: u32,
+    frame: String,
+    ack: Option<String>,
+    erase: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    protocol: BootstrapProtocol,
+    frames: Vec<ManifestEntry>,
+}
+
+/// Writes each frame and its expected acknowledgement into `dir`, as `frame_NNNN.bin` and
+/// `frame_NNNN.ack`, along with a `frames.json` manifest describing them.
+pub fn export_frames(
+    dir: &Path,
+    protocol: BootstrapProtocol,
+    frames: &[ExpectedFrame],
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut entries = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        let name = format!("frame_{:04}", i);
+        fs::write(dir.join(format!("{}.bin", name)), &frame.data)?;
+        let ack = match &frame.ack {
+            Some(ack) => {
+                fs::write(dir.join(format!("{}.ack", name)), ack)?;
+                Some(format!("{}.ack", name))
+            }
+            None => None,
+        };
+        entries.push(ManifestEntry {
+            frame_num: frame.frame_num,
+            flash_offset: frame.flash_offset,
+            frame: format!("{}.bin", name),
+            ack,
+            erase: frame.erase,
+        });
+    }
+    let manifest = Manifest {
+        protocol,
+        frames: entries,
+    };
+    fs::write(
+        dir.join("frames.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(())
+}
+
+/// A single SPI transaction from a captured trace.
+#[derive(Clone, Debug, Default)]
+pub struct SpiTransaction {
+    pub mosi: Vec<u8>,
+    pub miso: Vec<u8>,
+}
+
+/// Parses a captured SPI trace.  Each line describes one chip-select-framed transaction as
+/// hex encoded MOSI data, optionally followed by whitespace and hex encoded MISO data.  Empty
+/// lines and everything following a `#` are ignored.
+pub fn parse_trace(text: &str) -> Result<Vec<SpiTransaction>> {
+    let mut trace = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let decode = |s: &str| hex::decode(s).map_err(|e| TraceError::Parse(n + 1, e.to_string()));
+        match fields[..] {
+            [] => continue,
+            [mosi] => trace.push(SpiTransaction {
+                mosi: decode(mosi)?,
+                miso: Vec::new(),
+            }),
+            [mosi, miso] => trace.push(SpiTransaction {
+                mosi: decode(mosi)?,
+                miso: decode(miso)?,
+            }),
+            _ => {
+                return Err(TraceError::Parse(
+                    n + 1,
+                    format!("expected 1 or 2 fields, got {}", fields.len()),
+                )
+                .into())
+            }
+        }
+    }
+    Ok(trace)
+}
+
+/// Summary of a captured trace which matched the expected frame sequence.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TraceReport {
+    pub transactions: usize,
+    pub frames: usize,
+    pub retransmissions: usize,
+    pub frames_acked: usize,
+}
+
+/// Validates a captured SPI `trace` against the `frames` the bootstrap protocol is expected to
+/// send.  Frames may be retransmitted, but every frame must be sent, no frame may be sent
+/// before all preceding frames have been sent at least once, and every frame which the target
+/// is expected to acknowledge must be acknowledged.
+pub fn validate_trace(frames: &[ExpectedFrame], trace: &[SpiTransaction]) -> Result<TraceReport> {
+    // Index of the first frame which has not been sent yet.
+    let mut next = 0;
+    for (n, transaction) in trace.iter().enumerate() {
+        let index = frames
+            .iter()
+            .position(|f| f.data == transaction.mosi)
+            .ok_or(TraceError::UnknownFrame(n))?;
+        if index > next {
+            return Err(TraceError::OutOfOrder(n, index, next).into());
+        }
+        next = next.max(index + 1);
+    }
+    if next < frames.len() {
+        return Err(TraceError::Incomplete(next, frames.len()).into());
+    }
+    let acked = acknowledged(frames, trace);
+    if let Some(i) = (0..frames.len()).find(|&i| frames[i].ack.is_some() && !acked[i]) {
+        return Err(TraceError::Unacknowledged(i).into());
+    }
+    Ok(TraceReport {
+        transactions: trace.len(),
+        frames: frames.len(),
+        retransmissions: trace.len() - frames.len(),
+        frames_acked: acked.iter().filter(|&&a| a).count(),
+    })
+}
+
+/// Confirms that every one of `frames` which the target is expected to acknowledge was
+/// acknowledged during `trace`.  Returns the number of acknowledged frames.
+pub(crate) fn verify_acks(frames: &[ExpectedFrame], trace: &[SpiTransaction]) -> Result<usize> {
+    let acked = acknowledged(frames, trace).iter().filter(|&&a| a).count();
+    let expected = frames.iter().filter(|f| f.ack.is_some()).count();
+    if acked != expected {
+        return Err(BootstrapError::VerifyUnacknowledged(acked, expected).into());
+    }
+    Ok(acked)
+}
+
+/// Determines which of `frames` were acknowledged by the target during `trace`.
+fn acknowledged(frames: &[ExpectedFrame], trace: &[SpiTransaction]) -> Vec<bool> {
+    let mut acked = vec![false; frames.len()];
+    for transaction in trace {
+        let ack = frames.iter().position(|f| {
+            f.ack
+                .as_ref()
+                .map_or(false, |ack| transaction.miso.starts_with(ack))
+        });
+        if let Some(i) = ack {
+            acked[i] = true;
+        }
+    }
+    acked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstrap::legacy::Legacy;
+    use crate::bootstrap::primitive::Primitive;
+    use crate::bootstrap::UpdateProtocol;
+    use std::time::Duration;
+
+    const SIMPLE_BIN: &[u8; 2048] = include_bytes!("simple.bin");
+
+    fn primitive_frames() -> Vec<ExpectedFrame> {
+        let primitive = Primitive {
+            inter_frame_delay: Duration::ZERO,
+            flash_erase_delay: Duration::ZERO,
+            verify: false,
+        };
+        primitive.expected_frames(SIMPLE_BIN).unwrap()
+    }
+
+    fn transaction(frame: &ExpectedFrame, ack: Option<&ExpectedFrame>) -> SpiTransaction {
+        let mut miso = vec![0xff; frame.data.len()];
+        if let Some(ack) = ack.and_then(|f| f.ack.as_ref()) {
+            miso[..ack.len()].copy_from_slice(ack);
+        }
+        SpiTransaction {
+            mosi: frame.data.clone(),
+            miso,
+        }
+    }
+
+    #[test]
+    fn test_primitive_trace() -> Result<()> {
+        let frames = primitive_frames();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].erase);
+        assert!(frames[1].ack.is_none());
+
+        // The first attempt at frame 1 reads back garbage, so frame 1 is sent again.
+        let trace = vec![
+            transaction(&frames[0], None),
+            transaction(&frames[1], None),
+            transaction(&frames[1], Some(&frames[0])),
+        ];
+        let report = validate_trace(&frames, &trace)?;
+        assert_eq!(report.retransmissions, 1);
+        assert_eq!(report.frames_acked, 1);
+
+        let err = validate_trace(&frames, &trace[..2]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TraceError>(),
+            Some(TraceError::Unacknowledged(0))
+        ));
+        let err = validate_trace(&frames, &trace[1..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TraceError>(),
+            Some(TraceError::OutOfOrder(0, 1, 0))
+        ));
+        let err = validate_trace(&frames, &trace[..1]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TraceError>(),
+            Some(TraceError::Incomplete(1, 2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_trace() -> Result<()> {
+        let legacy = Legacy {
+            inter_frame_delay: Duration::ZERO,
+            verify: false,
+        };
+        let frames = legacy.expected_frames(SIMPLE_BIN).unwrap();
+        assert!(frames.iter().all(|f| f.ack.is_some()));
+
+        // The final frame is acknowledged while it is being sent again.
+        let trace = vec![
+            transaction(&frames[0], None),
+            transaction(&frames[1], Some(&frames[0])),
+            transaction(&frames[1], Some(&frames[1])),
+        ];
+        let report = validate_trace(&frames, &trace)?;
+        assert_eq!(report.frames_acked, 2);
+
+        let mut trace = trace;
+        trace[2].mosi[100] ^= 1;
+        let err = validate_trace(&frames, &trace).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TraceError>(),
+            Some(TraceError::UnknownFrame(2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_acks() -> Result<()> {
+        let frames = primitive_frames();
+        let mut trace = vec![
+            transaction(&frames[0], None),
+            transaction(&frames[1], Some(&frames[0])),
+        ];
+        assert_eq!(verify_acks(&frames, &trace)?, 1);
+
+        // An acknowledgement with the wrong hash does not count.
+        trace[1].miso[0] ^= 1;
+        let err = verify_acks(&frames, &trace).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BootstrapError>(),
+            Some(BootstrapError::VerifyUnacknowledged(0, 1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_trace() -> Result<()> {
+        let trace = parse_trace("# captured trace\n0102 a0a1\n\n0304  # no miso\n")?;
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].mosi, [1, 2]);
+        assert_eq!(trace[0].miso, [0xa0, 0xa1]);
+        assert_eq!(trace[1].mosi, [3, 4]);
+        assert!(trace[1].miso.is_empty());
+
+        let err = parse_trace("0102\nzz\n").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TraceError>(),
+            Some(TraceError::Parse(2, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_frames() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let frames = primitive_frames();
+        export_frames(dir.path(), BootstrapProtocol::Primitive, &frames)?;
+        assert_eq!(fs::read(dir.path().join("frame_0001.bin"))?, frames[1].data);
+        assert_eq!(
+            fs::read(dir.path().join("frame_0000.ack"))?,
+            frames[0].ack.clone().unwrap()
+        );
+        assert!(!dir.path().join("frame_0001.ack").exists());
+        let manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(dir.path().join("frames.json"))?)?;
+        assert_eq!(manifest.frames.len(), 2);
+        assert_eq!(manifest.frames[1].frame_num, 0x8000_0001);
+        Ok(())
+    }
+}
@@ -9,7 +9,10 @@ use thiserror::Error;
 use zerocopy::AsBytes;
 
 use crate::app::TransportWrapper;
-use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol};
+use crate::bootstrap::frames::verify_acks;
+use crate::bootstrap::{
+    Bootstrap, BootstrapOptions, ExpectedFrame, SpiTransaction, UpdateProtocol, UpdateStats,
+};
 use crate::impl_serializable_error;
 use crate::io::spi::Transfer;
 use crate::transport::{Capability, ProgressIndicator};
@@ -123,6 +126,8 @@ pub struct Legacy {
     /// before OpenTitan tool could read the ACK of the final transaction.  1ms is what the
     /// spiflash.cc tool from cr50-utils uses.
     pub inter_frame_delay: Duration,
+    /// Whether to confirm that every frame was acknowledged.
+    pub verify: bool,
 }
 
 impl Legacy {
@@ -133,6 +138,7 @@ impl Legacy {
     pub fn new(options: &BootstrapOptions) -> Self {
         Self {
             inter_frame_delay: options.inter_frame_delay.unwrap_or(Self::INTER_FRAME_DELAY),
+            verify: options.verify,
         }
     }
 }
@@ -221,6 +227,10 @@ impl UpdateProtocol for Legacy {
         true
     }
 
+    fn supports_verify(&self) -> bool {
+        true
+    }
+
     /// Performs the update protocol using the `transport` with the firmware `payload`.
     fn update(
         &self,
@@ -228,10 +238,14 @@ impl UpdateProtocol for Legacy {
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<UpdateStats> {
         let spi = container.spi_params.create(transport, "BOOTSTRAP")?;
 
         let frames = Frame::from_payload(payload);
+        let mut stats = UpdateStats {
+            frames: frames.len(),
+            ..Default::default()
+        };
 
         // All frames up to but not including this index have been ack'ed by the bootloader.
         // Once this reaches frames.len(), the operation was successful.
@@ -251,6 +265,9 @@ impl UpdateProtocol for Legacy {
         // recent re-transmission.
         let mut becoming_optimistic = false;
 
+        // The transactions exchanged with the target, kept to check the acknowledgements.
+        let mut trace = Vec::new();
+
         progress.new_stage("", payload.len());
         loop {
             if consecutive_errors > Self::MAX_CONSECUTIVE_ERRORS {
@@ -271,6 +288,12 @@ impl UpdateProtocol for Legacy {
             progress.progress(frame.header.flash_offset as usize);
             let mut response = [0u8; std::mem::size_of::<Frame>()];
             spi.run_transaction(&mut [Transfer::Both(frame.as_bytes(), &mut response)])?;
+            if self.verify {
+                trace.push(SpiTransaction {
+                    mosi: frame.as_bytes().to_vec(),
+                    miso: response.to_vec(),
+                });
+            }
 
             if becoming_optimistic {
                 optimistic = true;
@@ -286,21 +309,25 @@ impl UpdateProtocol for Legacy {
                 match LegacyBootstrapError::from(response[0]) {
                     LegacyBootstrapError::NotReady => {
                         consecutive_errors += 1;
+                        stats.retries += 1;
                         continue; // Retry sending same frame.
                     }
                     error => return Err(error.into()),
                 }
             }
 
+            let previous_unacked_index = first_unacked_index;
             if response[..Frame::HASH_LEN] == frames[first_unacked_index].frame_hash() {
                 first_unacked_index += 1;
             } else if response[..Frame::HASH_LEN] == frames[second_unacked_index].frame_hash() {
                 first_unacked_index = second_unacked_index + 1;
             } else {
                 consecutive_errors += 1;
+                stats.retries += 1;
                 optimistic = false;
                 continue;
             }
+            stats.frames_acked += first_unacked_index - previous_unacked_index;
 
             consecutive_errors = 0;
             if first_unacked_index == frames.len() {
@@ -314,7 +341,26 @@ impl UpdateProtocol for Legacy {
         }
         progress.progress(payload.len());
         eprintln!("success");
-        Ok(())
+        if self.verify {
+            let frames = self.expected_frames(payload).unwrap();
+            verify_acks(&frames, &trace)?;
+            stats.verified = true;
+        }
+        Ok(stats)
+    }
+
+    fn expected_frames(&self, payload: &[u8]) -> Option<Vec<ExpectedFrame>> {
+        let expected = Frame::from_payload(payload)
+            .iter()
+            .map(|frame| ExpectedFrame {
+                frame_num: frame.header.frame_num,
+                flash_offset: frame.header.flash_offset,
+                data: frame.as_bytes().to_vec(),
+                ack: Some(frame.frame_hash().to_vec()),
+                erase: false,
+            })
+            .collect();
+        Some(expected)
     }
 }
 
@@ -7,7 +7,7 @@ use clap::{Args, ValueEnum};
 use humantime::parse_duration;
 use serde::{Deserialize, Serialize};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 use crate::app::{NoProgressBar, TransportWrapper};
@@ -16,12 +16,19 @@ use crate::io::gpio::GpioPin;
 use crate::io::spi::SpiParams;
 use crate::io::uart::UartParams;
 use crate::transport::{Capability, ProgressIndicator};
+use crate::uart::console::UartConsole;
 
 mod eeprom;
+mod frames;
 mod legacy;
 mod primitive;
 mod rescue;
+mod rom_ext_rescue;
 
+pub use frames::{
+    export_frames, parse_trace, validate_trace, ExpectedFrame, SpiTransaction, TraceError,
+    TraceReport,
+};
 pub use legacy::LegacyBootstrapError;
 pub use rescue::RescueError;
 
@@ -29,6 +36,12 @@ pub use rescue::RescueError;
 pub enum BootstrapError {
     #[error("Invalid hash length: {0}")]
     InvalidHashLength(usize),
+    #[error("Verification failed: {0} of {1} frames acknowledged")]
+    VerifyUnacknowledged(usize, usize),
+    #[error("Protocol {0:?} cannot verify the payload")]
+    VerifyUnsupported(BootstrapProtocol),
+    #[error("Protocol {0:?} does not use pre-computed frames")]
+    FramesUnsupported(BootstrapProtocol),
 }
 impl_serializable_error!(BootstrapError);
 
@@ -37,6 +50,7 @@ impl_serializable_error!(BootstrapError);
 /// The `Legacy` SPI protocol is used by previous generations of Google Titan-class chips.
 /// The `Eeprom` SPI protocol is planned to be implemented for OpenTitan.
 /// The `Rescue` UART protocol is used by Google Ti50 firmware.
+/// The `RomExtRescue` UART protocol is used by the OpenTitan ROM_EXT.
 /// The 'Emulator' value indicates that this tool has a direct way
 /// of communicating with the OpenTitan emulator, to replace the
 /// contents of the emulated flash storage.
@@ -46,9 +60,42 @@ pub enum BootstrapProtocol {
     Legacy,
     Eeprom,
     Rescue,
+    RomExtRescue,
     Emulator,
 }
 
+/// Statistics gathered by an `UpdateProtocol` while transferring a payload.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct UpdateStats {
+    /// Number of frames (or flash pages) making up the payload.
+    pub frames: usize,
+    /// Number of frames which had to be sent again.
+    pub retries: usize,
+    /// Number of frames whose receipt the target confirmed with a matching hash.
+    pub frames_acked: usize,
+    /// Whether the payload was confirmed to have arrived intact.
+    pub verified: bool,
+    /// Time spent reading back the payload, for protocols which support it.
+    #[serde(with = "humantime_serde")]
+    pub verify_time: Option<Duration>,
+}
+
+/// A summary of a bootstrap operation.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BootstrapReport {
+    pub protocol: Option<BootstrapProtocol>,
+    pub payload_len: usize,
+    pub stats: UpdateStats,
+    /// Time spent transferring (and verifying) the payload.
+    #[serde(with = "humantime_serde")]
+    pub transfer_time: Duration,
+    /// The console output which matched the `wait_for` regex.
+    pub console_match: Option<String>,
+    /// Time from the final reset until the console output matched.
+    #[serde(with = "humantime_serde")]
+    pub boot_time: Option<Duration>,
+}
+
 // Implementations of bootstrap need to implement the `UpdateProtocol` trait.
 trait UpdateProtocol {
     /// Called before any action is taken, to allow the protocol to verify that the transport
@@ -61,14 +108,27 @@ trait UpdateProtocol {
     /// Indicates whether the caller should assert the bootstrap pin and reset the chip, before
     /// invoking update().
     fn uses_common_bootstrap_reset(&self) -> bool;
+    /// Indicates whether the protocol can confirm that the payload arrived intact, by reading it
+    /// back or by checking the frame acknowledgements.  Verification is only requested from
+    /// protocols which return true.
+    fn supports_verify(&self) -> bool {
+        false
+    }
     /// Invoked to perform the actual transfer of an executable image to the OpenTitan chip.
+    /// If verification was requested, the protocol confirms that the payload arrived intact
+    /// before returning.
     fn update(
         &self,
         container: &Bootstrap,
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()>;
+    ) -> Result<UpdateStats>;
+    /// Returns the sequence of frames which `update()` sends for `payload`, for protocols which
+    /// send pre-computed frames.
+    fn expected_frames(&self, _payload: &[u8]) -> Option<Vec<ExpectedFrame>> {
+        None
+    }
 }
 
 /// Options which control bootstrap behavior.
@@ -104,6 +164,23 @@ pub struct BootstrapOptions {
     pub inter_frame_delay: Option<Duration>,
     #[arg(long, value_parser = parse_duration, help = "Duration of the flash-erase delay")]
     pub flash_erase_delay: Option<Duration>,
+    #[arg(
+        long,
+        help = "Only erase and program the flash blocks which differ from the payload. For EEPROM only."
+    )]
+    pub incremental: bool,
+    #[arg(
+        long,
+        help = "Confirm the payload after transfer: read back flash for EEPROM, require all frame acknowledgements for primitive and legacy."
+    )]
+    pub verify: bool,
+    #[arg(
+        long,
+        help = "After bootstrap, wait for console output matching this regex."
+    )]
+    pub wait_for: Option<String>,
+    #[arg(long, value_parser = parse_duration, default_value = "10s", help = "Timeout for --wait-for")]
+    pub wait_timeout: Duration,
 }
 
 /// Bootstrap wraps and drives the various bootstrap protocols.
@@ -115,6 +192,8 @@ pub struct Bootstrap<'a> {
     reset_pin: Rc<dyn GpioPin>,
     reset_delay: Duration,
     leave_in_reset: bool,
+    wait_for: Option<&'a str>,
+    wait_timeout: Duration,
 }
 
 impl<'a> Bootstrap<'a> {
@@ -124,7 +203,7 @@ impl<'a> Bootstrap<'a> {
         transport: &TransportWrapper,
         options: &BootstrapOptions,
         payload: &[u8],
-    ) -> Result<()> {
+    ) -> Result<BootstrapReport> {
         Self::update_with_progress(transport, options, payload, &NoProgressBar)
     }
 
@@ -136,7 +215,7 @@ impl<'a> Bootstrap<'a> {
         options: &BootstrapOptions,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<BootstrapReport> {
         if transport
             .capabilities()?
             .request(Capability::PROXY)
@@ -146,19 +225,19 @@ impl<'a> Bootstrap<'a> {
             // The transport happens to be connection to a remove opentitan session.  Pass
             // payload along with all relevant command line arguments to the remote session, and
             // it will run the actual bootstrapping logic.
+            let start = Instant::now();
             transport.proxy_ops()?.bootstrap(options, payload)?;
-            return Ok(());
+            return Ok(BootstrapReport {
+                protocol: Some(options.protocol),
+                payload_len: payload.len(),
+                transfer_time: start.elapsed(),
+                ..Default::default()
+            });
+        }
+        let updater = Self::updater(options);
+        if options.verify && !updater.supports_verify() {
+            return Err(BootstrapError::VerifyUnsupported(options.protocol).into());
         }
-        let updater: Box<dyn UpdateProtocol> = match options.protocol {
-            BootstrapProtocol::Primitive => Box::new(primitive::Primitive::new(options)),
-            BootstrapProtocol::Legacy => Box::new(legacy::Legacy::new(options)),
-            BootstrapProtocol::Rescue => Box::new(rescue::Rescue::new(options)),
-            BootstrapProtocol::Eeprom => Box::new(eeprom::Eeprom::new()),
-            BootstrapProtocol::Emulator => {
-                // Not intended to be implemented by this struct.
-                unimplemented!();
-            }
-        };
         Bootstrap {
             protocol: options.protocol,
             clear_uart_rx: options.clear_uart.unwrap_or(false),
@@ -167,17 +246,45 @@ impl<'a> Bootstrap<'a> {
             reset_pin: transport.gpio_pin("RESET")?,
             reset_delay: options.reset_delay,
             leave_in_reset: options.leave_in_reset,
+            wait_for: options.wait_for.as_deref(),
+            wait_timeout: options.wait_timeout,
         }
         .do_update(updater, transport, payload, progress)
     }
 
+    /// Computes the frames which would be sent to the target when bootstrapping `payload`
+    /// using the protocol given in `options`, without accessing any transport.
+    pub fn expected_frames(
+        options: &BootstrapOptions,
+        payload: &[u8],
+    ) -> Result<Vec<ExpectedFrame>> {
+        match Self::updater(options).expected_frames(payload) {
+            Some(frames) => Ok(frames),
+            None => Err(BootstrapError::FramesUnsupported(options.protocol).into()),
+        }
+    }
+
+    fn updater(options: &BootstrapOptions) -> Box<dyn UpdateProtocol> {
+        match options.protocol {
+            BootstrapProtocol::Primitive => Box::new(primitive::Primitive::new(options)),
+            BootstrapProtocol::Legacy => Box::new(legacy::Legacy::new(options)),
+            BootstrapProtocol::Rescue => Box::new(rescue::Rescue::new(options)),
+            BootstrapProtocol::RomExtRescue => Box::new(rom_ext_rescue::RomExtRescue::new(options)),
+            BootstrapProtocol::Eeprom => Box::new(eeprom::Eeprom::new(options)),
+            BootstrapProtocol::Emulator => {
+                // Not intended to be implemented by this struct.
+                unimplemented!();
+            }
+        }
+    }
+
     fn do_update(
         &self,
         updater: Box<dyn UpdateProtocol>,
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<BootstrapReport> {
         updater.verify_capabilities(self, transport)?;
         let perform_bootstrap_reset = updater.uses_common_bootstrap_reset();
         let rom_boot_strapping = transport.pin_strapping("ROM_BOOTSTRAP")?;
@@ -188,7 +295,9 @@ impl<'a> Bootstrap<'a> {
             transport.reset_target(self.reset_delay, self.clear_uart_rx)?;
             log::info!("Performing bootstrap...");
         }
+        let start = Instant::now();
         let result = updater.update(self, transport, payload, progress);
+        let transfer_time = start.elapsed();
 
         if perform_bootstrap_reset {
             if self.leave_in_reset {
@@ -200,8 +309,58 @@ impl<'a> Bootstrap<'a> {
             rom_boot_strapping.remove()?;
         }
 
+        // Open the console before the final reset, so that no output is missed.
+        let uart = match (&result, self.wait_for) {
+            (Ok(_), Some(_)) => Some(self.uart_params.create(transport)?),
+            _ => None,
+        };
+
         // Don't clear the UART RX buffer after bootstrap to preserve the bootstrap output.
         transport.reset_target(self.reset_delay, false)?;
-        result
+        let mut report = BootstrapReport {
+            protocol: Some(self.protocol),
+            payload_len: payload.len(),
+            stats: result?,
+            transfer_time,
+            ..Default::default()
+        };
+        if let (Some(uart), Some(rx)) = (uart, self.wait_for) {
+            log::info!("Waiting for console output matching {:?}...", rx);
+            let start = Instant::now();
+            report.console_match = Some(UartConsole::wait_for(&*uart, rx, self.wait_timeout)?);
+            report.boot_time = Some(start.elapsed());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Opts {
+        #[command(flatten)]
+        bootstrap: BootstrapOptions,
+    }
+
+    #[test]
+    fn test_supports_verify() {
+        for (protocol, supported) in [
+            ("eeprom", true),
+            ("primitive", true),
+            ("legacy", true),
+            ("rescue", false),
+            ("rom-ext-rescue", false),
+        ] {
+            let opts = Opts::parse_from(["bootstrap", "--protocol", protocol, "--verify"]);
+            assert!(opts.bootstrap.verify);
+            assert_eq!(
+                Bootstrap::updater(&opts.bootstrap).supports_verify(),
+                supported,
+                "{protocol}"
+            );
+        }
     }
 }
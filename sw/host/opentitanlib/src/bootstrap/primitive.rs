@@ -8,7 +8,10 @@ use std::time::Duration;
 use zerocopy::AsBytes;
 
 use crate::app::TransportWrapper;
-use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol};
+use crate::bootstrap::frames::verify_acks;
+use crate::bootstrap::{
+    Bootstrap, BootstrapOptions, ExpectedFrame, SpiTransaction, UpdateProtocol, UpdateStats,
+};
 use crate::io::spi::Transfer;
 use crate::transport::{Capability, ProgressIndicator};
 
@@ -89,6 +92,7 @@ impl Frame {
 pub struct Primitive {
     pub inter_frame_delay: Duration,
     pub flash_erase_delay: Duration,
+    pub verify: bool,
 }
 
 impl Primitive {
@@ -102,6 +106,7 @@ impl Primitive {
         Primitive {
             inter_frame_delay: options.inter_frame_delay.unwrap_or(Self::INTER_FRAME_DELAY),
             flash_erase_delay: options.flash_erase_delay.unwrap_or(Self::FLASH_ERASE_DELAY),
+            verify: options.verify,
         }
     }
 }
@@ -123,6 +128,10 @@ impl UpdateProtocol for Primitive {
         true
     }
 
+    fn supports_verify(&self) -> bool {
+        true
+    }
+
     /// Performs the update protocol using the `transport` with the firmware `payload`.
     fn update(
         &self,
@@ -130,10 +139,16 @@ impl UpdateProtocol for Primitive {
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<UpdateStats> {
         let spi = container.spi_params.create(transport, "BOOTSTRAP")?;
 
         let frames = Frame::from_payload(payload);
+        let mut stats = UpdateStats {
+            frames: frames.len(),
+            ..Default::default()
+        };
+        // The transactions exchanged with the target, kept to check the acknowledgements.
+        let mut trace = Vec::new();
 
         progress.new_stage("", payload.len());
         let mut i = 0;
@@ -149,6 +164,12 @@ impl UpdateProtocol for Primitive {
             progress.progress(frame.header.flash_offset as usize);
             let mut prev_hash = [0u8; std::mem::size_of::<Frame>()];
             spi.run_transaction(&mut [Transfer::Both(frame.as_bytes(), &mut prev_hash)])?;
+            if self.verify {
+                trace.push(SpiTransaction {
+                    mosi: frame.as_bytes().to_vec(),
+                    miso: prev_hash.to_vec(),
+                });
+            }
 
             if i == 0 {
                 // If its the first frame, there is no hash to check.
@@ -166,12 +187,38 @@ impl UpdateProtocol for Primitive {
                     &prev_hash[..Frame::HASH_LEN],
                     want_hash
                 );
+                stats.retries += 1;
                 continue;
             }
+            stats.frames_acked += 1;
             i += 1;
         }
         progress.progress(payload.len());
-        Ok(())
+        if self.verify {
+            // The final frame is never acknowledged: the ROM stops listening once it has received
+            // it.
+            let frames = self.expected_frames(payload).unwrap();
+            verify_acks(&frames, &trace)?;
+            stats.verified = true;
+        }
+        Ok(stats)
+    }
+
+    fn expected_frames(&self, payload: &[u8]) -> Option<Vec<ExpectedFrame>> {
+        let frames = Frame::from_payload(payload);
+        let expected = frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| ExpectedFrame {
+                frame_num: frame.header.frame_num,
+                flash_offset: frame.header.flash_offset,
+                data: frame.as_bytes().to_vec(),
+                // The ROM stops listening after the final frame, so never acknowledges it.
+                ack: (i + 1 < frames.len()).then(|| frame.frame_hash().to_vec()),
+                erase: i == 0,
+            })
+            .collect();
+        Some(expected)
     }
 }
 
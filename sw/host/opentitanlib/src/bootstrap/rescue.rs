@@ -9,7 +9,7 @@ use thiserror::Error;
 use zerocopy::AsBytes;
 
 use crate::app::TransportWrapper;
-use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol};
+use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol, UpdateStats};
 use crate::impl_serializable_error;
 use crate::io::uart::Uart;
 use crate::transport::{Capability, ProgressIndicator};
@@ -308,9 +308,13 @@ impl UpdateProtocol for Rescue {
         transport: &TransportWrapper,
         payload: &[u8],
         progress: &dyn ProgressIndicator,
-    ) -> Result<()> {
+    ) -> Result<UpdateStats> {
         let frames = Frame::from_payload(payload)?;
         let uart = container.uart_params.create(transport)?;
+        let mut stats = UpdateStats {
+            frames: frames.len(),
+            ..Default::default()
+        };
 
         self.enter_rescue_mode(container, &*uart)?;
 
@@ -318,6 +322,9 @@ impl UpdateProtocol for Rescue {
         progress.new_stage("", frames.len() * Frame::DATA_LEN);
         'next_block: for (idx, frame) in frames.iter().enumerate() {
             for consecutive_errors in 0..Self::MAX_CONSECUTIVE_ERRORS {
+                if consecutive_errors > 0 {
+                    stats.retries += 1;
+                }
                 eprint!("{}.", idx);
                 progress.progress(idx * Frame::DATA_LEN);
                 uart.write(frame.as_bytes())?;
@@ -343,6 +350,7 @@ impl UpdateProtocol for Rescue {
                     eprint!("sync.");
                     self.synchronize(&*uart)?;
                 } else if response == frame.frame_hash() {
+                    stats.frames_acked += 1;
                     continue 'next_block;
                 } else {
                     self.flush_rx(&*uart);
@@ -363,6 +371,6 @@ impl UpdateProtocol for Rescue {
         }
         progress.progress(frames.len() * Frame::DATA_LEN);
         eprintln!("Success!");
-        Ok(())
+        Ok(stats)
     }
 }
@@ -0,0 +1,61 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+
+use crate::app::TransportWrapper;
+use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol, UpdateStats};
+use crate::rescue::RescueSerial;
+use crate::transport::{Capability, ProgressIndicator};
+
+/// Implements the UART rescue protocol of the OpenTitan ROM_EXT.
+pub struct RomExtRescue {}
+
+impl RomExtRescue {
+    /// Creates a new `RomExtRescue` protocol updater from `options`.
+    pub fn new(_options: &BootstrapOptions) -> Self {
+        RomExtRescue {}
+    }
+}
+
+impl UpdateProtocol for RomExtRescue {
+    fn verify_capabilities(
+        &self,
+        _container: &Bootstrap,
+        transport: &TransportWrapper,
+    ) -> Result<()> {
+        transport
+            .capabilities()?
+            .request(Capability::GPIO | Capability::UART)
+            .ok()?;
+        Ok(())
+    }
+
+    /// Returns false, as rescue mode is requested by a UART break during reset rather than by
+    /// the bootstrap pins.
+    fn uses_common_bootstrap_reset(&self) -> bool {
+        false
+    }
+
+    /// Performs the update protocol using the `transport` with the firmware `payload`.
+    fn update(
+        &self,
+        container: &Bootstrap,
+        transport: &TransportWrapper,
+        payload: &[u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<UpdateStats> {
+        let uart = container.uart_params.create(transport)?;
+        let rescue = RescueSerial::new(uart);
+        rescue.enter(transport, container.reset_delay)?;
+        let stats = rescue.update_firmware_with_progress(payload, progress)?;
+        // The ROM_EXT cannot read back the firmware, so the payload is never verified.
+        Ok(UpdateStats {
+            frames: stats.blocks,
+            retries: stats.retries,
+            frames_acked: stats.blocks_acked,
+            ..Default::default()
+        })
+    }
+}
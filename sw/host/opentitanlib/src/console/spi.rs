@@ -5,26 +5,111 @@
 use anyhow::Result;
 use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
 use std::time::Duration;
 
 use crate::io::console::ConsoleDevice;
+use crate::io::gpio::{Edge, GpioMonitoring, GpioPin};
 use crate::io::spi::{Target, Transfer};
 
+/// GPIO pin asserted by the device when it has console data to send.
+struct ReadyPin {
+    monitoring: Rc<dyn GpioMonitoring>,
+    pin: Rc<dyn GpioPin>,
+    started: Cell<bool>,
+    /// Whether the pin has been asserted since the last read which found no data.
+    ready: Cell<bool>,
+}
+
 pub struct SpiConsoleDevice<'a> {
     spi: &'a dyn Target,
     console_next_frame_number: Cell<u32>,
     rx_buf: RefCell<VecDeque<u8>>,
+    ready_pin: Option<ReadyPin>,
+    poll_interval: Duration,
+    /// Becomes readable every poll interval once the device has been registered with `mio`.
+    poll_timer: RefCell<Option<mio::unix::pipe::Receiver>>,
 }
 
 impl<'a> SpiConsoleDevice<'a> {
     const SPI_FRAME_HEADER_SIZE: usize = 8;
     const SPI_MAX_DATA_LENGTH: usize = 2032;
 
+    const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
     pub fn new(spi: &'a dyn Target) -> Self {
         Self {
             spi,
             rx_buf: RefCell::new(VecDeque::new()),
             console_next_frame_number: Cell::new(0),
+            ready_pin: None,
+            poll_interval: Self::DEFAULT_POLL_INTERVAL,
+            poll_timer: RefCell::new(None),
+        }
+    }
+
+    /// Sets how often the device is polled for data when used with `mio`.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Only reads frames from the device after `pin` has been seen asserted, rather than on
+    /// every poll.
+    pub fn with_ready_pin(
+        mut self,
+        monitoring: Rc<dyn GpioMonitoring>,
+        pin: Rc<dyn GpioPin>,
+    ) -> Self {
+        self.ready_pin = Some(ReadyPin {
+            monitoring,
+            pin,
+            started: Cell::new(false),
+            ready: Cell::new(false),
+        });
+        self
+    }
+
+    /// Returns whether the device may have data, according to the ready pin.
+    fn check_ready(&self) -> Result<bool> {
+        let Some(ready_pin) = &self.ready_pin else {
+            return Ok(true);
+        };
+        let pins = [ready_pin.pin.as_ref()];
+        if !ready_pin.started.get() {
+            let start = ready_pin.monitoring.monitoring_start(&pins)?;
+            ready_pin.started.set(true);
+            ready_pin.ready.set(start.initial_levels[0]);
+        }
+        let response = ready_pin.monitoring.monitoring_read(&pins, true)?;
+        if response.events.iter().any(|e| e.edge == Edge::Rising) {
+            ready_pin.ready.set(true);
+        }
+        Ok(ready_pin.ready.get())
+    }
+
+    /// Starts a thread which makes the returned pipe readable every `poll_interval`.  The thread
+    /// exits once the pipe is closed.
+    fn start_poll_timer(poll_interval: Duration) -> Result<mio::unix::pipe::Receiver> {
+        let (mut sender, receiver) = mio::unix::pipe::new()?;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(poll_interval);
+            match sender.write(&[0]) {
+                Ok(_) => (),
+                // A full pipe is readable already.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(_) => break,
+            }
+        });
+        Ok(receiver)
+    }
+
+    /// Empties the poll timer pipe, so that `mio` reports the next tick.
+    fn drain_poll_timer(&self) {
+        if let Some(receiver) = self.poll_timer.borrow_mut().as_mut() {
+            let mut buf = [0u8; 64];
+            while matches!(receiver.read(&mut buf), Ok(len) if len > 0) {}
         }
     }
 
@@ -57,9 +142,18 @@ impl<'a> SpiConsoleDevice<'a> {
 
 impl<'a> ConsoleDevice for SpiConsoleDevice<'a> {
     fn console_read(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+        self.drain_poll_timer();
         // Attempt to refill the internal data queue if it is empty.
-        if self.rx_buf.borrow().is_empty() && self.read_from_spi()? == 0 {
-            return Ok(0);
+        if self.rx_buf.borrow().is_empty() {
+            if !self.check_ready()? {
+                return Ok(0);
+            }
+            if self.read_from_spi()? == 0 {
+                if let Some(ready_pin) = &self.ready_pin {
+                    ready_pin.ready.set(false);
+                }
+                return Ok(0);
+            }
         }
 
         // Copy from the internal data queue to the output buffer.
@@ -71,4 +165,238 @@ impl<'a> ConsoleDevice for SpiConsoleDevice<'a> {
 
         Ok(i)
     }
+
+    fn supports_nonblocking_read(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// The SPI bus cannot signal data on its own, so a pipe which a thread writes to every poll
+    /// interval is registered instead, and the device is then read by `console_read()` on the
+    /// calling thread.  Registering again replaces the pipe, and so stops the thread serving the
+    /// previous registration, as does dropping the device.
+    fn register_nonblocking_read(&self, registry: &mio::Registry, token: mio::Token) -> Result<()> {
+        let mut receiver = Self::start_poll_timer(self.poll_interval)?;
+        registry.register(&mut receiver, token, mio::Interest::READABLE)?;
+        *self.poll_timer.borrow_mut() = Some(receiver);
+        Ok(())
+    }
+}
+
+impl<'a> Drop for SpiConsoleDevice<'a> {
+    fn drop(&mut self) {
+        if let Some(ready_pin) = &self.ready_pin {
+            if ready_pin.started.get() {
+                let _ = ready_pin
+                    .monitoring
+                    .monitoring_read(&[ready_pin.pin.as_ref()], false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gpio::{
+        ClockNature, MonitoringEvent, MonitoringReadResponse, MonitoringStartResponse, PinMode,
+        PullMode,
+    };
+    use crate::io::spi::{AssertChipSelect, MaxSizes, TransferMode};
+    use crate::uart::console::{ExitStatus, UartConsole};
+    use regex::Regex;
+
+    /// Device which sends one frame per chunk of `chunks`, and empty frames once they run out.
+    #[derive(Default)]
+    struct FakeSpi {
+        chunks: RefCell<VecDeque<&'static str>>,
+        frame_number: Cell<u32>,
+        /// Data of the frame whose header was read last.
+        data: RefCell<Option<Vec<u8>>>,
+        frames: Cell<usize>,
+    }
+
+    impl FakeSpi {
+        fn new(chunks: &[&'static str]) -> Self {
+            FakeSpi {
+                chunks: RefCell::new(chunks.iter().copied().collect()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Target for FakeSpi {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            unimplemented!()
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            unimplemented!()
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            unimplemented!()
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            unimplemented!()
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            unimplemented!()
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            unimplemented!()
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            unimplemented!()
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            unimplemented!()
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            unimplemented!()
+        }
+
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            let [Transfer::Write(_), Transfer::Read(buf)] = transaction else {
+                panic!("unexpected transaction");
+            };
+            if let Some(mut data) = self.data.borrow_mut().take() {
+                data.resize(buf.len(), 0);
+                buf.copy_from_slice(&data);
+                return Ok(());
+            }
+            let data = self.chunks.borrow_mut().pop_front().unwrap_or("");
+            buf[0..4].copy_from_slice(&self.frame_number.get().to_le_bytes());
+            buf[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            self.frame_number.set(self.frame_number.get() + 1);
+            self.frames.set(self.frames.get() + 1);
+            *self.data.borrow_mut() = Some(data.as_bytes().to_vec());
+            Ok(())
+        }
+    }
+
+    struct FakePin;
+
+    impl GpioPin for FakePin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!()
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            unimplemented!()
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            unimplemented!()
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            unimplemented!()
+        }
+    }
+
+    /// Monitoring of a single pin starting at `initial_level`, which reports the edges in
+    /// `edges` on the next read.
+    #[derive(Default)]
+    struct FakeMonitoring {
+        initial_level: bool,
+        edges: RefCell<Vec<Edge>>,
+        stopped: Cell<bool>,
+    }
+
+    impl GpioMonitoring for FakeMonitoring {
+        fn get_clock_nature(&self) -> Result<ClockNature> {
+            Ok(ClockNature::Unspecified)
+        }
+
+        fn monitoring_start(&self, _pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
+            Ok(MonitoringStartResponse {
+                timestamp: 0,
+                initial_levels: vec![self.initial_level],
+            })
+        }
+
+        fn monitoring_read(
+            &self,
+            _pins: &[&dyn GpioPin],
+            continue_monitoring: bool,
+        ) -> Result<MonitoringReadResponse> {
+            self.stopped.set(!continue_monitoring);
+            let edges = std::mem::take(&mut *self.edges.borrow_mut());
+            Ok(MonitoringReadResponse {
+                events: edges
+                    .into_iter()
+                    .map(|edge| MonitoringEvent {
+                        signal_index: 0,
+                        edge,
+                        timestamp: 0,
+                    })
+                    .collect(),
+                timestamp: 0,
+            })
+        }
+    }
+
+    #[test]
+    fn test_check_ready() -> Result<()> {
+        let spi = FakeSpi::new(&["hello"]);
+        let monitoring = Rc::new(FakeMonitoring::default());
+        let device =
+            SpiConsoleDevice::new(&spi).with_ready_pin(monitoring.clone(), Rc::new(FakePin));
+        let mut buf = [0u8; 16];
+
+        // The device is not read until the pin rises.
+        assert_eq!(device.console_read(&mut buf, Duration::ZERO)?, 0);
+        monitoring.edges.borrow_mut().push(Edge::Falling);
+        assert_eq!(device.console_read(&mut buf, Duration::ZERO)?, 0);
+        assert_eq!(spi.frames.get(), 0);
+
+        monitoring.edges.borrow_mut().push(Edge::Rising);
+        assert_eq!(device.console_read(&mut buf, Duration::ZERO)?, 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(device.check_ready()?);
+
+        // An empty frame clears the ready state.
+        assert_eq!(device.console_read(&mut buf, Duration::ZERO)?, 0);
+        assert_eq!(spi.frames.get(), 2);
+        assert!(!device.check_ready()?);
+        assert_eq!(device.console_read(&mut buf, Duration::ZERO)?, 0);
+        assert_eq!(spi.frames.get(), 2);
+
+        drop(device);
+        assert!(monitoring.stopped.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interact_nonblocking() -> Result<()> {
+        let spi = FakeSpi::new(&["boot\r\n", "PASS\r\n"]);
+        let monitoring = Rc::new(FakeMonitoring {
+            initial_level: true,
+            ..Default::default()
+        });
+        let device = SpiConsoleDevice::new(&spi)
+            .with_poll_interval(Duration::from_millis(1))
+            .with_ready_pin(monitoring, Rc::new(FakePin));
+        assert!(device.supports_nonblocking_read()?);
+
+        let mut console = UartConsole {
+            timeout: Some(Duration::from_secs(10)),
+            exit_success: Some(Regex::new(r"PASS")?),
+            ..Default::default()
+        };
+        let mut stdout = Vec::new();
+        assert_eq!(
+            console.interact(&device, None, Some(&mut stdout))?,
+            ExitStatus::ExitSuccess
+        );
+        assert_eq!(stdout, b"boot\r\nPASS\r\n");
+
+        // A later session registers the same device with a new `mio::Poll`.
+        spi.chunks.borrow_mut().push_back("again\r\n");
+        let mut console = UartConsole {
+            timeout: Some(Duration::from_secs(10)),
+            exit_success: Some(Regex::new(r"again")?),
+            ..Default::default()
+        };
+        assert_eq!(
+            console.interact(&device, None, None)?,
+            ExitStatus::ExitSuccess
+        );
+        Ok(())
+    }
 }
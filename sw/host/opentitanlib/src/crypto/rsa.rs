@@ -3,12 +3,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
 use num_bigint_dig::{traits::ModInverse, BigInt, BigUint, Sign::Minus};
 use rand::rngs::OsRng;
 use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
 use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use rsa::pss::Pss;
 use rsa::{pkcs1v15::Pkcs1v15Sign, PublicKey, PublicKeyParts};
-use sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::ops::Deref;
@@ -63,6 +66,73 @@ pub enum Error {
     VerifyFailed(#[source] anyhow::Error),
     #[error("Failed to compute key component")]
     KeyComponentComputeFailed,
+    #[error("Bad digest size: expected {0} bytes, but got {1} bytes")]
+    DigestSizeError(usize, usize),
+}
+
+/// Hash algorithm used to compute the digest being signed or verified.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha256 => <Sha256 as Digest>::output_size(),
+            Self::Sha384 => <Sha384 as Digest>::output_size(),
+            Self::Sha512 => <Sha512 as Digest>::output_size(),
+        }
+    }
+
+    /// Computes the digest of `data`, returned as big-endian bytes.
+    pub fn digest(&self, data: impl AsRef<[u8]>) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha384 => Sha384::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+
+    fn check_len(&self, digest: &[u8]) -> Result<()> {
+        if digest.len() != self.digest_len() {
+            bail!(Error::DigestSizeError(self.digest_len(), digest.len()));
+        }
+        Ok(())
+    }
+
+    fn pkcs1v15(&self) -> Pkcs1v15Sign {
+        match self {
+            Self::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+            Self::Sha384 => Pkcs1v15Sign::new::<Sha384>(),
+            Self::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+        }
+    }
+
+    fn pss(&self, salt_len: Option<usize>) -> Pss {
+        match (self, salt_len) {
+            (Self::Sha256, None) => Pss::new::<Sha256>(),
+            (Self::Sha384, None) => Pss::new::<Sha384>(),
+            (Self::Sha512, None) => Pss::new::<Sha512>(),
+            (Self::Sha256, Some(len)) => Pss::new_with_salt::<Sha256>(len),
+            (Self::Sha384, Some(len)) => Pss::new_with_salt::<Sha384>(len),
+            (Self::Sha512, Some(len)) => Pss::new_with_salt::<Sha512>(len),
+        }
+    }
+}
+
+/// RSA signature padding scheme.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ValueEnum)]
+pub enum PaddingScheme {
+    /// PKCS#1 v1.5 signature padding.
+    #[default]
+    Pkcs1v15,
+    /// RSASSA-PSS signature padding with MGF1 using the digest's hash algorithm.
+    Pss,
 }
 
 /// Ensure the components of `key` have the correct bit length.
@@ -165,13 +235,33 @@ impl RsaPublicKey {
 
     /// Verify a `signature` is valid for a given `digest` under this key.
     pub fn verify(&self, digest: &Sha256Digest, signature: &Signature) -> Result<()> {
-        self.key
-            .verify(
-                Pkcs1v15Sign::new::<Sha256>(),
-                digest.to_be_bytes().as_slice(),
-                signature.to_be_bytes().as_slice(),
-            )
-            .map_err(|e| anyhow!(Error::VerifyFailed(anyhow!(e))))
+        self.verify_digest(
+            HashAlgorithm::Sha256,
+            PaddingScheme::Pkcs1v15,
+            None,
+            digest.to_be_bytes().as_slice(),
+            signature,
+        )
+    }
+
+    /// Verify a `signature` is valid for a big-endian `digest` computed with `hash`.
+    ///
+    /// For PSS padding, `salt_len` is detected from the signature if not given.
+    pub fn verify_digest(
+        &self,
+        hash: HashAlgorithm,
+        padding: PaddingScheme,
+        salt_len: Option<usize>,
+        digest: &[u8],
+        signature: &Signature,
+    ) -> Result<()> {
+        hash.check_len(digest)?;
+        let signature = signature.to_be_bytes();
+        let result = match padding {
+            PaddingScheme::Pkcs1v15 => self.key.verify(hash.pkcs1v15(), digest, &signature),
+            PaddingScheme::Pss => self.key.verify(hash.pss(salt_len), digest, &signature),
+        };
+        result.map_err(|e| anyhow!(Error::VerifyFailed(anyhow!(e))))
     }
 }
 
@@ -222,10 +312,34 @@ impl RsaPrivateKey {
 
     /// Signs a SHA256 `digest` using PKCS1v15 padding scheme.
     pub fn sign(&self, digest: &Sha256Digest) -> Result<Signature> {
-        let signature = self
-            .key
-            .sign(Pkcs1v15Sign::new::<Sha256>(), &digest.to_be_bytes())
-            .map_err(|e| Error::SignFailed(anyhow!(e)))?;
+        self.sign_digest(
+            HashAlgorithm::Sha256,
+            PaddingScheme::Pkcs1v15,
+            None,
+            &digest.to_be_bytes(),
+        )
+    }
+
+    /// Signs a big-endian `digest` computed with `hash` using the given padding scheme.
+    ///
+    /// For PSS padding, `salt_len` defaults to the digest length.
+    pub fn sign_digest(
+        &self,
+        hash: HashAlgorithm,
+        padding: PaddingScheme,
+        salt_len: Option<usize>,
+        digest: &[u8],
+    ) -> Result<Signature> {
+        hash.check_len(digest)?;
+        let signature = match padding {
+            PaddingScheme::Pkcs1v15 => self.key.sign(hash.pkcs1v15(), digest),
+            PaddingScheme::Pss => self.key.sign_with_rng(
+                &mut OsRng,
+                hash.pss(Some(salt_len.unwrap_or_else(|| hash.digest_len()))),
+                digest,
+            ),
+        }
+        .map_err(|e| Error::SignFailed(anyhow!(e)))?;
         Ok(Signature::from_be_bytes(signature)?)
     }
 }
@@ -271,3 +385,70 @@ impl Deref for RsaPrivateKey {
         &self.key
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private_key() -> Result<RsaPrivateKey> {
+        let mut path = PathBuf::from(file!());
+        path.pop();
+        path.push("../image/testdata/alice.der");
+        RsaPrivateKey::from_pkcs8_der_file(path)
+    }
+
+    #[test]
+    fn test_sign_verify() -> Result<()> {
+        let private_key = private_key()?;
+        let public_key = RsaPublicKey::from_private_key(&private_key);
+        for hash in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ] {
+            let digest = hash.digest(b"test message");
+            for padding in [PaddingScheme::Pkcs1v15, PaddingScheme::Pss] {
+                let signature = private_key.sign_digest(hash, padding, None, &digest)?;
+                public_key.verify_digest(hash, padding, None, &digest, &signature)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_mismatch() -> Result<()> {
+        let private_key = private_key()?;
+        let public_key = RsaPublicKey::from_private_key(&private_key);
+        let hash = HashAlgorithm::Sha384;
+        let digest = hash.digest(b"test message");
+        let signature = private_key.sign_digest(hash, PaddingScheme::Pss, None, &digest)?;
+
+        // The wrong padding scheme.
+        assert!(public_key
+            .verify_digest(hash, PaddingScheme::Pkcs1v15, None, &digest, &signature)
+            .is_err());
+        // The wrong hash algorithm, over a digest of the right length.
+        let digest = &digest[..HashAlgorithm::Sha256.digest_len()];
+        assert!(public_key
+            .verify_digest(
+                HashAlgorithm::Sha256,
+                PaddingScheme::Pss,
+                None,
+                digest,
+                &signature
+            )
+            .is_err());
+        // The wrong hash algorithm, over the message.
+        let digest = HashAlgorithm::Sha512.digest(b"test message");
+        assert!(public_key
+            .verify_digest(
+                HashAlgorithm::Sha512,
+                PaddingScheme::Pss,
+                None,
+                &digest,
+                &signature
+            )
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,34 +1,129 @@
 // Copyright lowRISC contributors.
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
+use std::fmt;
+use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use pem_rfc7468::{Decoder, Encoder, LineEnding};
+use serde::{Deserialize, Serialize};
 
-use pqcrypto_sphincsplus::sphincsshake256128ssimple as spx;
 use pqcrypto_traits::sign::DetachedSignature;
 use pqcrypto_traits::sign::PublicKey;
 use pqcrypto_traits::sign::SecretKey;
 
-use crate::util::bigint::fixed_size_bigint;
 use crate::util::file::{FromReader, PemSerilizable, ToWriter};
 
 // Signature and key sizes taken from Table 8 on page 57 of the SPHINCS+ Round 3 Specification:
 // https://sphincs.org/data/sphincs+-round3-specification.pdf
 const PUBLIC_KEY_BYTE_LEN: usize = 32;
 const SECRET_KEY_BYTE_LEN: usize = 64;
-const SIGNATURE_BIT_LEN: usize = 7856 * 8;
-fixed_size_bigint!(Signature, at_most SIGNATURE_BIT_LEN);
+const SIGNATURE_BYTE_LEN_128S: usize = 7856;
+const SIGNATURE_BYTE_LEN_128F: usize = 17088;
+
+/// Name of the PEM header recording the SPHINCS+ parameter set of a key.
+const PEM_PARAMS_HEADER: &str = "SPHINCS+-Params";
+
+/// The supported SPHINCS+ parameter sets.
+///
+/// All parameter sets use the "simple" tweakable hash construction and target NIST security
+/// level 1 (128-bit).  `Small` sets produce small signatures while `Fast` sets sign quickly.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ValueEnum)]
+pub enum SpxParams {
+    #[value(name = "sha2-128s-simple")]
+    #[serde(rename = "sha2-128s-simple")]
+    Sha2Small,
+    #[value(name = "sha2-128f-simple")]
+    #[serde(rename = "sha2-128f-simple")]
+    Sha2Fast,
+    #[default]
+    #[value(name = "shake-128s-simple")]
+    #[serde(rename = "shake-128s-simple")]
+    ShakeSmall,
+    #[value(name = "shake-128f-simple")]
+    #[serde(rename = "shake-128f-simple")]
+    ShakeFast,
+}
+
+/// Evaluates `$body` with `$m` bound to the `pqcrypto_sphincsplus` module for `$params`.
+macro_rules! with_params {
+    ($params:expr, $m:ident => $body:expr) => {
+        match $params {
+            SpxParams::Sha2Small => {
+                use pqcrypto_sphincsplus::sphincssha256128ssimple as $m;
+                $body
+            }
+            SpxParams::Sha2Fast => {
+                use pqcrypto_sphincsplus::sphincssha256128fsimple as $m;
+                $body
+            }
+            SpxParams::ShakeSmall => {
+                use pqcrypto_sphincsplus::sphincsshake256128ssimple as $m;
+                $body
+            }
+            SpxParams::ShakeFast => {
+                use pqcrypto_sphincsplus::sphincsshake256128fsimple as $m;
+                $body
+            }
+        }
+    };
+}
+
+impl SpxParams {
+    /// The canonical name of the parameter set.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpxParams::Sha2Small => "sha2-128s-simple",
+            SpxParams::Sha2Fast => "sha2-128f-simple",
+            SpxParams::ShakeSmall => "shake-128s-simple",
+            SpxParams::ShakeFast => "shake-128f-simple",
+        }
+    }
+
+    /// Length of a public key in bytes.
+    pub fn public_key_len(&self) -> usize {
+        PUBLIC_KEY_BYTE_LEN
+    }
+
+    /// Length of a secret key in bytes.
+    pub fn secret_key_len(&self) -> usize {
+        SECRET_KEY_BYTE_LEN
+    }
+
+    /// Length of a signature in bytes.
+    pub fn signature_len(&self) -> usize {
+        match self {
+            SpxParams::Sha2Small | SpxParams::ShakeSmall => SIGNATURE_BYTE_LEN_128S,
+            SpxParams::Sha2Fast | SpxParams::ShakeFast => SIGNATURE_BYTE_LEN_128F,
+        }
+    }
+}
+
+impl fmt::Display for SpxParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SpxParams {
+    type Err = SpxError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, false).map_err(|_| SpxError::UnknownParams(s.into()))
+    }
+}
 
 /// Trait for implementing public key operations.
 pub trait SpxPublicKeyPart {
-    /// Returns the public key component.
-    fn pk(&self) -> &spx::PublicKey;
+    /// Returns the parameter set of the key.
+    fn params(&self) -> SpxParams;
 
-    fn pk_as_bytes(&self) -> &[u8] {
-        self.pk().as_bytes()
-    }
+    /// Returns the public key component.
+    fn pk_as_bytes(&self) -> &[u8];
 
     fn pk_len(&self) -> usize {
         self.pk_as_bytes().len()
@@ -36,11 +131,18 @@ pub trait SpxPublicKeyPart {
 
     /// Verify a message signature, returning Ok(()) if the signature matches.
     fn verify(&self, message: &[u8], sig: &SpxSignature) -> Result<()> {
-        spx::verify_detached_signature(
-            &spx::DetachedSignature::from_bytes(&sig.0.to_le_bytes())?,
-            message,
-            self.pk(),
-        )?;
+        let params = self.params();
+        ensure!(
+            sig.len() == params.signature_len(),
+            SpxError::SignatureLength(params, sig.len())
+        );
+        with_params!(params, spx => {
+            spx::verify_detached_signature(
+                &spx::DetachedSignature::from_bytes(sig.as_bytes())?,
+                message,
+                &spx::PublicKey::from_bytes(self.pk_as_bytes())?,
+            )?
+        });
         Ok(())
     }
 }
@@ -52,10 +154,17 @@ pub enum SpxKey {
 }
 
 impl SpxPublicKeyPart for SpxKey {
-    fn pk(&self) -> &spx::PublicKey {
+    fn params(&self) -> SpxParams {
         match self {
-            SpxKey::Public(k) => k.pk(),
-            SpxKey::Private(k) => k.pk(),
+            SpxKey::Public(k) => k.params(),
+            SpxKey::Private(k) => k.params(),
+        }
+    }
+
+    fn pk_as_bytes(&self) -> &[u8] {
+        match self {
+            SpxKey::Public(k) => k.pk_as_bytes(),
+            SpxKey::Private(k) => k.pk_as_bytes(),
         }
     }
 }
@@ -64,6 +173,12 @@ impl SpxPublicKeyPart for SpxKey {
 pub enum SpxError {
     #[error("SPHINCS+ key load error\nPublic key: {0}\nKey pair: {1}")]
     LoadError(anyhow::Error, anyhow::Error),
+    #[error("Unknown SPHINCS+ parameter set {0:?}")]
+    UnknownParams(String),
+    #[error("SPHINCS+ parameter set mismatch: expected {0} but the key uses {1}")]
+    ParamsMismatch(SpxParams, SpxParams),
+    #[error("Invalid {0} signature length {1}")]
+    SignatureLength(SpxParams, usize),
 }
 
 /// Given the path to either a SPHINCS+ public key or full keypair returns the appropriate `SpxKey`.
@@ -77,34 +192,137 @@ pub fn load_spx_key(key_file: &Path) -> Result<SpxKey> {
     })
 }
 
+/// Writes `bytes` to a PEM file, recording `params` in an encapsulated header.
+///
+/// The header is omitted for the default parameter set so that such files remain readable by
+/// tools that don't understand PEM headers.
+fn write_pem_with_params(path: &Path, label: &str, params: SpxParams, bytes: &[u8]) -> Result<()> {
+    const MAX_PEM_SIZE: usize = 4096;
+
+    let mut buf = [0u8; MAX_PEM_SIZE];
+    let mut encoder = Encoder::new(label, LineEnding::LF, &mut buf)?;
+    encoder.encode(bytes)?;
+    let len = encoder.finish()?;
+    let pem = std::str::from_utf8(&buf[..len])?;
+
+    let mut file = File::create(path)?;
+    if params == SpxParams::default() {
+        file.write_all(pem.as_bytes())?;
+    } else {
+        // Insert the header after the pre-encapsulation boundary.
+        let (begin, rest) = pem.split_once('\n').unwrap_or((pem, ""));
+        write!(file, "{begin}\n{PEM_PARAMS_HEADER}: {params}\n\n{rest}")?;
+    }
+    Ok(())
+}
+
+/// Reads a PEM file with the given `label`, returning the parameter set and decoded bytes.
+///
+/// Files without a parameter set header use the default parameter set.
+fn read_pem_with_params(path: &Path, label: &str) -> Result<(SpxParams, Vec<u8>)> {
+    let pem = std::fs::read_to_string(path)?;
+    let mut params = SpxParams::default();
+    let mut body = String::new();
+    let mut in_headers = false;
+    for line in pem.lines() {
+        if line.starts_with("-----BEGIN ") {
+            in_headers = true;
+        } else if in_headers {
+            if let Some((key, value)) = line.split_once(':') {
+                if key.trim() == PEM_PARAMS_HEADER {
+                    params = value.trim().parse()?;
+                }
+                continue;
+            }
+            in_headers = false;
+            if line.trim().is_empty() {
+                continue;
+            }
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+
+    let mut decoder = Decoder::new(body.as_bytes())?;
+    ensure!(
+        decoder.type_label() == label,
+        "PEM type error; expecting {:?} but got {:?}",
+        label,
+        decoder.type_label()
+    );
+    let mut buf = Vec::new();
+    decoder.decode_to_end(&mut buf)?;
+    Ok((params, buf))
+}
+
 /// A SPHINCS+ keypair consisting of the public and secret keys.
 #[derive(Clone)]
 pub struct SpxKeypair {
-    pk: spx::PublicKey,
-    sk: spx::SecretKey,
+    params: SpxParams,
+    pk: Vec<u8>,
+    sk: Vec<u8>,
 }
 
 impl SpxKeypair {
-    /// Generates a new SPHINCS+ keypair.
+    /// Generates a new SPHINCS+ keypair using the default parameter set.
     pub fn generate() -> Self {
-        let (pk, sk) = spx::keypair();
-        SpxKeypair { pk, sk }
+        Self::generate_with_params(SpxParams::default())
+    }
+
+    /// Generates a new SPHINCS+ keypair for the parameter set `params`.
+    pub fn generate_with_params(params: SpxParams) -> Self {
+        let (pk, sk) = with_params!(params, spx => {
+            let (pk, sk) = spx::keypair();
+            (pk.as_bytes().to_vec(), sk.as_bytes().to_vec())
+        });
+        SpxKeypair { params, pk, sk }
+    }
+
+    /// Constructs a keypair for `params` from the public key concatenated with the secret key.
+    pub fn from_bytes(params: SpxParams, b: &[u8]) -> Result<Self> {
+        let pk_len = params.public_key_len();
+        ensure!(
+            b.len() == pk_len + params.secret_key_len(),
+            "Invalid {} keypair length {}",
+            params,
+            b.len()
+        );
+        // Validate the key material with the underlying implementation.
+        with_params!(params, spx => {
+            spx::PublicKey::from_bytes(&b[..pk_len])?;
+            spx::SecretKey::from_bytes(&b[pk_len..])?;
+        });
+        Ok(SpxKeypair {
+            params,
+            pk: b[..pk_len].to_vec(),
+            sk: b[pk_len..].to_vec(),
+        })
     }
 
     /// Sign `message` using the secret key.
     pub fn sign(&self, message: &[u8]) -> SpxSignature {
-        let sm = spx::detached_sign(message, &self.sk);
-        SpxSignature(Signature::from_le_bytes(sm.as_bytes()).unwrap())
+        // The secret key was validated on construction so `unwrap()` here is safe.
+        with_params!(self.params, spx => {
+            let sk = spx::SecretKey::from_bytes(&self.sk).unwrap();
+            SpxSignature(spx::detached_sign(message, &sk).as_bytes().to_vec())
+        })
     }
 
     /// Consumes this keypair and returns the corrisponding public key.
     pub fn into_public_key(self) -> SpxPublicKey {
-        SpxPublicKey(self.pk)
+        SpxPublicKey {
+            params: self.params,
+            pk: self.pk,
+        }
     }
 }
 
 impl SpxPublicKeyPart for SpxKeypair {
-    fn pk(&self) -> &spx::PublicKey {
+    fn params(&self) -> SpxParams {
+        self.params
+    }
+
+    fn pk_as_bytes(&self) -> &[u8] {
         &self.pk
     }
 }
@@ -113,8 +331,8 @@ impl ToWriter for SpxKeypair {
     fn to_writer(&self, w: &mut impl Write) -> Result<()> {
         // Write out the keypair as a fixed length byte-string consisting of the public key
         // concatenated with the secret key.
-        w.write_all(self.pk.as_bytes())?;
-        w.write_all(self.sk.as_bytes())?;
+        w.write_all(&self.pk)?;
+        w.write_all(&self.sk)?;
         Ok(())
     }
 }
@@ -122,13 +340,11 @@ impl ToWriter for SpxKeypair {
 impl FromReader for SpxKeypair {
     fn from_reader(mut r: impl Read) -> Result<Self> {
         // Read in the buffer as a fixed length byte-string consisting of the public key
-        // concatenated with the secret key.
+        // concatenated with the secret key.  The raw format doesn't record the parameter set so
+        // the default is assumed.
         let mut buf = [0u8; PUBLIC_KEY_BYTE_LEN + SECRET_KEY_BYTE_LEN];
         r.read_exact(&mut buf)?;
-        Ok(SpxKeypair {
-            pk: spx::PublicKey::from_bytes(&buf[..PUBLIC_KEY_BYTE_LEN])?,
-            sk: spx::SecretKey::from_bytes(&buf[PUBLIC_KEY_BYTE_LEN..])?,
-        })
+        SpxKeypair::from_bytes(SpxParams::default(), &buf)
     }
 }
 
@@ -136,27 +352,49 @@ impl PemSerilizable for SpxKeypair {
     fn label() -> &'static str {
         "RAW SPHINCS+ PRIVATE KEY"
     }
+
+    fn write_pem_file(&self, path: &Path) -> Result<()> {
+        let mut bytes = Vec::new();
+        self.to_writer(&mut bytes)?;
+        write_pem_with_params(path, Self::label(), self.params, &bytes)
+    }
+
+    fn read_pem_file(path: &Path) -> Result<Self> {
+        let (params, bytes) = read_pem_with_params(path, Self::label())?;
+        SpxKeypair::from_bytes(params, &bytes)
+    }
 }
 
 /// Wrapper for a SPHINCS+ public key.
 #[derive(Clone)]
-pub struct SpxPublicKey(spx::PublicKey);
+pub struct SpxPublicKey {
+    params: SpxParams,
+    pk: Vec<u8>,
+}
 
 impl SpxPublicKey {
-    pub fn from_bytes(b: &[u8]) -> Result<Self> {
-        Ok(SpxPublicKey(spx::PublicKey::from_bytes(b)?))
+    pub fn from_bytes(params: SpxParams, b: &[u8]) -> Result<Self> {
+        with_params!(params, spx => { spx::PublicKey::from_bytes(b)?; });
+        Ok(SpxPublicKey {
+            params,
+            pk: b.to_vec(),
+        })
     }
 }
 
 impl SpxPublicKeyPart for SpxPublicKey {
-    fn pk(&self) -> &spx::PublicKey {
-        &self.0
+    fn params(&self) -> SpxParams {
+        self.params
+    }
+
+    fn pk_as_bytes(&self) -> &[u8] {
+        &self.pk
     }
 }
 
 impl ToWriter for SpxPublicKey {
     fn to_writer(&self, w: &mut impl Write) -> Result<()> {
-        w.write_all(self.0.as_bytes())?;
+        w.write_all(&self.pk)?;
         Ok(())
     }
 }
@@ -165,7 +403,7 @@ impl FromReader for SpxPublicKey {
     fn from_reader(mut r: impl Read) -> Result<Self> {
         let mut buf = [0u8; PUBLIC_KEY_BYTE_LEN];
         r.read_exact(&mut buf)?;
-        Ok(SpxPublicKey(spx::PublicKey::from_bytes(&buf)?))
+        SpxPublicKey::from_bytes(SpxParams::default(), &buf)
     }
 }
 
@@ -173,30 +411,64 @@ impl PemSerilizable for SpxPublicKey {
     fn label() -> &'static str {
         "RAW SPHINCS+ PUBLIC KEY"
     }
+
+    fn write_pem_file(&self, path: &Path) -> Result<()> {
+        write_pem_with_params(path, Self::label(), self.params, &self.pk)
+    }
+
+    fn read_pem_file(path: &Path) -> Result<Self> {
+        let (params, bytes) = read_pem_with_params(path, Self::label())?;
+        SpxPublicKey::from_bytes(params, &bytes)
+    }
 }
 
 /// Wrapper for a SPHINCS+ signature.
+///
+/// The length of the signature depends on the parameter set used to create it.
 #[derive(Clone)]
-pub struct SpxSignature(pub Signature);
+pub struct SpxSignature(Vec<u8>);
+
+impl SpxSignature {
+    /// The raw signature bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Length of the signature in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
 impl ToWriter for SpxSignature {
     fn to_writer(&self, w: &mut impl Write) -> Result<()> {
-        w.write_all(&self.0.to_le_bytes())?;
+        w.write_all(&self.0)?;
         Ok(())
     }
 }
 
 impl FromReader for SpxSignature {
     fn from_reader(mut r: impl Read) -> Result<Self> {
-        let mut buf = [0u8; SIGNATURE_BIT_LEN / 8];
-        let len = r.read(&mut buf)?;
-        Ok(SpxSignature(Signature::from_le_bytes(&buf[..len])?))
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        ensure!(
+            buf.len() == SIGNATURE_BYTE_LEN_128S || buf.len() == SIGNATURE_BYTE_LEN_128F,
+            "Invalid SPHINCS+ signature length {}",
+            buf.len()
+        );
+        Ok(SpxSignature(buf))
     }
 }
 
 impl ToString for SpxSignature {
     fn to_string(&self) -> String {
-        self.0.to_string()
+        // Signatures are displayed as a big-endian hex number.
+        let be = self.0.iter().rev().copied().collect::<Vec<u8>>();
+        format!("0x{}", hex::encode(be))
     }
 }
 
@@ -212,4 +484,63 @@ mod test {
         let sig = keypair.sign(msg);
         assert!(keypair.verify(msg, &sig).is_ok());
     }
+
+    #[test]
+    fn test_spx_params() {
+        let msg = b"Test message";
+
+        for params in SpxParams::value_variants() {
+            let keypair = SpxKeypair::generate_with_params(*params);
+            let sig = keypair.sign(msg);
+            assert_eq!(sig.len(), params.signature_len());
+            assert_eq!(keypair.pk_len(), params.public_key_len());
+            assert!(keypair.verify(msg, &sig).is_ok());
+            assert!(keypair.verify(b"Other message", &sig).is_err());
+        }
+    }
+
+    #[test]
+    fn test_spx_params_mismatch() {
+        let msg = b"Test message";
+
+        // A key with the same bytes but a different hash function must not verify.
+        let keypair = SpxKeypair::generate_with_params(SpxParams::ShakeSmall);
+        let sig = keypair.sign(msg);
+        let other = SpxPublicKey::from_bytes(SpxParams::Sha2Small, keypair.pk_as_bytes()).unwrap();
+        assert!(other.verify(msg, &sig).is_err());
+
+        // A signature of the wrong length is rejected before verification.
+        let other = SpxPublicKey::from_bytes(SpxParams::ShakeFast, keypair.pk_as_bytes()).unwrap();
+        assert!(other.verify(msg, &sig).is_err());
+    }
+
+    #[test]
+    fn test_spx_pem_params() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("key.pem");
+
+        let keypair = SpxKeypair::generate_with_params(SpxParams::Sha2Fast);
+        keypair.write_pem_file(&path)?;
+        let pem = std::fs::read_to_string(&path)?;
+        assert!(pem.contains("SPHINCS+-Params: sha2-128f-simple\n"));
+
+        let loaded = SpxKeypair::read_pem_file(&path)?;
+        assert_eq!(loaded.params(), SpxParams::Sha2Fast);
+        assert_eq!(loaded.pk_as_bytes(), keypair.pk_as_bytes());
+
+        let public = keypair.into_public_key();
+        public.write_pem_file(&path)?;
+        let loaded = SpxPublicKey::read_pem_file(&path)?;
+        assert_eq!(loaded.params(), SpxParams::Sha2Fast);
+
+        // Keys using the default parameter set are written without the header.
+        let keypair = SpxKeypair::generate();
+        keypair.write_pem_file(&path)?;
+        let pem = std::fs::read_to_string(&path)?;
+        assert!(!pem.contains(PEM_PARAMS_HEADER));
+        let loaded = SpxKeypair::read_pem_file(&path)?;
+        assert_eq!(loaded.params(), SpxParams::default());
+        assert_eq!(loaded.pk_as_bytes(), keypair.pk_as_bytes());
+        Ok(())
+    }
 }
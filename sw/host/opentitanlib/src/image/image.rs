@@ -204,6 +204,27 @@ impl Image {
         Ok(())
     }
 
+    /// Returns the `len` bytes of the manifest extension `id`, if the image contains it.
+    pub fn manifest_extension_data(&self, id: u32, len: usize) -> Result<Option<&[u8]>> {
+        let entry = self
+            .borrow_manifest()?
+            .extensions
+            .entries
+            .iter()
+            .find(|e| e.identifier == id && e.offset != 0);
+        match entry {
+            Some(e) => {
+                let start = e.offset as usize;
+                let end = start
+                    .checked_add(len)
+                    .filter(|&end| end <= self.size)
+                    .ok_or(ImageError::ExtensionOverflow)?;
+                Ok(Some(&self.data.bytes[start..end]))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Clears any entry in the manifest extension table that doesn't have an offset.
     ///
     /// This allows a manifest definition with a populated extension table to be used even when
@@ -2,8 +2,9 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use serde::{self, Deserialize, Serialize};
+use std::mem::size_of;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 use zerocopy::AsBytes;
@@ -19,6 +20,10 @@ use crate::with_unknown;
 pub enum ManifestExtError {
     #[error("Extension ID 0x{0:x} has duplicate extension data.")]
     DuplicateEntry(u32),
+    #[error("SPHINCS+ parameter set {0} doesn't fit the manifest extension layout.")]
+    UnsupportedSpxParams(spx::SpxParams),
+    #[error("SPHINCS+ signature length {0} doesn't fit the manifest extension layout.")]
+    SpxSignatureLength(usize),
 }
 
 with_unknown! {
@@ -113,7 +118,14 @@ impl ManifestExtEntrySpec {
 
 impl ManifestExtEntry {
     /// Creates a new manifest extension from a given SPHINCS+ `key`.
+    ///
+    /// The `spx_signature` extension has room for a single signature size, so only keys whose
+    /// parameter set produces signatures of that size (i.e. the "small" sets) are accepted.
     pub fn new_spx_key_entry(key: &spx::SpxKey) -> Result<Self> {
+        ensure!(
+            key.params().signature_len() == size_of::<SigverifySpxSignature>(),
+            ManifestExtError::UnsupportedSpxParams(key.params())
+        );
         Ok(ManifestExtEntry::SpxKey(ManifestExtSpxKey {
             header: ManifestExtHeader {
                 identifier: MANIFEST_EXT_ID_SPX_KEY,
@@ -127,6 +139,10 @@ impl ManifestExtEntry {
 
     /// Creates a new manifest extension from a given SPHINCS+ `signature`.
     pub fn new_spx_signature_entry(signature: &spx::SpxSignature) -> Result<Self> {
+        ensure!(
+            signature.len() == size_of::<SigverifySpxSignature>(),
+            ManifestExtError::SpxSignatureLength(signature.len())
+        );
         Ok(ManifestExtEntry::SpxSignature(Box::new(
             ManifestExtSpxSignature {
                 header: ManifestExtHeader {
@@ -134,7 +150,7 @@ impl ManifestExtEntry {
                     name: MANIFEST_EXT_NAME_SPX_SIGNATURE,
                 },
                 signature: SigverifySpxSignature {
-                    data: le_bytes_to_word_arr(&signature.0.to_le_bytes())?,
+                    data: le_bytes_to_word_arr(signature.as_bytes())?,
                 },
             },
         )))
@@ -193,6 +209,7 @@ impl ManifestExtEntry {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::spx::{SpxKey, SpxKeypair, SpxParams};
     use crate::testdata;
     use crate::util::num_de::HexEncoded;
 
@@ -223,4 +240,26 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_spx_params() {
+        let msg = b"Test message";
+        let keypair = SpxKeypair::generate();
+        assert!(ManifestExtEntry::new_spx_key_entry(&SpxKey::Private(keypair.clone())).is_ok());
+        assert!(ManifestExtEntry::new_spx_signature_entry(&keypair.sign(msg)).is_ok());
+
+        // Signatures from the "fast" parameter sets don't fit the extension.
+        let keypair = SpxKeypair::generate_with_params(SpxParams::ShakeFast);
+        let err =
+            ManifestExtEntry::new_spx_key_entry(&SpxKey::Private(keypair.clone())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ManifestExtError>(),
+            Some(ManifestExtError::UnsupportedSpxParams(SpxParams::ShakeFast))
+        ));
+        let err = ManifestExtEntry::new_spx_signature_entry(&keypair.sign(msg)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ManifestExtError>(),
+            Some(ManifestExtError::SpxSignatureLength(17088))
+        ));
+    }
 }
@@ -8,3 +8,4 @@ pub mod image;
 pub mod manifest;
 pub mod manifest_def;
 pub mod manifest_ext;
+pub mod signing_request;
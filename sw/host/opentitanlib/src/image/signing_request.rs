@@ -0,0 +1,632 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline multi-party (quorum) signing of images.
+//!
+//! A `SigningRequest` captures everything a signer needs to produce detached signatures for an
+//! image: the SHA256 digest of the signed region (signed with RSA) and the signed region itself
+//! (signed with SPHINCS+).  Once signatures have been collected, a `SigningPolicy` checks which of
+//! its authorized signers approved the request and, if at least `threshold` signers did, the
+//! signatures made with the keys referenced by the image's manifest are embedded into the image.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::crypto::rsa::{RsaPublicKey, Signature as RsaSignature};
+use crate::crypto::sha256::Sha256Digest;
+use crate::crypto::spx::{self, SpxKey, SpxPublicKeyPart, SpxSignature};
+use crate::image::image::Image;
+use crate::image::manifest::{ManifestExtHeader, ManifestExtSpxKey, MANIFEST_EXT_ID_SPX_KEY};
+use crate::image::manifest_def::ManifestSpec;
+use crate::image::manifest_ext::ManifestExtEntry;
+
+#[derive(Debug, Error)]
+pub enum SigningRequestError {
+    #[error("The image does not match the signing request: {0} differs")]
+    ImageMismatch(&'static str),
+    #[error("Invalid signing policy: {0}")]
+    InvalidPolicy(String),
+    #[error("Signer {0:?} has no keys")]
+    NoSignerKeys(String),
+    #[error("Quorum not met: {0} of {1} required signers approved")]
+    QuorumNotMet(usize, usize),
+    #[error("No approving signer holds the {0} key referenced by the manifest")]
+    NoSignerForKey(&'static str),
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A request for signatures over an image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningRequest {
+    /// SHA256 digest of the signed region of the image (big-endian).
+    #[serde(with = "hex_bytes")]
+    pub digest: Vec<u8>,
+    /// The message to be signed with SPHINCS+ (the signed region of the image).
+    #[serde(with = "hex_bytes")]
+    pub spx_message: Vec<u8>,
+}
+
+impl SigningRequest {
+    /// Creates a signing request for `image`.
+    ///
+    /// The image's manifest must already be complete: any change to the signed region after the
+    /// request is created invalidates the request.
+    pub fn from_image(image: &Image) -> Result<Self> {
+        Ok(SigningRequest {
+            digest: image.compute_digest()?.to_be_bytes(),
+            spx_message: image.map_signed_region(|buf| buf.to_vec())?,
+        })
+    }
+
+    /// Reads in a `SigningRequest` from a JSON file.
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Writes the `SigningRequest` to a JSON file.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Ensures `image` is the image this request was created for.
+    pub fn check_image(&self, image: &Image) -> Result<()> {
+        let other = Self::from_image(image)?;
+        ensure!(
+            self.digest == other.digest,
+            SigningRequestError::ImageMismatch("digest")
+        );
+        ensure!(
+            self.spx_message == other.spx_message,
+            SigningRequestError::ImageMismatch("spx_message")
+        );
+        Ok(())
+    }
+
+    /// Returns the digest to be signed with RSA.
+    pub fn rsa_digest(&self) -> Result<Sha256Digest> {
+        Ok(Sha256Digest::from_be_bytes(self.digest.as_slice())?)
+    }
+}
+
+/// An authorized signer as specified in a `SigningPolicy` file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SignerSpec {
+    /// The name of the signer.
+    pub name: String,
+    /// The path to the signer's RSA public (PKCS1 DER) key.
+    ///
+    /// Relative paths are resolved relative to the directory containing the policy file.
+    pub rsa_key: Option<PathBuf>,
+    /// The path to the signer's SPHINCS+ public key.
+    ///
+    /// Relative paths are resolved relative to the directory containing the policy file.
+    pub spx_key: Option<PathBuf>,
+}
+
+/// An M-of-N signing policy.
+///
+/// The policy file is an HJSON document:
+/// ```ignore
+/// {
+///     threshold: 2,
+///     signers: [
+///         { name: "alice", rsa_key: "alice.pub.der", spx_key: "alice_spx.pub.pem" },
+///         { name: "bob", rsa_key: "bob.pub.der", spx_key: "bob_spx.pub.pem" },
+///         { name: "carol", rsa_key: "carol.pub.der", spx_key: "carol_spx.pub.pem" },
+///     ]
+/// }
+/// ```
+///
+/// A signer approves a request when every key listed for that signer has produced a valid
+/// signature for the request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SigningPolicy {
+    /// The minimum number of signers which must approve a request.
+    pub threshold: usize,
+    /// The authorized signers.
+    pub signers: Vec<SignerSpec>,
+    #[serde(skip)]
+    relative_path: Option<PathBuf>,
+}
+
+/// A signer with its keys loaded.
+pub struct Signer {
+    pub name: String,
+    pub rsa_key: Option<RsaPublicKey>,
+    pub spx_key: Option<SpxKey>,
+}
+
+/// A signer which approved the request along with the signatures it produced.
+pub struct Approval {
+    /// Index of the signer in the list passed to `evaluate`.
+    pub signer: usize,
+    pub name: String,
+    pub rsa_signature: Option<RsaSignature>,
+    pub spx_signature: Option<SpxSignature>,
+}
+
+/// Summary of a finalized signing request.
+#[derive(Debug, Serialize, Annotate)]
+pub struct SigningReport {
+    /// The number of approvals required.
+    pub threshold: usize,
+    /// The signers which approved the request.
+    pub approved: Vec<String>,
+    /// The signers which did not approve the request.
+    pub missing: Vec<String>,
+    /// The signer whose RSA signature was embedded in the image.
+    pub rsa_signer: String,
+    /// The signer whose SPHINCS+ signature was embedded in the image, if any.
+    pub spx_signer: Option<String>,
+}
+
+impl SigningPolicy {
+    /// Reads in a `SigningPolicy` from an HJSON file.
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        let mut policy: Self = deser_hjson::from_str(&std::fs::read_to_string(path)?)?;
+        policy.relative_path = path.parent().map(|v| v.to_owned());
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for signer in &self.signers {
+            ensure!(
+                names.insert(signer.name.as_str()),
+                SigningRequestError::InvalidPolicy(format!("duplicate signer {:?}", signer.name))
+            );
+        }
+        ensure!(
+            self.threshold >= 1 && self.threshold <= self.signers.len(),
+            SigningRequestError::InvalidPolicy(format!(
+                "threshold {} must be between 1 and the number of signers ({})",
+                self.threshold,
+                self.signers.len()
+            ))
+        );
+        Ok(())
+    }
+
+    /// Loads the keys of all signers.
+    ///
+    /// No two signers may share a key, as a single signature would otherwise count as several
+    /// approvals.
+    pub fn load_signers(&self) -> Result<Vec<Signer>> {
+        let relative_path = self.relative_path.as_deref().unwrap_or(Path::new(""));
+        let signers = self
+            .signers
+            .iter()
+            .map(|s| {
+                ensure!(
+                    s.rsa_key.is_some() || s.spx_key.is_some(),
+                    SigningRequestError::NoSignerKeys(s.name.clone())
+                );
+                Ok(Signer {
+                    name: s.name.clone(),
+                    rsa_key: s
+                        .rsa_key
+                        .as_ref()
+                        .map(|k| RsaPublicKey::from_pkcs1_der_file(relative_path.join(k)))
+                        .transpose()?,
+                    spx_key: s
+                        .spx_key
+                        .as_ref()
+                        .map(|k| spx::load_spx_key(&relative_path.join(k)))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        check_distinct_keys(&signers)?;
+        Ok(signers)
+    }
+
+    /// Verifies the signatures and embeds them into `image`.
+    ///
+    /// The RSA signature embedded is the one made with the key matching the manifest's
+    /// `rsa_modulus`.  If the image has an `spx_key` extension, the SPHINCS+ signature made
+    /// with that key is also embedded.
+    pub fn finalize(
+        &self,
+        image: &mut Image,
+        request: &SigningRequest,
+        rsa_signatures: &[RsaSignature],
+        spx_signatures: &[SpxSignature],
+    ) -> Result<SigningReport> {
+        request.check_image(image)?;
+        let signers = self.load_signers()?;
+        let approvals = evaluate(&signers, request, rsa_signatures, spx_signatures)?;
+        ensure!(
+            approvals.len() >= self.threshold,
+            SigningRequestError::QuorumNotMet(approvals.len(), self.threshold)
+        );
+
+        let manifest: ManifestSpec = image.borrow_manifest()?.try_into()?;
+        let modulus = manifest
+            .rsa_modulus()
+            .map(|m| m.to_le_bytes())
+            .ok_or(SigningRequestError::NoSignerForKey("RSA"))?;
+        let (rsa_signer, rsa_signature) = approvals
+            .iter()
+            .find_map(|a| match (&a.rsa_signature, &signers[a.signer].rsa_key) {
+                (Some(sig), Some(key)) if key.modulus().to_le_bytes() == modulus => {
+                    Some((&a.name, sig))
+                }
+                _ => None,
+            })
+            .ok_or(SigningRequestError::NoSignerForKey("RSA"))?;
+        let rsa_signer = rsa_signer.clone();
+        image.update_rsa_signature(rsa_signature.clone())?;
+
+        let spx_signer = match image_spx_key(image)? {
+            Some(pk) => {
+                let (name, signature) = approvals
+                    .iter()
+                    .find_map(|a| match (&a.spx_signature, &signers[a.signer].spx_key) {
+                        (Some(sig), Some(key)) if key.pk_as_bytes() == pk.as_slice() => {
+                            Some((a.name.clone(), sig.clone()))
+                        }
+                        _ => None,
+                    })
+                    .ok_or(SigningRequestError::NoSignerForKey("SPHINCS+"))?;
+                image.add_manifest_extension(ManifestExtEntry::new_spx_signature_entry(
+                    &signature,
+                )?)?;
+                Some(name)
+            }
+            None => None,
+        };
+
+        let approved = approvals
+            .iter()
+            .map(|a| a.name.clone())
+            .collect::<HashSet<_>>();
+        Ok(SigningReport {
+            threshold: self.threshold,
+            missing: signers
+                .iter()
+                .filter(|s| !approved.contains(&s.name))
+                .map(|s| s.name.clone())
+                .collect(),
+            approved: approvals.into_iter().map(|a| a.name).collect(),
+            rsa_signer,
+            spx_signer,
+        })
+    }
+}
+
+/// Ensures that no key is held by more than one of `signers`.
+fn check_distinct_keys(signers: &[Signer]) -> Result<()> {
+    let mut keys = HashSet::new();
+    for signer in signers {
+        let rsa_key = signer
+            .rsa_key
+            .as_ref()
+            .map(|k| ("RSA", k.modulus().to_le_bytes()));
+        let spx_key = signer
+            .spx_key
+            .as_ref()
+            .map(|k| ("SPHINCS+", k.pk_as_bytes().to_vec()));
+        for (kind, key) in rsa_key.into_iter().chain(spx_key) {
+            ensure!(
+                keys.insert((kind, key)),
+                SigningRequestError::InvalidPolicy(format!(
+                    "the {kind} key of signer {:?} is also held by another signer",
+                    signer.name
+                ))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the SPHINCS+ public key bytes from the image's `spx_key` extension, if present.
+fn image_spx_key(image: &Image) -> Result<Option<Vec<u8>>> {
+    Ok(image
+        .manifest_extension_data(MANIFEST_EXT_ID_SPX_KEY, size_of::<ManifestExtSpxKey>())?
+        .map(|data| data[size_of::<ManifestExtHeader>()..].to_vec()))
+}
+
+/// Determines which `signers` approved `request`.
+///
+/// Each signature is checked against every signer's key, so signatures may be supplied in any
+/// order and without naming the signer.  The returned approvals are in the same order as
+/// `signers`, omitting signers which did not approve.
+pub fn evaluate(
+    signers: &[Signer],
+    request: &SigningRequest,
+    rsa_signatures: &[RsaSignature],
+    spx_signatures: &[SpxSignature],
+) -> Result<Vec<Approval>> {
+    let digest = request.rsa_digest()?;
+    let mut approvals = Vec::new();
+    for (index, signer) in signers.iter().enumerate() {
+        let rsa_signature = signer.rsa_key.as_ref().and_then(|key| {
+            rsa_signatures
+                .iter()
+                .find(|sig| key.verify(&digest, sig).is_ok())
+                .cloned()
+        });
+        let spx_signature = signer.spx_key.as_ref().and_then(|key| {
+            spx_signatures
+                .iter()
+                .find(|sig| key.verify(&request.spx_message, sig).is_ok())
+                .cloned()
+        });
+        let approved = (signer.rsa_key.is_some() || signer.spx_key.is_some())
+            && (signer.rsa_key.is_none() || rsa_signature.is_some())
+            && (signer.spx_key.is_none() || spx_signature.is_some());
+        if approved {
+            log::info!("Signer {:?} approved the request", signer.name);
+            approvals.push(Approval {
+                signer: index,
+                name: signer.name.clone(),
+                rsa_signature,
+                spx_signature,
+            });
+        } else {
+            log::warn!("Signer {:?} did not approve the request", signer.name);
+        }
+    }
+    Ok(approvals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::rsa::RsaPrivateKey;
+    use crate::crypto::spx::SpxKeypair;
+    use crate::image::manifest::{ManifestExtSpxSignature, MANIFEST_EXT_ID_SPX_SIGNATURE};
+    use crate::testdata;
+    use crate::util::file::{FromReader, PemSerilizable};
+
+    fn policy(hjson: &str) -> SigningPolicy {
+        let mut policy: SigningPolicy = deser_hjson::from_str(hjson).unwrap();
+        policy.relative_path = Some(testdata!());
+        policy.validate().unwrap();
+        policy
+    }
+
+    fn spx_signer(name: &str, keypair: &SpxKeypair) -> Signer {
+        Signer {
+            name: name.into(),
+            rsa_key: None,
+            spx_key: Some(SpxKey::Private(keypair.clone())),
+        }
+    }
+
+    #[test]
+    fn test_request_serialization() -> Result<()> {
+        let request = SigningRequest {
+            digest: vec![0x01, 0x23, 0x45, 0x67],
+            spx_message: b"Test message".to_vec(),
+        };
+        let json = serde_json::to_string(&request)?;
+        assert_eq!(
+            json,
+            r#"{"digest":"01234567","spx_message":"54657374206d657373616765"}"#
+        );
+        assert_eq!(serde_json::from_str::<SigningRequest>(&json)?, request);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluate_quorum() -> Result<()> {
+        let request = SigningRequest {
+            digest: vec![0u8; 32],
+            spx_message: b"Test message".to_vec(),
+        };
+        let alice = SpxKeypair::generate();
+        let bob = SpxKeypair::generate();
+        let carol = SpxKeypair::generate();
+        let signers = vec![
+            spx_signer("alice", &alice),
+            spx_signer("bob", &bob),
+            spx_signer("carol", &carol),
+        ];
+
+        // A signature over a different message must not count.
+        let signatures = vec![
+            carol.sign(&request.spx_message),
+            bob.sign(b"Other message"),
+            alice.sign(&request.spx_message),
+        ];
+        let approvals = evaluate(&signers, &request, &[], &signatures)?;
+        let names = approvals
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["alice", "carol"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy_validation() {
+        let policy: SigningPolicy = deser_hjson::from_str(
+            r#"{
+                threshold: 3,
+                signers: [
+                    { name: "alice", spx_key: "alice.pem" },
+                    { name: "bob", spx_key: "bob.pem" },
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(policy.validate().is_err());
+
+        let policy: SigningPolicy = deser_hjson::from_str(
+            r#"{
+                threshold: 1,
+                signers: [
+                    { name: "alice", spx_key: "alice.pem" },
+                    { name: "alice", spx_key: "bob.pem" },
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let policy = policy(
+            r#"{
+                threshold: 2,
+                signers: [
+                    { name: "alice", rsa_key: "alice.pub.der" },
+                    { name: "mallory", rsa_key: "alice.pub.der" },
+                ]
+            }"#,
+        );
+        assert!(policy.load_signers().is_err());
+
+        let alice = SpxKeypair::generate();
+        let signers = vec![spx_signer("alice", &alice), spx_signer("mallory", &alice)];
+        assert!(check_distinct_keys(&signers).is_err());
+        let signers = vec![
+            spx_signer("alice", &alice),
+            spx_signer("bob", &SpxKeypair::generate()),
+        ];
+        assert!(check_distinct_keys(&signers).is_ok());
+    }
+
+    #[test]
+    fn test_finalize() -> Result<()> {
+        let policy = policy(
+            r#"{
+                threshold: 2,
+                signers: [
+                    { name: "alice", rsa_key: "alice.pub.der" },
+                    { name: "bob", rsa_key: "bob.pub.der" },
+                    { name: "carol", rsa_key: "carol.pub.der" },
+                ]
+            }"#,
+        );
+        let alice = RsaPrivateKey::from_pkcs8_der_file(testdata!("alice.der"))?;
+        let bob = RsaPrivateKey::from_pkcs8_der_file(testdata!("bob.der"))?;
+        let mut image = Image::read_from_file(&testdata!("test_image.bin"))?;
+        image.update_modulus(RsaPublicKey::from_private_key(&bob).modulus())?;
+        image.update_signed_region(&HashSet::new())?;
+        let request = SigningRequest::from_image(&image)?;
+        let digest = request.rsa_digest()?;
+        let signatures = vec![bob.sign(&digest)?, alice.sign(&digest)?];
+
+        let err = policy
+            .finalize(&mut image, &request, &signatures[1..], &[])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SigningRequestError>(),
+            Some(SigningRequestError::QuorumNotMet(1, 2))
+        ));
+
+        let report = policy.finalize(&mut image, &request, &signatures, &[])?;
+        assert_eq!(report.approved, ["alice", "bob"]);
+        assert_eq!(report.missing, ["carol"]);
+        assert_eq!(report.rsa_signer, "bob");
+        assert_eq!(report.spx_signer, None);
+        let manifest: ManifestSpec = image.borrow_manifest()?.try_into()?;
+        assert_eq!(
+            manifest.rsa_signature().unwrap().to_le_bytes(),
+            signatures[0].to_le_bytes()
+        );
+
+        // The signature lies outside of the signed region, so the request still matches.
+        request.check_image(&image)?;
+        image.update_modulus(RsaPublicKey::from_private_key(&alice).modulus())?;
+        let err = policy
+            .finalize(&mut image, &request, &signatures, &[])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SigningRequestError>(),
+            Some(SigningRequestError::ImageMismatch("digest"))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_finalize_spx() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bob = SpxKeypair::generate();
+        let carol = SpxKeypair::generate();
+        bob.write_pem_file(&dir.path().join("bob.pem"))?;
+        carol.write_pem_file(&dir.path().join("carol.pem"))?;
+        let policy = policy(&format!(
+            r#"{{
+                threshold: 2,
+                signers: [
+                    {{ name: "alice", rsa_key: "alice.pub.der" }},
+                    {{ name: "bob", spx_key: {:?} }},
+                    {{ name: "carol", spx_key: {:?} }},
+                ]
+            }}"#,
+            dir.path().join("bob.pem"),
+            dir.path().join("carol.pem"),
+        ));
+        let alice = RsaPrivateKey::from_pkcs8_der_file(testdata!("alice.der"))?;
+
+        // Lay out the image as `opentitantool image manifest update` does: the signed `spx_key`
+        // extension followed by space for the unsigned `spx_signature` extension.
+        let mut image = Image::read_from_file(&testdata!("test_image.bin"))?;
+        let entries = &mut image.borrow_manifest_mut()?.extensions.entries;
+        entries[0].identifier = MANIFEST_EXT_ID_SPX_KEY;
+        entries[1].identifier = MANIFEST_EXT_ID_SPX_SIGNATURE;
+        image.update_modulus(RsaPublicKey::from_private_key(&alice).modulus())?;
+        image.add_manifest_extension(ManifestExtEntry::new_spx_key_entry(&SpxKey::Public(
+            bob.clone().into_public_key(),
+        ))?)?;
+        let spx_signature_len = size_of::<ManifestExtSpxSignature>();
+        image.allocate_manifest_extension(MANIFEST_EXT_ID_SPX_SIGNATURE, spx_signature_len)?;
+        image.update_length()?;
+        image.update_signed_region(&HashSet::from([MANIFEST_EXT_ID_SPX_KEY]))?;
+        let request = SigningRequest::from_image(&image)?;
+        let rsa_signatures = vec![alice.sign(&request.rsa_digest()?)?];
+
+        // Carol's approval meets the quorum, but Carol's key is not the one in the image.
+        let carol_signature = carol.sign(&request.spx_message);
+        let err = policy
+            .finalize(&mut image, &request, &rsa_signatures, &[carol_signature])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SigningRequestError>(),
+            Some(SigningRequestError::NoSignerForKey("SPHINCS+"))
+        ));
+
+        let bob_signature = bob.sign(&request.spx_message);
+        let report = policy.finalize(
+            &mut image,
+            &request,
+            &rsa_signatures,
+            &[bob_signature.clone()],
+        )?;
+        assert_eq!(report.rsa_signer, "alice");
+        assert_eq!(report.spx_signer.as_deref(), Some("bob"));
+        let ext = image
+            .manifest_extension_data(MANIFEST_EXT_ID_SPX_SIGNATURE, spx_signature_len)?
+            .unwrap();
+        assert_eq!(
+            &ext[size_of::<ManifestExtHeader>()..],
+            bob_signature.as_bytes()
+        );
+        request.check_image(&image)?;
+        Ok(())
+    }
+}
@@ -54,3 +54,40 @@ pub trait ConsoleDevice {
         Ok(Rc::new(NoNonblockingHelp))
     }
 }
+
+/// A console for tests which produces a fixed sequence of chunks, one per read, and records
+/// what is written to it.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FakeConsole {
+    chunks: std::cell::RefCell<std::collections::VecDeque<&'static str>>,
+    pub written: std::cell::RefCell<String>,
+}
+
+#[cfg(test)]
+impl FakeConsole {
+    pub fn new(chunks: &[&'static str]) -> Self {
+        FakeConsole {
+            chunks: std::cell::RefCell::new(chunks.iter().copied().collect()),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl ConsoleDevice for FakeConsole {
+    fn console_read(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+        let Some(chunk) = self.chunks.borrow_mut().pop_front() else {
+            return Ok(0);
+        };
+        buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+        Ok(chunk.len())
+    }
+
+    fn console_write(&self, buf: &[u8]) -> Result<()> {
+        self.written
+            .borrow_mut()
+            .push_str(std::str::from_utf8(buf)?);
+        Ok(())
+    }
+}
@@ -138,6 +138,14 @@ pub const MODE_1S1D1D: Mode = Mode {
     double_transfer_rate: true,
 };
 
+/// Quad-wire address and data, with double transfer rate on address and data phase
+pub const MODE_1S4D4D: Mode = Mode {
+    dummy_cycles: 0,
+    switch: Switch::Mode1NN,
+    width: DataWidth::Quad,
+    double_transfer_rate: true,
+};
+
 /// Single-wire address, dual-wire data
 pub const MODE_112: Mode = Mode {
     dummy_cycles: 0,
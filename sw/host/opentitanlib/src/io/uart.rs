@@ -77,6 +77,11 @@ pub trait Uart {
     /// Writes data from `buf` to the UART.
     fn write(&self, buf: &[u8]) -> Result<()>;
 
+    /// Asserts (or releases) a break condition on the UART transmit line.
+    fn set_break(&self, _enable: bool) -> Result<()> {
+        Err(UartError::GenericError("break condition not supported".into()).into())
+    }
+
     /// Clears the UART RX buffer.
     fn clear_rx_buffer(&self) -> Result<()> {
         // Keep reading while until the RX buffer is empty.
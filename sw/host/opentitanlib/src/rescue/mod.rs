@@ -0,0 +1,372 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host side of the OpenTitan ROM_EXT rescue protocol.
+//!
+//! Rescue mode is requested by holding the console UART in a break condition while the chip
+//! comes out of reset, to which the ROM_EXT responds with a `rescue:` banner.  The host then
+//! selects an operation by sending a four character mode, which the ROM_EXT echoes back as
+//! `mode: XXXX`.  Firmware is sent to the chip, and the boot log or boot data read back, using
+//! XMODEM-CRC.
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::rc::Rc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::app::{NoProgressBar, TransportWrapper};
+use crate::impl_serializable_error;
+use crate::io::uart::Uart;
+use crate::transport::ProgressIndicator;
+use crate::uart::console::UartConsole;
+
+pub mod xmodem;
+
+pub use xmodem::{Xmodem, XmodemError, XmodemStats};
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum RescueError {
+    #[error("Rescue mode {0:?} not acknowledged, ROM_EXT reported {1:?}")]
+    ModeMismatch(String, String),
+    #[error("Invalid boot data: {0}")]
+    BadBootData(String),
+    #[error("Truncated {0}: expected {1} bytes, got {2}")]
+    Truncated(String, usize, usize),
+}
+impl_serializable_error!(RescueError);
+
+/// The ROM_EXT boot data record, as returned by the `BootData` rescue mode.
+#[derive(Clone, Debug, Serialize, Annotate)]
+pub struct BootData {
+    #[annotate(format=hex)]
+    pub digest: Vec<u8>,
+    pub is_valid: bool,
+    #[annotate(format=hex)]
+    pub identifier: u32,
+    #[annotate(format=hex)]
+    pub version: u32,
+    pub counter: u32,
+    pub min_security_version_rom_ext: u32,
+    pub min_security_version_bl0: u32,
+}
+
+impl BootData {
+    /// Size of the serialized `boot_data_t` structure.
+    pub const SIZE: usize = 128;
+    /// Value of `identifier` in a boot data record (`BODA`).
+    pub const IDENTIFIER: u32 = 0x41444f42;
+    /// Value of `is_valid` in a valid boot data record.
+    const VALID: u64 = u64::MAX;
+}
+
+impl TryFrom<&[u8]> for BootData {
+    type Error = RescueError;
+
+    fn try_from(buf: &[u8]) -> std::result::Result<Self, Self::Error> {
+        if buf.len() < Self::SIZE {
+            return Err(RescueError::BadBootData(format!(
+                "expected {} bytes, got {}",
+                Self::SIZE,
+                buf.len()
+            )));
+        }
+        let word = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let is_valid = u64::from_le_bytes(buf[32..40].try_into().unwrap());
+        let boot_data = BootData {
+            digest: buf[0..32].to_vec(),
+            is_valid: is_valid == Self::VALID,
+            identifier: word(40),
+            version: word(44),
+            counter: word(48),
+            min_security_version_rom_ext: word(52),
+            min_security_version_bl0: word(56),
+        };
+        if boot_data.identifier != Self::IDENTIFIER {
+            return Err(RescueError::BadBootData(format!(
+                "bad identifier {:#x}",
+                boot_data.identifier
+            )));
+        }
+        Ok(boot_data)
+    }
+}
+
+/// Drives the ROM_EXT rescue protocol over a UART.
+pub struct RescueSerial {
+    uart: Rc<dyn Uart>,
+    /// How long to wait for the ROM_EXT to respond to the rescue request or a mode change.
+    pub timeout: Duration,
+    /// Parameters for the XMODEM transfers.
+    pub xmodem: Xmodem,
+}
+
+impl RescueSerial {
+    /// Mode for uploading firmware.
+    pub const RESCUE: [u8; 4] = *b"RESQ";
+    /// Mode for reading the boot log.
+    pub const BOOT_LOG: [u8; 4] = *b"BLOG";
+    /// Mode for reading the boot data record.
+    pub const BOOT_DATA: [u8; 4] = *b"BREQ";
+    /// Mode for leaving rescue and rebooting the chip.
+    pub const REBOOT: [u8; 4] = *b"REBO";
+    /// Size of the serialized `boot_log_t` structure.
+    pub const BOOT_LOG_SIZE: usize = 128;
+
+    pub fn new(uart: Rc<dyn Uart>) -> Self {
+        RescueSerial {
+            uart,
+            timeout: Duration::from_secs(5),
+            xmodem: Xmodem::default(),
+        }
+    }
+
+    /// Resets the chip while holding the UART in a break condition, and waits for the ROM_EXT
+    /// to announce that it has entered rescue mode.
+    pub fn enter(&self, transport: &TransportWrapper, reset_delay: Duration) -> Result<()> {
+        self.enter_with_reset(|| transport.reset_target(reset_delay, true))
+    }
+
+    /// Requests rescue mode as `enter` does, with `reset` resetting the chip.
+    fn enter_with_reset(&self, reset: impl FnOnce() -> Result<()>) -> Result<()> {
+        log::info!("Setting serial break to request rescue mode.");
+        self.uart.set_break(true)?;
+        let result = reset()
+            .and_then(|_| UartConsole::wait_for(&*self.uart, r"rescue:.*\r\n", self.timeout));
+        self.uart.set_break(false)?;
+        result?;
+        Ok(())
+    }
+
+    /// Selects the rescue operation `mode`, and confirms that the ROM_EXT switched to it.
+    pub fn set_mode(&self, mode: [u8; 4]) -> Result<()> {
+        let rx = Regex::new(r"mode: ([0-9A-Za-z]{4})\r\n")?;
+        self.uart.write(&mode)?;
+        let result = UartConsole::wait_for(&*self.uart, rx.as_str(), self.timeout)?;
+        let expected = String::from_utf8_lossy(&mode);
+        let reported = rx
+            .captures(&result)
+            .and_then(|c| c.get(1))
+            .map_or("", |m| m.as_str());
+        if reported != expected {
+            return Err(RescueError::ModeMismatch(expected.into(), reported.into()).into());
+        }
+        Ok(())
+    }
+
+    /// Sends a firmware `payload` to the ROM_EXT.
+    pub fn update_firmware(&self, payload: &[u8]) -> Result<XmodemStats> {
+        self.update_firmware_with_progress(payload, &NoProgressBar)
+    }
+
+    /// Sends a firmware `payload` to the ROM_EXT.
+    pub fn update_firmware_with_progress(
+        &self,
+        payload: &[u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<XmodemStats> {
+        self.set_mode(Self::RESCUE)?;
+        self.xmodem
+            .send_with_progress(&*self.uart, payload, progress)
+    }
+
+    /// Receives a `len` byte record in `mode`, dropping the padding of the final XMODEM block.
+    fn receive_record(&self, mode: [u8; 4], len: usize) -> Result<Vec<u8>> {
+        self.set_mode(mode)?;
+        let mut data = self.xmodem.receive(&*self.uart)?;
+        if data.len() < len {
+            let name = String::from_utf8_lossy(&mode).into_owned();
+            return Err(RescueError::Truncated(name, len, data.len()).into());
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Reads the boot log maintained by the ROM and ROM_EXT.
+    pub fn get_boot_log(&self) -> Result<Vec<u8>> {
+        self.receive_record(Self::BOOT_LOG, Self::BOOT_LOG_SIZE)
+    }
+
+    /// Reads the currently active boot data record.
+    pub fn get_boot_data(&self) -> Result<BootData> {
+        let data = self.receive_record(Self::BOOT_DATA, BootData::SIZE)?;
+        Ok(BootData::try_from(data.as_slice())?)
+    }
+
+    /// Instructs the ROM_EXT to leave rescue mode and reboot.
+    pub fn reboot(&self) -> Result<()> {
+        self.set_mode(Self::REBOOT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc::{Crc, CRC_16_XMODEM};
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    /// A ROM_EXT waiting for rescue requests, connected to the host through the `Uart` trait.
+    #[derive(Default)]
+    struct FakeRomExt {
+        /// Bytes waiting to be read by the host.
+        rx: RefCell<VecDeque<u8>>,
+        brk: Cell<bool>,
+        /// The mode to report after a mode change, instead of the requested one.
+        reported_mode: Cell<Option<[u8; 4]>>,
+        /// The current rescue mode.
+        mode: Cell<[u8; 4]>,
+        /// The record sent with XMODEM in the `BOOT_LOG` mode.
+        boot_log: RefCell<Vec<u8>>,
+    }
+
+    impl FakeRomExt {
+        fn reset(&self) -> Result<()> {
+            let banner: &[u8] = if self.brk.get() {
+                b"rescue: RESQ\r\n"
+            } else {
+                b"ROM_EXT booting\r\n"
+            };
+            self.rx.borrow_mut().extend(banner);
+            Ok(())
+        }
+
+        /// Queues the boot log as 1K XMODEM blocks padded with 0x1a, followed by EOT.
+        fn send_boot_log(&self) {
+            let crc16 = Crc::<u16>::new(&CRC_16_XMODEM);
+            let mut rx = self.rx.borrow_mut();
+            for (i, chunk) in self.boot_log.borrow().chunks(1024).enumerate() {
+                let block = (i + 1) as u8;
+                let mut data = chunk.to_vec();
+                data.resize(1024, 0x1a);
+                rx.extend([0x02, block, !block]);
+                rx.extend(&data);
+                rx.extend(crc16.checksum(&data).to_be_bytes());
+            }
+            rx.push_back(0x04);
+        }
+    }
+
+    impl Uart for FakeRomExt {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            self.read_timeout(buf, Duration::ZERO)
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            let len = std::cmp::min(buf.len(), rx.len());
+            for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+                *dst = src;
+            }
+            Ok(len)
+        }
+
+        fn write(&self, buf: &[u8]) -> Result<()> {
+            if let Ok(mode) = <[u8; 4]>::try_from(buf) {
+                self.mode.set(mode);
+                let reported = self.reported_mode.get().unwrap_or(mode);
+                let mut rx = self.rx.borrow_mut();
+                rx.extend(b"mode: ");
+                rx.extend(reported);
+                rx.extend(b"\r\n");
+            } else if buf == b"C" && self.mode.get() == RescueSerial::BOOT_LOG {
+                self.send_boot_log();
+            }
+            Ok(())
+        }
+
+        fn set_break(&self, enable: bool) -> Result<()> {
+            self.brk.set(enable);
+            Ok(())
+        }
+    }
+
+    fn rescue(rom_ext: &Rc<FakeRomExt>) -> RescueSerial {
+        let mut rescue = RescueSerial::new(rom_ext.clone());
+        rescue.timeout = Duration::from_millis(100);
+        rescue
+    }
+
+    #[test]
+    fn test_enter_and_set_mode() -> Result<()> {
+        let rom_ext = Rc::new(FakeRomExt::default());
+        let rescue = rescue(&rom_ext);
+        rescue.enter_with_reset(|| rom_ext.reset())?;
+        assert!(!rom_ext.brk.get());
+
+        rescue.set_mode(RescueSerial::BOOT_DATA)?;
+        assert_eq!(rom_ext.mode.get(), RescueSerial::BOOT_DATA);
+
+        rom_ext.reported_mode.set(Some(RescueSerial::RESCUE));
+        let err = rescue.set_mode(RescueSerial::REBOOT).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RescueError>(),
+            Some(RescueError::ModeMismatch(expected, reported))
+                if expected == "REBO" && reported == "RESQ"
+        ));
+
+        // Without the break condition, the ROM_EXT boots normally and the break is released
+        // after the banner fails to appear.
+        rom_ext.rx.borrow_mut().clear();
+        assert!(rescue.enter_with_reset(|| Ok(())).is_err());
+        assert!(!rom_ext.brk.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_log() -> Result<()> {
+        let rom_ext = Rc::new(FakeRomExt::default());
+        let rescue = rescue(&rom_ext);
+        let log = (0..RescueSerial::BOOT_LOG_SIZE)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        *rom_ext.boot_log.borrow_mut() = log.clone();
+        assert_eq!(rescue.get_boot_log()?, log);
+
+        rom_ext.boot_log.borrow_mut().clear();
+        let err = rescue.get_boot_log().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RescueError>(),
+            Some(RescueError::Truncated(_, 128, 0))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_data() -> Result<()> {
+        let mut buf = vec![0xffu8; BootData::SIZE];
+        buf[..32].copy_from_slice(&[0x5a; 32]);
+        buf[32..40].copy_from_slice(&BootData::VALID.to_le_bytes());
+        buf[40..44].copy_from_slice(&BootData::IDENTIFIER.to_le_bytes());
+        buf[44..48].copy_from_slice(&1u32.to_le_bytes());
+        buf[48..52].copy_from_slice(&7u32.to_le_bytes());
+        buf[52..56].copy_from_slice(&2u32.to_le_bytes());
+        buf[56..60].copy_from_slice(&3u32.to_le_bytes());
+        let boot_data = BootData::try_from(buf.as_slice())?;
+        assert!(boot_data.is_valid);
+        assert_eq!(boot_data.digest, vec![0x5a; 32]);
+        assert_eq!(boot_data.version, 1);
+        assert_eq!(boot_data.counter, 7);
+        assert_eq!(boot_data.min_security_version_rom_ext, 2);
+        assert_eq!(boot_data.min_security_version_bl0, 3);
+
+        buf[40] ^= 1;
+        assert!(matches!(
+            BootData::try_from(buf.as_slice()),
+            Err(RescueError::BadBootData(_))
+        ));
+        assert!(BootData::try_from(&buf[..64]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use crc::{Crc, CRC_16_XMODEM};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::app::NoProgressBar;
+use crate::impl_serializable_error;
+use crate::io::uart::Uart;
+use crate::transport::ProgressIndicator;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum XmodemError {
+    #[error("XMODEM transfer cancelled by peer")]
+    Cancelled,
+    #[error("XMODEM transfer failed after {0} consecutive errors")]
+    ExhaustedRetries(usize),
+    #[error("XMODEM block out of sequence: expected {0}, got {1}")]
+    Sequence(u8, u8),
+    #[error("XMODEM unsupported block length: {0}")]
+    BlockLength(usize),
+}
+impl_serializable_error!(XmodemError);
+
+/// Statistics gathered while sending data with XMODEM.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct XmodemStats {
+    /// Number of blocks making up the data.
+    pub blocks: usize,
+    /// Number of blocks acknowledged by the receiver.
+    pub blocks_acked: usize,
+    /// Number of blocks which had to be resent.
+    pub retries: usize,
+}
+
+/// Implements the XMODEM-CRC file transfer protocol, with optional 1K blocks.
+#[derive(Clone, Debug)]
+pub struct Xmodem {
+    /// Number of consecutive errors tolerated before giving up.
+    pub max_errors: usize,
+    /// Byte used to pad the final block when sending.
+    pub pad_byte: u8,
+    /// Size of blocks when sending: either 128 or 1024.
+    pub block_len: usize,
+    /// How long to wait for each response from the peer.
+    pub timeout: Duration,
+}
+
+impl Default for Xmodem {
+    fn default() -> Self {
+        Xmodem {
+            max_errors: 16,
+            pad_byte: 0xff,
+            block_len: 1024,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Xmodem {
+    const SOH: u8 = 0x01;
+    const STX: u8 = 0x02;
+    const EOT: u8 = 0x04;
+    const ACK: u8 = 0x06;
+    const NAK: u8 = 0x15;
+    const CAN: u8 = 0x18;
+    const CRC: u8 = b'C';
+    const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+    /// Creates an `Xmodem` instance with default parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read_byte(&self, uart: &dyn Uart) -> Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+        match uart.read_timeout(&mut buf, self.timeout)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    /// Reads exactly `buf.len()` bytes, returning false if the peer stops sending first.
+    fn read_exact(&self, uart: &dyn Uart, buf: &mut [u8]) -> Result<bool> {
+        let mut index = 0;
+        while index < buf.len() {
+            match uart.read_timeout(&mut buf[index..], self.timeout)? {
+                0 => return Ok(false),
+                n => index += n,
+            }
+        }
+        Ok(true)
+    }
+
+    /// Sends `data` to a receiver.
+    pub fn send(&self, uart: &dyn Uart, data: &[u8]) -> Result<XmodemStats> {
+        self.send_with_progress(uart, data, &NoProgressBar)
+    }
+
+    /// Sends `data` to a receiver.  The `progress` callback is invoked with the number of bytes
+    /// acknowledged so far.
+    pub fn send_with_progress(
+        &self,
+        uart: &dyn Uart,
+        data: &[u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<XmodemStats> {
+        let start = match self.block_len {
+            128 => Self::SOH,
+            1024 => Self::STX,
+            n => return Err(XmodemError::BlockLength(n).into()),
+        };
+        self.wait_for_receiver(uart)?;
+        progress.new_stage("", data.len());
+        let mut stats = XmodemStats {
+            blocks: (data.len() + self.block_len - 1) / self.block_len,
+            ..Default::default()
+        };
+        let mut block = 1u8;
+        for (index, chunk) in data.chunks(self.block_len).enumerate() {
+            let mut frame = Vec::with_capacity(self.block_len + 5);
+            frame.extend_from_slice(&[start, block, !block]);
+            frame.extend_from_slice(chunk);
+            frame.resize(3 + self.block_len, self.pad_byte);
+            let crc = Self::CRC16.checksum(&frame[3..]);
+            frame.extend_from_slice(&crc.to_be_bytes());
+            stats.retries += self.send_frame(uart, &frame)?;
+            stats.blocks_acked += 1;
+            progress.progress(index * self.block_len + chunk.len());
+            block = block.wrapping_add(1);
+        }
+        self.send_frame(uart, &[Self::EOT])?;
+        Ok(stats)
+    }
+
+    /// Waits for the receiver to request a CRC mode transfer.
+    fn wait_for_receiver(&self, uart: &dyn Uart) -> Result<()> {
+        for _ in 0..=self.max_errors {
+            match self.read_byte(uart)? {
+                Some(Self::CRC) => return Ok(()),
+                Some(Self::CAN) => return Err(XmodemError::Cancelled.into()),
+                _ => continue,
+            }
+        }
+        Err(XmodemError::ExhaustedRetries(self.max_errors).into())
+    }
+
+    /// Sends `frame` until acknowledged, returning the number of retransmissions.
+    fn send_frame(&self, uart: &dyn Uart, frame: &[u8]) -> Result<usize> {
+        for attempt in 0..=self.max_errors {
+            uart.write(frame)?;
+            match self.read_byte(uart)? {
+                Some(Self::ACK) => return Ok(attempt),
+                Some(Self::CAN) => return Err(XmodemError::Cancelled.into()),
+                response => {
+                    log::warn!("XMODEM block not acknowledged: {:x?}", response);
+                    uart.clear_rx_buffer()?;
+                }
+            }
+        }
+        Err(XmodemError::ExhaustedRetries(self.max_errors).into())
+    }
+
+    /// Receives data from a sender, requesting a CRC mode transfer.  Both 128 and 1024 byte
+    /// blocks are accepted.  The data includes whatever padding the sender appended to the
+    /// final block.
+    pub fn receive(&self, uart: &dyn Uart) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut errors = 0;
+        let mut expected = 1u8;
+        let mut started = false;
+        uart.write(&[Self::CRC])?;
+        loop {
+            if errors > self.max_errors {
+                return Err(XmodemError::ExhaustedRetries(self.max_errors).into());
+            }
+            let Some(start) = self.read_byte(uart)? else {
+                // Keep prompting the sender: with `C` until the first block, `NAK` afterwards.
+                uart.write(&[if started { Self::NAK } else { Self::CRC }])?;
+                errors += 1;
+                continue;
+            };
+            let len = match start {
+                Self::SOH => 128,
+                Self::STX => 1024,
+                Self::EOT => {
+                    uart.write(&[Self::ACK])?;
+                    return Ok(data);
+                }
+                Self::CAN => return Err(XmodemError::Cancelled.into()),
+                _ => {
+                    // Stray characters before the first block are to be expected, e.g. the
+                    // remainder of a console message.
+                    if started {
+                        errors += 1;
+                    }
+                    continue;
+                }
+            };
+            started = true;
+            let mut frame = vec![0u8; len + 4];
+            if !self.read_exact(uart, &mut frame)? {
+                log::warn!("XMODEM block truncated");
+                errors += 1;
+                uart.write(&[Self::NAK])?;
+                continue;
+            }
+            let (block, inverse) = (frame[0], frame[1]);
+            let crc = u16::from_be_bytes([frame[len + 2], frame[len + 3]]);
+            if block != !inverse || Self::CRC16.checksum(&frame[2..len + 2]) != crc {
+                log::warn!("XMODEM block {} corrupted", block);
+                errors += 1;
+                uart.clear_rx_buffer()?;
+                uart.write(&[Self::NAK])?;
+                continue;
+            }
+            if block == expected.wrapping_sub(1) {
+                // Our acknowledgement of the previous block was lost, acknowledge it again.
+                uart.write(&[Self::ACK])?;
+                continue;
+            }
+            if block != expected {
+                uart.write(&[Self::CAN, Self::CAN])?;
+                return Err(XmodemError::Sequence(expected, block).into());
+            }
+            data.extend_from_slice(&frame[2..len + 2]);
+            expected = expected.wrapping_add(1);
+            errors = 0;
+            uart.write(&[Self::ACK])?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// Returns `len` bytes of data whose blocks all differ from one another.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// An in-memory XMODEM peer, connected to the host through the `Uart` trait.
+    #[derive(Default)]
+    struct Peer {
+        /// Bytes waiting to be read by the host.
+        rx: RefCell<VecDeque<u8>>,
+        /// Bytes written by the host, not yet processed.
+        tx: RefCell<Vec<u8>>,
+        /// Data received from the host, or to be sent to the host.
+        data: RefCell<Vec<u8>>,
+        /// Index of the next block to send to the host.
+        block: RefCell<usize>,
+        /// Number of blocks to corrupt before behaving correctly.
+        corrupt: RefCell<usize>,
+        done: RefCell<bool>,
+    }
+
+    impl Peer {
+        fn receiver(corrupt: usize) -> Self {
+            let peer = Peer::default();
+            peer.rx.borrow_mut().push_back(Xmodem::CRC);
+            *peer.corrupt.borrow_mut() = corrupt;
+            peer
+        }
+
+        fn sender(data: &[u8], corrupt: usize) -> Self {
+            let peer = Peer::default();
+            *peer.data.borrow_mut() = data.to_vec();
+            *peer.corrupt.borrow_mut() = corrupt;
+            peer
+        }
+
+        /// Processes bytes written by the host, acting as a receiver.
+        fn receive(&self) {
+            let mut tx = self.tx.borrow_mut();
+            let len = match tx.first() {
+                Some(&Xmodem::EOT) => {
+                    tx.clear();
+                    *self.done.borrow_mut() = true;
+                    self.rx.borrow_mut().push_back(Xmodem::ACK);
+                    return;
+                }
+                Some(&Xmodem::SOH) => 128,
+                Some(&Xmodem::STX) => 1024,
+                _ => return,
+            };
+            if tx.len() < len + 5 {
+                return;
+            }
+            let frame: Vec<u8> = tx.drain(..len + 5).collect();
+            let crc = Xmodem::CRC16.checksum(&frame[3..len + 3]);
+            let response = if *self.corrupt.borrow() > 0 {
+                *self.corrupt.borrow_mut() -= 1;
+                Xmodem::NAK
+            } else if crc.to_be_bytes() == frame[len + 3..] {
+                self.data.borrow_mut().extend_from_slice(&frame[3..len + 3]);
+                Xmodem::ACK
+            } else {
+                Xmodem::NAK
+            };
+            self.rx.borrow_mut().push_back(response);
+        }
+
+        /// Processes bytes written by the host, acting as a sender of 128 byte blocks.
+        fn send(&self) {
+            let Some(response) = self.tx.borrow_mut().pop() else {
+                return;
+            };
+            if response == Xmodem::ACK && *self.block.borrow() > 0 {
+                *self.block.borrow_mut() += 1;
+            } else if response == Xmodem::CRC {
+                *self.block.borrow_mut() = 1;
+            }
+            let block = *self.block.borrow();
+            let data = self.data.borrow();
+            let mut rx = self.rx.borrow_mut();
+            if (block - 1) * 128 >= data.len() {
+                if *self.done.borrow() {
+                    return;
+                }
+                *self.done.borrow_mut() = true;
+                rx.push_back(Xmodem::EOT);
+                return;
+            }
+            let mut payload = data[(block - 1) * 128..].to_vec();
+            payload.resize(128, 0x1a);
+            let mut crc = Xmodem::CRC16.checksum(&payload);
+            if *self.corrupt.borrow() > 0 {
+                *self.corrupt.borrow_mut() -= 1;
+                crc ^= 1;
+            }
+            rx.extend([Xmodem::SOH, block as u8, !(block as u8)]);
+            rx.extend(payload);
+            rx.extend(crc.to_be_bytes());
+        }
+    }
+
+    /// Wraps a `Peer` acting either as the receiver or the sender.
+    struct PeerUart(Peer, bool);
+
+    impl Uart for PeerUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            self.read_timeout(buf, Duration::ZERO)
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            let mut rx = self.0.rx.borrow_mut();
+            let len = std::cmp::min(buf.len(), rx.len());
+            for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+                *dst = src;
+            }
+            Ok(len)
+        }
+
+        fn write(&self, buf: &[u8]) -> Result<()> {
+            self.0.tx.borrow_mut().extend_from_slice(buf);
+            match self.1 {
+                true => self.0.receive(),
+                false => self.0.send(),
+            }
+            Ok(())
+        }
+
+        fn clear_rx_buffer(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send() -> Result<()> {
+        let data = pattern(2500);
+        let uart = PeerUart(Peer::receiver(0), true);
+        let stats = Xmodem::new().send(&uart, &data)?;
+        assert_eq!(
+            stats,
+            XmodemStats {
+                blocks: 3,
+                blocks_acked: 3,
+                retries: 0,
+            }
+        );
+        assert!(*uart.0.done.borrow());
+        let received = uart.0.data.borrow();
+        assert_eq!(received.len(), 3072);
+        assert_eq!(&received[..2500], &data);
+        assert!(received[2500..].iter().all(|&b| b == 0xff));
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_retry() -> Result<()> {
+        let data = pattern(300);
+        let uart = PeerUart(Peer::receiver(2), true);
+        let xmodem = Xmodem {
+            block_len: 128,
+            ..Default::default()
+        };
+        let stats = xmodem.send(&uart, &data)?;
+        assert_eq!(
+            stats,
+            XmodemStats {
+                blocks: 3,
+                blocks_acked: 3,
+                retries: 2,
+            }
+        );
+        assert_eq!(&uart.0.data.borrow()[..300], &data);
+
+        let uart = PeerUart(Peer::receiver(100), true);
+        let err = xmodem.send(&uart, &data).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<XmodemError>(),
+            Some(XmodemError::ExhaustedRetries(16))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive() -> Result<()> {
+        let data = pattern(300);
+        let uart = PeerUart(Peer::sender(&data, 2), false);
+        let received = Xmodem::new().receive(&uart)?;
+        assert_eq!(received.len(), 384);
+        assert_eq!(&received[..300], &data);
+        assert!(received[300..].iter().all(|&b| b == 0x1a));
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Chunked, resumable dumping of a SPI flash part to a file.
+//!
+//! The flash is read in fixed-size chunks.  After each chunk is written to the output file,
+//! its CRC is recorded in a sidecar progress file, so that an interrupted dump can pick up
+//! where it left off.  Optionally, every chunk is read a second time and re-read until two
+//! consecutive reads agree, which guards against signal integrity problems on long or slow
+//! links.  A SHA-256 digest of the complete image is computed at the end.
+
+use anyhow::Result;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::app::NoProgressBar;
+use crate::io::spi::Target;
+use crate::spiflash::{ReadMode, SpiFlash};
+use crate::transport::ProgressIndicator;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("chunk at {0:#x} did not read back consistently after {1} reads")]
+    InconsistentChunk(u32, usize),
+    #[error("dump of {0:#x}+{1:#x} exceeds the flash size {2:#x}")]
+    OutOfBounds(u32, usize, u32),
+    #[error("chunk size must be non-zero")]
+    ZeroChunkSize,
+    #[error("read mode {0:?} requires the quad enable (QE) bit, which a dump does not set")]
+    QuadNotEnabled(ReadMode),
+}
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The contents of the sidecar progress file.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpProgress {
+    pub start: u32,
+    pub length: usize,
+    pub chunk_size: usize,
+    /// The CRC32 of each chunk already written to the output file.
+    pub chunks: Vec<Option<u32>>,
+}
+
+impl DumpProgress {
+    fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Write the progress file via a temporary file, so that an interruption never leaves a
+    // truncated progress file behind.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// The outcome of a `FlashDump`.
+#[derive(Debug, Default, Serialize)]
+pub struct DumpReport {
+    /// The number of bytes dumped.
+    pub length: usize,
+    /// The number of chunks making up the dump.
+    pub chunks: usize,
+    /// The number of chunks taken from a previous, interrupted dump.
+    pub chunks_resumed: usize,
+    /// The number of chunks which had to be re-read because two reads disagreed.
+    pub chunks_reread: usize,
+    /// The SHA-256 digest of the dumped image, in hex.
+    pub sha256: String,
+}
+
+/// Describes a chunked dump of a region of flash.
+#[derive(Debug, Clone)]
+pub struct FlashDump {
+    /// The flash address at which to start.
+    pub start: u32,
+    /// The number of bytes to dump.
+    pub length: usize,
+    /// The size of each chunk.
+    pub chunk_size: usize,
+    /// Read every chunk twice and compare the CRCs.
+    pub verify: bool,
+    /// The number of additional reads permitted for a chunk whose CRCs disagree.
+    pub retries: usize,
+}
+
+// Forwards the progress of a single chunk to the progress of the whole dump.
+struct ChunkProgress<'a> {
+    inner: &'a dyn ProgressIndicator,
+    base: usize,
+}
+
+impl ProgressIndicator for ChunkProgress<'_> {
+    fn new_stage(&self, _name: &str, _total: usize) {}
+    fn progress(&self, absolute: usize) {
+        self.inner.progress(self.base + absolute);
+    }
+}
+
+impl FlashDump {
+    /// The path of the sidecar progress file for a dump to `path`.
+    pub fn progress_path(path: &Path) -> PathBuf {
+        let mut progress = path.as_os_str().to_owned();
+        progress.push(".progress");
+        PathBuf::from(progress)
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        CRC32.checksum(data)
+    }
+
+    // Reads the chunk at `address` into `buf`, returning its CRC and the number of re-reads
+    // which were needed to obtain two consistent reads.
+    fn read_chunk(
+        &self,
+        flash: &SpiFlash,
+        spi: &dyn Target,
+        address: u32,
+        buf: &mut [u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<(u32, usize)> {
+        flash.read_with_progress(spi, address, buf, progress)?;
+        let mut crc = Self::crc(buf);
+        if !self.verify {
+            return Ok((crc, 0));
+        }
+        for reread in 0..=self.retries {
+            flash.read_with_progress(spi, address, buf, &NoProgressBar)?;
+            let again = Self::crc(buf);
+            if again == crc {
+                return Ok((crc, reread));
+            }
+            log::warn!(
+                "Chunk at {address:#x} read back inconsistently: {crc:#010x} != {again:#010x}"
+            );
+            crc = again;
+        }
+        Err(Error::InconsistentChunk(address, self.retries + 2).into())
+    }
+
+    /// Dumps the flash to `path`, resuming from the sidecar progress file if there is one
+    /// describing the same dump.  The progress file is removed once the dump is complete.
+    pub fn run(
+        &self,
+        flash: &SpiFlash,
+        spi: &dyn Target,
+        path: &Path,
+        progress: &dyn ProgressIndicator,
+    ) -> Result<DumpReport> {
+        if self.chunk_size == 0 {
+            return Err(Error::ZeroChunkSize.into());
+        }
+        if self.start as u64 + self.length as u64 > flash.size as u64 {
+            return Err(Error::OutOfBounds(self.start, self.length, flash.size).into());
+        }
+        // A dump never writes to the part, so a quad read relies on the QE bit already being set
+        // rather than letting `flash` set it.  A QE bit which cannot be read back is trusted.
+        if flash.read_mode.is_quad() && flash.quad_enable_readable() && !flash.quad_enabled(spi)? {
+            return Err(Error::QuadNotEnabled(flash.read_mode).into());
+        }
+        let chunks = (self.length + self.chunk_size - 1) / self.chunk_size;
+        let progress_path = Self::progress_path(path);
+        let mut state = DumpProgress {
+            start: self.start,
+            length: self.length,
+            chunk_size: self.chunk_size,
+            chunks: vec![None; chunks],
+        };
+        if progress_path.exists() {
+            match DumpProgress::load(&progress_path) {
+                Ok(previous)
+                    if previous.start == state.start
+                        && previous.length == state.length
+                        && previous.chunk_size == state.chunk_size
+                        && previous.chunks.len() == chunks =>
+                {
+                    state = previous
+                }
+                Ok(_) => log::warn!("Ignoring progress file for a different dump"),
+                Err(e) => log::warn!("Ignoring unreadable progress file: {e}"),
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        file.set_len(self.length as u64)?;
+
+        let mut report = DumpReport {
+            length: self.length,
+            chunks,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; self.chunk_size];
+        progress.new_stage("", self.length);
+        for index in 0..chunks {
+            let offset = index * self.chunk_size;
+            let buf = &mut buf[..std::cmp::min(self.chunk_size, self.length - offset)];
+            if let Some(crc) = state.chunks[index] {
+                // Trust a previously dumped chunk only if the file still holds what was read.
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(buf)?;
+                if Self::crc(buf) == crc {
+                    report.chunks_resumed += 1;
+                    progress.progress(offset + buf.len());
+                    continue;
+                }
+                log::warn!("Chunk at offset {offset:#x} in {path:?} is corrupt; reading it again");
+            }
+
+            let address = self.start + offset as u32;
+            let chunk_progress = ChunkProgress {
+                inner: progress,
+                base: offset,
+            };
+            let (crc, rereads) = self.read_chunk(flash, spi, address, buf, &chunk_progress)?;
+            if rereads != 0 {
+                report.chunks_reread += 1;
+            }
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(buf)?;
+            file.sync_data()?;
+            state.chunks[index] = Some(crc);
+            state.save(&progress_path)?;
+        }
+
+        report.sha256 = hex::encode(Self::sha256(&mut file)?);
+        if progress_path.exists() {
+            fs::remove_file(&progress_path)?;
+        }
+        Ok(report)
+    }
+
+    fn sha256(file: &mut File) -> Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 65536];
+        file.seek(SeekFrom::Start(0))?;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spiflash::emulator::{test_emulator as emulator, test_pattern as pattern};
+
+    fn dump(verify: bool, retries: usize) -> FlashDump {
+        FlashDump {
+            start: 0x10000,
+            length: 0x24000,
+            chunk_size: 0x10000,
+            verify,
+            retries,
+        }
+    }
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(0x24000);
+        emu.load(0x10000, &data);
+        let flash = SpiFlash::from_spi(&emu)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dump.bin");
+
+        let report = dump(false, 0).run(&flash, &emu, &path, &NoProgressBar)?;
+        assert_eq!(report.length, data.len());
+        assert_eq!(report.chunks, 3);
+        assert_eq!(report.chunks_resumed, 0);
+        assert_eq!(report.chunks_reread, 0);
+        assert_eq!(report.sha256, hex::encode(Sha256::digest(&data)));
+        assert_eq!(fs::read(&path)?, data);
+        assert!(!FlashDump::progress_path(&path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_quad_enable() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(0x24000);
+        emu.load(0x10000, &data);
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        flash.read_mode = ReadMode::QuadIo;
+        flash.enable_quad = true;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dump.bin");
+
+        // The dump refuses to set the QE bit, even if `flash` would.
+        let err = dump(false, 0)
+            .run(&flash, &emu, &path, &NoProgressBar)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::QuadNotEnabled(ReadMode::QuadIo))
+        ));
+        assert!(!emu.quad_enabled());
+        assert!(!path.exists());
+
+        flash.set_quad_enable(&emu, true, true)?;
+        let status = emu.status();
+        dump(false, 0).run(&flash, &emu, &path, &NoProgressBar)?;
+        assert_eq!(fs::read(&path)?, data);
+        assert_eq!(emu.status(), status);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_resume() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(0x24000);
+        emu.load(0x10000, &data);
+        let flash = SpiFlash::from_spi(&emu)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dump.bin");
+
+        // Simulate an interrupted dump which completed the first two chunks, but where the
+        // second chunk was damaged in the file afterwards.
+        let mut partial = data[..0x20000].to_vec();
+        partial[0x10004] ^= 0x55;
+        fs::write(&path, &partial)?;
+        DumpProgress {
+            start: 0x10000,
+            length: 0x24000,
+            chunk_size: 0x10000,
+            chunks: vec![
+                Some(FlashDump::crc(&data[..0x10000])),
+                Some(FlashDump::crc(&data[0x10000..0x20000])),
+                None,
+            ],
+        }
+        .save(&FlashDump::progress_path(&path))?;
+
+        let report = dump(false, 0).run(&flash, &emu, &path, &NoProgressBar)?;
+        assert_eq!(report.chunks_resumed, 1);
+        assert_eq!(fs::read(&path)?, data);
+        assert!(!FlashDump::progress_path(&path).exists());
+
+        // A progress file for a different dump is ignored.
+        fs::write(&path, b"")?;
+        DumpProgress {
+            start: 0,
+            length: 0x24000,
+            chunk_size: 0x10000,
+            chunks: vec![Some(0); 3],
+        }
+        .save(&FlashDump::progress_path(&path))?;
+        let report = dump(false, 0).run(&flash, &emu, &path, &NoProgressBar)?;
+        assert_eq!(report.chunks_resumed, 0);
+        assert_eq!(fs::read(&path)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_reread() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(0x24000);
+        emu.load(0x10000, &data);
+        let flash = SpiFlash::from_spi(&emu)?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dump.bin");
+
+        // The first read of the first chunk is corrupted, which takes an extra read to resolve.
+        emu.inject_read_errors(1);
+        let report = dump(true, 2).run(&flash, &emu, &path, &NoProgressBar)?;
+        assert_eq!(report.chunks_reread, 1);
+        assert_eq!(fs::read(&path)?, data);
+
+        // Without any retries, the inconsistency is fatal.
+        fs::remove_file(&path)?;
+        emu.inject_read_errors(1);
+        let result = dump(true, 0).run(&flash, &emu, &path, &NoProgressBar);
+        assert!(result.is_err());
+        Ok(())
+    }
+}
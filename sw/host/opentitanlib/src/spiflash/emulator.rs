@@ -0,0 +1,790 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An in-memory model of a SPI NOR flash part.
+//!
+//! `SpiFlashEmulator` implements `io::spi::Target` so that code written against a real flash
+//! part (such as `SpiFlash` or the EEPROM bootstrap protocol) can be exercised without hardware.
+//! The emulator accepts both generic SPI transfers via `run_transaction` and the higher level
+//! `run_eeprom_transactions`, the latter being required for the dual and quad read modes.
+//!
+//! Like a real part, the emulator ignores modifying commands which are not preceded by
+//! WRITE_ENABLE and any command other than status reads while a program or erase operation is in
+//! progress.  Instead of elapsed time, the duration of an operation is measured in the number of
+//! status register reads during which the WIP bit remains set.
+
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::io::eeprom::{Cmd, DataWidth, Switch, Transaction};
+use crate::io::spi::{
+    AssertChipSelect, MaxSizes, SpiError, Target, TargetChipDeassert, Transfer, TransferMode,
+};
+use crate::spiflash::sfdp::{BlockEraseSize, FastReadParam, Sfdp};
+use crate::spiflash::SpiFlash;
+
+/// Additional opcodes understood by the emulator.
+const READ_4B: u8 = 0x13;
+const FAST_READ_4B: u8 = 0x0c;
+const FAST_READ_122: u8 = 0xbb;
+const FAST_READ_144: u8 = 0xeb;
+const FAST_READ_DTR_144: u8 = 0xed;
+const PAGE_PROGRAM_4B: u8 = 0x12;
+const CHIP_ERASE_ALT: u8 = 0x60;
+
+/// Parameters describing the emulated flash part.
+#[derive(Clone, Debug)]
+pub struct EmulatorParams {
+    /// Size of the flash in bytes.
+    pub size: u32,
+    /// Size of a program page in bytes.
+    pub page_size: u32,
+    /// The bytes returned by READ_ID.
+    pub jedec_id: Vec<u8>,
+    /// The bytes returned by READ_SFDP.
+    pub sfdp: Vec<u8>,
+    /// Supported erase operations as (opcode, size) pairs.
+    pub erase: Vec<(u8, u32)>,
+    /// Dummy cycles required by the 1-1-2, 1-2-2, 1-1-4 and 1-4-4 fast reads.
+    pub dummy_112: u8,
+    pub dummy_122: u8,
+    pub dummy_114: u8,
+    pub dummy_144: u8,
+    /// Dummy cycles required by fast reads in QPI (4-4-4) mode.
+    pub dummy_444: u8,
+    /// Dummy cycles required by the 1S-4D-4D DTR read, or `None` if the part does not support it.
+    pub dummy_1s4d4d: Option<u8>,
+    /// The SFDP quad enable requirements, determining the location of the QE bit.
+    pub quad_enable_requirements: u8,
+    /// Number of status reads during which WIP stays set after a page program.
+    pub program_busy_polls: u32,
+    /// Number of status reads during which WIP stays set after a sector or block erase.
+    pub erase_busy_polls: u32,
+    /// Number of status reads during which WIP stays set after a chip erase.
+    pub chip_erase_busy_polls: u32,
+}
+
+impl Default for EmulatorParams {
+    fn default() -> Self {
+        EmulatorParams {
+            size: 1024 * 1024,
+            page_size: SpiFlash::LEGACY_PAGE_SIZE,
+            jedec_id: vec![0xc2, 0x20, 0x1b],
+            sfdp: Vec::new(),
+            erase: vec![
+                (SpiFlash::SECTOR_ERASE, 4096),
+                (SpiFlash::BLOCK_ERASE_32K, 32768),
+                (SpiFlash::BLOCK_ERASE_64K, 65536),
+            ],
+            dummy_112: 8,
+            dummy_122: 4,
+            dummy_114: 8,
+            dummy_144: 6,
+            dummy_444: 6,
+            dummy_1s4d4d: None,
+            quad_enable_requirements: 0,
+            program_busy_polls: 1,
+            erase_busy_polls: 2,
+            chip_erase_busy_polls: 4,
+        }
+    }
+}
+
+impl EmulatorParams {
+    /// Creates parameters for the part described by the SFDP table `sfdp`.
+    pub fn from_sfdp(sfdp: &[u8]) -> Result<Self> {
+        let table = Sfdp::try_from(sfdp)?;
+        let jedec = &table.jedec;
+        let mut erase = jedec
+            .erase
+            .iter()
+            .filter(|e| e.size != 0)
+            .map(|e| (e.opcode, e.size))
+            .collect::<Vec<_>>();
+        if jedec.block_erase_size == BlockEraseSize::Block4KiB && !erase.iter().any(|e| e.1 == 4096)
+        {
+            erase.push((jedec.erase_opcode_4kib, 4096));
+        }
+        let dummy = |p: &FastReadParam| p.wait_states + p.mode_bits;
+        let defaults = EmulatorParams::default();
+        Ok(EmulatorParams {
+            size: jedec.density,
+            page_size: jedec
+                .rev_b
+                .as_ref()
+                .map(|r| r.page_size)
+                .unwrap_or(defaults.page_size),
+            sfdp: sfdp.to_vec(),
+            erase,
+            dummy_112: dummy(&jedec.param_112),
+            dummy_122: dummy(&jedec.param_122),
+            dummy_114: dummy(&jedec.param_114),
+            dummy_144: dummy(&jedec.param_144),
+            dummy_444: dummy(&jedec.param_444),
+            dummy_1s4d4d: jedec
+                .rev_f
+                .as_ref()
+                .and_then(|f| f.param_1s4d4d.as_ref())
+                .map(dummy),
+            quad_enable_requirements: jedec
+                .rev_b
+                .as_ref()
+                .map(|r| r.quad_enable_requirements)
+                .unwrap_or(defaults.quad_enable_requirements),
+            ..defaults
+        })
+    }
+}
+
+/// The mutable state of the emulated part.
+struct State {
+    memory: Vec<u8>,
+    /// Status registers 1-3.  Only the WIP and WEL bits of register 1 have a fixed meaning.
+    status: [u8; 3],
+    /// The non-volatile copy of the status registers, restored on power cycle.
+    status_nv: [u8; 3],
+    /// Remaining status reads until the current operation completes.
+    busy: u32,
+    four_byte: bool,
+    qpi: bool,
+    reset_enabled: bool,
+    volatile_enabled: bool,
+    /// Remaining flash array reads to corrupt.
+    read_errors: u32,
+    /// The bytes sent so far in a generic SPI transaction.
+    raw: Vec<u8>,
+}
+
+/// An in-memory SPI NOR flash.
+pub struct SpiFlashEmulator {
+    params: EmulatorParams,
+    state: RefCell<State>,
+    transfer_mode: Cell<TransferMode>,
+    bits_per_word: Cell<u32>,
+    max_speed: Cell<u32>,
+    cs_asserted: Cell<usize>,
+}
+
+impl SpiFlashEmulator {
+    /// Creates an erased flash part described by `params`.
+    pub fn new(params: EmulatorParams) -> Self {
+        let memory = vec![0xffu8; params.size as usize];
+        SpiFlashEmulator {
+            params,
+            state: RefCell::new(State {
+                memory,
+                status: [0; 3],
+                status_nv: [0; 3],
+                busy: 0,
+                four_byte: false,
+                qpi: false,
+                reset_enabled: false,
+                volatile_enabled: false,
+                read_errors: 0,
+                raw: Vec::new(),
+            }),
+            transfer_mode: Cell::new(TransferMode::Mode0),
+            bits_per_word: Cell::new(8),
+            max_speed: Cell::new(1_000_000),
+            cs_asserted: Cell::new(0),
+        }
+    }
+
+    /// Creates an erased flash part described by the SFDP table `sfdp`.
+    pub fn from_sfdp(sfdp: &[u8]) -> Result<Self> {
+        Ok(Self::new(EmulatorParams::from_sfdp(sfdp)?))
+    }
+
+    /// The parameters of the emulated part.
+    pub fn params(&self) -> &EmulatorParams {
+        &self.params
+    }
+
+    /// Returns a copy of `len` bytes of flash contents starting at `address`.
+    pub fn contents(&self, address: u32, len: usize) -> Vec<u8> {
+        let start = address as usize;
+        self.state.borrow().memory[start..start + len].to_vec()
+    }
+
+    /// Replaces the flash contents starting at `address` with `data`.
+    pub fn load(&self, address: u32, data: &[u8]) {
+        let start = address as usize;
+        self.state.borrow_mut().memory[start..start + data.len()].copy_from_slice(data);
+    }
+
+    /// Returns status register 1.
+    pub fn status(&self) -> u8 {
+        self.state.borrow().status[0]
+    }
+
+    /// Simulates a power cycle: volatile state is lost and the status registers are restored
+    /// from their non-volatile copy.
+    pub fn power_cycle(&self) {
+        let mut state = self.state.borrow_mut();
+        state.status = state.status_nv;
+        state.busy = 0;
+        state.four_byte = false;
+        state.qpi = false;
+        state.reset_enabled = false;
+        state.volatile_enabled = false;
+        state.raw.clear();
+    }
+
+    /// Corrupts the data returned by the next `count` reads of the flash array, as if
+    /// they had been disturbed on the wire.  The contents of the flash are unaffected.
+    pub fn inject_read_errors(&self, count: u32) {
+        self.state.borrow_mut().read_errors = count;
+    }
+
+    /// Returns whether the part is in QPI (4-4-4) mode.
+    pub fn qpi_mode(&self) -> bool {
+        self.state.borrow().qpi
+    }
+
+    /// Returns whether the quad enable (QE) bit is set, or the part has none.
+    pub fn quad_enabled(&self) -> bool {
+        let status = self.state.borrow().status;
+        // JESD216D section 6.4.18.
+        match self.params.quad_enable_requirements {
+            1 | 4 | 5 | 6 => status[1] & 0x02 != 0,
+            2 => status[0] & 0x40 != 0,
+            3 => status[1] & 0x80 != 0,
+            _ => true,
+        }
+    }
+
+    /// Returns whether status register 2 can be read with READ_STATUS2.  Parts with quad enable
+    /// requirements 1 and 4 have no such command.
+    fn has_read_status2(&self) -> bool {
+        !matches!(self.params.quad_enable_requirements, 1 | 4)
+    }
+
+    /// Returns whether the part is in 4-byte address mode.
+    pub fn four_byte_mode(&self) -> bool {
+        self.state.borrow().four_byte
+    }
+
+    /// Returns the number of address bytes which follow `opcode`, if it takes an address.
+    fn address_len(&self, opcode: u8) -> Option<usize> {
+        let state = self.state.borrow();
+        let current = if state.four_byte { 4 } else { 3 };
+        match opcode {
+            READ_4B | FAST_READ_4B | PAGE_PROGRAM_4B => Some(4),
+            SpiFlash::SECTOR_ERASE_4B
+            | SpiFlash::BLOCK_ERASE_32K_4B
+            | SpiFlash::BLOCK_ERASE_64K_4B => Some(4),
+            // READ_SFDP always uses a 3-byte address.
+            SpiFlash::READ_SFDP => Some(3),
+            SpiFlash::READ
+            | SpiFlash::FAST_READ
+            | SpiFlash::FAST_DUAL_READ
+            | SpiFlash::FAST_QUAD_READ
+            | FAST_READ_122
+            | FAST_READ_144
+            | FAST_READ_DTR_144
+            | SpiFlash::PAGE_PROGRAM => Some(current),
+            op if self.erase_size(op).is_some() => Some(current),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of dummy bytes which follow the address of `opcode` on a single-wire
+    /// bus.
+    fn dummy_bytes(&self, opcode: u8) -> usize {
+        match opcode {
+            SpiFlash::FAST_READ | FAST_READ_4B | SpiFlash::READ_SFDP => 1,
+            _ => 0,
+        }
+    }
+
+    /// Returns the size erased by `opcode`, if it is an erase.
+    fn erase_size(&self, opcode: u8) -> Option<u32> {
+        let size = match opcode {
+            SpiFlash::SECTOR_ERASE_4B => Some(4096),
+            SpiFlash::BLOCK_ERASE_32K_4B => Some(32768),
+            SpiFlash::BLOCK_ERASE_64K_4B => Some(65536),
+            _ => None,
+        };
+        size.or_else(|| {
+            self.params
+                .erase
+                .iter()
+                .find(|(op, _)| *op == opcode)
+                .map(|(_, size)| *size)
+        })
+    }
+
+    /// Checks that a command without a data phase from the part was issued in the current bus
+    /// mode: single-wire normally, quad-wire in QPI mode.
+    fn check_command_mode(&self, cmd: &Cmd) -> Result<()> {
+        if cmd.get_double_transfer_rate() {
+            return Err(SpiError::InvalidDoubleTransferRate().into());
+        }
+        let expected = if self.state.borrow().qpi {
+            (Switch::ModeNNN, DataWidth::Quad)
+        } else {
+            (Switch::Mode111, DataWidth::Single)
+        };
+        if (cmd.get_switch(), cmd.get_width()) != expected {
+            return Err(SpiError::InvalidDataWidth(cmd.get_width()).into());
+        }
+        Ok(())
+    }
+
+    /// Checks that a read `opcode` was issued with the bus mode and dummy cycles it requires.
+    fn check_read_mode(&self, cmd: &Cmd) -> Result<()> {
+        let opcode = cmd.get_opcode()[0];
+        if opcode == SpiFlash::READ_STATUS2 && !self.has_read_status2() {
+            return Err(
+                SpiError::InvalidOption(format!("Unsupported read opcode {opcode:#x}")).into(),
+            );
+        }
+        let (switch, width, dummy) = (cmd.get_switch(), cmd.get_width(), cmd.get_dummy_cycles());
+        let expected = if cmd.get_double_transfer_rate() {
+            // The only DTR read understood by the emulator is the 1S-4D-4D quad I/O read.
+            match self.params.dummy_1s4d4d {
+                Some(dummy) if opcode == FAST_READ_DTR_144 && !self.state.borrow().qpi => {
+                    (Switch::Mode1NN, DataWidth::Quad, dummy)
+                }
+                _ => return Err(SpiError::InvalidDoubleTransferRate().into()),
+            }
+        } else if self.state.borrow().qpi {
+            match opcode {
+                SpiFlash::READ_STATUS
+                | SpiFlash::READ_STATUS2
+                | SpiFlash::READ_STATUS3
+                | SpiFlash::READ_STATUS2_ALT
+                | SpiFlash::READ_ID => (Switch::ModeNNN, DataWidth::Quad, 0),
+                SpiFlash::FAST_READ | FAST_READ_144 => {
+                    (Switch::ModeNNN, DataWidth::Quad, self.params.dummy_444)
+                }
+                _ => {
+                    return Err(SpiError::InvalidOption(format!(
+                        "Unsupported QPI read opcode {opcode:#x}"
+                    ))
+                    .into())
+                }
+            }
+        } else {
+            self.expected_read_mode(opcode)?
+        };
+        if (switch, width) != (expected.0, expected.1) {
+            return Err(SpiError::InvalidDataWidth(width).into());
+        }
+        if dummy != expected.2 {
+            return Err(SpiError::InvalidDummyCycles(dummy).into());
+        }
+        Ok(())
+    }
+
+    /// Returns the bus mode and dummy cycles required by a read `opcode` outside of QPI mode.
+    fn expected_read_mode(&self, opcode: u8) -> Result<(Switch, DataWidth, u8)> {
+        Ok(match opcode {
+            SpiFlash::READ | READ_4B | SpiFlash::READ_ID => (Switch::Mode111, DataWidth::Single, 0),
+            SpiFlash::READ_STATUS
+            | SpiFlash::READ_STATUS2
+            | SpiFlash::READ_STATUS3
+            | SpiFlash::READ_STATUS2_ALT => (Switch::Mode111, DataWidth::Single, 0),
+            SpiFlash::FAST_READ | FAST_READ_4B | SpiFlash::READ_SFDP => {
+                (Switch::Mode111, DataWidth::Single, 8)
+            }
+            SpiFlash::FAST_DUAL_READ => (Switch::Mode11N, DataWidth::Dual, self.params.dummy_112),
+            FAST_READ_122 => (Switch::Mode1NN, DataWidth::Dual, self.params.dummy_122),
+            SpiFlash::FAST_QUAD_READ => (Switch::Mode11N, DataWidth::Quad, self.params.dummy_114),
+            FAST_READ_144 => (Switch::Mode1NN, DataWidth::Quad, self.params.dummy_144),
+            _ => {
+                return Err(
+                    SpiError::InvalidOption(format!("Unsupported read opcode {opcode:#x}")).into(),
+                )
+            }
+        })
+    }
+
+    /// Produces the byte at offset `index` of the data phase of a read `opcode`.
+    fn read_byte(&self, opcode: u8, address: u32, index: usize) -> u8 {
+        let mut state = self.state.borrow_mut();
+        if state.busy != 0 && opcode != SpiFlash::READ_STATUS {
+            return 0xff;
+        }
+        match opcode {
+            SpiFlash::READ_STATUS => {
+                let status = state.status[0];
+                if state.busy != 0 {
+                    state.busy -= 1;
+                    if state.busy == 0 {
+                        state.status[0] &= !(SpiFlash::STATUS_WIP | SpiFlash::STATUS_WEL);
+                    }
+                }
+                status
+            }
+            SpiFlash::READ_STATUS2 if !self.has_read_status2() => 0xff,
+            SpiFlash::READ_STATUS2 | SpiFlash::READ_STATUS2_ALT => state.status[1],
+            SpiFlash::READ_STATUS3 => state.status[2],
+            SpiFlash::READ_ID => *self.params.jedec_id.get(index).unwrap_or(&0),
+            SpiFlash::READ_SFDP => *self
+                .params
+                .sfdp
+                .get(address as usize + index)
+                .unwrap_or(&0xff),
+            _ => {
+                let size = state.memory.len();
+                state.memory[(address as usize + index) % size]
+            }
+        }
+    }
+
+    /// Returns whether a modifying command may proceed, consuming the write enable latch.
+    fn take_write_enable(&self, opcode: u8) -> bool {
+        let state = self.state.borrow();
+        if state.busy != 0 {
+            log::warn!("Ignoring opcode {opcode:#x} while busy");
+            return false;
+        }
+        if state.status[0] & SpiFlash::STATUS_WEL == 0 {
+            log::warn!("Ignoring opcode {opcode:#x} without write enable");
+            return false;
+        }
+        true
+    }
+
+    /// Completes a modifying operation, leaving the part busy for `polls` status reads.
+    fn start_busy(&self, polls: u32) {
+        let mut state = self.state.borrow_mut();
+        if polls == 0 {
+            state.status[0] &= !(SpiFlash::STATUS_WIP | SpiFlash::STATUS_WEL);
+        } else {
+            state.status[0] |= SpiFlash::STATUS_WIP;
+            state.busy = polls;
+        }
+    }
+
+    /// Executes a command which does not read data.
+    fn execute(&self, opcode: u8, address: u32, data: &[u8]) -> Result<()> {
+        let reset_enabled = std::mem::take(&mut self.state.borrow_mut().reset_enabled);
+        let volatile_enabled = std::mem::take(&mut self.state.borrow_mut().volatile_enabled);
+        if self.state.borrow().busy != 0 {
+            log::warn!("Ignoring opcode {opcode:#x} while busy");
+            return Ok(());
+        }
+        match opcode {
+            SpiFlash::WRITE_ENABLE => self.state.borrow_mut().status[0] |= SpiFlash::STATUS_WEL,
+            SpiFlash::WRITE_DISABLE => self.state.borrow_mut().status[0] &= !SpiFlash::STATUS_WEL,
+            SpiFlash::ENTER_4B => self.state.borrow_mut().four_byte = true,
+            SpiFlash::EXIT_4B => self.state.borrow_mut().four_byte = false,
+            SpiFlash::NOP => {}
+            // ENTER_QPI_ALT shares its opcode with READ_STATUS2, but has no data phase.
+            SpiFlash::ENTER_QPI | SpiFlash::ENTER_QPI_ALT => self.state.borrow_mut().qpi = true,
+            SpiFlash::EXIT_QPI | SpiFlash::EXIT_QPI_ALT => self.state.borrow_mut().qpi = false,
+            SpiFlash::RESET_ENABLE => self.state.borrow_mut().reset_enabled = true,
+            SpiFlash::WRITE_ENABLE_VOLATILE => self.state.borrow_mut().volatile_enabled = true,
+            SpiFlash::RESET => {
+                if reset_enabled {
+                    let mut state = self.state.borrow_mut();
+                    state.status[0] &= !SpiFlash::STATUS_WEL;
+                    state.four_byte = false;
+                    state.qpi = false;
+                }
+            }
+            SpiFlash::WRITE_STATUS
+            | SpiFlash::WRITE_STATUS2
+            | SpiFlash::WRITE_STATUS3
+            | SpiFlash::WRITE_STATUS2_ALT => {
+                // A write following WRITE_ENABLE_VOLATILE only updates the volatile copy and
+                // completes immediately.
+                if !volatile_enabled && !self.take_write_enable(opcode) {
+                    return Ok(());
+                }
+                let first = match opcode {
+                    SpiFlash::WRITE_STATUS => 0,
+                    SpiFlash::WRITE_STATUS2 | SpiFlash::WRITE_STATUS2_ALT => 1,
+                    _ => 2,
+                };
+                let mut state = self.state.borrow_mut();
+                for (i, &value) in data.iter().take(3 - first).enumerate() {
+                    // The WIP and WEL bits are not writable.
+                    let value = if first + i == 0 {
+                        (value & !(SpiFlash::STATUS_WIP | SpiFlash::STATUS_WEL))
+                            | (state.status[0] & SpiFlash::STATUS_WEL)
+                    } else {
+                        value
+                    };
+                    state.status[first + i] = value;
+                    if !volatile_enabled {
+                        state.status_nv[first + i] = value & !SpiFlash::STATUS_WEL;
+                    }
+                }
+                drop(state);
+                if !volatile_enabled {
+                    self.start_busy(self.params.program_busy_polls);
+                }
+            }
+            SpiFlash::PAGE_PROGRAM | PAGE_PROGRAM_4B => {
+                if !self.take_write_enable(opcode) {
+                    return Ok(());
+                }
+                let page_size = self.params.page_size as usize;
+                let base = address as usize % self.params.size as usize;
+                let page_base = base - base % page_size;
+                let offset = base % page_size;
+                // Data beyond the page boundary wraps around to the start of the page, with later
+                // bytes replacing earlier ones.
+                let mut page = vec![0xffu8; page_size];
+                for (i, &byte) in data.iter().enumerate() {
+                    page[(offset + i) % page_size] = byte;
+                }
+                let mut state = self.state.borrow_mut();
+                for (cell, byte) in state.memory[page_base..page_base + page_size]
+                    .iter_mut()
+                    .zip(page)
+                {
+                    *cell &= byte;
+                }
+                drop(state);
+                self.start_busy(self.params.program_busy_polls);
+            }
+            SpiFlash::CHIP_ERASE | CHIP_ERASE_ALT => {
+                if !self.take_write_enable(opcode) {
+                    return Ok(());
+                }
+                self.state.borrow_mut().memory.fill(0xff);
+                self.start_busy(self.params.chip_erase_busy_polls);
+            }
+            op => match self.erase_size(op) {
+                Some(size) => {
+                    if !self.take_write_enable(opcode) {
+                        return Ok(());
+                    }
+                    let start = (address - address % size) as usize % self.params.size as usize;
+                    let end = std::cmp::min(start + size as usize, self.params.size as usize);
+                    self.state.borrow_mut().memory[start..end].fill(0xff);
+                    self.start_busy(self.params.erase_busy_polls);
+                }
+                None => {
+                    return Err(
+                        SpiError::InvalidOption(format!("Unsupported opcode {op:#x}")).into(),
+                    )
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns whether `opcode` reads data from the part.
+    fn is_read(opcode: u8) -> bool {
+        matches!(
+            opcode,
+            SpiFlash::READ
+                | READ_4B
+                | SpiFlash::FAST_READ
+                | FAST_READ_4B
+                | SpiFlash::FAST_DUAL_READ
+                | SpiFlash::FAST_QUAD_READ
+                | FAST_READ_122
+                | FAST_READ_144
+                | SpiFlash::READ_STATUS
+                | SpiFlash::READ_STATUS2
+                | SpiFlash::READ_STATUS3
+                | SpiFlash::READ_STATUS2_ALT
+                | SpiFlash::READ_ID
+                | SpiFlash::READ_SFDP
+        )
+    }
+
+    /// Parses the header of a generic SPI transaction, returning the opcode, address and header
+    /// length once enough bytes have been received.
+    fn parse_raw_header(&self, raw: &[u8]) -> Option<(u8, u32, usize)> {
+        let opcode = *raw.first()?;
+        let addr_len = self.address_len(opcode).unwrap_or(0);
+        let header_len = 1 + addr_len + self.dummy_bytes(opcode);
+        if raw.len() < 1 + addr_len {
+            return None;
+        }
+        let address = raw[1..1 + addr_len]
+            .iter()
+            .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+        Some((opcode, address, header_len))
+    }
+
+    /// Clocks one byte of a generic SPI transaction, returning the byte driven by the part.
+    fn clock_raw(&self, mosi: u8) -> u8 {
+        let pos = self.state.borrow().raw.len();
+        let header = self.parse_raw_header(&self.state.borrow().raw);
+        self.state.borrow_mut().raw.push(mosi);
+        match header {
+            Some((opcode, address, header_len)) if Self::is_read(opcode) && pos >= header_len => {
+                self.read_byte(opcode, address, pos - header_len)
+            }
+            _ => 0xff,
+        }
+    }
+
+    /// Ends a generic SPI transaction, executing any non-read command it contained.
+    fn end_raw(&self) -> Result<()> {
+        let raw = std::mem::take(&mut self.state.borrow_mut().raw);
+        match self.parse_raw_header(&raw) {
+            Some((opcode, _, _)) if Self::is_read(opcode) => Ok(()),
+            Some((opcode, address, header_len)) => {
+                self.execute(opcode, address, raw.get(header_len..).unwrap_or(&[]))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Target for SpiFlashEmulator {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        Ok(self.transfer_mode.get())
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.transfer_mode.set(mode);
+        Ok(())
+    }
+
+    fn get_bits_per_word(&self) -> Result<u32> {
+        Ok(self.bits_per_word.get())
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        match bits_per_word {
+            8 => {
+                self.bits_per_word.set(bits_per_word);
+                Ok(())
+            }
+            _ => Err(SpiError::InvalidWordSize(bits_per_word).into()),
+        }
+    }
+
+    fn get_max_speed(&self) -> Result<u32> {
+        Ok(self.max_speed.get())
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        self.max_speed.set(max_speed);
+        Ok(())
+    }
+
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        Ok(usize::MAX)
+    }
+
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        Ok(MaxSizes {
+            read: 4096,
+            write: 4096,
+        })
+    }
+
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        for transfer in transaction.iter_mut() {
+            match transfer {
+                Transfer::Read(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = self.clock_raw(0xff);
+                    }
+                }
+                Transfer::Write(buf) => {
+                    for &byte in buf.iter() {
+                        self.clock_raw(byte);
+                    }
+                }
+                Transfer::Both(wbuf, rbuf) => {
+                    if wbuf.len() != rbuf.len() {
+                        return Err(SpiError::MismatchedDataLength(wbuf.len(), rbuf.len()).into());
+                    }
+                    for (&w, r) in wbuf.iter().zip(rbuf.iter_mut()) {
+                        *r = self.clock_raw(w);
+                    }
+                }
+            }
+        }
+        if self.cs_asserted.get() == 0 {
+            self.end_raw()?;
+        }
+        Ok(())
+    }
+
+    fn run_eeprom_transactions(&self, transactions: &mut [Transaction]) -> Result<()> {
+        for transaction in transactions.iter_mut() {
+            match transaction {
+                Transaction::Command(cmd) => {
+                    self.check_command_mode(cmd)?;
+                    self.execute(cmd.get_opcode()[0], cmd.get_address(), &[])?;
+                }
+                Transaction::Write(cmd, data) => {
+                    self.check_command_mode(cmd)?;
+                    self.execute(cmd.get_opcode()[0], cmd.get_address(), *data)?;
+                }
+                Transaction::Read(cmd, buf) => {
+                    let opcode = cmd.get_opcode()[0];
+                    self.check_read_mode(cmd)?;
+                    if let Some(len) = self.address_len(opcode) {
+                        if len != cmd.get_address_len() as usize {
+                            return Err(SpiError::InvalidOption(format!(
+                                "Opcode {opcode:#x} requires a {len}-byte address"
+                            ))
+                            .into());
+                        }
+                    }
+                    // Without the QE bit, IO2 and IO3 act as WP# and HOLD# and the part does not
+                    // drive them.
+                    if cmd.get_width() == DataWidth::Quad
+                        && !self.qpi_mode()
+                        && !self.quad_enabled()
+                    {
+                        log::warn!("Quad read {opcode:#x} without the QE bit set");
+                        buf.fill(0xff);
+                        continue;
+                    }
+                    let address = cmd.get_address();
+                    for (i, byte) in buf.iter_mut().enumerate() {
+                        *byte = self.read_byte(opcode, address, i);
+                    }
+                    let array_read =
+                        opcode != SpiFlash::READ_SFDP && self.address_len(opcode).is_some();
+                    let mut state = self.state.borrow_mut();
+                    if array_read && state.read_errors != 0 && !buf.is_empty() {
+                        state.read_errors -= 1;
+                        buf[0] ^= 0xff;
+                    }
+                }
+                Transaction::WaitForBusyClear => {
+                    while self.read_byte(SpiFlash::READ_STATUS, 0, 0) & SpiFlash::STATUS_WIP != 0 {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        self.cs_asserted.set(self.cs_asserted.get() + 1);
+        Ok(AssertChipSelect::new(self))
+    }
+}
+
+impl TargetChipDeassert for SpiFlashEmulator {
+    fn deassert_cs(&self) {
+        let count = self.cs_asserted.get() - 1;
+        self.cs_asserted.set(count);
+        if count == 0 {
+            if let Err(e) = self.end_raw() {
+                log::error!("SPI flash emulator: {e}");
+            }
+        }
+    }
+}
+
+/// An emulated MX66L1G part for tests.  To keep the tests light, the emulated part is smaller than
+/// the 128 MiB of the real one, with the density in its SFDP table reduced to `size` bytes.
+#[cfg(test)]
+pub(crate) fn test_emulator(size: u32) -> Result<SpiFlashEmulator> {
+    let mut sfdp = Sfdp::try_from(&include_bytes!("SFDP_MX66L1G.bin")[..])?;
+    sfdp.jedec.density = size;
+    SpiFlashEmulator::from_sfdp(&sfdp.to_bytes()?)
+}
+
+/// A `len` byte test pattern which does not repeat with a power-of-two period.
+#[cfg(test)]
+pub(crate) fn test_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
@@ -3,14 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::app::NoProgressBar;
-use crate::io::eeprom::{AddressMode, Mode, Transaction, MODE_111, MODE_112, MODE_114};
+use crate::io::eeprom::{
+    AddressMode, Mode, Transaction, MODE_111, MODE_112, MODE_114, MODE_144, MODE_1S4D4D, MODE_444,
+};
 use crate::io::spi::Target;
 use crate::spiflash::sfdp::{
-    BlockEraseSize, FastReadParam, SectorErase, Sfdp, SupportedAddressModes,
+    BlockEraseSize, FastReadParam, JedecParamsRevB, SectorErase, Sfdp, SupportedAddressModes,
 };
-use crate::transport::ProgressIndicator;
+use crate::transport::{Capabilities, Capability, ProgressIndicator};
 use anyhow::{ensure, Result};
 use clap::ValueEnum;
+use serde::Serialize;
 use std::convert::TryFrom;
 use thiserror::Error;
 
@@ -26,6 +29,14 @@ pub enum Error {
     BadSequenceLength(usize),
     #[error("unsupported mode: {0:?}")]
     UnsupportedMode(ReadMode),
+    #[error("unsupported quad enable requirements: {0}")]
+    UnsupportedQuadEnable(u8),
+    #[error("quad enable (QE) bit is not set")]
+    QuadNotEnabled,
+    #[error("unsupported QPI sequence: {0:#x}")]
+    UnsupportedQpiSequence(u8),
+    #[error("verify failed at address {0:#x}")]
+    VerifyFailed(u32),
 }
 
 impl From<SupportedAddressModes> for AddressMode {
@@ -37,7 +48,7 @@ impl From<SupportedAddressModes> for AddressMode {
     }
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
 #[value(rename_all = "verbatim")]
 pub enum ReadMode {
     #[default]
@@ -45,13 +56,37 @@ pub enum ReadMode {
     Fast,
     Dual,
     Quad,
+    /// Quad I/O read (1-4-4).
+    QuadIo,
+    /// Quad peripheral interface read (4-4-4).  The part is switched into QPI mode for the
+    /// duration of the read.
+    Qpi,
+    /// Quad I/O read with double transfer rate address and data (1S-4D-4D).
+    QuadDtr,
+    /// Select the fastest mode supported by both the flash and the transport.  Must be resolved
+    /// with `SpiFlash::set_read_mode_auto` before reading.
+    Auto,
 }
 
+impl ReadMode {
+    /// Whether the mode transfers data on the IO2 and IO3 lines, requiring the QE bit.
+    pub fn is_quad(&self) -> bool {
+        matches!(
+            self,
+            ReadMode::Quad | ReadMode::QuadIo | ReadMode::Qpi | ReadMode::QuadDtr
+        )
+    }
+}
+
+/// Read parameters for each `ReadMode`.  An opcode of zero indicates an unsupported mode.
 pub struct ReadTypes {
     pub standard: FastReadParam,
     pub fast: FastReadParam,
     pub dual: FastReadParam,
     pub quad: FastReadParam,
+    pub quad_io: FastReadParam,
+    pub qpi: FastReadParam,
+    pub quad_dtr: FastReadParam,
 }
 
 impl Default for ReadTypes {
@@ -77,6 +112,9 @@ impl Default for ReadTypes {
                 mode_bits: 0,
                 opcode: SpiFlash::FAST_QUAD_READ,
             },
+            quad_io: Default::default(),
+            qpi: Default::default(),
+            quad_dtr: Default::default(),
         }
     }
 }
@@ -104,6 +142,22 @@ impl ReadTypes {
             } else {
                 Default::default()
             },
+            quad_io: if sfdp.jedec.support_fast_read_144 {
+                sfdp.jedec.param_144.clone()
+            } else {
+                Default::default()
+            },
+            qpi: if sfdp.jedec.support_fast_read_444 {
+                sfdp.jedec.param_444.clone()
+            } else {
+                Default::default()
+            },
+            quad_dtr: sfdp
+                .jedec
+                .rev_f
+                .as_ref()
+                .and_then(|f| f.param_1s4d4d.clone())
+                .unwrap_or_default(),
         }
     }
 }
@@ -116,12 +170,29 @@ pub enum EraseMode {
     Block,
 }
 
+/// Statistics about an incremental program operation.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct IncrementalStats {
+    /// The number of erase blocks examined.
+    pub blocks: usize,
+    /// The number of blocks which already held the desired contents.
+    pub blocks_skipped: usize,
+    /// The number of blocks which had to be erased.
+    pub blocks_erased: usize,
+    /// The number of pages programmed.
+    pub pages_programmed: usize,
+}
+
 pub struct SpiFlash {
     pub size: u32,
     pub program_size: u32,
     pub address_mode: AddressMode,
     pub read_mode: ReadMode,
     pub erase_mode: EraseMode,
+    /// Whether a quad read may set a clear quad enable (QE) bit.  The bit is set with a volatile
+    /// status register write, so the part reverts to its programmed configuration on power
+    /// cycle.
+    pub enable_quad: bool,
     pub sfdp: Option<Sfdp>,
     pub read_type: ReadTypes,
     pub erase: Vec<SectorErase>,
@@ -135,6 +206,7 @@ impl Default for SpiFlash {
             address_mode: Default::default(),
             read_mode: Default::default(),
             erase_mode: Default::default(),
+            enable_quad: false,
             sfdp: None,
             read_type: Default::default(),
             erase: vec![SectorErase {
@@ -162,6 +234,7 @@ impl SpiFlash {
     pub const CHIP_ERASE: u8 = 0xc7;
     pub const WRITE_ENABLE: u8 = 0x06;
     pub const WRITE_DISABLE: u8 = 0x04;
+    pub const WRITE_ENABLE_VOLATILE: u8 = 0x50;
     pub const READ_STATUS: u8 = 0x05;
     // Winbond parts use 0x35 and 0x15 for extended status reads.
     pub const READ_STATUS2: u8 = 0x35;
@@ -177,6 +250,13 @@ impl SpiFlash {
     pub const NOP: u8 = 0x00;
     pub const RESET_ENABLE: u8 = 0x66;
     pub const RESET: u8 = 0x99;
+    // Status register 2 access for parts with quad enable requirements 011b.
+    pub const READ_STATUS2_ALT: u8 = 0x3f;
+    pub const WRITE_STATUS2_ALT: u8 = 0x3e;
+    pub const ENTER_QPI: u8 = 0x38;
+    pub const ENTER_QPI_ALT: u8 = 0x35;
+    pub const EXIT_QPI: u8 = 0xff;
+    pub const EXIT_QPI_ALT: u8 = 0xf5;
 
     /// The legacy JEDEC page size for programming operations is 256 bytes.
     pub const LEGACY_PAGE_SIZE: u32 = 256;
@@ -307,6 +387,7 @@ impl SpiFlash {
             address_mode: AddressMode::from(sfdp.jedec.address_modes),
             read_mode: Default::default(),
             erase_mode: Default::default(),
+            enable_quad: false,
             sfdp: Some(sfdp),
             read_type,
             erase,
@@ -347,18 +428,207 @@ impl SpiFlash {
         self.read_with_progress(spi, address, buffer, &NoProgressBar)
     }
 
-    fn select_read(&self) -> Result<(Mode, &FastReadParam)> {
-        match self.read_mode {
-            ReadMode::Standard => Ok((MODE_111, &self.read_type.standard)),
-            ReadMode::Fast => Ok((MODE_111, &self.read_type.fast)),
-            ReadMode::Dual if self.read_type.dual.opcode != 0 => {
-                Ok((MODE_112, &self.read_type.dual))
+    fn select_read(&self, read_mode: ReadMode) -> Result<(Mode, &FastReadParam)> {
+        let (mode, param) = match read_mode {
+            ReadMode::Standard => (MODE_111, &self.read_type.standard),
+            ReadMode::Fast => (MODE_111, &self.read_type.fast),
+            ReadMode::Dual => (MODE_112, &self.read_type.dual),
+            ReadMode::Quad => (MODE_114, &self.read_type.quad),
+            ReadMode::QuadIo => (MODE_144, &self.read_type.quad_io),
+            ReadMode::Qpi => (MODE_444, &self.read_type.qpi),
+            ReadMode::QuadDtr => (MODE_1S4D4D, &self.read_type.quad_dtr),
+            ReadMode::Auto => return Err(Error::UnsupportedMode(read_mode).into()),
+        };
+        if param.opcode == 0 {
+            return Err(Error::UnsupportedMode(read_mode).into());
+        }
+        Ok((mode, param))
+    }
+
+    /// Whether the SPI flash supports `mode`.
+    pub fn supports_read_mode(&self, mode: ReadMode) -> bool {
+        self.select_read(mode).is_ok()
+    }
+
+    /// Select the fastest read mode supported by both the SPI flash and a transport with the
+    /// given `capabilities`.  QPI and DTR modes are never selected automatically: QPI leaves the
+    /// part in a state other tools cannot talk to if a read is interrupted, and the transport
+    /// capabilities do not advertise DTR support.  Quad modes are only selected if the QE bit is
+    /// already set or `enable_quad` permits setting it.
+    pub fn set_read_mode_auto(
+        &mut self,
+        spi: &dyn Target,
+        capabilities: &Capabilities,
+    ) -> Result<ReadMode> {
+        let quad = self.enable_quad || self.quad_enabled(spi)?;
+        let candidates = [
+            (ReadMode::QuadIo, Capability::SPI_QUAD),
+            (ReadMode::Quad, Capability::SPI_QUAD),
+            (ReadMode::Dual, Capability::SPI_DUAL),
+            (ReadMode::Fast, Capability::SPI),
+        ];
+        self.read_mode = candidates
+            .into_iter()
+            .find(|(mode, cap)| {
+                (quad || !mode.is_quad())
+                    && self.supports_read_mode(*mode)
+                    && capabilities.request(*cap).ok().is_ok()
+            })
+            .map(|(mode, _)| mode)
+            .unwrap_or(ReadMode::Standard);
+        Ok(self.read_mode)
+    }
+
+    /// The SFDP quad enable requirements, or zero if unknown.
+    fn quad_enable_requirements(&self) -> u8 {
+        self.rev_b()
+            .map(|rev_b| rev_b.quad_enable_requirements)
+            .unwrap_or(0)
+    }
+
+    /// Read a single status register with `opcode`.
+    fn read_status_register(spi: &dyn Target, opcode: u8) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        spi.run_eeprom_transactions(&mut [Transaction::Read(MODE_111.cmd(opcode), &mut buf)])?;
+        Ok(buf[0])
+    }
+
+    /// Whether the QE bit can be read back.  Parts with quad enable requirements 1 and 4 have no
+    /// command to read status register 2.
+    pub fn quad_enable_readable(&self) -> bool {
+        !matches!(self.quad_enable_requirements(), 1 | 4)
+    }
+
+    /// Read the state of the quad enable (QE) bit.  Parts without a QE bit always report true;
+    /// parts whose QE bit cannot be read back always report false.
+    pub fn quad_enabled(&self, spi: &dyn Target) -> Result<bool> {
+        // JESD216D section 6.4.18.
+        match self.quad_enable_requirements() {
+            0 => Ok(true),
+            1 | 4 => Ok(false),
+            5 | 6 => Ok(Self::read_status_register(spi, Self::READ_STATUS2)? & 0x02 != 0),
+            2 => Ok(Self::read_status_register(spi, Self::READ_STATUS)? & 0x40 != 0),
+            3 => Ok(Self::read_status_register(spi, Self::READ_STATUS2_ALT)? & 0x80 != 0),
+            qer => Err(Error::UnsupportedQuadEnable(qer).into()),
+        }
+    }
+
+    /// Set or clear the quad enable (QE) bit as described by the SFDP quad enable requirements.
+    /// A `volatile` write is lost on power cycle; otherwise the non-volatile status register is
+    /// updated.
+    pub fn set_quad_enable(&self, spi: &dyn Target, enable: bool, volatile: bool) -> Result<()> {
+        let update = |value: u8, bit: u8| {
+            if enable {
+                value | bit
+            } else {
+                value & !bit
+            }
+        };
+        let write_enable = if volatile {
+            SpiFlash::WRITE_ENABLE_VOLATILE
+        } else {
+            SpiFlash::WRITE_ENABLE
+        };
+        let write = |opcode: u8, data: &[u8]| {
+            spi.run_eeprom_transactions(&mut [
+                Transaction::Command(MODE_111.cmd(write_enable)),
+                Transaction::Write(MODE_111.cmd(opcode), data),
+                Transaction::WaitForBusyClear,
+            ])
+        };
+        match self.quad_enable_requirements() {
+            0 => Ok(()),
+            // QE is bit 1 of status register 2, written together with status register 1.  Status
+            // register 2 cannot be read, so the other bits of it are written as zero.
+            1 | 4 => {
+                let sr1 = Self::read_status_register(spi, Self::READ_STATUS)?;
+                write(Self::WRITE_STATUS, &[sr1, update(0, 0x02)])
+            }
+            5 => {
+                let sr1 = Self::read_status_register(spi, Self::READ_STATUS)?;
+                let sr2 = Self::read_status_register(spi, Self::READ_STATUS2)?;
+                write(Self::WRITE_STATUS, &[sr1, update(sr2, 0x02)])
             }
-            ReadMode::Quad if self.read_type.quad.opcode != 0 => {
-                Ok((MODE_114, &self.read_type.quad))
+            // QE is bit 6 of status register 1.
+            2 => {
+                let sr1 = Self::read_status_register(spi, Self::READ_STATUS)?;
+                write(Self::WRITE_STATUS, &[update(sr1, 0x40)])
             }
-            _ => Err(Error::UnsupportedMode(self.read_mode).into()),
+            // QE is bit 7 of status register 2, accessed with dedicated opcodes.
+            3 => {
+                let sr2 = Self::read_status_register(spi, Self::READ_STATUS2_ALT)?;
+                write(Self::WRITE_STATUS2_ALT, &[update(sr2, 0x80)])
+            }
+            // QE is bit 1 of status register 2, written on its own.
+            6 => {
+                let sr2 = Self::read_status_register(spi, Self::READ_STATUS2)?;
+                write(Self::WRITE_STATUS2, &[update(sr2, 0x02)])
+            }
+            qer => Err(Error::UnsupportedQuadEnable(qer).into()),
+        }
+    }
+
+    /// Check that the QE bit is set.  If it is clear and `enable_quad` allows it, set the bit
+    /// with a volatile status register write.  A QE bit which cannot be read back is always
+    /// written when `enable_quad` allows it.
+    fn ensure_quad_enable(&self, spi: &dyn Target) -> Result<()> {
+        if self.quad_enabled(spi)? {
+            return Ok(());
+        }
+        if !self.enable_quad {
+            return Err(Error::QuadNotEnabled.into());
+        }
+        log::info!("Setting the quad enable bit (volatile)");
+        self.set_quad_enable(spi, true, true)?;
+        if self.quad_enable_readable() && !self.quad_enabled(spi)? {
+            return Err(Error::QuadNotEnabled.into());
+        }
+        Ok(())
+    }
+
+    fn rev_b(&self) -> Option<&JedecParamsRevB> {
+        self.sfdp
+            .as_ref()
+            .and_then(|sfdp| sfdp.jedec.rev_b.as_ref())
+    }
+
+    /// Switch the part into QPI (4-4-4) mode using the SFDP 4-4-4 enable sequence.
+    pub fn enter_qpi(&self, spi: &dyn Target) -> Result<()> {
+        let enable = self.rev_b().map(|r| r.mode_444_enable).unwrap_or(0);
+        // JESD216D section 6.4.18, 4-4-4 mode enable sequences.
+        let opcode = if enable & 0x01 != 0 {
+            self.ensure_quad_enable(spi)?;
+            Self::ENTER_QPI
+        } else if enable & 0x02 != 0 {
+            Self::ENTER_QPI
+        } else if enable & 0x04 != 0 {
+            Self::ENTER_QPI_ALT
+        } else {
+            return Err(Error::UnsupportedQpiSequence(enable).into());
+        };
+        spi.run_eeprom_transactions(&mut [Transaction::Command(MODE_111.cmd(opcode))])?;
+        Ok(())
+    }
+
+    /// Return the part from QPI (4-4-4) mode using the SFDP 4-4-4 disable sequence.
+    pub fn exit_qpi(&self, spi: &dyn Target) -> Result<()> {
+        let disable = self.rev_b().map(|r| r.mode_444_disable).unwrap_or(0);
+        // JESD216D section 6.4.18, 4-4-4 mode disable sequences.
+        if disable & 0x01 != 0 {
+            spi.run_eeprom_transactions(&mut [Transaction::Command(MODE_444.cmd(Self::EXIT_QPI))])?;
+        } else if disable & 0x02 != 0 {
+            spi.run_eeprom_transactions(&mut [Transaction::Command(
+                MODE_444.cmd(Self::EXIT_QPI_ALT),
+            )])?;
+        } else if disable & 0x08 != 0 {
+            spi.run_eeprom_transactions(&mut [
+                Transaction::Command(MODE_444.cmd(Self::RESET_ENABLE)),
+                Transaction::Command(MODE_444.cmd(Self::RESET)),
+            ])?;
+        } else {
+            return Err(Error::UnsupportedQpiSequence(disable).into());
         }
+        Ok(())
     }
 
     /// Read into `buffer` from the SPI flash starting at `address`.
@@ -366,26 +636,42 @@ impl SpiFlash {
     pub fn read_with_progress(
         &self,
         spi: &dyn Target,
-        mut address: u32,
+        address: u32,
         buffer: &mut [u8],
         progress: &dyn ProgressIndicator,
     ) -> Result<&Self> {
         progress.new_stage("", buffer.len());
-        let (mode, param) = self.select_read()?;
+        let (mode, param) = self.select_read(self.read_mode)?;
+        if self.read_mode.is_quad() && self.read_mode != ReadMode::Qpi {
+            self.ensure_quad_enable(spi)?;
+        }
+        if self.read_mode == ReadMode::Qpi {
+            self.enter_qpi(spi)?;
+        }
+        // The mode bits are clocked out as part of the dummy phase.  Idle lines read as all
+        // ones, which does not select continuous read mode on any known part.
+        let dummy_cycles = param.wait_states + param.mode_bits;
         // Break the read up according to the maximum chunksize the backend can handle.
         let chunk_size = spi.get_eeprom_max_transfer_sizes()?.read;
-        for (idx, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
-            progress.progress(idx * chunk_size);
-            spi.run_eeprom_transactions(&mut [Transaction::Read(
-                mode.dummy_cycles(param.wait_states).cmd_addr(
-                    param.opcode,
-                    address,
-                    self.address_mode,
-                ),
-                chunk,
-            )])?;
-            address += chunk.len() as u32;
+        let result = buffer
+            .chunks_mut(chunk_size)
+            .enumerate()
+            .try_for_each(|(idx, chunk)| {
+                progress.progress(idx * chunk_size);
+                spi.run_eeprom_transactions(&mut [Transaction::Read(
+                    mode.dummy_cycles(dummy_cycles).cmd_addr(
+                        param.opcode,
+                        address + (idx * chunk_size) as u32,
+                        self.address_mode,
+                    ),
+                    chunk,
+                )])
+            });
+        // Always leave QPI mode, even if the read failed.
+        if self.read_mode == ReadMode::Qpi {
+            self.exit_qpi(spi)?;
         }
+        result?;
         progress.progress(buffer.len());
         Ok(self)
     }
@@ -505,6 +791,148 @@ impl SpiFlash {
         Ok(self)
     }
 
+    /// Read back a segment of the SPI flash starting at `address` and compare it with `buffer`.
+    pub fn verify(&self, spi: &dyn Target, address: u32, buffer: &[u8]) -> Result<&Self> {
+        self.verify_with_progress(spi, address, buffer, &NoProgressBar)
+    }
+
+    /// Read back a segment of the SPI flash starting at `address` and compare it with `buffer`.
+    /// The `progress` callback will be invoked after each chunk of the read operation.
+    pub fn verify_with_progress(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        buffer: &[u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<&Self> {
+        let mut readback = vec![0u8; buffer.len()];
+        self.read_with_progress(spi, address, &mut readback, progress)?;
+        if let Some(offset) = readback.iter().zip(buffer).position(|(r, b)| r != b) {
+            return Err(Error::VerifyFailed(address + offset as u32).into());
+        }
+        Ok(self)
+    }
+
+    /// Select the erase to use for the block at `address`.  In `EraseMode::Block`, this is the
+    /// largest erase which is aligned at `address` and fits below `end`.
+    fn select_incremental_erase(&self, address: u32, end: u32) -> &SectorErase {
+        let standard = self.erase.last().unwrap();
+        if self.erase_mode == EraseMode::Standard {
+            return standard;
+        }
+        self.erase
+            .iter()
+            .find(|e| address % e.size == 0 && e.size <= end - address)
+            .unwrap_or(standard)
+    }
+
+    /// Program a segment of the SPI flash starting at `address` with the contents of `buffer`,
+    /// only touching the parts of the flash which differ from `buffer`.
+    ///
+    /// Each erase block covering the segment is read back and compared against the desired
+    /// contents.  Blocks which already match are skipped.  Blocks which can reach the desired
+    /// contents by clearing bits are programmed without erasing; all other blocks are erased
+    /// first.  Bytes within the erase blocks but outside of the segment are preserved.  Every
+    /// modified block is read back and verified.
+    pub fn program_incremental(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<IncrementalStats> {
+        self.program_incremental_with_progress(spi, address, buffer, &NoProgressBar)
+    }
+
+    /// Like `program_incremental`, invoking the `progress` callback after each erase block.
+    pub fn program_incremental_with_progress(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        buffer: &[u8],
+        progress: &dyn ProgressIndicator,
+    ) -> Result<IncrementalStats> {
+        let mut stats = IncrementalStats::default();
+        if buffer.is_empty() {
+            return Ok(stats);
+        }
+        let len = u32::try_from(buffer.len()).unwrap_or(u32::MAX);
+        let buffer_end = address
+            .checked_add(len)
+            .filter(|&end| end <= self.size)
+            .ok_or(Error::AddressOutOfBounds(
+                address.saturating_add(len),
+                self.size,
+            ))?;
+        let min_erase_size = self.erase.last().unwrap().size;
+        let start = address - address % min_erase_size;
+        let end = match buffer_end % min_erase_size {
+            0 => buffer_end,
+            rem => buffer_end.saturating_add(min_erase_size - rem),
+        };
+        let end = std::cmp::min(end, self.size);
+
+        progress.new_stage("", (end - start) as usize);
+        let mut addr = start;
+        while addr < end {
+            let erase = self.select_incremental_erase(addr, end);
+            let block_end = addr + erase.size;
+
+            // Merge the portion of `buffer` which overlaps this block into the current contents.
+            let mut current = vec![0u8; erase.size as usize];
+            self.read(spi, addr, &mut current)?;
+            let mut desired = current.clone();
+            let overlap_start = std::cmp::max(addr, address);
+            let overlap_end = std::cmp::min(block_end, buffer_end);
+            desired[(overlap_start - addr) as usize..(overlap_end - addr) as usize]
+                .copy_from_slice(
+                    &buffer[(overlap_start - address) as usize..(overlap_end - address) as usize],
+                );
+
+            stats.blocks += 1;
+            if current == desired {
+                stats.blocks_skipped += 1;
+            } else {
+                // Programming can only clear bits; an erase is needed to set any bit.
+                let needs_erase = current
+                    .iter()
+                    .zip(desired.iter())
+                    .any(|(&c, &d)| c & d != d);
+                if needs_erase {
+                    spi.run_eeprom_transactions(&mut [
+                        Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
+                        Transaction::Command(MODE_111.cmd_addr(
+                            erase.opcode,
+                            addr,
+                            self.address_mode,
+                        )),
+                        Transaction::WaitForBusyClear,
+                    ])?;
+                    stats.blocks_erased += 1;
+                    current.fill(0xff);
+                }
+                let page_size = self.program_size as usize;
+                for (page, (cur, want)) in current
+                    .chunks(page_size)
+                    .zip(desired.chunks(page_size))
+                    .enumerate()
+                {
+                    if cur != want {
+                        self.program(spi, addr + (page * page_size) as u32, want)?;
+                        stats.pages_programmed += 1;
+                    }
+                }
+
+                self.read(spi, addr, &mut current)?;
+                if let Some(offset) = current.iter().zip(desired.iter()).position(|(c, d)| c != d) {
+                    return Err(Error::VerifyFailed(addr + offset as u32).into());
+                }
+            }
+            addr = block_end;
+            progress.progress((addr - start) as usize);
+        }
+        Ok(stats)
+    }
+
     /// Send the software reset sequence to the `spi` target.
     pub fn chip_reset(spi: &dyn Target) -> Result<()> {
         spi.run_eeprom_transactions(&mut [
@@ -514,3 +942,461 @@ impl SpiFlash {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::eeprom::default_run_eeprom_transactions;
+    use crate::spiflash::emulator::{
+        test_emulator as emulator, test_pattern as pattern, EmulatorParams, SpiFlashEmulator,
+    };
+
+    const SFDP_MX66L1G: &[u8; 512] = include_bytes!("SFDP_MX66L1G.bin");
+
+    #[test]
+    fn test_identify() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        assert_eq!(SpiFlash::read_jedec_id(&emu, 3)?, [0xc2, 0x20, 0x1b]);
+        // The SFDP density matches the size of the emulated part.
+        let flash = SpiFlash::from_spi(&emu)?;
+        assert_eq!(flash.size, 1 << 20);
+        assert_eq!(
+            flash.erase.iter().map(|e| e.size).collect::<Vec<_>>(),
+            vec![65536, 32768, 4096]
+        );
+        assert_eq!(flash.read_type.dual.opcode, SpiFlash::FAST_DUAL_READ);
+        assert_eq!(flash.read_type.quad.opcode, SpiFlash::FAST_QUAD_READ);
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_read_erase() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        let data = pattern(5000);
+        flash.program(&emu, 0x1234, &data)?;
+        assert_eq!(emu.contents(0x1234, data.len()), data);
+        assert_eq!(emu.status() & SpiFlash::STATUS_WEL, 0);
+
+        flash.enable_quad = true;
+        for mode in [
+            ReadMode::Standard,
+            ReadMode::Fast,
+            ReadMode::Dual,
+            ReadMode::Quad,
+        ] {
+            flash.read_mode = mode;
+            let mut buf = vec![0u8; data.len()];
+            flash.read(&emu, 0x1234, &mut buf)?;
+            assert_eq!(buf, data, "read mode {mode:?}");
+        }
+
+        // Erasing the first 8 KiB leaves the remainder of the data intact.
+        flash.erase(&emu, 0, 0x2000)?;
+        assert!(emu.contents(0, 0x2000).iter().all(|&b| b == 0xff));
+        assert_eq!(
+            emu.contents(0x2000, 0x1234 + data.len() - 0x2000),
+            data[0x2000 - 0x1234..]
+        );
+
+        flash.chip_erase(&emu)?;
+        assert!(emu.contents(0, 0x4000).iter().all(|&b| b == 0xff));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let flash = SpiFlash::from_spi(&emu)?;
+        let data = pattern(3000);
+        flash.program(&emu, 0x100, &data)?;
+        flash.verify(&emu, 0x100, &data)?;
+
+        emu.load(0x100 + 1234, &[!data[1234]]);
+        let err = flash.verify(&emu, 0x100, &data).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::VerifyFailed(0x5d2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_mode_mismatch() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        // A quad read with the wrong number of dummy cycles is rejected.
+        flash.read_mode = ReadMode::Quad;
+        flash.read_type.quad.wait_states = 6;
+        let mut buf = [0u8; 16];
+        assert!(flash.read(&emu, 0, &mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_timing() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let polls = emu.params().program_busy_polls;
+
+        // Without WRITE_ENABLE, the program is ignored.
+        emu.run_eeprom_transactions(&mut [Transaction::Write(
+            MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, 0, AddressMode::Mode3b),
+            &[0x00; 4],
+        )])?;
+        assert_eq!(emu.contents(0, 4), [0xff; 4]);
+        assert_eq!(SpiFlash::read_status(&emu)?, 0);
+
+        SpiFlash::set_write_enable(&emu)?;
+        assert_eq!(SpiFlash::read_status(&emu)?, SpiFlash::STATUS_WEL);
+        emu.run_eeprom_transactions(&mut [Transaction::Write(
+            MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, 0, AddressMode::Mode3b),
+            &[0x00; 4],
+        )])?;
+        assert_eq!(emu.contents(0, 4), [0x00; 4]);
+
+        // While busy, reads return 0xff and further commands are ignored.
+        let mut buf = [0u8; 4];
+        emu.run_eeprom_transactions(&mut [Transaction::Read(
+            MODE_111.cmd_addr(SpiFlash::READ, 0, AddressMode::Mode3b),
+            &mut buf,
+        )])?;
+        assert_eq!(buf, [0xff; 4]);
+        SpiFlash::set_write_enable(&emu)?;
+        for _ in 0..polls {
+            assert_eq!(
+                SpiFlash::read_status(&emu)?,
+                SpiFlash::STATUS_WIP | SpiFlash::STATUS_WEL
+            );
+        }
+        assert_eq!(SpiFlash::read_status(&emu)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_program_wrap() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(16);
+        // A single page program crossing the page boundary wraps to the start of the page.
+        emu.run_eeprom_transactions(&mut [
+            Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
+            Transaction::Write(
+                MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, 0x1fa, AddressMode::Mode3b),
+                &data,
+            ),
+            Transaction::WaitForBusyClear,
+        ])?;
+        assert_eq!(emu.contents(0x1fa, 6), data[..6]);
+        assert_eq!(emu.contents(0x100, 10), data[6..]);
+        assert_eq!(emu.contents(0x200, 16), [0xff; 16]);
+
+        // `SpiFlash` splits programs on page boundaries.
+        let flash = SpiFlash::from_spi(&emu)?;
+        flash.program(&emu, 0x2fa, &data)?;
+        assert_eq!(emu.contents(0x2fa, 16), data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_4b_addressing() -> Result<()> {
+        let emu = emulator(32 * 1024 * 1024)?;
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        flash.set_address_mode_auto(&emu)?;
+        assert!(emu.four_byte_mode());
+        assert_eq!(flash.address_mode, AddressMode::Mode4b);
+
+        let data = pattern(300);
+        flash.erase(&emu, 0x0100_0000, 0x1000)?;
+        flash.program(&emu, 0x0100_0000, &data)?;
+        assert_eq!(emu.contents(0x0100_0000, data.len()), data);
+        assert_eq!(emu.contents(0, data.len()), vec![0xff; data.len()]);
+
+        let mut buf = vec![0u8; data.len()];
+        flash.read(&emu, 0x0100_0000, &mut buf)?;
+        assert_eq!(buf, data);
+
+        flash.set_address_mode(&emu, AddressMode::Mode3b)?;
+        assert!(!emu.four_byte_mode());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generic_transfers() -> Result<()> {
+        // Drive the emulator through generic SPI transfers rather than EEPROM transactions.
+        let emu = emulator(1 << 20)?;
+        let data = pattern(100);
+        default_run_eeprom_transactions(
+            &emu,
+            &mut [
+                Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
+                Transaction::Write(
+                    MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, 0x400, AddressMode::Mode3b),
+                    &data,
+                ),
+                Transaction::WaitForBusyClear,
+            ],
+        )?;
+        assert_eq!(emu.contents(0x400, data.len()), data);
+
+        let mut id = [0u8; 3];
+        let mut buf = vec![0u8; data.len()];
+        default_run_eeprom_transactions(
+            &emu,
+            &mut [
+                Transaction::Read(MODE_111.cmd(SpiFlash::READ_ID), &mut id),
+                Transaction::Read(
+                    MODE_111.dummy_cycles(8).cmd_addr(
+                        SpiFlash::FAST_READ,
+                        0x400,
+                        AddressMode::Mode3b,
+                    ),
+                    &mut buf,
+                ),
+            ],
+        )?;
+        assert_eq!(id, emu.params().jedec_id.as_slice());
+        assert_eq!(buf, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quad_enable() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        // The MX66L1G keeps the QE bit in bit 6 of status register 1.
+        assert_eq!(emu.params().quad_enable_requirements, 2);
+        let data = pattern(64);
+        emu.load(0, &data);
+
+        // Without the QE bit, quad reads return garbage.
+        let mut buf = vec![0u8; data.len()];
+        emu.run_eeprom_transactions(&mut [Transaction::Read(
+            MODE_114
+                .dummy_cycles(8)
+                .cmd_addr(SpiFlash::FAST_QUAD_READ, 0, AddressMode::Mode3b),
+            &mut buf,
+        )])?;
+        assert_eq!(buf, [0xff; 64]);
+
+        // `SpiFlash` refuses a quad read unless permitted to set the QE bit.
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        assert!(!flash.quad_enabled(&emu)?);
+        flash.read_mode = ReadMode::QuadIo;
+        let err = flash.read(&emu, 0, &mut buf).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::QuadNotEnabled)
+        ));
+        assert!(!emu.quad_enabled());
+
+        // When permitted, the QE bit is only set in the volatile status register.
+        flash.enable_quad = true;
+        flash.read(&emu, 0, &mut buf)?;
+        assert_eq!(buf, data);
+        assert!(flash.quad_enabled(&emu)?);
+        assert_eq!(emu.status() & 0x40, 0x40);
+        emu.power_cycle();
+        assert!(!emu.quad_enabled());
+
+        // An explicit non-volatile write survives a power cycle.
+        flash.set_quad_enable(&emu, true, false)?;
+        emu.power_cycle();
+        assert!(emu.quad_enabled());
+        flash.set_quad_enable(&emu, false, false)?;
+        assert!(!emu.quad_enabled());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quad_enable_write_only() -> Result<()> {
+        // Parts with quad enable requirements 1 and 4 have no command to read status register 2,
+        // so the QE bit is written together with status register 1 without reading it first.
+        for qer in [1, 4] {
+            let mut sfdp = Sfdp::try_from(&SFDP_MX66L1G[..])?;
+            sfdp.jedec.density = 1 << 20;
+            sfdp.jedec.rev_b.as_mut().unwrap().quad_enable_requirements = qer;
+            let emu = SpiFlashEmulator::from_sfdp(&sfdp.to_bytes()?)?;
+            assert!(SpiFlash::read_status_register(&emu, SpiFlash::READ_STATUS2).is_err());
+            let data = pattern(64);
+            emu.load(0, &data);
+
+            let mut flash = SpiFlash::from_spi(&emu)?;
+            assert!(!flash.quad_enable_readable());
+            flash.read_mode = ReadMode::QuadIo;
+            flash.enable_quad = true;
+            let mut buf = vec![0u8; data.len()];
+            flash.read(&emu, 0, &mut buf)?;
+            assert_eq!(buf, data);
+            assert!(emu.quad_enabled());
+
+            flash.set_quad_enable(&emu, false, false)?;
+            assert!(!emu.quad_enabled());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_qpi_read() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let data = pattern(1000);
+        emu.load(0x800, &data);
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        flash.read_mode = ReadMode::Qpi;
+        let mut buf = vec![0u8; data.len()];
+        flash.read(&emu, 0x800, &mut buf)?;
+        assert_eq!(buf, data);
+        // The part is returned to SPI mode after the read.
+        assert!(!emu.qpi_mode());
+        assert_eq!(SpiFlash::read_jedec_id(&emu, 3)?, emu.params().jedec_id);
+
+        // Commands in the wrong bus mode are rejected.
+        flash.enter_qpi(&emu)?;
+        assert!(emu.qpi_mode());
+        assert!(SpiFlash::read_status(&emu).is_err());
+        flash.exit_qpi(&emu)?;
+        assert!(!emu.qpi_mode());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quad_dtr_read() -> Result<()> {
+        let emu = SpiFlashEmulator::new(EmulatorParams {
+            dummy_1s4d4d: Some(8),
+            ..emulator(1 << 20)?.params().clone()
+        });
+        let data = pattern(700);
+        emu.load(0x1000, &data);
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        flash.read_type.quad_dtr = FastReadParam {
+            wait_states: 6,
+            mode_bits: 2,
+            opcode: 0xed,
+        };
+        flash.read_mode = ReadMode::QuadDtr;
+        flash.enable_quad = true;
+        let mut buf = vec![0u8; data.len()];
+        flash.read(&emu, 0x1000, &mut buf)?;
+        assert_eq!(buf, data);
+
+        // The wrong number of dummy cycles is rejected.
+        flash.read_type.quad_dtr.wait_states = 4;
+        assert!(flash.read(&emu, 0x1000, &mut buf).is_err());
+
+        // A part without DTR support rejects the read.
+        let emu = emulator(1 << 20)?;
+        emu.load(0x1000, &data);
+        flash.read_type.quad_dtr.wait_states = 6;
+        assert!(flash.read(&emu, 0x1000, &mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_mode_auto() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        // The MX66L1G table does not describe any DTR reads.
+        assert!(!flash.supports_read_mode(ReadMode::QuadDtr));
+        assert!(flash.supports_read_mode(ReadMode::Qpi));
+
+        let all = Capabilities::new(Capability::SPI | Capability::SPI_DUAL | Capability::SPI_QUAD);
+        assert_eq!(
+            flash.set_read_mode_auto(&emu, &Capabilities::new(Capability::SPI))?,
+            ReadMode::Fast
+        );
+        assert_eq!(
+            flash.set_read_mode_auto(
+                &emu,
+                &Capabilities::new(Capability::SPI | Capability::SPI_DUAL)
+            )?,
+            ReadMode::Dual
+        );
+        // Quad modes are skipped while the QE bit is clear.
+        assert_eq!(flash.set_read_mode_auto(&emu, &all)?, ReadMode::Dual);
+        flash.enable_quad = true;
+        assert_eq!(flash.set_read_mode_auto(&emu, &all)?, ReadMode::QuadIo);
+
+        let data = pattern(300);
+        emu.load(0, &data);
+        let mut buf = vec![0u8; data.len()];
+        flash.read(&emu, 0, &mut buf)?;
+        assert_eq!(buf, data);
+
+        flash.read_mode = ReadMode::Auto;
+        assert!(flash.read(&emu, 0, &mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_incremental() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let flash = SpiFlash::from_spi(&emu)?;
+        let mut data = pattern(0x3000);
+        flash.erase(&emu, 0, 0x4000)?;
+        emu.load(0, &data);
+
+        // Identical contents are skipped entirely.
+        let stats = flash.program_incremental(&emu, 0, &data)?;
+        assert_eq!(stats.blocks_skipped, stats.blocks);
+        assert_eq!(stats.pages_programmed, 0);
+
+        // Clearing bits only requires a program of the affected page.
+        data[0x1010] &= 0x0f;
+        let stats = flash.program_incremental(&emu, 0, &data)?;
+        assert_eq!(stats.blocks_erased, 0);
+        assert_eq!(stats.pages_programmed, 1);
+
+        // Setting bits requires an erase.
+        data[0x2020] |= 0xf0;
+        data[0x2021] = 0xff;
+        let stats = flash.program_incremental(&emu, 0, &data)?;
+        assert_eq!(stats.blocks_erased, 1);
+        assert_eq!(emu.contents(0, data.len()), data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_incremental_erase_mode() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let mut flash = SpiFlash::from_spi(&emu)?;
+        let data = pattern(0x10000);
+        emu.load(0x10000, &pattern(0x10000 + 0x10)[0x10..]);
+        emu.load(0x20000, &[0x00; 4]);
+
+        // Standard erase mode erases each 4 KiB sector on its own.
+        let stats = flash.program_incremental(&emu, 0x10000, &data)?;
+        assert_eq!(stats.blocks, 16);
+        assert_eq!(stats.blocks_erased, 16);
+        assert_eq!(emu.contents(0x10000, data.len()), data);
+
+        // Block erase mode uses the largest erase which fits.
+        flash.erase_mode = EraseMode::Block;
+        let data = pattern(0x10000 + 1)[1..].to_vec();
+        let stats = flash.program_incremental(&emu, 0x10000, &data)?;
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.blocks_erased, 1);
+        assert_eq!(emu.contents(0x10000, data.len()), data);
+
+        // Bytes outside of the segment but within its erase blocks are preserved.
+        let stats = flash.program_incremental(&emu, 0x20002, &[0xff; 4])?;
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(
+            emu.contents(0x20000, 8),
+            [0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_incremental_bounds() -> Result<()> {
+        let emu = emulator(1 << 20)?;
+        let flash = SpiFlash::from_spi(&emu)?;
+        for address in [flash.size - 1, u32::MAX - 1] {
+            let err = flash
+                .program_incremental(&emu, address, &[0; 4])
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::AddressOutOfBounds(_, _))
+            ));
+        }
+        Ok(())
+    }
+}
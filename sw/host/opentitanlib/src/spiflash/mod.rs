@@ -2,8 +2,12 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod dump;
+pub mod emulator;
 pub mod flash;
+pub mod protect;
 pub mod sfdp;
 
-pub use flash::{EraseMode, ReadMode, SpiFlash};
+pub use dump::{DumpReport, FlashDump};
+pub use flash::{EraseMode, IncrementalStats, ReadMode, SpiFlash};
 pub use sfdp::{BlockEraseSize, Sfdp, SupportedAddressModes, WriteGranularity};
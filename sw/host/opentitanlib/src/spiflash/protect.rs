@@ -0,0 +1,674 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Block protection (write protection) support for SPI NOR flash parts.
+//!
+//! Most SPI NOR parts protect a contiguous range at the top or bottom of the array based on a
+//! set of block protect (BP) bits in the status registers, optionally modified by top/bottom
+//! (TB), sector (SEC) and complement (CMP) bits.  The status register protect (SRP) bits control
+//! whether the status registers themselves may be written.  The position and meaning of these
+//! bits are not described by SFDP and vary by vendor, so a `ProtectScheme` is selected from a
+//! table keyed by the JEDEC ID.  SFDP is only used to learn how the status registers are written.
+
+use anyhow::{bail, ensure, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_annotate::Annotate;
+use thiserror::Error;
+
+use crate::io::eeprom::{Transaction, MODE_111};
+use crate::io::spi::Target;
+use crate::spiflash::sfdp::Sfdp;
+use crate::spiflash::SpiFlash;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no block protection scheme known for JEDEC ID {0}")]
+    UnknownPart(String),
+    #[error("range {0:#x}+{1:#x} cannot be expressed with the block protect bits")]
+    UnsupportedRange(u32, u32),
+    #[error("status register protection {0:?} is not supported by this part")]
+    UnsupportedLock(StatusLock),
+    #[error("the status registers are permanently locked")]
+    Locked,
+    #[error("volatile status register writes are not supported by this part")]
+    VolatileUnsupported,
+    #[error("status register write failed: wrote {0:#08x}, read back {1:#08x}")]
+    WriteFailed(u32, u32),
+}
+
+/// How the status registers are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum StatusWrite {
+    /// Only status register 1 exists or is writable with WRITE_STATUS.
+    Single,
+    /// WRITE_STATUS takes status registers 1 and 2 as a two byte sequence.
+    Combined,
+    /// Each status register is written with its own opcode.
+    Separate,
+}
+
+impl StatusWrite {
+    /// Determine the status register write method from the SFDP quad enable requirements.
+    /// See JESD216D section 6.4.18.
+    pub fn from_sfdp(sfdp: &Sfdp) -> Option<Self> {
+        match sfdp.jedec.rev_b.as_ref()?.quad_enable_requirements {
+            0 | 2 | 3 => Some(StatusWrite::Single),
+            1 | 4 | 5 => Some(StatusWrite::Combined),
+            6 => Some(StatusWrite::Separate),
+            _ => None,
+        }
+    }
+}
+
+/// Size of the range protected by a block protect value of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectUnit {
+    /// A fixed number of bytes.
+    Bytes(u32),
+    /// A fraction (1/n) of the flash size.
+    Fraction(u32),
+}
+
+/// The status register protection state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum StatusLock {
+    /// The status registers may be written after WRITE_ENABLE.
+    None,
+    /// The status registers may not be written while the WP# pin is low.
+    Hardware,
+    /// The status registers may not be written until the next power cycle.
+    PowerSupply,
+    /// The status registers are permanently locked.
+    OneTimeProgram,
+}
+
+/// Describes the location and interpretation of the block protection bits of a flash part.
+///
+/// Bit positions index into the combined status value returned by `SpiFlash::read_status_ex`,
+/// i.e. bits 0-7 are status register 1, bits 8-15 status register 2 and bits 16-23 status
+/// register 3.
+#[derive(Clone, Debug)]
+pub struct ProtectScheme {
+    pub name: &'static str,
+    /// The BP bits, least significant first.
+    pub bp: &'static [u8],
+    pub tb: Option<u8>,
+    pub sec: Option<u8>,
+    pub cmp: Option<u8>,
+    /// The SRP bits, SRP0 first.
+    pub srp: &'static [u8],
+    pub unit: ProtectUnit,
+    pub status_write: StatusWrite,
+}
+
+/// Vendor protection schemes keyed by JEDEC ID prefix.  More specific prefixes must precede
+/// less specific ones.
+const SCHEMES: &[(&[u8], ProtectScheme)] = &[
+    (
+        // Winbond W25Q256/W25Q512: four BP bits, no SEC bit.
+        &[0xef, 0x40, 0x19],
+        ProtectScheme {
+            name: "winbond-4bp",
+            bp: &[2, 3, 4, 5],
+            tb: Some(6),
+            sec: None,
+            cmp: Some(14),
+            srp: &[7, 8],
+            unit: ProtectUnit::Bytes(64 * 1024),
+            status_write: StatusWrite::Separate,
+        },
+    ),
+    (
+        &[0xef],
+        ProtectScheme {
+            name: "winbond",
+            bp: &[2, 3, 4],
+            tb: Some(5),
+            sec: Some(6),
+            cmp: Some(14),
+            srp: &[7, 8],
+            unit: ProtectUnit::Fraction(64),
+            status_write: StatusWrite::Separate,
+        },
+    ),
+    (
+        &[0xc8],
+        ProtectScheme {
+            name: "gigadevice",
+            bp: &[2, 3, 4],
+            tb: Some(5),
+            sec: Some(6),
+            cmp: Some(14),
+            srp: &[7, 8],
+            unit: ProtectUnit::Fraction(64),
+            status_write: StatusWrite::Combined,
+        },
+    ),
+    (
+        // Macronix keeps the TB bit in the one-time programmable configuration register, so
+        // only top protection is supported.
+        &[0xc2],
+        ProtectScheme {
+            name: "macronix",
+            bp: &[2, 3, 4, 5],
+            tb: None,
+            sec: None,
+            cmp: None,
+            srp: &[7],
+            unit: ProtectUnit::Bytes(64 * 1024),
+            status_write: StatusWrite::Single,
+        },
+    ),
+    (
+        // Micron places BP3 above the TB bit.
+        &[0x20],
+        ProtectScheme {
+            name: "micron",
+            bp: &[2, 3, 4, 6],
+            tb: Some(5),
+            sec: None,
+            cmp: None,
+            srp: &[7],
+            unit: ProtectUnit::Bytes(64 * 1024),
+            status_write: StatusWrite::Single,
+        },
+    ),
+    (
+        &[0x9d],
+        ProtectScheme {
+            name: "issi",
+            bp: &[2, 3, 4, 5],
+            tb: None,
+            sec: None,
+            cmp: None,
+            srp: &[7],
+            unit: ProtectUnit::Bytes(64 * 1024),
+            status_write: StatusWrite::Single,
+        },
+    ),
+    (
+        &[0x01],
+        ProtectScheme {
+            name: "spansion",
+            bp: &[2, 3, 4],
+            tb: None,
+            sec: None,
+            cmp: None,
+            srp: &[7],
+            unit: ProtectUnit::Fraction(64),
+            status_write: StatusWrite::Single,
+        },
+    ),
+];
+
+/// A range of protected addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Annotate)]
+pub struct ProtectedRange {
+    #[annotate(format=hex)]
+    pub start: u32,
+    #[annotate(format=hex)]
+    pub length: u32,
+}
+
+/// The decoded block protection state of a flash part.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Annotate)]
+pub struct Protection {
+    pub scheme: String,
+    #[annotate(format=hex)]
+    pub status: u32,
+    pub bp: u8,
+    pub tb: bool,
+    pub sec: bool,
+    pub cmp: bool,
+    pub lock: StatusLock,
+    pub range: ProtectedRange,
+}
+
+impl ProtectScheme {
+    /// Select the protection scheme for the part identified by `jedec_id`.  If available, the
+    /// SFDP table determines how the status registers are written.
+    pub fn for_part(jedec_id: &[u8], sfdp: Option<&Sfdp>) -> Result<Self> {
+        // Skip JEDEC continuation codes.
+        let id: &[u8] = match jedec_id.iter().position(|&b| b != 0x7f) {
+            Some(n) => &jedec_id[n..],
+            None => &[],
+        };
+        let mut scheme = SCHEMES
+            .iter()
+            .find(|(prefix, _)| id.starts_with(prefix))
+            .map(|(_, scheme)| scheme.clone())
+            .ok_or_else(|| Error::UnknownPart(hex::encode(jedec_id)))?;
+        if let Some(write) = sfdp.and_then(StatusWrite::from_sfdp) {
+            scheme.status_write = write;
+        }
+        Ok(scheme)
+    }
+
+    fn bit(status: u32, bit: Option<u8>) -> bool {
+        bit.map(|b| status & (1 << b) != 0).unwrap_or(false)
+    }
+
+    fn all_bits(&self) -> impl Iterator<Item = u8> + '_ {
+        self.bp
+            .iter()
+            .chain(self.srp.iter())
+            .copied()
+            .chain(self.tb)
+            .chain(self.sec)
+            .chain(self.cmp)
+    }
+
+    /// The mask of status bits controlling block protection (excluding SRP).
+    pub fn protect_mask(&self) -> u32 {
+        self.bp
+            .iter()
+            .copied()
+            .chain(self.tb)
+            .chain(self.sec)
+            .chain(self.cmp)
+            .fold(0, |mask, b| mask | 1 << b)
+    }
+
+    /// The mask of the SRP bits.
+    pub fn lock_mask(&self) -> u32 {
+        self.srp.iter().fold(0, |mask, b| mask | 1 << b)
+    }
+
+    /// The number of status registers which hold protection bits.
+    pub fn registers(&self) -> usize {
+        self.all_bits()
+            .map(|b| b as usize / 8 + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// The range protected by the given combination of protection bits.
+    pub fn range(&self, size: u32, bp: u8, tb: bool, sec: bool, cmp: bool) -> ProtectedRange {
+        let max = (1u32 << self.bp.len()) - 1;
+        let length = match bp as u32 {
+            0 => 0,
+            n if n == max => size,
+            n if sec => std::cmp::min(4096u32 << (n - 1), 32768),
+            n => {
+                let unit = match self.unit {
+                    ProtectUnit::Bytes(bytes) => bytes,
+                    ProtectUnit::Fraction(denominator) => size / denominator,
+                };
+                unit.checked_shl(n - 1)
+                    .map(|l| std::cmp::min(l, size))
+                    .unwrap_or(size)
+            }
+        };
+        let (start, length) = match (tb, cmp) {
+            (false, false) => (size - length, length),
+            (true, false) => (0, length),
+            // The complement of a top range is a bottom range and vice versa.
+            (false, true) => (0, size - length),
+            (true, true) => (length, size - length),
+        };
+        ProtectedRange {
+            start: if length == 0 { 0 } else { start },
+            length,
+        }
+    }
+
+    /// Decode the protection state from the combined `status` value.
+    pub fn decode(&self, status: u32, size: u32) -> Protection {
+        let bp = self
+            .bp
+            .iter()
+            .enumerate()
+            .fold(0u8, |bp, (i, &b)| bp | ((status >> b) as u8 & 1) << i);
+        let tb = Self::bit(status, self.tb);
+        let sec = Self::bit(status, self.sec);
+        let cmp = Self::bit(status, self.cmp);
+        let srp = self
+            .srp
+            .iter()
+            .enumerate()
+            .fold(0u8, |srp, (i, &b)| srp | ((status >> b) as u8 & 1) << i);
+        let lock = match srp {
+            0 => StatusLock::None,
+            1 => StatusLock::Hardware,
+            2 => StatusLock::PowerSupply,
+            _ => StatusLock::OneTimeProgram,
+        };
+        Protection {
+            scheme: self.name.to_string(),
+            status,
+            bp,
+            tb,
+            sec,
+            cmp,
+            lock,
+            range: self.range(size, bp, tb, sec, cmp),
+        }
+    }
+
+    /// Compute the protection bits (within `protect_mask`) which protect exactly `range`.
+    pub fn encode(&self, range: ProtectedRange, size: u32) -> Result<u32> {
+        let max = (1u8 << self.bp.len()) - 1;
+        let options = |bit: Option<u8>| if bit.is_some() { 2 } else { 1 };
+        // Prefer encodings without CMP or SEC, and lower BP values.
+        for cmp in 0..options(self.cmp) {
+            for sec in 0..options(self.sec) {
+                for tb in 0..options(self.tb) {
+                    for bp in 0..=max {
+                        let candidate = self.range(size, bp, tb != 0, sec != 0, cmp != 0);
+                        if candidate != range {
+                            continue;
+                        }
+                        let mut status = 0u32;
+                        for (i, &b) in self.bp.iter().enumerate() {
+                            status |= (((bp as u32) >> i) & 1) << b;
+                        }
+                        for (value, bit) in [(tb, self.tb), (sec, self.sec), (cmp, self.cmp)] {
+                            if let Some(b) = bit {
+                                status |= (value as u32) << b;
+                            }
+                        }
+                        return Ok(status);
+                    }
+                }
+            }
+        }
+        Err(Error::UnsupportedRange(range.start, range.length).into())
+    }
+
+    /// Compute the SRP bits for `lock`.
+    pub fn encode_lock(&self, lock: StatusLock) -> Result<u32> {
+        let value = match lock {
+            StatusLock::None => 0,
+            StatusLock::Hardware => 1,
+            StatusLock::PowerSupply if self.srp.len() > 1 => 2,
+            _ => return Err(Error::UnsupportedLock(lock).into()),
+        };
+        Ok(self
+            .srp
+            .iter()
+            .enumerate()
+            .fold(0, |status, (i, &b)| status | ((value >> i) & 1) << b))
+    }
+}
+
+/// Block protection management for a flash part.
+pub struct BlockProtect {
+    pub scheme: ProtectScheme,
+    pub size: u32,
+    /// Whether volatile status register writes (WRITE_ENABLE_VOLATILE) are supported.
+    pub volatile_supported: bool,
+}
+
+impl BlockProtect {
+    /// Create a `BlockProtect` for `flash` by reading its JEDEC ID from `spi`.
+    pub fn from_spi(spi: &dyn Target, flash: &SpiFlash) -> Result<Self> {
+        let jedec_id = SpiFlash::read_jedec_id(spi, 16)?;
+        Self::new(&jedec_id, flash.sfdp.as_ref(), flash.size)
+    }
+
+    pub fn new(jedec_id: &[u8], sfdp: Option<&Sfdp>, size: u32) -> Result<Self> {
+        let scheme = ProtectScheme::for_part(jedec_id, sfdp)?;
+        // Without SFDP information, assume volatile writes work and let the readback check
+        // catch parts which do not support them.
+        let volatile_supported = match sfdp {
+            Some(sfdp) => {
+                sfdp.jedec.write_en_opcode == SpiFlash::WRITE_ENABLE_VOLATILE
+                    || sfdp
+                        .jedec
+                        .rev_b
+                        .as_ref()
+                        .map(|r| r.status_reg1_write_enable & 0x0c != 0)
+                        .unwrap_or(true)
+            }
+            None => true,
+        };
+        Ok(BlockProtect {
+            scheme,
+            size,
+            volatile_supported,
+        })
+    }
+
+    fn read_status(&self, spi: &dyn Target) -> Result<u32> {
+        let seq = [
+            SpiFlash::READ_STATUS,
+            SpiFlash::READ_STATUS2,
+            SpiFlash::READ_STATUS3,
+        ];
+        SpiFlash::read_status_ex(spi, Some(&seq[..self.scheme.registers()]))
+    }
+
+    /// Read and decode the current protection state.
+    pub fn read(&self, spi: &dyn Target) -> Result<Protection> {
+        Ok(self.scheme.decode(self.read_status(spi)?, self.size))
+    }
+
+    /// Write the status registers with `status`, preserving all bits outside of `mask`.
+    /// Volatile writes are lost on power cycle.
+    pub fn write_status(
+        &self,
+        spi: &dyn Target,
+        status: u32,
+        mask: u32,
+        volatile: bool,
+    ) -> Result<Protection> {
+        ensure!(
+            !volatile || self.volatile_supported,
+            Error::VolatileUnsupported
+        );
+        let current = self.read_status(spi)?;
+        if self.scheme.decode(current, self.size).lock == StatusLock::OneTimeProgram {
+            bail!(Error::Locked);
+        }
+        let status = (current & !mask) | (status & mask);
+        let write_enable = if volatile {
+            SpiFlash::WRITE_ENABLE_VOLATILE
+        } else {
+            SpiFlash::WRITE_ENABLE
+        };
+        let bytes = status.to_le_bytes();
+        let registers = self.scheme.registers();
+        match self.scheme.status_write {
+            StatusWrite::Single | StatusWrite::Combined => {
+                let len = match self.scheme.status_write {
+                    StatusWrite::Single => 1,
+                    _ => std::cmp::max(registers, 2),
+                };
+                spi.run_eeprom_transactions(&mut [
+                    Transaction::Command(MODE_111.cmd(write_enable)),
+                    Transaction::Write(MODE_111.cmd(SpiFlash::WRITE_STATUS), &bytes[..len]),
+                    Transaction::WaitForBusyClear,
+                ])?;
+            }
+            StatusWrite::Separate => {
+                let opcodes = [
+                    SpiFlash::WRITE_STATUS,
+                    SpiFlash::WRITE_STATUS2,
+                    SpiFlash::WRITE_STATUS3,
+                ];
+                for (i, &opcode) in opcodes.iter().enumerate().take(registers) {
+                    // Only touch the registers which change.
+                    if (current ^ status) & (0xff << (8 * i)) == 0 {
+                        continue;
+                    }
+                    spi.run_eeprom_transactions(&mut [
+                        Transaction::Command(MODE_111.cmd(write_enable)),
+                        Transaction::Write(MODE_111.cmd(opcode), &bytes[i..i + 1]),
+                        Transaction::WaitForBusyClear,
+                    ])?;
+                }
+            }
+        }
+        let readback = self.read_status(spi)?;
+        if readback & mask != status & mask {
+            bail!(Error::WriteFailed(status & mask, readback & mask));
+        }
+        Ok(self.scheme.decode(readback, self.size))
+    }
+
+    /// Protect exactly `range`, optionally also locking the status registers.
+    pub fn set(
+        &self,
+        spi: &dyn Target,
+        range: ProtectedRange,
+        lock: Option<StatusLock>,
+        volatile: bool,
+    ) -> Result<Protection> {
+        let mut status = self.scheme.encode(range, self.size)?;
+        let mut mask = self.scheme.protect_mask();
+        if let Some(lock) = lock {
+            status |= self.scheme.encode_lock(lock)?;
+            mask |= self.scheme.lock_mask();
+        }
+        self.write_status(spi, status, mask, volatile)
+    }
+
+    /// Remove all block protection.
+    pub fn clear(&self, spi: &dyn Target, volatile: bool) -> Result<Protection> {
+        self.set(spi, ProtectedRange::default(), None, volatile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spiflash::emulator::{EmulatorParams, SpiFlashEmulator};
+
+    const MIB: u32 = 1024 * 1024;
+
+    #[test]
+    fn test_decode_winbond() -> Result<()> {
+        let scheme = ProtectScheme::for_part(&[0xef, 0x40, 0x18], None)?;
+        assert_eq!(scheme.name, "winbond");
+        let size = 16 * MIB;
+
+        // BP=001: upper 1/64.
+        let p = scheme.decode(0x04, size);
+        assert_eq!(p.bp, 1);
+        assert_eq!(
+            p.range,
+            ProtectedRange {
+                start: size - 256 * 1024,
+                length: 256 * 1024
+            }
+        );
+        // BP=010, TB=1: lower 1/32.
+        let p = scheme.decode(0x28, size);
+        assert_eq!(
+            p.range,
+            ProtectedRange {
+                start: 0,
+                length: 512 * 1024
+            }
+        );
+        // BP=010, SEC=1: upper 8 KiB.
+        let p = scheme.decode(0x48, size);
+        assert_eq!(p.range.length, 8 * 1024);
+        assert_eq!(p.range.start, size - 8 * 1024);
+        // BP=111: everything, regardless of SEC.
+        assert_eq!(scheme.decode(0x1c, size).range.length, size);
+        assert_eq!(
+            scheme.decode(0x5c, size).range,
+            ProtectedRange {
+                start: 0,
+                length: size
+            }
+        );
+        // BP=001, CMP=1: all but the upper 1/64.
+        let p = scheme.decode(0x4004, size);
+        assert_eq!(
+            p.range,
+            ProtectedRange {
+                start: 0,
+                length: size - 256 * 1024
+            }
+        );
+        // SRP1=1, SRP0=0: power supply lock-down.
+        assert_eq!(scheme.decode(0x100, size).lock, StatusLock::PowerSupply);
+        assert_eq!(scheme.decode(0x180, size).lock, StatusLock::OneTimeProgram);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        let size = 16 * MIB;
+        for id in [
+            &[0xef, 0x40, 0x18][..],
+            &[0xc2, 0x20, 0x18],
+            &[0x20, 0xba, 0x18],
+        ] {
+            let scheme = ProtectScheme::for_part(id, None)?;
+            // Every encodable state round-trips to the same range.
+            for status in 0..(1u32 << 16) {
+                let status = status & scheme.protect_mask();
+                let range = scheme.decode(status, size).range;
+                let encoded = scheme.encode(range, size)?;
+                assert_eq!(scheme.decode(encoded, size).range, range);
+            }
+        }
+        let scheme = ProtectScheme::for_part(&[0xc2, 0x20, 0x18], None)?;
+        assert!(scheme
+            .encode(
+                ProtectedRange {
+                    start: 0,
+                    length: 64 * 1024
+                },
+                size
+            )
+            .is_err());
+        assert!(ProtectScheme::for_part(&[0x7f, 0x7f, 0x42], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_clear() -> Result<()> {
+        let emu = SpiFlashEmulator::new(EmulatorParams {
+            jedec_id: vec![0xef, 0x40, 0x17],
+            size: 8 * MIB,
+            ..Default::default()
+        });
+        let protect = BlockProtect::new(&SpiFlash::read_jedec_id(&emu, 3)?, None, 8 * MIB)?;
+        assert_eq!(protect.read(&emu)?.range.length, 0);
+
+        let range = ProtectedRange {
+            start: 0,
+            length: 256 * 1024,
+        };
+        let p = protect.set(&emu, range, Some(StatusLock::Hardware), false)?;
+        assert_eq!(p.range, range);
+        assert_eq!(p.lock, StatusLock::Hardware);
+        assert!(p.tb);
+        assert_eq!(protect.read(&emu)?, p);
+
+        let p = protect.clear(&emu, true)?;
+        assert_eq!(p.range.length, 0);
+        // Clearing leaves the lock in place.
+        assert_eq!(p.lock, StatusLock::Hardware);
+        // Volatile writes are lost on power cycle.
+        emu.power_cycle();
+        assert_eq!(protect.read(&emu)?.range, range);
+        Ok(())
+    }
+
+    #[test]
+    fn test_volatile_support() -> Result<()> {
+        const SFDP_MX66L1G: &[u8] = include_bytes!("SFDP_MX66L1G.bin");
+        let jedec_id = [0xc2, 0x20, 0x1b];
+        // JESD216B status register 1 write enable encodings; bits 2 and 3 indicate volatile
+        // writes using 50h.
+        for (encoding, supported) in [(0x01, false), (0x02, false), (0x04, true), (0x08, true)] {
+            let mut sfdp = Sfdp::try_from(SFDP_MX66L1G)?;
+            sfdp.jedec.write_en_opcode = SpiFlash::WRITE_ENABLE;
+            sfdp.jedec.rev_b.as_mut().unwrap().status_reg1_write_enable = encoding;
+            let protect = BlockProtect::new(&jedec_id, Some(&sfdp), 128 * MIB)?;
+            assert_eq!(
+                protect.volatile_supported, supported,
+                "encoding {encoding:#x}"
+            );
+        }
+        Ok(())
+    }
+}
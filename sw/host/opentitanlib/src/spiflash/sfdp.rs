@@ -2,9 +2,9 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::FromPrimitive;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_annotate::Annotate;
 use std::convert::TryFrom;
 use std::time::Duration;
@@ -22,6 +22,14 @@ pub enum Error {
     SliceRange(usize, usize),
     #[error("SFDP header contains incorrect signature: {0:#010x}")]
     WrongHeaderSignature(u32),
+    #[error("expected {0} parameter tables, but found {1}")]
+    ParamTableCount(usize, usize),
+    #[error("parameter table {0} should be {1} dwords long, but is {2}")]
+    ParamTableLength(usize, usize, usize),
+    #[error("{0} cannot be represented in an SFDP table")]
+    Unrepresentable(String),
+    #[error("unsupported density: 2^{0} bits")]
+    UnsupportedDensity(u32),
 
     // This is only needed to meet the error conversion requirements for the most
     // general case in the field! macro below.
@@ -30,9 +38,10 @@ pub enum Error {
 }
 
 /// The SFDP header identifies a valid SFDP, its version and the number of parameter headers.
-#[derive(Debug, Serialize, Annotate)]
+#[derive(Debug, Serialize, Deserialize, Annotate)]
 pub struct SfdpHeader {
     #[annotate(format=hex, comment=comment_signature())]
+    #[serde(default = "SfdpHeader::default_signature")]
     pub signature: u32,
     #[annotate(comment=comment_version())]
     pub minor: u8,
@@ -40,10 +49,19 @@ pub struct SfdpHeader {
     #[annotate(comment = "Number of parameter headers minus one")]
     pub nph: u8,
     #[annotate(format=bin, comment="The reserved field should be all ones")]
+    #[serde(default = "SfdpHeader::default_reserved")]
     pub reserved: u8,
 }
 
 impl SfdpHeader {
+    const SIGNATURE: u32 = 0x50444653;
+
+    fn default_signature() -> u32 {
+        Self::SIGNATURE
+    }
+    fn default_reserved() -> u8 {
+        0xff
+    }
     fn comment_signature(&self) -> Option<String> {
         let signature = self.signature.to_le_bytes().map(|b| {
             match b {
@@ -65,8 +83,6 @@ impl SfdpHeader {
 impl TryFrom<&[u8]> for SfdpHeader {
     type Error = Error;
     fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
-        const SFDP_HEADER_SIGNATURE: u32 = 0x50444653;
-
         let mut reader = std::io::Cursor::new(buf);
         let header = SfdpHeader {
             signature: reader.read_u32::<LittleEndian>()?,
@@ -76,7 +92,7 @@ impl TryFrom<&[u8]> for SfdpHeader {
             reserved: reader.read_u8()?,
         };
         match header.signature {
-            SFDP_HEADER_SIGNATURE => Ok(header),
+            SfdpHeader::SIGNATURE => Ok(header),
             v => Err(Error::WrongHeaderSignature(v)),
         }
     }
@@ -84,7 +100,7 @@ impl TryFrom<&[u8]> for SfdpHeader {
 
 /// The SFDP parameter header identifies additional parameter tables within the SFDP.
 /// All devices are required to have a JEDEC parameter header and corresponding table.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SfdpPhdr {
     pub id: u8,
     pub minor: u8,
@@ -332,11 +348,29 @@ impl InternalJedecParams {
     field!(wait_states_4s4d4d -> u8, 23, 16, 5);
     field!(mode_bits_4s4d4d   -> u8, 23, 21, 3);
     field!(read_opcode_4s4d4d -> u8, 23, 24, 8);
+
+    // Emplace `value` into the bitfield of `bitsize` bits starting at `bitoffset` in the
+    // `field`th "dword" of the data.  As with the `field` macro, `field` is 1-based.
+    // Fields beyond the end of the table are silently dropped.
+    fn set(&mut self, field: usize, bitoffset: u32, bitsize: u32, value: u32) {
+        if let Some(word) = self.data.get_mut(field - 1) {
+            let bits = BitField::new(bitoffset, bitsize);
+            *word = (*word & !bits.mask()) | bits.emplace(value);
+        }
+    }
+
+    // Emplace the wait states, mode bits and opcode of a fast read mode, which are
+    // always packed as a 16-bit group starting at `bitoffset`.
+    fn set_read_param(&mut self, field: usize, bitoffset: u32, param: &FastReadParam) {
+        self.set(field, bitoffset, 5, param.wait_states.into());
+        self.set(field, bitoffset + 5, 3, param.mode_bits.into());
+        self.set(field, bitoffset + 8, 8, param.opcode.into());
+    }
 }
 
 /// `BlockEraseSize` represents whether or not the device can perform
 /// a 4KiB erase.
-#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize)]
+#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum BlockEraseSize {
     Reserved0 = 0,
@@ -358,7 +392,7 @@ impl Default for BlockEraseSize {
 
 /// `WriteGranularity` represents whether or not the device has an internal
 /// buffer for program operations.
-#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize)]
+#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum WriteGranularity {
     Granularity1Byte = 0,
@@ -378,7 +412,7 @@ impl Default for WriteGranularity {
 
 /// `SupportedAddressModes` represents which addressing modes are valid for
 /// the device.
-#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize)]
+#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum SupportedAddressModes {
     Mode3b = 0,
@@ -398,7 +432,7 @@ impl Default for SupportedAddressModes {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize)]
+#[derive(Debug, Eq, PartialEq, FromPrimitive, Clone, Copy, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum MaxSpeed {
     Reserved0 = 0,
@@ -430,7 +464,7 @@ impl Default for MaxSpeed {
 }
 
 /// `FastReadParam` represents the parameters for the different styles of fast read.
-#[derive(Clone, Default, Debug, Serialize, Annotate)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize, Annotate)]
 pub struct FastReadParam {
     pub wait_states: u8,
     pub mode_bits: u8,
@@ -439,7 +473,7 @@ pub struct FastReadParam {
 }
 
 /// `SectorErase` represents the supported erase sector sizes of the device.
-#[derive(Clone, Default, Debug, Serialize, Annotate)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize, Annotate)]
 pub struct SectorErase {
     pub size: u32,
     #[annotate(format=hex)]
@@ -447,7 +481,7 @@ pub struct SectorErase {
     pub time: Option<TimeBound>,
 }
 
-#[derive(Clone, Default, Debug, Serialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct TimeBound {
     #[serde(with = "humantime_serde")]
     pub typical: Duration,
@@ -473,7 +507,8 @@ pub struct TimeBound {
 ///   eight-lane SPI modes.
 /// - Rev F, version 1.7 extends the table to 23 "dwords", including information about
 ///   dual data rate operations.
-#[derive(Default, Debug, Serialize, Annotate)]
+#[derive(Default, Debug, Serialize, Deserialize, Annotate)]
+#[serde(default)]
 pub struct JedecParams {
     /// Erase granularity.
     pub block_erase_size: BlockEraseSize,
@@ -526,7 +561,8 @@ pub struct JedecParams {
 }
 
 /// The Rev B extensions to the JEDEC parameters table.
-#[derive(Default, Debug, Serialize, Annotate)]
+#[derive(Default, Debug, Serialize, Deserialize, Annotate)]
+#[serde(default)]
 pub struct JedecParamsRevB {
     pub page_size: u32,
     pub page_program_time: TimeBound,
@@ -578,7 +614,8 @@ pub struct JedecParamsRevB {
 }
 
 /// The Rev D extensions to the JEDEC parameters table.
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct JedecParamsRevD {
     pub param_118: FastReadParam,
     pub param_188: FastReadParam,
@@ -609,7 +646,8 @@ pub struct JedecParamsRevD {
 }
 
 /// The Rev F extensions to the JEDEC parameters table.
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct JedecParamsRevF {
     pub param_1s1d1d: Option<FastReadParam>,
     pub param_1s2d2d: Option<FastReadParam>,
@@ -644,6 +682,13 @@ impl JedecParams {
         Duration::from_micros(64),
     ];
 
+    // Unsupported fast read modes are encoded with an opcode of 0xff.
+    const UNSUPPORTED_READ: FastReadParam = FastReadParam {
+        wait_states: 0,
+        mode_bits: 0,
+        opcode: 0xff,
+    };
+
     fn pow2_size(shift: u8) -> u32 {
         if shift == 0 {
             0
@@ -651,6 +696,272 @@ impl JedecParams {
             1u32 << shift
         }
     }
+
+    // The inverse of `pow2_size`.
+    fn pow2_shift(size: u32, what: &str) -> Result<u32, Error> {
+        match size {
+            0 => Ok(0),
+            _ if size.is_power_of_two() => Ok(size.trailing_zeros()),
+            _ => Err(Error::Unrepresentable(format!("{what} of {size} bytes"))),
+        }
+    }
+
+    // Encode `value` as a `(unit, count)` pair, where `value = units[unit] * (count + 1)`
+    // and `count` fits in `bits` bits.  The smallest unit which represents `value` exactly
+    // is preferred; failing that, the value is rounded up to the smallest unit which can
+    // represent it.
+    fn encode_time(value: Duration, units: &[Duration], bits: u32) -> Result<(u32, u32), Error> {
+        let value_ns = value.as_nanos();
+        for exact in [true, false] {
+            for (unit, unit_ns) in units.iter().map(Duration::as_nanos).enumerate() {
+                if exact && value_ns % unit_ns != 0 {
+                    continue;
+                }
+                let count = std::cmp::max(1, (value_ns + unit_ns - 1) / unit_ns);
+                if count <= 1 << bits {
+                    return Ok((unit as u32, count as u32 - 1));
+                }
+            }
+        }
+        Err(Error::Unrepresentable(format!("a duration of {value:?}")))
+    }
+
+    // Encode the ratio between the maximum and typical times as the multiplier
+    // `maximum = typical * 2 * (multiplier + 1)`.
+    fn encode_multiplier(time: &TimeBound) -> u32 {
+        let typical = time.typical.as_nanos();
+        let ratio = if typical == 0 {
+            0
+        } else {
+            time.maximum.as_nanos() / typical
+        };
+        (ratio / 2).saturating_sub(1).min(15) as u32
+    }
+
+    /// Encode the parameters into a JEDEC parameter table `dwords` long.
+    /// Fields which are not represented in this structure (reserved or not
+    /// yet decoded) are filled with ones, as required for reserved fields
+    /// by JESD216.
+    pub fn to_dwords(&self, dwords: usize) -> Result<Vec<u32>, Error> {
+        let mut p = InternalJedecParams {
+            data: vec![0xFFFF_FFFF; dwords],
+        };
+        p.set(1, 0, 2, self.block_erase_size as u32);
+        p.set(1, 2, 1, self.write_granularity as u32);
+        p.set(1, 3, 1, self.write_en_required.into());
+        p.set(1, 4, 1, (self.write_en_opcode == 0x50).into());
+        p.set(1, 8, 8, self.erase_opcode_4kib.into());
+        p.set(1, 16, 1, self.support_fast_read_112.into());
+        p.set(1, 17, 2, self.address_modes as u32);
+        p.set(1, 19, 1, self.support_double_rate_clocking.into());
+        p.set(1, 20, 1, self.support_fast_read_122.into());
+        p.set(1, 21, 1, self.support_fast_read_144.into());
+        p.set(1, 22, 1, self.support_fast_read_114.into());
+
+        let bits = u64::from(self.density) * 8;
+        if bits > 0 && bits <= 1 << 31 {
+            p.set(2, 0, 31, (bits - 1) as u32);
+            p.set(2, 31, 1, 0);
+        } else if self.density.is_power_of_two() {
+            p.set(2, 0, 31, self.density.trailing_zeros() + 3);
+            p.set(2, 31, 1, 1);
+        } else {
+            return Err(Error::Unrepresentable(format!(
+                "a density of {} bytes",
+                self.density
+            )));
+        }
+
+        let read_param = |supported: bool, param: &FastReadParam| {
+            if supported {
+                param.clone()
+            } else {
+                Self::UNSUPPORTED_READ
+            }
+        };
+        p.set_read_param(
+            3,
+            0,
+            &read_param(self.support_fast_read_144, &self.param_144),
+        );
+        p.set_read_param(
+            3,
+            16,
+            &read_param(self.support_fast_read_114, &self.param_114),
+        );
+        p.set_read_param(
+            4,
+            0,
+            &read_param(self.support_fast_read_112, &self.param_112),
+        );
+        p.set_read_param(
+            4,
+            16,
+            &read_param(self.support_fast_read_122, &self.param_122),
+        );
+        p.set(5, 0, 1, self.support_fast_read_222.into());
+        p.set(5, 4, 1, self.support_fast_read_444.into());
+        p.set_read_param(
+            6,
+            16,
+            &read_param(self.support_fast_read_222, &self.param_222),
+        );
+        p.set_read_param(
+            7,
+            16,
+            &read_param(self.support_fast_read_444, &self.param_444),
+        );
+
+        for (i, erase) in self.erase.iter().enumerate() {
+            let (field, bitoffset) = (8 + i / 2, 16 * (i as u32 % 2));
+            p.set(
+                field,
+                bitoffset,
+                8,
+                Self::pow2_shift(erase.size, "an erase size")?,
+            );
+            p.set(field, bitoffset + 8, 8, erase.opcode.into());
+        }
+        if let Some(time) = &self.erase[0].time {
+            p.set(10, 0, 4, Self::encode_multiplier(time));
+        }
+        for (i, erase) in self.erase.iter().enumerate() {
+            if let Some(time) = &erase.time {
+                let (unit, count) = Self::encode_time(time.typical, &Self::ERASE_TIME_UNITS, 5)?;
+                let bitoffset = 4 + 7 * i as u32;
+                p.set(10, bitoffset, 5, count);
+                p.set(10, bitoffset + 5, 2, unit);
+            }
+        }
+
+        if let Some(b) = &self.rev_b {
+            p.set(11, 0, 4, Self::encode_multiplier(&b.page_program_time));
+            p.set(11, 4, 4, Self::pow2_shift(b.page_size, "a page size")?);
+            let (unit, count) =
+                Self::encode_time(b.page_program_time.typical, &Self::PAGE_PGM_UNITS, 5)?;
+            p.set(11, 8, 5, count);
+            p.set(11, 13, 1, unit);
+            let (unit, count) =
+                Self::encode_time(b.byte_program_time.typical, &Self::BYTE_PGM_UNITS, 4)?;
+            p.set(11, 14, 4, count);
+            p.set(11, 18, 1, unit);
+            let (unit, count) = Self::encode_time(
+                b.additional_byte_program_time.typical,
+                &Self::BYTE_PGM_UNITS,
+                4,
+            )?;
+            p.set(11, 19, 4, count);
+            p.set(11, 23, 1, unit);
+            let (unit, count) =
+                Self::encode_time(b.chip_erase_time.typical, &Self::CHIP_ERASE_UNITS, 5)?;
+            p.set(11, 24, 5, count);
+            p.set(11, 29, 2, unit);
+
+            const RESUME_TO_SUSPEND_UNITS: [Duration; 1] = [Duration::from_millis(64)];
+            p.set(12, 0, 4, b.prohibited_ops_program_suspend.into());
+            p.set(12, 4, 4, b.prohibited_ops_erase_suspend.into());
+            let (_, count) =
+                Self::encode_time(b.program_resume_to_suspend, &RESUME_TO_SUSPEND_UNITS, 4)?;
+            p.set(12, 9, 4, count);
+            let (unit, count) = Self::encode_time(
+                b.suspend_in_progress_program_latency,
+                &Self::SUSPEND_RESUME_UNITS,
+                5,
+            )?;
+            p.set(12, 13, 5, count);
+            p.set(12, 18, 2, unit);
+            let (_, count) =
+                Self::encode_time(b.erase_resume_to_suspend, &RESUME_TO_SUSPEND_UNITS, 4)?;
+            p.set(12, 20, 4, count);
+            let (unit, count) = Self::encode_time(
+                b.suspend_in_progress_erase_latency,
+                &Self::SUSPEND_RESUME_UNITS,
+                5,
+            )?;
+            p.set(12, 24, 5, count);
+            p.set(12, 29, 2, unit);
+            p.set(12, 31, 1, (!b.suspend_resume_supported).into());
+
+            p.set(13, 0, 8, b.program_resume_instruction.into());
+            p.set(13, 8, 8, b.program_suspend_instruction.into());
+            p.set(13, 16, 8, b.resume_instruction.into());
+            p.set(13, 24, 8, b.suspend_instruction.into());
+
+            p.set(14, 2, 6, b.status_register_polling.into());
+            let (unit, count) =
+                Self::encode_time(b.exit_deep_powerdown_delay, &Self::SUSPEND_RESUME_UNITS, 5)?;
+            p.set(14, 8, 5, count);
+            p.set(14, 13, 2, unit);
+            p.set(14, 15, 8, b.exit_deep_powerdown_instruction.into());
+            p.set(14, 23, 8, b.enter_deep_powerdown_instruction.into());
+            p.set(14, 31, 1, (!b.deep_powerdown_supported).into());
+
+            p.set(15, 0, 4, b.mode_444_disable.into());
+            p.set(15, 4, 5, b.mode_444_enable.into());
+            p.set(15, 9, 1, b.mode_444_supported.into());
+            p.set(15, 10, 6, b.mode_444_exit.into());
+            p.set(15, 16, 4, b.mode_444_entry.into());
+            p.set(15, 20, 3, b.quad_enable_requirements.into());
+            p.set(15, 23, 1, b.hold_or_reset_disable.into());
+
+            p.set(16, 0, 6, b.status_reg1_write_enable.into());
+            p.set(16, 8, 6, b.soft_reset_support.into());
+            p.set(16, 14, 10, b.exit_4b_addressing.into());
+            p.set(16, 24, 8, b.enter_4b_addressing.into());
+        }
+
+        if let Some(d) = &self.rev_d {
+            p.set_read_param(17, 0, &d.param_188);
+            p.set_read_param(17, 16, &d.param_118);
+
+            p.set(18, 18, 5, d.output_driver_strength.into());
+            p.set(18, 23, 1, d.jedec_spi_protocol_reset.into());
+            p.set(18, 24, 2, d.data_strobe_waveform_str.into());
+            p.set(18, 26, 1, d.data_strobe_support_4s4s4s.into());
+            p.set(18, 27, 1, d.data_strobe_support_4d4d4d.into());
+            p.set(18, 29, 2, d.octal_dtr_command.into());
+            p.set(18, 31, 1, d.octal_byte_order.into());
+
+            p.set(19, 0, 4, d.mode_8s8s8s_disable.into());
+            p.set(19, 4, 5, d.mode_8s8s8s_enable.into());
+            p.set(19, 9, 1, d.mode_088_supported.into());
+            p.set(19, 10, 6, d.mode_088_exit.into());
+            p.set(19, 16, 4, d.mode_088_enter.into());
+            p.set(19, 20, 3, d.octal_enable_requirements.into());
+
+            let speeds = [
+                d.max_speed_4s4s4s_no_strobe,
+                d.max_speed_4s4s4s_strobe,
+                d.max_speed_4d4d4d_no_strobe,
+                d.max_speed_4d4d4d_strobe,
+                d.max_speed_8s8s8s_no_strobe,
+                d.max_speed_8s8s8s_strobe,
+                d.max_speed_8d8d8d_no_strobe,
+                d.max_speed_8d8d8d_strobe,
+            ];
+            for (i, speed) in speeds.into_iter().enumerate() {
+                p.set(20, 4 * i as u32, 4, speed as u32);
+            }
+        }
+
+        if let Some(f) = &self.rev_f {
+            let params = [
+                (&f.param_1s1d1d, 22, 0),
+                (&f.param_1s2d2d, 22, 16),
+                (&f.param_1s4d4d, 23, 0),
+                (&f.param_4s4d4d, 23, 16),
+            ];
+            for (i, (param, field, bitoffset)) in params.into_iter().enumerate() {
+                p.set(21, i as u32, 1, param.is_some().into());
+                p.set_read_param(
+                    field,
+                    bitoffset,
+                    param.as_ref().unwrap_or(&Self::UNSUPPORTED_READ),
+                );
+            }
+        }
+        Ok(p.data)
+    }
 }
 
 impl TryFrom<&[u8]> for JedecParams {
@@ -658,7 +969,12 @@ impl TryFrom<&[u8]> for JedecParams {
     fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
         let p = InternalJedecParams::try_from(buf)?;
         let size_bytes = if p.density_pow2()? {
-            1u32 << ((p.density()? & 0x7FFF_FFFF) - 8)
+            // The density is expressed as 2^N bits.  Anything below one byte or above 2^32 bits
+            // does not fit in `density`.
+            let n = p.density()?;
+            n.checked_sub(3)
+                .and_then(|shift| 1u32.checked_shl(shift))
+                .ok_or(Error::UnsupportedDensity(n))?
         } else {
             (p.density()? + 1) / 8
         };
@@ -806,8 +1122,8 @@ impl TryFrom<&[u8]> for JedecParams {
                     * (1 + p.suspend_erase_latency_time()?),
                 program_resume_instruction: p.pgm_resume_instruction()?,
                 program_suspend_instruction: p.pgm_suspend_instruction()?,
-                resume_instruction: p.suspend_instruction()?,
-                suspend_instruction: p.resume_instruction()?,
+                resume_instruction: p.resume_instruction()?,
+                suspend_instruction: p.suspend_instruction()?,
 
                 deep_powerdown_supported: !p.deep_powerdown_supported()?,
                 enter_deep_powerdown_instruction: p.enter_deep_powerdown_instruction()?,
@@ -907,7 +1223,7 @@ impl TryFrom<&[u8]> for JedecParams {
 
 /// An `UnknownParams` structure represents SFDP parameter tables for which
 /// we don't have a specialized parser.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct UnknownParams {
     pub data: Vec<u32>,
 }
@@ -923,7 +1239,7 @@ impl TryFrom<&[u8]> for UnknownParams {
 }
 
 /// The `Sfdp` structure represents the decoded SFDP table.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Sfdp {
     pub header: SfdpHeader,
     pub phdr: Vec<SfdpPhdr>,
@@ -932,6 +1248,52 @@ pub struct Sfdp {
 }
 
 impl Sfdp {
+    /// Encode the SFDP structure into its binary representation.  The parameter
+    /// tables are placed at the offsets given by their parameter headers and any
+    /// gaps between them are filled with 0xff.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let tables = self.header.nph as usize + 1;
+        if self.phdr.len() != tables {
+            return Err(Error::ParamTableCount(tables, self.phdr.len()));
+        }
+        if self.params.len() + 1 != tables {
+            return Err(Error::ParamTableCount(tables, self.params.len() + 1));
+        }
+
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(self.header.signature)?;
+        buf.write_u8(self.header.minor)?;
+        buf.write_u8(self.header.major)?;
+        buf.write_u8(self.header.nph)?;
+        buf.write_u8(self.header.reserved)?;
+        for ph in self.phdr.iter() {
+            buf.write_u8(ph.id)?;
+            buf.write_u8(ph.minor)?;
+            buf.write_u8(ph.major)?;
+            buf.write_u8(ph.dwords)?;
+            // The top byte is the MSB of the parameter ID, which is 0xff for
+            // all of the JEDEC-defined tables.
+            buf.write_u32::<LittleEndian>(ph.offset | 0xFF00_0000)?;
+        }
+
+        let jedec = self.jedec.to_dwords(self.phdr[0].dwords as usize)?;
+        let data = std::iter::once(&jedec).chain(self.params.iter().map(|p| &p.data));
+        for (i, (ph, data)) in self.phdr.iter().zip(data).enumerate() {
+            if data.len() != ph.dwords as usize {
+                return Err(Error::ParamTableLength(i, ph.dwords as usize, data.len()));
+            }
+            let start = ph.offset as usize;
+            let end = start + data.len() * 4;
+            if buf.len() < end {
+                buf.resize(end, 0xff);
+            }
+            for (chunk, word) in buf[start..end].chunks_exact_mut(4).zip(data) {
+                chunk.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+        Ok(buf)
+    }
+
     /// Given an initial SFDP buffer calculate the number of bytes needed for
     /// the entire SFDP.
     pub fn length_required(buf: &[u8]) -> Result<usize, Error> {
@@ -1094,6 +1456,110 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_encode_mx66l1g() -> Result<()> {
+        let sfdp = Sfdp::try_from(&SFDP_MX66L1G[..])?;
+        let bytes = sfdp.to_bytes()?;
+        assert_eq!(bytes.len(), Sfdp::length_required(&SFDP_MX66L1G[..])?);
+        assert_eq!(bytes[..], SFDP_MX66L1G[..bytes.len()]);
+        // The sampled SFDP holds vendor data at 0x1e0 which no parameter header describes, so
+        // the encoding ends with the last described table.
+        assert_eq!(bytes.len(), 0x120);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_round_trip() -> Result<()> {
+        let sfdp = Sfdp::try_from(&SFDP_MX66L1G[..])?;
+        let json = serde_json::to_string_pretty(&sfdp)?;
+        let sfdp = deser_hjson::from_str::<Sfdp>(&json)?;
+        let bytes = sfdp.to_bytes()?;
+        assert_eq!(bytes[..], SFDP_MX66L1G[..bytes.len()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_custom() -> Result<()> {
+        // A minimal description of a 1MiB part, relying on the defaults for
+        // everything not mentioned.
+        let sfdp = deser_hjson::from_str::<Sfdp>(
+            r#"{
+                header: { minor: 6, major: 1, nph: 0 }
+                phdr: [
+                    { id: 0, minor: 6, major: 1, dwords: 16, offset: 16 }
+                ]
+                jedec: {
+                    block_erase_size: "Block4KiB"
+                    write_granularity: "Granularity64Bytes"
+                    erase_opcode_4kib: 32
+                    address_modes: "Mode3b"
+                    density: 1048576
+                    support_fast_read_114: true
+                    param_114: { wait_states: 8, mode_bits: 0, opcode: 107 }
+                    erase: [
+                        { size: 4096, opcode: 32, time: { typical: "45ms", maximum: "400ms" } }
+                        { size: 32768, opcode: 82 }
+                        { size: 65536, opcode: 216 }
+                        { size: 0, opcode: 255 }
+                    ]
+                    rev_b: {
+                        page_size: 256
+                        page_program_time: { typical: "700us", maximum: "3ms" }
+                        chip_erase_time: { typical: "3s", maximum: "10s" }
+                        quad_enable_requirements: 4
+                    }
+                }
+                params: []
+            }"#,
+        )?;
+        let bytes = sfdp.to_bytes()?;
+        assert_eq!(bytes.len(), 16 + 16 * 4);
+        assert_eq!(&bytes[0..8], b"SFDP\x06\x01\x00\xff");
+
+        let decoded = Sfdp::try_from(&bytes[..])?;
+        assert_eq!(decoded.jedec.block_erase_size, BlockEraseSize::Block4KiB);
+        assert_eq!(decoded.jedec.density, 1048576);
+        assert!(decoded.jedec.support_fast_read_114);
+        assert!(!decoded.jedec.support_fast_read_144);
+        assert_eq!(decoded.jedec.param_114.opcode, 0x6b);
+        assert_eq!(decoded.jedec.param_114.wait_states, 8);
+        assert_eq!(decoded.jedec.erase[1].size, 32768);
+        assert_eq!(decoded.jedec.erase[2].opcode, 0xd8);
+        // Times which aren't representable are rounded up.
+        let time = decoded.jedec.erase[0].time.as_ref().unwrap();
+        assert_eq!(time.typical, parse_duration("48ms")?);
+        assert_eq!(time.maximum, parse_duration("384ms")?);
+        let rev_b = decoded.jedec.rev_b.as_ref().unwrap();
+        assert_eq!(rev_b.page_size, 256);
+        assert_eq!(rev_b.page_program_time.typical, parse_duration("704us")?);
+        assert_eq!(rev_b.chip_erase_time.typical, parse_duration("3s 72ms")?);
+        assert_eq!(rev_b.quad_enable_requirements, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_density_pow2_bounds() -> Result<()> {
+        // The JEDEC table pointer is in the first parameter header; its second dword holds the
+        // density.
+        let ptr = u32::from_le_bytes([SFDP_MX66L1G[12], SFDP_MX66L1G[13], SFDP_MX66L1G[14], 0]);
+        let offset = ptr as usize + 4;
+        let decode = |n: u32| {
+            let mut buf = SFDP_MX66L1G.to_vec();
+            buf[offset..offset + 4].copy_from_slice(&(0x8000_0000 | n).to_le_bytes());
+            Sfdp::try_from(&buf[..])
+        };
+        assert_eq!(decode(3)?.jedec.density, 1);
+        assert_eq!(decode(34)?.jedec.density, 1 << 31);
+        for n in [0, 2, 35, 0x7fff_ffff] {
+            let err = decode(n).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::UnsupportedDensity(x)) if *x == n),
+                "{err}"
+            );
+        }
+        Ok(())
+    }
+
     // Regression test for https://github.com/lowRISC/opentitan/issues/13477
     #[test]
     fn test_bad_header_signature() -> Result<()> {
@@ -0,0 +1,1496 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Marshalling and unmarshalling of a subset of TPM2 commands, as described in the TPM 2.0
+//! Library specification, parts 2 (structures) and 3 (commands).
+//!
+//! Each command is a function which assembles the command bytes, executes them through a
+//! `Driver`, checks the response code and parses the response.  Commands which require
+//! authorization use a password session.
+
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+use crate::impl_serializable_error;
+use crate::tpm::Driver;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum CommandError {
+    #[error("TPM returned {0}")]
+    Response(ResponseCode),
+    #[error("Response truncated")]
+    Truncated,
+    #[error("Response size {0} does not match header size {1}")]
+    Size(usize, usize),
+    #[error("Unexpected response tag {0:#x}")]
+    Tag(u16),
+    #[error("Unsupported {0}: {1:#x}")]
+    Unsupported(String, u32),
+    #[error("Digest for {0:?} must be {1} bytes, got {2}")]
+    DigestLength(HashAlg, usize, usize),
+    #[error("Buffer of {0} bytes does not fit in a TPM2B")]
+    Oversized(usize),
+    #[error("NV range of {1} bytes at offset {0:#x} exceeds the 16-bit offset")]
+    NvRange(u16, usize),
+    #[error("TPM returned no data reading NV offset {0:#x}")]
+    NvEmpty(u16),
+}
+impl_serializable_error!(CommandError);
+
+mod hex_bytes {
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+}
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_ST_ATTEST_QUOTE: u16 = 0x8018;
+const TPM_GENERATED_VALUE: u32 = 0xff54_4347;
+
+const TPM_CC_NV_DEFINE_SPACE: u32 = 0x12a;
+const TPM_CC_CREATE_PRIMARY: u32 = 0x131;
+const TPM_CC_NV_WRITE: u32 = 0x137;
+const TPM_CC_SELF_TEST: u32 = 0x143;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_NV_READ: u32 = 0x14e;
+const TPM_CC_QUOTE: u32 = 0x158;
+const TPM_CC_FLUSH_CONTEXT: u32 = 0x165;
+const TPM_CC_READ_PUBLIC: u32 = 0x173;
+const TPM_CC_GET_CAPABILITY: u32 = 0x17a;
+const TPM_CC_GET_RANDOM: u32 = 0x17b;
+const TPM_CC_PCR_READ: u32 = 0x17e;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+
+/// Handle of the password authorization session.
+const TPM_RS_PW: u32 = 0x4000_0009;
+/// Session attribute `continueSession`.
+const SESSION_CONTINUE: u8 = 0x01;
+
+/// Maximum amount of NV data transferred by a single `NV_Read` or `NV_Write` command.
+const NV_CHUNK_SIZE: usize = 512;
+
+/// Algorithm identifiers (`TPM_ALG_ID`).
+pub mod alg {
+    pub const RSA: u16 = 0x0001;
+    pub const SHA1: u16 = 0x0004;
+    pub const AES: u16 = 0x0006;
+    pub const SHA256: u16 = 0x000b;
+    pub const SHA384: u16 = 0x000c;
+    pub const SHA512: u16 = 0x000d;
+    pub const NULL: u16 = 0x0010;
+    pub const RSASSA: u16 = 0x0014;
+    pub const RSAES: u16 = 0x0015;
+    pub const RSAPSS: u16 = 0x0016;
+    pub const ECDSA: u16 = 0x0018;
+    pub const ECDAA: u16 = 0x001a;
+    pub const ECC: u16 = 0x0023;
+    pub const CFB: u16 = 0x0043;
+}
+
+/// Object attributes (`TPMA_OBJECT`).
+pub mod object {
+    pub const FIXED_TPM: u32 = 1 << 1;
+    pub const FIXED_PARENT: u32 = 1 << 4;
+    pub const SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+    pub const USER_WITH_AUTH: u32 = 1 << 6;
+    pub const NO_DA: u32 = 1 << 10;
+    pub const RESTRICTED: u32 = 1 << 16;
+    pub const DECRYPT: u32 = 1 << 17;
+    pub const SIGN: u32 = 1 << 18;
+}
+
+/// NV index attributes (`TPMA_NV`).
+pub mod nv {
+    pub const OWNERWRITE: u32 = 1 << 1;
+    pub const AUTHWRITE: u32 = 1 << 2;
+    pub const OWNERREAD: u32 = 1 << 17;
+    pub const AUTHREAD: u32 = 1 << 18;
+    pub const NO_DA: u32 = 1 << 25;
+}
+
+/// ECC curve identifier of NIST P-256.
+const TPM_ECC_NIST_P256: u16 = 0x0003;
+
+/// A TPM response code, `TPM_RC`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseCode(pub u32);
+
+impl ResponseCode {
+    pub const SUCCESS: ResponseCode = ResponseCode(0);
+    const RC_VER1: u32 = 0x100;
+    const RC_FMT1: u32 = 0x080;
+    const RC_WARN: u32 = 0x900;
+    const RC_VENDOR: u32 = 0x400;
+
+    pub fn is_success(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether this is a format-one code, which identifies the parameter, handle or session
+    /// causing the error.
+    fn is_format_one(&self) -> bool {
+        self.0 & Self::RC_FMT1 != 0
+    }
+
+    /// Returns the response code with any parameter, handle or session number removed.
+    pub fn base(&self) -> u32 {
+        if self.is_format_one() {
+            self.0 & 0x0bf
+        } else {
+            self.0
+        }
+    }
+
+    /// Returns the number of the parameter which caused the error, if indicated.
+    pub fn parameter(&self) -> Option<u32> {
+        (self.is_format_one() && self.0 & 0x040 != 0).then_some((self.0 >> 8) & 0xf)
+    }
+
+    /// Returns the number of the handle which caused the error, if indicated.
+    pub fn handle(&self) -> Option<u32> {
+        let n = (self.0 >> 8) & 0x7;
+        (self.is_format_one() && self.0 & 0x840 == 0 && n != 0).then_some(n)
+    }
+
+    /// Returns the number of the session which caused the error, if indicated.
+    pub fn session(&self) -> Option<u32> {
+        let n = (self.0 >> 8) & 0x7;
+        (self.is_format_one() && self.0 & 0x840 == 0x800 && n != 0).then_some(n)
+    }
+
+    /// Returns the name of the response code, as used in the TPM 2.0 specification.
+    pub fn name(&self) -> Option<&'static str> {
+        let base = self.base();
+        if self.is_format_one() {
+            return Some(match base & 0x3f {
+                0x001 => "TPM_RC_ASYMMETRIC",
+                0x002 => "TPM_RC_ATTRIBUTES",
+                0x003 => "TPM_RC_HASH",
+                0x004 => "TPM_RC_VALUE",
+                0x005 => "TPM_RC_HIERARCHY",
+                0x007 => "TPM_RC_KEY_SIZE",
+                0x008 => "TPM_RC_MGF",
+                0x009 => "TPM_RC_MODE",
+                0x00a => "TPM_RC_TYPE",
+                0x00b => "TPM_RC_HANDLE",
+                0x00c => "TPM_RC_KDF",
+                0x00d => "TPM_RC_RANGE",
+                0x00e => "TPM_RC_AUTH_FAIL",
+                0x00f => "TPM_RC_NONCE",
+                0x010 => "TPM_RC_PP",
+                0x012 => "TPM_RC_SCHEME",
+                0x015 => "TPM_RC_SIZE",
+                0x016 => "TPM_RC_SYMMETRIC",
+                0x017 => "TPM_RC_TAG",
+                0x018 => "TPM_RC_SELECTOR",
+                0x01a => "TPM_RC_INSUFFICIENT",
+                0x01b => "TPM_RC_SIGNATURE",
+                0x01c => "TPM_RC_KEY",
+                0x01d => "TPM_RC_POLICY_FAIL",
+                0x01f => "TPM_RC_INTEGRITY",
+                0x020 => "TPM_RC_TICKET",
+                0x021 => "TPM_RC_RESERVED_BITS",
+                0x022 => "TPM_RC_BAD_AUTH",
+                0x023 => "TPM_RC_EXPIRED",
+                0x024 => "TPM_RC_POLICY_CC",
+                0x025 => "TPM_RC_BINDING",
+                0x026 => "TPM_RC_CURVE",
+                0x027 => "TPM_RC_ECC_POINT",
+                _ => return None,
+            });
+        }
+        if base & Self::RC_VENDOR != 0 || base & Self::RC_VER1 == 0 {
+            return None;
+        }
+        if base & Self::RC_WARN == Self::RC_WARN {
+            return Some(match base & 0x7f {
+                0x001 => "TPM_RC_CONTEXT_GAP",
+                0x002 => "TPM_RC_OBJECT_MEMORY",
+                0x003 => "TPM_RC_SESSION_MEMORY",
+                0x004 => "TPM_RC_MEMORY",
+                0x005 => "TPM_RC_SESSION_HANDLES",
+                0x006 => "TPM_RC_OBJECT_HANDLES",
+                0x007 => "TPM_RC_LOCALITY",
+                0x008 => "TPM_RC_YIELDED",
+                0x009 => "TPM_RC_CANCELED",
+                0x00a => "TPM_RC_TESTING",
+                0x020 => "TPM_RC_NV_RATE",
+                0x021 => "TPM_RC_LOCKOUT",
+                0x022 => "TPM_RC_RETRY",
+                0x023 => "TPM_RC_NV_UNAVAILABLE",
+                _ => return None,
+            });
+        }
+        Some(match base & 0x7f {
+            0x000 => "TPM_RC_INITIALIZE",
+            0x001 => "TPM_RC_FAILURE",
+            0x003 => "TPM_RC_SEQUENCE",
+            0x00b => "TPM_RC_PRIVATE",
+            0x019 => "TPM_RC_HMAC",
+            0x020 => "TPM_RC_DISABLED",
+            0x021 => "TPM_RC_EXCLUSIVE",
+            0x024 => "TPM_RC_AUTH_TYPE",
+            0x025 => "TPM_RC_AUTH_MISSING",
+            0x026 => "TPM_RC_POLICY",
+            0x027 => "TPM_RC_PCR",
+            0x028 => "TPM_RC_PCR_CHANGED",
+            0x02d => "TPM_RC_UPGRADE",
+            0x02e => "TPM_RC_TOO_MANY_CONTEXTS",
+            0x02f => "TPM_RC_AUTH_UNAVAILABLE",
+            0x030 => "TPM_RC_REBOOT",
+            0x031 => "TPM_RC_UNBALANCED",
+            0x042 => "TPM_RC_COMMAND_SIZE",
+            0x043 => "TPM_RC_COMMAND_CODE",
+            0x044 => "TPM_RC_AUTHSIZE",
+            0x045 => "TPM_RC_AUTH_CONTEXT",
+            0x046 => "TPM_RC_NV_RANGE",
+            0x047 => "TPM_RC_NV_SIZE",
+            0x048 => "TPM_RC_NV_LOCKED",
+            0x049 => "TPM_RC_NV_AUTHORIZATION",
+            0x04a => "TPM_RC_NV_UNINITIALIZED",
+            0x04b => "TPM_RC_NV_SPACE",
+            0x04c => "TPM_RC_NV_DEFINED",
+            0x050 => "TPM_RC_BAD_CONTEXT",
+            0x051 => "TPM_RC_CPHASH",
+            0x052 => "TPM_RC_PARENT",
+            0x053 => "TPM_RC_NEEDS_TEST",
+            0x054 => "TPM_RC_NO_RESULT",
+            0x055 => "TPM_RC_SENSITIVE",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:#x})",
+            self.name().unwrap_or("unknown response code"),
+            self.0
+        )?;
+        if let Some(n) = self.parameter() {
+            write!(f, " for parameter {}", n)?;
+        } else if let Some(n) = self.handle() {
+            write!(f, " for handle {}", n)?;
+        } else if let Some(n) = self.session() {
+            write!(f, " for session {}", n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash algorithms usable for PCR banks and object names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum HashAlg {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlg {
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::Sha1 => alg::SHA1,
+            Self::Sha256 => alg::SHA256,
+            Self::Sha384 => alg::SHA384,
+            Self::Sha512 => alg::SHA512,
+        }
+    }
+
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+}
+
+/// Permanent hierarchy handles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum Hierarchy {
+    Owner,
+    Null,
+    Endorsement,
+    Platform,
+}
+
+impl Hierarchy {
+    pub fn handle(&self) -> u32 {
+        match self {
+            Self::Owner => 0x4000_0001,
+            Self::Null => 0x4000_0007,
+            Self::Endorsement => 0x4000_000b,
+            Self::Platform => 0x4000_000c,
+        }
+    }
+}
+
+/// Argument of the `Startup` command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum StartupType {
+    Clear,
+    State,
+}
+
+/// Capabilities which can be queried using `GetCapability`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum Capability {
+    Algorithms,
+    Handles,
+    Commands,
+    Pcrs,
+    Properties,
+}
+
+impl Capability {
+    fn id(&self) -> u32 {
+        match self {
+            Self::Algorithms => 0,
+            Self::Handles => 1,
+            Self::Commands => 2,
+            Self::Pcrs => 5,
+            Self::Properties => 6,
+        }
+    }
+}
+
+/// Builds up TPM2 structures in their big-endian wire format.
+#[derive(Default)]
+struct Marshal(Vec<u8>);
+
+impl Marshal {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    /// Appends a `TPM2B` structure: a 16-bit size followed by `value`.
+    fn tpm2b(&mut self, value: &[u8]) -> Result<&mut Self, CommandError> {
+        let len = u16::try_from(value.len()).map_err(|_| CommandError::Oversized(value.len()))?;
+        Ok(self.u16(len).bytes(value))
+    }
+
+    /// Appends a size-prefixed structure, the contents of which are produced by `f`.
+    fn sized(
+        &mut self,
+        f: impl FnOnce(&mut Marshal) -> Result<(), CommandError>,
+    ) -> Result<&mut Self, CommandError> {
+        let mut inner = Marshal::default();
+        f(&mut inner)?;
+        self.tpm2b(&inner.0)
+    }
+}
+
+/// Parses TPM2 structures from their big-endian wire format.
+struct Unmarshal<'a>(&'a [u8]);
+
+impl<'a> Unmarshal<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CommandError> {
+        if self.0.len() < len {
+            return Err(CommandError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, CommandError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CommandError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CommandError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CommandError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn tpm2b(&mut self) -> Result<Vec<u8>, CommandError> {
+        let len = self.u16()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    /// Returns a parser for the contents of a size-prefixed structure.
+    fn sized(&mut self) -> Result<Unmarshal<'a>, CommandError> {
+        let len = self.u16()? as usize;
+        Ok(Unmarshal(self.bytes(len)?))
+    }
+
+    /// Reads a list with a 32-bit count, parsing each element with `f`.
+    fn list<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Self) -> Result<T, CommandError>,
+    ) -> Result<Vec<T>, CommandError> {
+        let count = self.u32()?;
+        (0..count).map(|_| f(self)).collect()
+    }
+}
+
+/// Selection of PCRs in one bank (`TPMS_PCR_SELECTION`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PcrSelection {
+    pub hash: u16,
+    pub pcrs: Vec<u8>,
+}
+
+impl PcrSelection {
+    pub fn new(hash: HashAlg, pcrs: &[u8]) -> Self {
+        PcrSelection {
+            hash: hash.id(),
+            pcrs: pcrs.to_vec(),
+        }
+    }
+
+    fn marshal(&self, m: &mut Marshal) {
+        let max = self.pcrs.iter().copied().max().unwrap_or(0) as usize;
+        let mut select = vec![0u8; std::cmp::max(3, max / 8 + 1)];
+        for &pcr in &self.pcrs {
+            select[pcr as usize / 8] |= 1 << (pcr % 8);
+        }
+        m.u16(self.hash).u8(select.len() as u8).bytes(&select);
+    }
+
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let hash = u.u16()?;
+        let len = u.u8()? as usize;
+        let select = u.bytes(len)?;
+        let pcrs = (0..len * 8)
+            .filter(|&i| select[i / 8] & (1 << (i % 8)) != 0)
+            .map(|i| i as u8)
+            .collect();
+        Ok(PcrSelection { hash, pcrs })
+    }
+}
+
+fn marshal_pcr_selections(m: &mut Marshal, selections: &[PcrSelection]) {
+    m.u32(selections.len() as u32);
+    for selection in selections {
+        selection.marshal(m);
+    }
+}
+
+/// Symmetric algorithm of a storage key (`TPMT_SYM_DEF_OBJECT`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SymDef {
+    pub algorithm: u16,
+    pub key_bits: u16,
+    pub mode: u16,
+}
+
+impl SymDef {
+    pub const NULL: SymDef = SymDef {
+        algorithm: alg::NULL,
+        key_bits: 0,
+        mode: 0,
+    };
+    pub const AES_128_CFB: SymDef = SymDef {
+        algorithm: alg::AES,
+        key_bits: 128,
+        mode: alg::CFB,
+    };
+
+    fn marshal(&self, m: &mut Marshal) {
+        m.u16(self.algorithm);
+        if self.algorithm != alg::NULL {
+            m.u16(self.key_bits).u16(self.mode);
+        }
+    }
+
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let algorithm = u.u16()?;
+        if algorithm == alg::NULL {
+            return Ok(Self::NULL);
+        }
+        Ok(SymDef {
+            algorithm,
+            key_bits: u.u16()?,
+            mode: u.u16()?,
+        })
+    }
+}
+
+/// A signing, encryption or key derivation scheme, along with its hash algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Scheme {
+    pub scheme: u16,
+    pub hash: u16,
+}
+
+impl Scheme {
+    pub const NULL: Scheme = Scheme {
+        scheme: alg::NULL,
+        hash: 0,
+    };
+
+    pub fn new(scheme: u16, hash: HashAlg) -> Self {
+        Scheme {
+            scheme,
+            hash: hash.id(),
+        }
+    }
+
+    fn marshal(&self, m: &mut Marshal) {
+        m.u16(self.scheme);
+        if self.scheme != alg::NULL && self.scheme != alg::RSAES {
+            m.u16(self.hash);
+        }
+        if self.scheme == alg::ECDAA {
+            m.u16(0);
+        }
+    }
+
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let scheme = u.u16()?;
+        if scheme == alg::NULL || scheme == alg::RSAES {
+            return Ok(Scheme { scheme, hash: 0 });
+        }
+        let hash = u.u16()?;
+        if scheme == alg::ECDAA {
+            u.u16()?;
+        }
+        Ok(Scheme { scheme, hash })
+    }
+}
+
+/// Type specific parameters and unique identifier of a public key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum PublicKey {
+    Rsa {
+        symmetric: SymDef,
+        scheme: Scheme,
+        key_bits: u16,
+        exponent: u32,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        modulus: Vec<u8>,
+    },
+    Ecc {
+        symmetric: SymDef,
+        scheme: Scheme,
+        curve: u16,
+        kdf: Scheme,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        x: Vec<u8>,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        y: Vec<u8>,
+    },
+}
+
+/// The public area of an object (`TPMT_PUBLIC`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Public {
+    pub name_alg: u16,
+    pub attributes: u32,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub auth_policy: Vec<u8>,
+    pub key: PublicKey,
+}
+
+impl Public {
+    fn marshal(&self, m: &mut Marshal) -> Result<(), CommandError> {
+        let object_type = match self.key {
+            PublicKey::Rsa { .. } => alg::RSA,
+            PublicKey::Ecc { .. } => alg::ECC,
+        };
+        m.u16(object_type)
+            .u16(self.name_alg)
+            .u32(self.attributes)
+            .tpm2b(&self.auth_policy)?;
+        match &self.key {
+            PublicKey::Rsa {
+                symmetric,
+                scheme,
+                key_bits,
+                exponent,
+                modulus,
+            } => {
+                symmetric.marshal(m);
+                scheme.marshal(m);
+                m.u16(*key_bits).u32(*exponent).tpm2b(modulus)?;
+            }
+            PublicKey::Ecc {
+                symmetric,
+                scheme,
+                curve,
+                kdf,
+                x,
+                y,
+            } => {
+                symmetric.marshal(m);
+                scheme.marshal(m);
+                m.u16(*curve);
+                kdf.marshal(m);
+                m.tpm2b(x)?.tpm2b(y)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let object_type = u.u16()?;
+        let name_alg = u.u16()?;
+        let attributes = u.u32()?;
+        let auth_policy = u.tpm2b()?;
+        let key = match object_type {
+            alg::RSA => PublicKey::Rsa {
+                symmetric: SymDef::unmarshal(u)?,
+                scheme: Scheme::unmarshal(u)?,
+                key_bits: u.u16()?,
+                exponent: u.u32()?,
+                modulus: u.tpm2b()?,
+            },
+            alg::ECC => PublicKey::Ecc {
+                symmetric: SymDef::unmarshal(u)?,
+                scheme: Scheme::unmarshal(u)?,
+                curve: u.u16()?,
+                kdf: Scheme::unmarshal(u)?,
+                x: u.tpm2b()?,
+                y: u.tpm2b()?,
+            },
+            t => return Err(CommandError::Unsupported("object type".into(), t as u32)),
+        };
+        Ok(Public {
+            name_alg,
+            attributes,
+            auth_policy,
+            key,
+        })
+    }
+}
+
+/// Templates for primary keys, following the conventions of the TCG EK and SRK profiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum KeyTemplate {
+    /// RSA 2048 restricted decryption key, usable as the parent of other keys.
+    RsaStorage,
+    /// ECC NIST P-256 restricted decryption key, usable as the parent of other keys.
+    EccStorage,
+    /// RSA 2048 restricted RSASSA-SHA256 signing key, usable for quotes.
+    RsaSigning,
+    /// ECC NIST P-256 restricted ECDSA-SHA256 signing key, usable for quotes.
+    EccSigning,
+}
+
+impl KeyTemplate {
+    pub fn public(&self) -> Public {
+        const COMMON: u32 = object::FIXED_TPM
+            | object::FIXED_PARENT
+            | object::SENSITIVE_DATA_ORIGIN
+            | object::USER_WITH_AUTH
+            | object::NO_DA
+            | object::RESTRICTED;
+        let (attributes, symmetric, scheme) = match self {
+            Self::RsaStorage | Self::EccStorage => {
+                (COMMON | object::DECRYPT, SymDef::AES_128_CFB, Scheme::NULL)
+            }
+            Self::RsaSigning => (
+                COMMON | object::SIGN,
+                SymDef::NULL,
+                Scheme::new(alg::RSASSA, HashAlg::Sha256),
+            ),
+            Self::EccSigning => (
+                COMMON | object::SIGN,
+                SymDef::NULL,
+                Scheme::new(alg::ECDSA, HashAlg::Sha256),
+            ),
+        };
+        let key = match self {
+            Self::RsaStorage | Self::RsaSigning => PublicKey::Rsa {
+                symmetric,
+                scheme,
+                key_bits: 2048,
+                exponent: 0,
+                modulus: Vec::new(),
+            },
+            Self::EccStorage | Self::EccSigning => PublicKey::Ecc {
+                symmetric,
+                scheme,
+                curve: TPM_ECC_NIST_P256,
+                kdf: Scheme::NULL,
+                x: Vec::new(),
+                y: Vec::new(),
+            },
+        };
+        Public {
+            name_alg: alg::SHA256,
+            attributes,
+            auth_policy: Vec::new(),
+            key,
+        }
+    }
+}
+
+/// The public area of an NV index (`TPMS_NV_PUBLIC`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NvPublic {
+    pub index: u32,
+    pub name_alg: u16,
+    pub attributes: u32,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub auth_policy: Vec<u8>,
+    pub size: u16,
+}
+
+impl NvPublic {
+    /// Attributes of an index which is read and written using its own password.
+    pub const DEFAULT_ATTRIBUTES: u32 = nv::AUTHWRITE | nv::AUTHREAD | nv::NO_DA;
+}
+
+/// Assembles a command with the given `handles` and marshalled `params`, authorizing the first
+/// `auths.len()` handles with password sessions.  Executes the command and checks the response
+/// code, returning `response_handles` handles and the response parameters.
+fn execute(
+    tpm: &dyn Driver,
+    code: u32,
+    handles: &[u32],
+    auths: &[&[u8]],
+    params: &[u8],
+    response_handles: usize,
+) -> Result<(Vec<u32>, Vec<u8>)> {
+    let sessions = !auths.is_empty();
+    let mut body = Marshal::default();
+    for &handle in handles {
+        body.u32(handle);
+    }
+    if sessions {
+        let mut area = Marshal::default();
+        for auth in auths {
+            area.u32(TPM_RS_PW)
+                .tpm2b(&[])?
+                .u8(SESSION_CONTINUE)
+                .tpm2b(auth)?;
+        }
+        body.u32(area.0.len() as u32).bytes(&area.0);
+    }
+    body.bytes(params);
+    let mut cmd = Marshal::default();
+    cmd.u16(if sessions {
+        TPM_ST_SESSIONS
+    } else {
+        TPM_ST_NO_SESSIONS
+    })
+    .u32(10 + body.0.len() as u32)
+    .u32(code)
+    .bytes(&body.0);
+
+    let response = tpm.execute_command(&cmd.0)?;
+    let mut u = Unmarshal(&response);
+    let tag = u.u16()?;
+    let size = u.u32()? as usize;
+    let rc = ResponseCode(u.u32()?);
+    ensure!(
+        size == response.len(),
+        CommandError::Size(response.len(), size)
+    );
+    ensure!(rc.is_success(), CommandError::Response(rc));
+    ensure!(
+        tag == if sessions {
+            TPM_ST_SESSIONS
+        } else {
+            TPM_ST_NO_SESSIONS
+        },
+        CommandError::Tag(tag)
+    );
+    let handles = (0..response_handles)
+        .map(|_| u.u32())
+        .collect::<Result<Vec<_>, _>>()?;
+    // With sessions, the parameters are size-prefixed and followed by the session area.
+    let params = if sessions {
+        let len = u.u32()? as usize;
+        u.bytes(len)?
+    } else {
+        u.0
+    };
+    Ok((handles, params.to_vec()))
+}
+
+/// Initializes the TPM after a reset.
+pub fn startup(tpm: &dyn Driver, startup_type: StartupType) -> Result<()> {
+    let su = match startup_type {
+        StartupType::Clear => 0,
+        StartupType::State => 1,
+    };
+    execute(
+        tpm,
+        TPM_CC_STARTUP,
+        &[],
+        &[],
+        &Marshal::default().u16(su).0,
+        0,
+    )?;
+    Ok(())
+}
+
+/// Runs the TPM self test, either of all functions or only of those not yet tested.
+pub fn self_test(tpm: &dyn Driver, full: bool) -> Result<()> {
+    execute(tpm, TPM_CC_SELF_TEST, &[], &[], &[full as u8], 0)?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AlgorithmProperty {
+    pub algorithm: u16,
+    pub attributes: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TaggedProperty {
+    pub property: u32,
+    pub value: u32,
+}
+
+/// Capability data returned by `GetCapability`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum CapabilityData {
+    Algorithms(Vec<AlgorithmProperty>),
+    Handles(Vec<u32>),
+    Commands(Vec<u32>),
+    Pcrs(Vec<PcrSelection>),
+    Properties(Vec<TaggedProperty>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GetCapabilityResponse {
+    pub more_data: bool,
+    pub data: CapabilityData,
+}
+
+/// Queries up to `count` values of `capability`, starting at `property`.
+pub fn get_capability(
+    tpm: &dyn Driver,
+    capability: Capability,
+    property: u32,
+    count: u32,
+) -> Result<GetCapabilityResponse> {
+    let params = Marshal::default()
+        .u32(capability.id())
+        .u32(property)
+        .u32(count)
+        .0
+        .clone();
+    let (_, response) = execute(tpm, TPM_CC_GET_CAPABILITY, &[], &[], &params, 0)?;
+    let mut u = Unmarshal(&response);
+    let more_data = u.u8()? != 0;
+    let id = u.u32()?;
+    let data = match id {
+        0 => CapabilityData::Algorithms(u.list(|u| {
+            Ok(AlgorithmProperty {
+                algorithm: u.u16()?,
+                attributes: u.u32()?,
+            })
+        })?),
+        1 => CapabilityData::Handles(u.list(|u| u.u32())?),
+        2 => CapabilityData::Commands(u.list(|u| u.u32())?),
+        5 => CapabilityData::Pcrs(u.list(PcrSelection::unmarshal)?),
+        6 => CapabilityData::Properties(u.list(|u| {
+            Ok(TaggedProperty {
+                property: u.u32()?,
+                value: u.u32()?,
+            })
+        })?),
+        _ => return Err(CommandError::Unsupported("capability".into(), id).into()),
+    };
+    Ok(GetCapabilityResponse { more_data, data })
+}
+
+/// Returns `count` random bytes from the TPM.  The TPM may return fewer bytes than requested.
+pub fn get_random(tpm: &dyn Driver, count: u16) -> Result<Vec<u8>> {
+    let params = Marshal::default().u16(count).0.clone();
+    let (_, response) = execute(tpm, TPM_CC_GET_RANDOM, &[], &[], &params, 0)?;
+    Ok(Unmarshal(&response).tpm2b()?)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PcrValue {
+    pub hash: u16,
+    pub pcr: u8,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub digest: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PcrReadResponse {
+    pub update_counter: u32,
+    pub values: Vec<PcrValue>,
+}
+
+/// Reads the PCRs in `selections`.  The TPM may return only some of them, in which case the
+/// remainder must be requested in another call.
+pub fn pcr_read(tpm: &dyn Driver, selections: &[PcrSelection]) -> Result<PcrReadResponse> {
+    let mut params = Marshal::default();
+    marshal_pcr_selections(&mut params, selections);
+    let (_, response) = execute(tpm, TPM_CC_PCR_READ, &[], &[], &params.0, 0)?;
+    let mut u = Unmarshal(&response);
+    let update_counter = u.u32()?;
+    let selected = u.list(PcrSelection::unmarshal)?;
+    let mut digests = u.list(|u| u.tpm2b())?.into_iter();
+    let mut values = Vec::new();
+    for selection in selected {
+        for pcr in selection.pcrs {
+            let Some(digest) = digests.next() else {
+                return Err(CommandError::Truncated.into());
+            };
+            values.push(PcrValue {
+                hash: selection.hash,
+                pcr,
+                digest,
+            });
+        }
+    }
+    Ok(PcrReadResponse {
+        update_counter,
+        values,
+    })
+}
+
+/// Extends PCR `pcr` with the given digests, one per bank.
+pub fn pcr_extend(
+    tpm: &dyn Driver,
+    pcr: u32,
+    auth: &[u8],
+    digests: &[(HashAlg, &[u8])],
+) -> Result<()> {
+    let mut params = Marshal::default();
+    params.u32(digests.len() as u32);
+    for (hash, digest) in digests {
+        ensure!(
+            digest.len() == hash.digest_len(),
+            CommandError::DigestLength(*hash, hash.digest_len(), digest.len())
+        );
+        params.u16(hash.id()).bytes(digest);
+    }
+    execute(tpm, TPM_CC_PCR_EXTEND, &[pcr], &[auth], &params.0, 0)?;
+    Ok(())
+}
+
+/// Defines the NV index described by `public`, with password `index_auth`.  The index is
+/// created in the hierarchy given by `auth_handle`, authorized by `auth`.
+pub fn nv_define_space(
+    tpm: &dyn Driver,
+    auth_handle: u32,
+    auth: &[u8],
+    index_auth: &[u8],
+    public: &NvPublic,
+) -> Result<()> {
+    let mut params = Marshal::default();
+    params.tpm2b(index_auth)?.sized(|m| {
+        m.u32(public.index)
+            .u16(public.name_alg)
+            .u32(public.attributes)
+            .tpm2b(&public.auth_policy)?
+            .u16(public.size);
+        Ok(())
+    })?;
+    execute(
+        tpm,
+        TPM_CC_NV_DEFINE_SPACE,
+        &[auth_handle],
+        &[auth],
+        &params.0,
+        0,
+    )?;
+    Ok(())
+}
+
+/// Returns the NV offset `delta` bytes past `offset`, if it fits in 16 bits.
+fn nv_offset(offset: u16, delta: usize) -> Result<u16, CommandError> {
+    u16::try_from(delta)
+        .ok()
+        .and_then(|delta| offset.checked_add(delta))
+        .ok_or(CommandError::NvRange(offset, delta))
+}
+
+/// Reads `size` bytes at `offset` of NV `index`, authorized by `auth` for `auth_handle`
+/// (typically the index itself).
+pub fn nv_read(
+    tpm: &dyn Driver,
+    auth_handle: u32,
+    index: u32,
+    auth: &[u8],
+    offset: u16,
+    size: u16,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while data.len() < size as usize {
+        let chunk = std::cmp::min(size as usize - data.len(), NV_CHUNK_SIZE) as u16;
+        let chunk_offset = nv_offset(offset, data.len())?;
+        let params = Marshal::default().u16(chunk).u16(chunk_offset).0.clone();
+        let (_, response) = execute(
+            tpm,
+            TPM_CC_NV_READ,
+            &[auth_handle, index],
+            &[auth],
+            &params,
+            0,
+        )?;
+        let chunk = Unmarshal(&response).tpm2b()?;
+        ensure!(!chunk.is_empty(), CommandError::NvEmpty(chunk_offset));
+        data.extend(chunk);
+    }
+    Ok(data)
+}
+
+/// Writes `data` at `offset` of NV `index`, authorized by `auth` for `auth_handle`
+/// (typically the index itself).
+pub fn nv_write(
+    tpm: &dyn Driver,
+    auth_handle: u32,
+    index: u32,
+    auth: &[u8],
+    offset: u16,
+    data: &[u8],
+) -> Result<()> {
+    for (i, chunk) in data.chunks(NV_CHUNK_SIZE).enumerate() {
+        let params = Marshal::default()
+            .tpm2b(chunk)?
+            .u16(nv_offset(offset, i * NV_CHUNK_SIZE)?)
+            .0
+            .clone();
+        execute(
+            tpm,
+            TPM_CC_NV_WRITE,
+            &[auth_handle, index],
+            &[auth],
+            &params,
+            0,
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CreatePrimaryResponse {
+    pub handle: u32,
+    pub public: Public,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub name: Vec<u8>,
+}
+
+/// Creates a primary key from `template` in `hierarchy`, authorized by `auth`.  The key will
+/// have password `key_auth`.
+pub fn create_primary(
+    tpm: &dyn Driver,
+    hierarchy: Hierarchy,
+    auth: &[u8],
+    template: &Public,
+    key_auth: &[u8],
+) -> Result<CreatePrimaryResponse> {
+    let mut params = Marshal::default();
+    params
+        // inSensitive: the key password, and no sensitive data.
+        .sized(|m| {
+            m.tpm2b(key_auth)?.tpm2b(&[])?;
+            Ok(())
+        })?
+        .sized(|m| template.marshal(m))?
+        // outsideInfo and creationPCR.
+        .tpm2b(&[])?
+        .u32(0);
+    let (handles, response) = execute(
+        tpm,
+        TPM_CC_CREATE_PRIMARY,
+        &[hierarchy.handle()],
+        &[auth],
+        &params.0,
+        1,
+    )?;
+    let mut u = Unmarshal(&response);
+    let public = Public::unmarshal(&mut u.sized()?)?;
+    // creationData, creationHash and creationTicket.
+    u.tpm2b()?;
+    u.tpm2b()?;
+    u.u16()?;
+    u.u32()?;
+    u.tpm2b()?;
+    let name = u.tpm2b()?;
+    Ok(CreatePrimaryResponse {
+        handle: handles[0],
+        public,
+        name,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ReadPublicResponse {
+    pub public: Public,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub name: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub qualified_name: Vec<u8>,
+}
+
+/// Reads the public area of the object `handle`.
+pub fn read_public(tpm: &dyn Driver, handle: u32) -> Result<ReadPublicResponse> {
+    let (_, response) = execute(tpm, TPM_CC_READ_PUBLIC, &[handle], &[], &[], 0)?;
+    let mut u = Unmarshal(&response);
+    Ok(ReadPublicResponse {
+        public: Public::unmarshal(&mut u.sized()?)?,
+        name: u.tpm2b()?,
+        qualified_name: u.tpm2b()?,
+    })
+}
+
+/// Removes the transient object or session `handle` from the TPM.
+pub fn flush_context(tpm: &dyn Driver, handle: u32) -> Result<()> {
+    let params = Marshal::default().u32(handle).0.clone();
+    execute(tpm, TPM_CC_FLUSH_CONTEXT, &[], &[], &params, 0)?;
+    Ok(())
+}
+
+/// A signature (`TPMT_SIGNATURE`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Signature {
+    Rsa {
+        scheme: u16,
+        hash: u16,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        signature: Vec<u8>,
+    },
+    Ecc {
+        scheme: u16,
+        hash: u16,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        r: Vec<u8>,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        s: Vec<u8>,
+    },
+}
+
+impl Signature {
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let scheme = u.u16()?;
+        match scheme {
+            alg::RSASSA | alg::RSAPSS => Ok(Signature::Rsa {
+                scheme,
+                hash: u.u16()?,
+                signature: u.tpm2b()?,
+            }),
+            alg::ECDSA => Ok(Signature::Ecc {
+                scheme,
+                hash: u.u16()?,
+                r: u.tpm2b()?,
+                s: u.tpm2b()?,
+            }),
+            _ => Err(CommandError::Unsupported(
+                "signature scheme".into(),
+                scheme as u32,
+            )),
+        }
+    }
+}
+
+/// The contents of a quote (`TPMS_ATTEST` with `TPMS_QUOTE_INFO`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QuoteInfo {
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub signer: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub extra_data: Vec<u8>,
+    pub clock: u64,
+    pub reset_count: u32,
+    pub restart_count: u32,
+    pub safe: bool,
+    pub firmware_version: u64,
+    pub pcr_select: Vec<PcrSelection>,
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub pcr_digest: Vec<u8>,
+}
+
+impl QuoteInfo {
+    fn unmarshal(u: &mut Unmarshal) -> Result<Self, CommandError> {
+        let magic = u.u32()?;
+        if magic != TPM_GENERATED_VALUE {
+            return Err(CommandError::Unsupported("attestation magic".into(), magic));
+        }
+        let tag = u.u16()?;
+        if tag != TPM_ST_ATTEST_QUOTE {
+            return Err(CommandError::Unsupported(
+                "attestation type".into(),
+                tag as u32,
+            ));
+        }
+        Ok(QuoteInfo {
+            signer: u.tpm2b()?,
+            extra_data: u.tpm2b()?,
+            clock: u.u64()?,
+            reset_count: u.u32()?,
+            restart_count: u.u32()?,
+            safe: u.u8()? != 0,
+            firmware_version: u.u64()?,
+            pcr_select: u.list(PcrSelection::unmarshal)?,
+            pcr_digest: u.tpm2b()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QuoteResponse {
+    /// The signed `TPMS_ATTEST` structure.
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub attest: Vec<u8>,
+    pub quote: QuoteInfo,
+    pub signature: Signature,
+}
+
+/// Quotes the PCRs in `selections` with the signing key `key` (authorized by `auth`), including
+/// `qualifying_data` (e.g. a nonce) in the signed structure.
+pub fn quote(
+    tpm: &dyn Driver,
+    key: u32,
+    auth: &[u8],
+    qualifying_data: &[u8],
+    selections: &[PcrSelection],
+) -> Result<QuoteResponse> {
+    let mut params = Marshal::default();
+    // Sign using the scheme of the key.
+    params.tpm2b(qualifying_data)?.u16(alg::NULL);
+    marshal_pcr_selections(&mut params, selections);
+    let (_, response) = execute(tpm, TPM_CC_QUOTE, &[key], &[auth], &params.0, 0)?;
+    let mut u = Unmarshal(&response);
+    let attest = u.tpm2b()?;
+    let quote = QuoteInfo::unmarshal(&mut Unmarshal(&attest))?;
+    Ok(QuoteResponse {
+        attest,
+        quote,
+        signature: Signature::unmarshal(&mut u)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm::Register;
+    use std::cell::RefCell;
+
+    /// Records the last command, and answers it with a canned response.
+    struct FakeTpm {
+        command: RefCell<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl FakeTpm {
+        /// Creates a fake answering with `rc`, and `body` following the response header.
+        fn new(sessions: bool, rc: u32, body: &[u8]) -> Self {
+            let tag = if sessions {
+                TPM_ST_SESSIONS
+            } else {
+                TPM_ST_NO_SESSIONS
+            };
+            let mut response = Marshal::default();
+            response
+                .u16(tag)
+                .u32(10 + body.len() as u32)
+                .u32(rc)
+                .bytes(body);
+            FakeTpm {
+                command: RefCell::default(),
+                response: response.0,
+            }
+        }
+    }
+
+    impl Driver for FakeTpm {
+        fn read_register(&self, _register: Register, _data: &mut [u8]) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn write_register(&self, _register: Register, _data: &[u8]) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn execute_command(&self, cmd: &[u8]) -> Result<Vec<u8>> {
+            *self.command.borrow_mut() = cmd.to_vec();
+            Ok(self.response.clone())
+        }
+    }
+
+    #[test]
+    fn test_startup() -> Result<()> {
+        let tpm = FakeTpm::new(false, 0, &[]);
+        startup(&tpm, StartupType::Clear)?;
+        assert_eq!(
+            hex::encode(&*tpm.command.borrow()),
+            "80010000000c000001440000"
+        );
+
+        let tpm = FakeTpm::new(false, 0x100, &[]);
+        let err = startup(&tpm, StartupType::Clear).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::Response(ResponseCode(0x100)))
+        ));
+        assert_eq!(err.to_string(), "TPM returned TPM_RC_INITIALIZE (0x100)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_code() {
+        assert_eq!(ResponseCode(0x14c).name(), Some("TPM_RC_NV_DEFINED"));
+        assert_eq!(ResponseCode(0x922).name(), Some("TPM_RC_RETRY"));
+        let rc = ResponseCode(0x1c4);
+        assert_eq!(rc.name(), Some("TPM_RC_VALUE"));
+        assert_eq!(rc.parameter(), Some(1));
+        assert_eq!(rc.to_string(), "TPM_RC_VALUE (0x1c4) for parameter 1");
+        let rc = ResponseCode(0x98e);
+        assert_eq!(rc.name(), Some("TPM_RC_AUTH_FAIL"));
+        assert_eq!(rc.session(), Some(1));
+        assert_eq!(rc.handle(), None);
+        assert_eq!(ResponseCode(0x28b).handle(), Some(2));
+        assert_eq!(ResponseCode(0x501).name(), None);
+    }
+
+    #[test]
+    fn test_get_random() -> Result<()> {
+        let tpm = FakeTpm::new(false, 0, &[0, 4, 1, 2, 3, 4]);
+        assert_eq!(get_random(&tpm, 4)?, [1, 2, 3, 4]);
+        assert_eq!(
+            hex::encode(&*tpm.command.borrow()),
+            "80010000000c0000017b0004"
+        );
+
+        // The response claims more bytes than it contains.
+        let tpm = FakeTpm::new(false, 0, &[0, 8, 1, 2, 3, 4]);
+        let err = get_random(&tpm, 8).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::Truncated)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pcr_read() -> Result<()> {
+        let mut body = Marshal::default();
+        body.u32(7);
+        marshal_pcr_selections(&mut body, &[PcrSelection::new(HashAlg::Sha256, &[0, 10])]);
+        body.u32(2).tpm2b(&[0xaa; 32])?.tpm2b(&[0xbb; 32])?;
+        let tpm = FakeTpm::new(false, 0, &body.0);
+        let response = pcr_read(&tpm, &[PcrSelection::new(HashAlg::Sha256, &[0, 10])])?;
+        assert_eq!(
+            hex::encode(&*tpm.command.borrow()),
+            "8001000000140000017e00000001000b03010400"
+        );
+        assert_eq!(response.update_counter, 7);
+        assert_eq!(response.values.len(), 2);
+        assert_eq!(response.values[1].pcr, 10);
+        assert_eq!(response.values[1].digest, [0xbb; 32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nv_write_session() -> Result<()> {
+        let tpm = FakeTpm::new(true, 0, &[0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        nv_write(&tpm, 0x0150_0000, 0x0150_0000, b"pw", 0, &[0x12, 0x34])?;
+        assert_eq!(
+            hex::encode(&*tpm.command.borrow()),
+            concat!(
+                "80020000002700000137",
+                // Handles.
+                "0150000001500000",
+                // Password session.
+                "0000000b40000009000001000270 77",
+                // Data and offset.
+                "000212340000"
+            )
+            .replace(' ', "")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_nv_read_bounds() -> Result<()> {
+        // A successful response with an empty buffer must not loop forever.
+        let tpm = FakeTpm::new(true, 0, &[0, 0, 0, 2, 0, 0, 0, 0, 1, 0, 0]);
+        let err = nv_read(&tpm, 0x0150_0000, 0x0150_0000, b"", 0x10, 4).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::NvEmpty(0x10))
+        ));
+
+        // The second chunk would start beyond the 16-bit offset range.
+        let mut body = Marshal::default();
+        body.u32(2 + NV_CHUNK_SIZE as u32)
+            .tpm2b(&[0; NV_CHUNK_SIZE])?
+            .bytes(&[0, 0, 1, 0, 0]);
+        let tpm = FakeTpm::new(true, 0, &body.0);
+        let offset = (0x10000 - NV_CHUNK_SIZE) as u16;
+        let err = nv_read(
+            &tpm,
+            0x0150_0000,
+            0x0150_0000,
+            b"",
+            offset,
+            2 * NV_CHUNK_SIZE as u16,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::NvRange(o, d)) if *o == offset && *d == NV_CHUNK_SIZE
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tpm2b_oversized() {
+        let mut m = Marshal::default();
+        assert!(m.tpm2b(&[0; 0xffff]).is_ok());
+        assert!(matches!(
+            m.tpm2b(&[0; 0x10000]),
+            Err(CommandError::Oversized(0x10000))
+        ));
+    }
+
+    #[test]
+    fn test_public_round_trip() -> Result<()> {
+        for template in [
+            KeyTemplate::RsaStorage,
+            KeyTemplate::EccStorage,
+            KeyTemplate::RsaSigning,
+            KeyTemplate::EccSigning,
+        ] {
+            let public = template.public();
+            let mut m = Marshal::default();
+            public.marshal(&mut m)?;
+            let mut u = Unmarshal(&m.0);
+            assert_eq!(Public::unmarshal(&mut u)?, public);
+            assert!(u.0.is_empty());
+        }
+        let mut m = Marshal::default();
+        KeyTemplate::RsaStorage.public().marshal(&mut m)?;
+        assert_eq!(
+            hex::encode(&m.0),
+            "0001000b00030472000000060080004300100800000000000000"
+        );
+        Ok(())
+    }
+}
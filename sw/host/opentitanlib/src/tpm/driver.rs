@@ -5,6 +5,7 @@
 use anyhow::{bail, ensure, Result};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::rc::Rc;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -13,6 +14,7 @@ use thiserror::Error;
 use crate::io::i2c;
 use crate::io::spi;
 use crate::tpm::access::TpmAccess;
+use crate::tpm::interrupt::{TpmInterrupt, TpmIrq};
 use crate::tpm::status::TpmStatus;
 
 /// Tpm registers, can be specified in command line arguments.
@@ -65,8 +67,15 @@ pub enum TpmError {
     ReadStatusFail,
     #[error("Timeout polling for response")]
     ResponseTimeout,
+    #[error("Invalid locality {0}")]
+    InvalidLocality(u8),
+    #[error("Timeout requesting locality {0}")]
+    LocalityTimeout(u8),
 }
 
+/// Highest locality defined by the TPM interface specifications.
+pub const MAX_LOCALITY: u8 = 4;
+
 /// Low level interface for accessing TPM.  Separate implementations exist for SPI and I2C.
 pub trait Driver {
     /// Initialize the TPM by claiming the access register.
@@ -79,9 +88,105 @@ pub trait Driver {
             Register::ACCESS,
             &TpmAccess::REQUEST_USE.bits().to_be_bytes(),
         )?;
+        self.enable_interrupts()?;
         Ok(())
     }
 
+    /// The interrupt line of the TPM, if one is configured.
+    fn interrupt(&self) -> Option<&TpmIrq> {
+        None
+    }
+
+    /// Enables the TPM to signal `dataAvail` and `commandReady` on its interrupt line, if one is
+    /// configured.
+    fn enable_interrupts(&self) -> Result<()> {
+        if let Some(irq) = self.interrupt() {
+            let enable = TpmInterrupt::GLOBAL_ENABLE
+                | irq.polarity()
+                | TpmInterrupt::DATA_AVAIL
+                | TpmInterrupt::COMMAND_READY;
+            self.write_register(Register::INT_ENABLE, &enable.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `INTERRUPT_TIMEOUT`, but no later than `deadline`, for the TPM to raise one of
+    /// `interrupts`, and clears the raised interrupts.  Returns whether one of `interrupts` was
+    /// raised, or `false` right away if no interrupt line is configured.  As an interrupt may be
+    /// missed, the caller is expected to check the status register either way.
+    fn wait_for_interrupt(&self, interrupts: u32, deadline: Instant) -> Result<bool> {
+        let Some(irq) = self.interrupt() else {
+            return Ok(false);
+        };
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .min(INTERRUPT_TIMEOUT);
+        if !irq.wait(timeout)? {
+            log::debug!("No TPM interrupt within {:?}", timeout);
+            return Ok(false);
+        }
+        let mut status = [0u8; 4];
+        self.read_register(Register::INT_STATUS, &mut status)?;
+        // Interrupt status bits are cleared by writing ones.
+        self.write_register(Register::INT_STATUS, &status)?;
+        let status = u32::from_le_bytes(status);
+        if status & interrupts == 0 {
+            log::debug!("Unexpected TPM interrupt status {:#x}", status);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Maximum number of bytes to transfer to or from the FIFO in one transaction.  The TPM may
+    /// restrict individual transfers further through its burst count.
+    fn max_transaction_size(&self) -> usize {
+        MAX_TRANSACTION_SIZE
+    }
+
+    /// The locality at which registers are accessed.
+    fn locality(&self) -> u8 {
+        0
+    }
+
+    /// Selects the locality at which registers are accessed.  This does not request use of the
+    /// locality from the TPM, see `request_locality`.
+    fn set_locality(&self, locality: u8) -> Result<()> {
+        ensure!(locality == 0, TpmError::InvalidLocality(locality));
+        Ok(())
+    }
+
+    /// Selects `locality` and requests its use, waiting until it becomes the active locality.
+    fn request_locality(&self, locality: u8) -> Result<()> {
+        const LOCALITY_TIMEOUT: Duration = Duration::from_millis(1000);
+        self.set_locality(locality)?;
+        self.write_register(
+            Register::ACCESS,
+            &TpmAccess::REQUEST_USE.bits().to_be_bytes(),
+        )?;
+        let deadline = Instant::now() + LOCALITY_TIMEOUT;
+        loop {
+            let mut access = [0u8; 1];
+            self.read_register(Register::ACCESS, &mut access)?;
+            let access = TpmAccess::from_bits_truncate(access[0]);
+            if access.contains(TpmAccess::VALID | TpmAccess::ACTIVE_LOCALITY) {
+                return Ok(());
+            }
+            ensure!(
+                Instant::now() <= deadline,
+                TpmError::LocalityTimeout(locality)
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Gives up the current locality, allowing the TPM to grant another locality.
+    fn relinquish_locality(&self) -> Result<()> {
+        self.write_register(
+            Register::ACCESS,
+            &TpmAccess::ACTIVE_LOCALITY.bits().to_be_bytes(),
+        )
+    }
+
     /// Read from the given TPM register, number of bytes to read given by length of data slice.
     fn read_register(&self, register: Register, data: &mut [u8]) -> Result<()>;
 
@@ -93,9 +198,26 @@ pub trait Driver {
         self.write_register(Register::STS, &TpmStatus::CMD_READY.to_le_bytes())?;
 
         log::debug!("RUN({}) {:02X?}", cmd.len(), cmd);
-        self.poll_for_ready()?;
-        for slice in cmd.chunks(MAX_TRANSACTION_SIZE) {
-            self.write_register(Register::DATA_FIFO, slice)?;
+        let max = self.max_transaction_size();
+        let mut sts = self.poll_for_ready()?;
+        let mut deadline = Instant::now() + BURST_COUNT_TIMEOUT;
+        let mut remaining = cmd;
+        while !remaining.is_empty() {
+            // Write no more than the TPM is able to accept, as indicated by the burst count.
+            let burst = sts.burst_count().min(max).min(remaining.len());
+            if burst > 0 {
+                let (chunk, rest) = remaining.split_at(burst);
+                self.write_register(Register::DATA_FIFO, chunk)?;
+                remaining = rest;
+                // The timeout applies to each wait for the TPM to accept more data.
+                deadline = Instant::now() + BURST_COUNT_TIMEOUT;
+            } else {
+                ensure!(Instant::now() <= deadline, TpmError::Timeout);
+                thread::sleep(Duration::from_millis(1));
+            }
+            if !remaining.is_empty() {
+                sts = self.read_status()?;
+            }
         }
         self.write_register(Register::STS, &TpmStatus::TPM_GO.to_le_bytes())?;
 
@@ -103,7 +225,7 @@ pub trait Driver {
             .poll_for_data_available()?
             .burst_count()
             .max(RESPONSE_HEADER_SIZE)
-            .min(MAX_TRANSACTION_SIZE);
+            .min(max);
         let mut result: Vec<u8> = vec![0; sz];
         self.read_register(Register::DATA_FIFO, result.as_mut_slice())?;
         let resp_size: usize = u32::from_be_bytes(result[2..6].try_into().unwrap()) as usize;
@@ -115,7 +237,7 @@ pub trait Driver {
 
         let mut sts = self.read_status()?;
         while sts.is_valid() && sts.data_available() && remaining > 0 {
-            let to_read: usize = remaining.min(MAX_TRANSACTION_SIZE);
+            let to_read: usize = remaining.min(sts.burst_count().clamp(1, max));
             let mut result2: Vec<u8> = vec![0; to_read];
             self.read_register(Register::DATA_FIFO, result2.as_mut_slice())?;
             result.append(&mut result2);
@@ -131,6 +253,20 @@ pub trait Driver {
         Ok(result)
     }
 
+    /// Execute a TPM command at `locality`, first switching to that locality if it is not the
+    /// current one.
+    fn execute_command_at(&self, locality: u8, cmd: &[u8]) -> Result<Vec<u8>> {
+        ensure!(
+            locality <= MAX_LOCALITY,
+            TpmError::InvalidLocality(locality)
+        );
+        if locality != self.locality() {
+            self.relinquish_locality()?;
+            self.request_locality(locality)?;
+        }
+        self.execute_command(cmd)
+    }
+
     /// Fetches the current status.
     fn read_status(&self) -> Result<TpmStatus> {
         let mut out = [0u8; 4];
@@ -160,8 +296,10 @@ pub trait Driver {
                 log::error!("Status poll timeout.");
                 return Err(TpmError::ResponseTimeout.into());
             }
+            if !self.wait_for_interrupt(TpmInterrupt::DATA_AVAIL, deadline)? {
+                thread::sleep(Duration::from_millis(10));
+            }
             sts = self.read_status()?;
-            thread::sleep(Duration::from_millis(10));
         }
         Ok(sts)
     }
@@ -180,21 +318,74 @@ pub trait Driver {
             || (sts.raw_value() & 0xFF) == 0xFF
         {
             ensure!(Instant::now() <= deadline, TpmError::Timeout);
+            if !self.wait_for_interrupt(TpmInterrupt::COMMAND_READY, deadline)? {
+                thread::sleep(Duration::from_millis(10));
+            }
             sts = self.read_status()?;
-            thread::sleep(Duration::from_millis(10));
         }
         Ok(sts)
     }
 }
 
+/// Runs `f` with `tpm` at `locality`.  Locality 0 is only selected; any other locality is
+/// requested from the TPM before `f` runs and relinquished afterwards, even if `f` fails, leaving
+/// locality 0 active again.
+pub fn run_at_locality<T>(
+    tpm: &dyn Driver,
+    locality: u8,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    if locality == 0 {
+        tpm.set_locality(0)?;
+        return f();
+    }
+    ensure!(
+        locality <= MAX_LOCALITY,
+        TpmError::InvalidLocality(locality)
+    );
+    tpm.relinquish_locality()?;
+    tpm.request_locality(locality)?;
+    let result = f();
+    let restored = tpm
+        .relinquish_locality()
+        .and_then(|_| tpm.request_locality(0));
+    let value = result?;
+    restored?;
+    Ok(value)
+}
+
 /// Implementation of the low level interface via standard SPI protocol.
 pub struct SpiDriver {
     spi: Rc<dyn spi::Target>,
+    locality: Cell<u8>,
+    irq: Option<TpmIrq>,
+    max_transaction_size: usize,
 }
 
 impl SpiDriver {
     pub fn new(spi: Rc<dyn spi::Target>) -> Self {
-        Self { spi }
+        // Use transactions up to the maximum of the SPI protocol, if the transport allows it.
+        let max_transaction_size = spi
+            .get_max_transfer_sizes()
+            .map(|sizes| {
+                sizes
+                    .read
+                    .min(sizes.write)
+                    .min(SPI_TPM_MAX_TRANSACTION_SIZE)
+            })
+            .unwrap_or(MAX_TRANSACTION_SIZE);
+        Self {
+            spi,
+            locality: Cell::new(0),
+            irq: None,
+            max_transaction_size,
+        }
+    }
+
+    /// Waits for the TPM using its interrupt line `irq`, rather than only polling its status.
+    pub fn with_interrupt(mut self, irq: TpmIrq) -> Self {
+        self.irq = Some(irq);
+        self
     }
 
     /// Numerical TPM register address as used in SPI protocol.
@@ -214,17 +405,24 @@ impl SpiDriver {
         }
     }
 
-    /// Write a transaction header for the given register and length.
-    fn write_header(&self, register: Register, len: usize, is_read: bool) -> Result<()> {
-        let mut buffer = vec![0u8; 4];
-        let mut req: u32 = ((len as u32 - 1) << SPI_TPM_DATA_LEN_POS)
+    /// The transaction header for accessing `len` bytes of the given register at the current
+    /// locality.
+    fn header(&self, register: Register, len: usize, is_read: bool) -> u32 {
+        let req = ((len as u32 - 1) << SPI_TPM_DATA_LEN_POS)
             | SPI_TPM_ADDRESS_OFFSET
+            | ((self.locality.get() as u32) << SPI_TPM_LOCALITY_POS)
             | (Self::addr(register) as u32);
         if is_read {
-            req |= SPI_TPM_READ;
+            req | SPI_TPM_READ
         } else {
-            req |= SPI_TPM_WRITE;
+            req | SPI_TPM_WRITE
         }
+    }
+
+    /// Write a transaction header for the given register and length.
+    fn write_header(&self, register: Register, len: usize, is_read: bool) -> Result<()> {
+        let mut buffer = vec![0u8; 4];
+        let req = self.header(register, len, is_read);
         self.spi
             .run_transaction(&mut [spi::Transfer::Both(&req.to_be_bytes(), &mut buffer)])?;
         if buffer[3] & 1 == 0 {
@@ -248,12 +446,39 @@ const SPI_TPM_READ: u32 = 0xC0000000;
 const SPI_TPM_WRITE: u32 = 0x40000000;
 const SPI_TPM_DATA_LEN_POS: u8 = 24;
 const SPI_TPM_ADDRESS_OFFSET: u32 = 0x00D40000;
+const SPI_TPM_LOCALITY_POS: u8 = 12;
+/// The SPI protocol limits the data of a transaction to 64 bytes.
+const SPI_TPM_MAX_TRANSACTION_SIZE: usize = 64;
 
 const MAX_TRANSACTION_SIZE: usize = 32;
 const RESPONSE_HEADER_SIZE: usize = 6;
 const MAX_RESPONSE_SIZE: usize = 4096;
+const BURST_COUNT_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Longest wait for the TPM interrupt before the status register is checked again.
+const INTERRUPT_TIMEOUT: Duration = Duration::from_millis(100);
 
 impl Driver for SpiDriver {
+    fn interrupt(&self) -> Option<&TpmIrq> {
+        self.irq.as_ref()
+    }
+
+    fn max_transaction_size(&self) -> usize {
+        self.max_transaction_size
+    }
+
+    fn locality(&self) -> u8 {
+        self.locality.get()
+    }
+
+    fn set_locality(&self, locality: u8) -> Result<()> {
+        ensure!(
+            locality <= MAX_LOCALITY,
+            TpmError::InvalidLocality(locality)
+        );
+        self.locality.set(locality);
+        Ok(())
+    }
+
     fn read_register(&self, register: Register, data: &mut [u8]) -> Result<()> {
         let _cs_asserted = Rc::clone(&self.spi).assert_cs()?; // Deasserts when going out of scope.
         self.write_header(register, data.len(), true)?;
@@ -274,11 +499,16 @@ impl Driver for SpiDriver {
 pub struct I2cDriver {
     i2c: Rc<dyn i2c::Bus>,
     addr: u8,
+    locality: Cell<u8>,
 }
 
 impl I2cDriver {
     pub fn new(i2c: Rc<dyn i2c::Bus>, addr: u8) -> Self {
-        Self { i2c, addr }
+        Self {
+            i2c,
+            addr,
+            locality: Cell::new(0),
+        }
     }
 
     /// Numerical TPM register address as used in Google I2C protocol.
@@ -291,9 +521,27 @@ impl I2cDriver {
             _ => None,
         }
     }
+
+    /// Register address at the current locality, which is encoded in the upper nibble.
+    fn locality_addr(&self, reg: Register) -> u8 {
+        Self::addr(reg).unwrap() | (self.locality.get() << 4)
+    }
 }
 
 impl Driver for I2cDriver {
+    fn locality(&self) -> u8 {
+        self.locality.get()
+    }
+
+    fn set_locality(&self, locality: u8) -> Result<()> {
+        ensure!(
+            locality <= MAX_LOCALITY,
+            TpmError::InvalidLocality(locality)
+        );
+        self.locality.set(locality);
+        Ok(())
+    }
+
     fn read_register(&self, register: Register, data: &mut [u8]) -> Result<()> {
         const MAX_TRIES: usize = 10;
         let mut count = 0;
@@ -303,7 +551,7 @@ impl Driver for I2cDriver {
             let res = self.i2c.run_transaction(
                 self.addr,
                 &mut [
-                    i2c::Transfer::Write(&[Self::addr(register).unwrap()]),
+                    i2c::Transfer::Write(&[self.locality_addr(register)]),
                     i2c::Transfer::Read(data),
                 ],
             );
@@ -311,7 +559,7 @@ impl Driver for I2cDriver {
                 Err(e) => {
                     log::trace!(
                         "Register 0x{:X} access error: {}",
-                        Self::addr(register).unwrap(),
+                        self.locality_addr(register),
                         e
                     );
                     if count == MAX_TRIES {
@@ -334,10 +582,352 @@ impl Driver for I2cDriver {
     }
 
     fn write_register(&self, register: Register, data: &[u8]) -> Result<()> {
-        let mut buffer = vec![Self::addr(register).unwrap()];
+        let mut buffer = vec![self.locality_addr(register)];
         buffer.extend_from_slice(data);
         self.i2c
             .run_transaction(self.addr, &mut [i2c::Transfer::Write(&buffer)])?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm::emulator::{
+        CannedResponses, EmulatorError, EmulatorParams, Fault, TpmEmulator,
+    };
+    use std::cell::RefCell;
+
+    /// Models the FIFO of a TPM which accepts and returns at most `burst` bytes per transfer,
+    /// and answers any command with `response`.
+    struct FifoTpm {
+        burst: usize,
+        response: Vec<u8>,
+        command: RefCell<Vec<u8>>,
+        transfers: RefCell<Vec<usize>>,
+        read_pos: Cell<usize>,
+        executing: Cell<bool>,
+    }
+
+    impl FifoTpm {
+        fn new(burst: usize, response: Vec<u8>) -> Self {
+            FifoTpm {
+                burst,
+                response,
+                command: RefCell::default(),
+                transfers: RefCell::default(),
+                read_pos: Cell::new(0),
+                executing: Cell::new(false),
+            }
+        }
+
+        fn available(&self) -> usize {
+            if self.executing.get() {
+                (self.response.len() - self.read_pos.get()).min(self.burst)
+            } else {
+                self.burst
+            }
+        }
+    }
+
+    impl Driver for FifoTpm {
+        fn max_transaction_size(&self) -> usize {
+            64
+        }
+
+        fn read_register(&self, register: Register, data: &mut [u8]) -> Result<()> {
+            match register {
+                Register::STS => {
+                    let mut sts = 0x80 | TpmStatus::CMD_READY | (self.available() << 8) as u32;
+                    if self.executing.get() && self.available() > 0 {
+                        sts |= 0x10;
+                    }
+                    data.copy_from_slice(&sts.to_le_bytes());
+                }
+                Register::DATA_FIFO => {
+                    assert!(data.len() <= self.available());
+                    let pos = self.read_pos.get();
+                    data.copy_from_slice(&self.response[pos..pos + data.len()]);
+                    self.read_pos.set(pos + data.len());
+                    self.transfers.borrow_mut().push(data.len());
+                }
+                _ => unimplemented!(),
+            }
+            Ok(())
+        }
+
+        fn write_register(&self, register: Register, data: &[u8]) -> Result<()> {
+            match register {
+                Register::STS => {
+                    if u32::from_le_bytes(data.try_into().unwrap()) == TpmStatus::TPM_GO {
+                        self.executing.set(true);
+                    }
+                }
+                Register::DATA_FIFO => {
+                    assert!(data.len() <= self.available());
+                    self.command.borrow_mut().extend_from_slice(data);
+                    self.transfers.borrow_mut().push(data.len());
+                }
+                _ => unimplemented!(),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_burst_count() -> Result<()> {
+        let cmd = (0..100).collect::<Vec<u8>>();
+        let mut response = vec![0x80, 0x01, 0, 0, 0, 90];
+        response.resize(90, 0xa5);
+
+        // Transfers are limited by the burst count of the TPM.
+        let tpm = FifoTpm::new(40, response.clone());
+        assert_eq!(tpm.execute_command(&cmd)?, response);
+        assert_eq!(*tpm.command.borrow(), cmd);
+        assert_eq!(*tpm.transfers.borrow(), [40, 40, 20, 40, 40, 10]);
+
+        // And by the maximum transaction size of the driver.
+        let tpm = FifoTpm::new(100, response.clone());
+        assert_eq!(tpm.execute_command(&cmd)?, response);
+        assert_eq!(*tpm.transfers.borrow(), [64, 36, 64, 26]);
+        Ok(())
+    }
+
+    /// TPM2_Startup(TPM_SU_CLEAR) and its successful response.
+    const STARTUP: [u8; 12] = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x44, 0, 0];
+    const SUCCESS: [u8; 10] = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0];
+
+    fn emulator(params: EmulatorParams) -> Rc<TpmEmulator> {
+        let responses = CannedResponses::new().add(&STARTUP, &SUCCESS);
+        Rc::new(TpmEmulator::new(params, Box::new(responses)))
+    }
+
+    /// A command longer than the burst count, and a response to it spanning several bursts.
+    fn long_exchange() -> (Vec<u8>, Vec<u8>) {
+        let mut cmd = vec![0x80, 0x01, 0, 0, 0, 100];
+        cmd.resize(100, 0x5a);
+        let mut response = vec![0x80, 0x01, 0, 0, 0, 90];
+        response.resize(90, 0xa5);
+        (cmd, response)
+    }
+
+    #[test]
+    fn test_spi_emulator() -> Result<()> {
+        let (cmd, response) = long_exchange();
+        let emulator = Rc::new(TpmEmulator::new(
+            EmulatorParams {
+                wait_states: 3,
+                burst_count: 16,
+                execution_polls: 2,
+                ..Default::default()
+            },
+            Box::new(CannedResponses::new().add(&cmd, &response)),
+        ));
+        let tpm = SpiDriver::new(emulator.clone());
+        tpm.init()?;
+
+        let mut did_vid = [0u8; 4];
+        tpm.read_register(Register::DID_VID, &mut did_vid)?;
+        assert_eq!(u32::from_le_bytes(did_vid), emulator.params().did_vid);
+
+        assert_eq!(tpm.execute_command(&cmd)?, response);
+        assert_eq!(emulator.commands(), [(0, cmd)]);
+
+        // Commands missing from the table fail with TPM_RC_COMMAND_CODE.
+        assert_eq!(
+            tpm.execute_command(&STARTUP)?,
+            [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x43]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_wait_states() -> Result<()> {
+        let tpm = SpiDriver::new(emulator(EmulatorParams {
+            wait_states: 10,
+            ..Default::default()
+        }));
+        tpm.init()?;
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+
+        // The driver gives up after ten wait states.
+        let tpm = SpiDriver::new(emulator(EmulatorParams {
+            wait_states: 11,
+            ..Default::default()
+        }));
+        let err = tpm.init().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpmError>(),
+            Some(TpmError::Timeout)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_emulator_burst_count() -> Result<()> {
+        let emulator = emulator(EmulatorParams {
+            burst_count: 8,
+            ..Default::default()
+        });
+        let tpm = SpiDriver::new(emulator.clone());
+        tpm.init()?;
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+
+        // Writing more than the burst count is caught by the emulator.
+        tpm.write_register(Register::STS, &TpmStatus::CMD_READY.to_le_bytes())?;
+        let err = tpm
+            .write_register(Register::DATA_FIFO, &STARTUP)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<EmulatorError>(),
+            Some(EmulatorError::BurstOverflow(12, 8))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_header() -> Result<()> {
+        let tpm = SpiDriver::new(emulator(EmulatorParams::default()));
+        assert_eq!(tpm.header(Register::STS, 4, true), 0xc3d4_0018);
+        assert_eq!(tpm.header(Register::DATA_FIFO, 64, false), 0x7fd4_0024);
+        // The locality selects bits 15:12 of the register address.
+        tpm.set_locality(3)?;
+        assert_eq!(tpm.header(Register::STS, 4, true), 0xc3d4_3018);
+        tpm.set_locality(MAX_LOCALITY)?;
+        assert_eq!(tpm.header(Register::ACCESS, 1, false), 0x40d4_4000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_locality() -> Result<()> {
+        let emulator = emulator(EmulatorParams::default());
+        let tpm = SpiDriver::new(emulator.clone());
+        tpm.init()?;
+        assert_eq!(emulator.active_locality(), Some(0));
+        assert_eq!(tpm.execute_command_at(0, &STARTUP)?, SUCCESS);
+        assert_eq!(tpm.execute_command_at(3, &STARTUP)?, SUCCESS);
+        assert_eq!(tpm.locality(), 3);
+        assert_eq!(emulator.active_locality(), Some(3));
+        assert_eq!(
+            emulator.commands(),
+            [(0, STARTUP.to_vec()), (3, STARTUP.to_vec())]
+        );
+
+        let err = tpm.execute_command_at(5, &STARTUP).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpmError>(),
+            Some(TpmError::InvalidLocality(5))
+        ));
+        let err = tpm.set_locality(5).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpmError>(),
+            Some(TpmError::InvalidLocality(5))
+        ));
+
+        // Registers of inactive localities read as all ones.
+        tpm.set_locality(1)?;
+        assert_eq!(tpm.read_status()?.raw_value(), !0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_at_locality() -> Result<()> {
+        let emulator = emulator(EmulatorParams::default());
+        let tpm = SpiDriver::new(emulator.clone());
+        tpm.init()?;
+        let response = run_at_locality(&tpm, 2, || {
+            assert_eq!(emulator.active_locality(), Some(2));
+            tpm.execute_command(&STARTUP)
+        })?;
+        assert_eq!(response, SUCCESS);
+        assert_eq!(emulator.active_locality(), Some(0));
+
+        // The locality is given up even if the command fails.
+        let err = run_at_locality(&tpm, 3, || -> Result<()> { bail!("failed") }).unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(emulator.active_locality(), Some(0));
+
+        run_at_locality(&tpm, 0, || tpm.execute_command(&STARTUP))?;
+        assert_eq!(
+            emulator.commands(),
+            [(2, STARTUP.to_vec()), (0, STARTUP.to_vec())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_interrupt() -> Result<()> {
+        let params = EmulatorParams {
+            execution_polls: 20,
+            ..Default::default()
+        };
+        // Without an interrupt line, the status is polled until the command completes.
+        let polled = emulator(params.clone());
+        let tpm = SpiDriver::new(polled.clone());
+        tpm.init()?;
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+        assert_eq!(polled.status_reads(), 23);
+
+        // With one, the driver waits for the interrupt instead.
+        let interrupting = emulator(params);
+        let irq = TpmIrq::from_pin(Rc::new(interrupting.irq_pin()), None);
+        let tpm = SpiDriver::new(interrupting.clone()).with_interrupt(irq);
+        tpm.init()?;
+        let mut enable = [0u8; 4];
+        tpm.read_register(Register::INT_ENABLE, &mut enable)?;
+        assert_eq!(
+            u32::from_le_bytes(enable),
+            TpmInterrupt::GLOBAL_ENABLE
+                | TpmInterrupt::LOW_LEVEL
+                | TpmInterrupt::DATA_AVAIL
+                | TpmInterrupt::COMMAND_READY
+        );
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+        assert!(interrupting.status_reads() <= 5);
+
+        // The interrupt line of an idle TPM never asserts, so the driver falls back to polling.
+        let idle = emulator(EmulatorParams::default());
+        let irq = TpmIrq::from_pin(Rc::new(idle.irq_pin()), None);
+        let tpm = SpiDriver::new(emulator(EmulatorParams::default())).with_interrupt(irq);
+        tpm.init()?;
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+        Ok(())
+    }
+
+    #[test]
+    fn test_i2c_emulator() -> Result<()> {
+        let (cmd, response) = long_exchange();
+        let params = EmulatorParams::default();
+        let addr = params.i2c_addr;
+        let emulator = Rc::new(TpmEmulator::new(
+            params,
+            Box::new(CannedResponses::new().add(&cmd, &response)),
+        ));
+        let tpm = I2cDriver::new(emulator.clone(), addr);
+        tpm.init()?;
+        assert_eq!(tpm.execute_command_at(2, &cmd)?, response);
+        assert_eq!(emulator.commands(), [(2, cmd)]);
+
+        // Register reads are retried after bus errors.
+        emulator.inject(Fault::BusErrors(1));
+        let mut did_vid = [0u8; 4];
+        tpm.read_register(Register::DID_VID, &mut did_vid)?;
+        assert_eq!(u32::from_le_bytes(did_vid), emulator.params().did_vid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_glitches() -> Result<()> {
+        let emulator = emulator(EmulatorParams::default());
+        let tpm = SpiDriver::new(emulator.clone());
+        tpm.init()?;
+        emulator.inject(Fault::StatusGlitches(2));
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+
+        // Bus errors other than on I2C register reads are not retried.
+        emulator.inject(Fault::BusErrors(1));
+        assert!(tpm.execute_command(&STARTUP).is_err());
+        assert_eq!(tpm.execute_command(&STARTUP)?, SUCCESS);
+        Ok(())
+    }
+}
@@ -0,0 +1,675 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A model of the TPM TIS register interface, as accessed over SPI or the Google I2C protocol.
+//!
+//! `TpmEmulator` implements both `io::spi::Target` and `io::i2c::Bus`, so that `SpiDriver` and
+//! `I2cDriver` can be exercised without hardware.  The emulator follows the TIS state machine
+//! (idle, ready, command reception, execution and completion), enforces localities and burst
+//! counts, and delegates the execution of commands to a `CommandHandler`, such as a table of
+//! `CannedResponses`.  SPI wait states, the duration of command execution and bus faults can be
+//! configured to test how the drivers cope with them.  Like the SPI flash emulator, durations
+//! are measured in status register reads rather than elapsed time.  The interrupt line of the
+//! TPM is available as a `GpioPin`, reads of which also count towards the duration.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use thiserror::Error;
+
+use crate::impl_serializable_error;
+use crate::io::gpio::{GpioError, GpioPin, PinMode, PullMode};
+use crate::io::i2c;
+use crate::io::spi::{
+    AssertChipSelect, MaxSizes, SpiError, Target, TargetChipDeassert, Transfer, TransferMode,
+};
+use crate::tpm::access::TpmAccess;
+use crate::tpm::interrupt::TpmInterrupt;
+use crate::tpm::status::TpmStatus;
+use crate::tpm::{I2cDriver, Register, SpiDriver, MAX_LOCALITY};
+use crate::transport::TransportError;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum EmulatorError {
+    #[error("Injected bus error")]
+    BusError,
+    #[error("Address {0:#x} is not a TPM register")]
+    BadAddress(u32),
+    #[error("Transfer of {0} bytes exceeds the burst count {1}")]
+    BurstOverflow(usize, usize),
+    #[error("No TPM at I2C address {0:#x}")]
+    Nack(u8),
+    #[error("Unsupported I2C transaction")]
+    BadTransaction,
+}
+impl_serializable_error!(EmulatorError);
+
+/// Executes the TPM commands received by a `TpmEmulator`.
+pub trait CommandHandler {
+    /// Returns the response to the complete TPM `command`, received at `locality`.
+    fn execute(&mut self, locality: u8, command: &[u8]) -> Vec<u8>;
+}
+
+/// Answers commands from a table of canned responses, and any other command with
+/// `TPM_RC_COMMAND_CODE`.
+#[derive(Clone, Debug, Default)]
+pub struct CannedResponses {
+    responses: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl CannedResponses {
+    /// Response to commands missing from the table.
+    const UNKNOWN_COMMAND: [u8; 10] = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x43];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `command` with `response`.
+    pub fn add(mut self, command: &[u8], response: &[u8]) -> Self {
+        self.responses.insert(command.to_vec(), response.to_vec());
+        self
+    }
+}
+
+impl CommandHandler for CannedResponses {
+    fn execute(&mut self, _locality: u8, command: &[u8]) -> Vec<u8> {
+        self.responses
+            .get(command)
+            .cloned()
+            .unwrap_or_else(|| Self::UNKNOWN_COMMAND.to_vec())
+    }
+}
+
+/// Parameters of the emulated TPM.
+#[derive(Clone, Debug)]
+pub struct EmulatorParams {
+    /// Number of wait states the SPI interface inserts before the data of each transaction.
+    pub wait_states: u32,
+    /// Largest burst count reported in the status register.
+    pub burst_count: usize,
+    /// Number of status reads after TPM_GO during which the command is still executing.
+    pub execution_polls: u32,
+    /// Value of the DID_VID register.
+    pub did_vid: u32,
+    /// Value of the RID register.
+    pub rid: u8,
+    /// I2C address of the emulated TPM.
+    pub i2c_addr: u8,
+}
+
+impl Default for EmulatorParams {
+    fn default() -> Self {
+        EmulatorParams {
+            wait_states: 0,
+            burst_count: 32,
+            execution_polls: 1,
+            did_vid: 0x504a_6666,
+            rid: 0,
+            i2c_addr: 0x50,
+        }
+    }
+}
+
+/// Faults which can be injected into the emulated TPM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The next `n` bus transactions fail.
+    BusErrors(u32),
+    /// The next `n` status register reads return all ones, as a TPM which does not respond
+    /// would.
+    StatusGlitches(u32),
+}
+
+/// States of the TIS state machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TisState {
+    Idle,
+    Ready,
+    Reception,
+    Execution,
+    Completion,
+}
+
+/// Progress of the SPI transaction during which chip select is asserted.
+#[derive(Default)]
+struct SpiFrame {
+    header: Vec<u8>,
+    /// The register access described by the header, once it has been received.
+    access: Option<(u8, Register, bool, usize)>,
+    /// Remaining wait states before the data phase.
+    wait: u32,
+    /// Data read from the register, or written to it so far.
+    data: Vec<u8>,
+    pos: usize,
+}
+
+/// The mutable state of the emulated TPM.
+struct State {
+    tis: TisState,
+    active_locality: Option<u8>,
+    command: Vec<u8>,
+    response: Vec<u8>,
+    response_pos: usize,
+    /// Remaining status reads until the current command completes.
+    execution_polls: u32,
+    /// Number of status register reads so far.
+    status_reads: usize,
+    int_enable: u32,
+    int_status: u32,
+    bus_errors: u32,
+    status_glitches: u32,
+    /// The commands received so far, along with their locality.
+    commands: Vec<(u8, Vec<u8>)>,
+    spi: SpiFrame,
+    /// Register selected by an I2C write without data.
+    i2c_register: u8,
+}
+
+/// An emulated TPM with TIS register interface.
+pub struct TpmEmulator {
+    params: EmulatorParams,
+    handler: RefCell<Box<dyn CommandHandler>>,
+    state: RefCell<State>,
+    transfer_mode: Cell<TransferMode>,
+    bits_per_word: Cell<u32>,
+    max_speed: Cell<u32>,
+    cs_asserted: Cell<usize>,
+}
+
+impl TpmEmulator {
+    /// Value of the `INTF_CAPABILITY` register: data available, command ready and locality
+    /// change interrupts, with low level interrupts only.
+    const INTF_CAPABILITY: u32 = 0x0000_0095;
+
+    /// Creates an idle TPM described by `params`, which answers commands using `handler`.
+    pub fn new(params: EmulatorParams, handler: Box<dyn CommandHandler>) -> Self {
+        TpmEmulator {
+            params,
+            handler: RefCell::new(handler),
+            state: RefCell::new(State {
+                tis: TisState::Idle,
+                active_locality: None,
+                command: Vec::new(),
+                response: Vec::new(),
+                response_pos: 0,
+                execution_polls: 0,
+                status_reads: 0,
+                int_enable: 0,
+                int_status: 0,
+                bus_errors: 0,
+                status_glitches: 0,
+                commands: Vec::new(),
+                spi: SpiFrame::default(),
+                i2c_register: 0,
+            }),
+            transfer_mode: Cell::new(TransferMode::Mode0),
+            bits_per_word: Cell::new(8),
+            max_speed: Cell::new(1_000_000),
+            cs_asserted: Cell::new(0),
+        }
+    }
+
+    /// The parameters of the emulated TPM.
+    pub fn params(&self) -> &EmulatorParams {
+        &self.params
+    }
+
+    /// Injects `fault` into subsequent transactions.
+    pub fn inject(&self, fault: Fault) {
+        let mut state = self.state.borrow_mut();
+        match fault {
+            Fault::BusErrors(n) => state.bus_errors = n,
+            Fault::StatusGlitches(n) => state.status_glitches = n,
+        }
+    }
+
+    /// Returns the commands received so far, along with the locality each was received at.
+    pub fn commands(&self) -> Vec<(u8, Vec<u8>)> {
+        self.state.borrow().commands.clone()
+    }
+
+    /// Returns the currently active locality.
+    pub fn active_locality(&self) -> Option<u8> {
+        self.state.borrow().active_locality
+    }
+
+    /// Returns the number of status register reads so far.
+    pub fn status_reads(&self) -> usize {
+        self.state.borrow().status_reads
+    }
+
+    /// Whether the interrupt line is asserted.
+    pub fn irq_asserted(&self) -> bool {
+        Self::interrupt_pending(&self.state.borrow())
+    }
+
+    /// Returns the interrupt line of the TPM.
+    pub fn irq_pin(self: &Rc<Self>) -> IrqPin {
+        IrqPin {
+            emulator: Rc::clone(self),
+        }
+    }
+
+    fn interrupt_pending(state: &State) -> bool {
+        state.int_enable & TpmInterrupt::GLOBAL_ENABLE != 0
+            && state.int_status & state.int_enable != 0
+    }
+
+    /// Fails the transaction if a bus error has been injected.
+    fn check_bus_error(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if state.bus_errors > 0 {
+            state.bus_errors -= 1;
+            state.spi = SpiFrame::default();
+            return Err(EmulatorError::BusError.into());
+        }
+        Ok(())
+    }
+
+    fn raise_interrupt(state: &mut State, interrupt: u32) {
+        state.int_status |= interrupt & state.int_enable;
+    }
+
+    fn command_complete(state: &State) -> bool {
+        state.command.len() >= 6
+            && state.command.len()
+                >= u32::from_be_bytes(state.command[2..6].try_into().unwrap()) as usize
+    }
+
+    /// Advances the execution of the current command by one poll.
+    fn advance_execution(state: &mut State) {
+        if state.tis == TisState::Execution {
+            if state.execution_polls > 0 {
+                state.execution_polls -= 1;
+            } else {
+                state.tis = TisState::Completion;
+                Self::raise_interrupt(state, TpmInterrupt::DATA_AVAIL);
+            }
+        }
+    }
+
+    /// Computes the status register, advancing the execution of the current command.
+    fn status(&self, state: &mut State) -> u32 {
+        state.status_reads += 1;
+        Self::advance_execution(state);
+        let burst = self.params.burst_count;
+        let mut sts = 0x80;
+        match state.tis {
+            TisState::Idle | TisState::Execution => (),
+            TisState::Ready => sts |= TpmStatus::CMD_READY | (burst << 8) as u32,
+            TisState::Reception => {
+                sts |= (burst << 8) as u32;
+                if !Self::command_complete(state) {
+                    // The `expect` bit.
+                    sts |= 0x08;
+                }
+            }
+            TisState::Completion => {
+                let available = (state.response.len() - state.response_pos).min(burst);
+                if available > 0 {
+                    // The `dataAvail` bit.
+                    sts |= 0x10 | (available << 8) as u32;
+                }
+            }
+        }
+        sts
+    }
+
+    /// Reads `len` bytes from `register` at `locality`.
+    fn read_register(&self, locality: u8, register: Register, len: usize) -> Result<Vec<u8>> {
+        let mut state = self.state.borrow_mut();
+        let active = state.active_locality == Some(locality);
+        let value = match register {
+            Register::ACCESS => {
+                let mut access = TpmAccess::VALID;
+                if active {
+                    access |= TpmAccess::ACTIVE_LOCALITY;
+                }
+                access.bits() as u32
+            }
+            // Other localities read as all ones, like a bus without a device.
+            _ if !active => return Ok(vec![0xff; len]),
+            Register::STS if state.status_glitches > 0 => {
+                state.status_glitches -= 1;
+                return Ok(vec![0xff; len]);
+            }
+            Register::STS => self.status(&mut state),
+            Register::DATA_FIFO | Register::XDATA_FIFO => {
+                if state.tis != TisState::Completion {
+                    return Ok(vec![0xff; len]);
+                }
+                let pos = state.response_pos;
+                let available = (state.response.len() - pos).min(self.params.burst_count);
+                if len > available {
+                    return Err(EmulatorError::BurstOverflow(len, available).into());
+                }
+                state.response_pos += len;
+                return Ok(state.response[pos..pos + len].to_vec());
+            }
+            Register::INT_ENABLE => state.int_enable,
+            Register::INT_STATUS => state.int_status,
+            Register::INTF_CAPABILITY => Self::INTF_CAPABILITY,
+            Register::DID_VID => self.params.did_vid,
+            Register::RID => self.params.rid as u32,
+            Register::INT_VECTOR | Register::INTERFACE_ID => 0,
+        };
+        let mut data = value.to_le_bytes().to_vec();
+        data.resize(len, 0);
+        Ok(data)
+    }
+
+    /// Writes `data` to `register` at `locality`.
+    fn write_register(&self, locality: u8, register: Register, data: &[u8]) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if register == Register::ACCESS {
+            let access = TpmAccess::from_bits_truncate(data.first().copied().unwrap_or(0));
+            if access.contains(TpmAccess::ACTIVE_LOCALITY)
+                && state.active_locality == Some(locality)
+            {
+                state.active_locality = None;
+                state.tis = TisState::Idle;
+                Self::raise_interrupt(&mut state, TpmInterrupt::LOCALITY_CHANGE);
+            }
+            if access.contains(TpmAccess::REQUEST_USE) && state.active_locality.is_none() {
+                state.active_locality = Some(locality);
+                Self::raise_interrupt(&mut state, TpmInterrupt::LOCALITY_CHANGE);
+            }
+            return Ok(());
+        }
+        if state.active_locality != Some(locality) {
+            // Writes from other localities are ignored.
+            return Ok(());
+        }
+        let mut value = [0u8; 4];
+        let n = data.len().min(4);
+        value[..n].copy_from_slice(&data[..n]);
+        let value = u32::from_le_bytes(value);
+        match register {
+            Register::STS => {
+                if value & TpmStatus::CMD_READY != 0 {
+                    state.tis = TisState::Ready;
+                    state.command.clear();
+                    state.response.clear();
+                    state.response_pos = 0;
+                    Self::raise_interrupt(&mut state, TpmInterrupt::COMMAND_READY);
+                } else if value & TpmStatus::TPM_GO != 0
+                    && state.tis == TisState::Reception
+                    && Self::command_complete(&state)
+                {
+                    let command = std::mem::take(&mut state.command);
+                    state.response = self.handler.borrow_mut().execute(locality, &command);
+                    state.commands.push((locality, command));
+                    state.response_pos = 0;
+                    state.tis = TisState::Execution;
+                    state.execution_polls = self.params.execution_polls;
+                }
+            }
+            Register::DATA_FIFO | Register::XDATA_FIFO => {
+                if matches!(state.tis, TisState::Ready | TisState::Reception) {
+                    if data.len() > self.params.burst_count {
+                        return Err(EmulatorError::BurstOverflow(
+                            data.len(),
+                            self.params.burst_count,
+                        )
+                        .into());
+                    }
+                    state.command.extend_from_slice(data);
+                    state.tis = TisState::Reception;
+                }
+            }
+            Register::INT_ENABLE => state.int_enable = value,
+            // Interrupt status bits are cleared by writing ones.
+            Register::INT_STATUS => state.int_status &= !value,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Decodes the register and locality from a SPI address.
+    fn spi_register(address: u32) -> Result<(u8, Register)> {
+        let locality = ((address >> 12) & 0xf) as u8;
+        let offset = (address & 0xfff) as u16;
+        let register = Register::value_variants()
+            .iter()
+            .find(|&&r| SpiDriver::addr(r) == offset);
+        match register {
+            Some(&register) if address & 0xff_0000 == 0xd4_0000 && locality <= MAX_LOCALITY => {
+                Ok((locality, register))
+            }
+            _ => Err(EmulatorError::BadAddress(address).into()),
+        }
+    }
+
+    /// Decodes the register and locality from an I2C register address.
+    fn i2c_register(address: u8) -> Result<(u8, Register)> {
+        let locality = address >> 4;
+        let register = Register::value_variants()
+            .iter()
+            .find(|&&r| I2cDriver::addr(r) == Some(address & 0xf));
+        match register {
+            Some(&register) if locality <= MAX_LOCALITY => Ok((locality, register)),
+            _ => Err(EmulatorError::BadAddress(address as u32).into()),
+        }
+    }
+
+    /// Clocks one byte of a SPI transaction, returning the byte driven by the TPM.
+    fn clock_spi(&self, mosi: u8) -> Result<u8> {
+        let mut state = self.state.borrow_mut();
+        let frame = &mut state.spi;
+        let Some((locality, register, is_read, len)) = frame.access else {
+            frame.header.push(mosi);
+            if frame.header.len() < 4 {
+                return Ok(0);
+            }
+            let header = &frame.header;
+            let address = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+            let (locality, register) = Self::spi_register(address)?;
+            let is_read = header[0] & 0x80 != 0;
+            let len = (header[0] & 0x3f) as usize + 1;
+            frame.access = Some((locality, register, is_read, len));
+            frame.wait = self.params.wait_states;
+            if frame.wait > 0 {
+                return Ok(0);
+            }
+            drop(state);
+            self.start_spi_data()?;
+            return Ok(1);
+        };
+        if frame.wait > 0 {
+            frame.wait -= 1;
+            if frame.wait > 0 {
+                return Ok(0);
+            }
+            drop(state);
+            self.start_spi_data()?;
+            return Ok(1);
+        }
+        if is_read {
+            let byte = frame.data.get(frame.pos).copied().unwrap_or(0xff);
+            frame.pos += 1;
+            return Ok(byte);
+        }
+        frame.data.push(mosi);
+        if frame.data.len() == len {
+            let data = std::mem::take(&mut frame.data);
+            drop(state);
+            self.write_register(locality, register, &data)?;
+        }
+        Ok(0xff)
+    }
+
+    /// Begins the data phase of a SPI transaction, reading the register if requested.
+    fn start_spi_data(&self) -> Result<()> {
+        let access = self.state.borrow().spi.access;
+        if let Some((locality, register, true, len)) = access {
+            let data = self.read_register(locality, register, len)?;
+            self.state.borrow_mut().spi.data = data;
+        }
+        Ok(())
+    }
+
+    fn end_spi_frame(&self) {
+        self.state.borrow_mut().spi = SpiFrame::default();
+    }
+}
+
+impl Target for TpmEmulator {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        Ok(self.transfer_mode.get())
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.transfer_mode.set(mode);
+        Ok(())
+    }
+
+    fn get_bits_per_word(&self) -> Result<u32> {
+        Ok(self.bits_per_word.get())
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        match bits_per_word {
+            8 => {
+                self.bits_per_word.set(bits_per_word);
+                Ok(())
+            }
+            _ => Err(SpiError::InvalidWordSize(bits_per_word).into()),
+        }
+    }
+
+    fn get_max_speed(&self) -> Result<u32> {
+        Ok(self.max_speed.get())
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        self.max_speed.set(max_speed);
+        Ok(())
+    }
+
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        Ok(usize::MAX)
+    }
+
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        Ok(MaxSizes {
+            read: 4096,
+            write: 4096,
+        })
+    }
+
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        self.check_bus_error()?;
+        let result = transaction
+            .iter_mut()
+            .try_for_each(|transfer| match transfer {
+                Transfer::Read(buf) => buf.iter_mut().try_for_each(|byte| {
+                    *byte = self.clock_spi(0xff)?;
+                    Ok(())
+                }),
+                Transfer::Write(buf) => buf.iter().try_for_each(|&byte| {
+                    self.clock_spi(byte)?;
+                    Ok(())
+                }),
+                Transfer::Both(wbuf, rbuf) => {
+                    if wbuf.len() != rbuf.len() {
+                        return Err(SpiError::MismatchedDataLength(wbuf.len(), rbuf.len()).into());
+                    }
+                    wbuf.iter().zip(rbuf.iter_mut()).try_for_each(|(&w, r)| {
+                        *r = self.clock_spi(w)?;
+                        Ok(())
+                    })
+                }
+            });
+        if result.is_err() || self.cs_asserted.get() == 0 {
+            self.end_spi_frame();
+        }
+        result
+    }
+
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        self.cs_asserted.set(self.cs_asserted.get() + 1);
+        Ok(AssertChipSelect::new(self))
+    }
+}
+
+impl TargetChipDeassert for TpmEmulator {
+    fn deassert_cs(&self) {
+        let count = self.cs_asserted.get() - 1;
+        self.cs_asserted.set(count);
+        if count == 0 {
+            self.end_spi_frame();
+        }
+    }
+}
+
+/// The active low interrupt line (`PIRQ#`) of a `TpmEmulator`.
+pub struct IrqPin {
+    emulator: Rc<TpmEmulator>,
+}
+
+impl GpioPin for IrqPin {
+    /// Reads the interrupt line.  Like a status register read, this advances the execution of
+    /// the current command.
+    fn read(&self) -> Result<bool> {
+        let mut state = self.emulator.state.borrow_mut();
+        TpmEmulator::advance_execution(&mut state);
+        Ok(!TpmEmulator::interrupt_pending(&state))
+    }
+
+    fn write(&self, _value: bool) -> Result<()> {
+        bail!(TransportError::UnsupportedOperation)
+    }
+
+    fn set_mode(&self, mode: PinMode) -> Result<()> {
+        match mode {
+            PinMode::Input => Ok(()),
+            _ => bail!(GpioError::UnsupportedPinMode(mode)),
+        }
+    }
+
+    fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
+        match mode {
+            PullMode::None | PullMode::PullUp => Ok(()),
+            _ => bail!(GpioError::UnsupportedPullMode(mode)),
+        }
+    }
+}
+
+impl i2c::Bus for TpmEmulator {
+    fn get_max_speed(&self) -> Result<u32> {
+        Ok(self.max_speed.get())
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        self.max_speed.set(max_speed);
+        Ok(())
+    }
+
+    fn run_transaction(&self, addr: u8, transaction: &mut [i2c::Transfer]) -> Result<()> {
+        self.check_bus_error()?;
+        if addr != self.params.i2c_addr {
+            return Err(EmulatorError::Nack(addr).into());
+        }
+        match transaction {
+            [i2c::Transfer::Write([reg])] => {
+                self.state.borrow_mut().i2c_register = *reg;
+            }
+            [i2c::Transfer::Write([reg, data @ ..])] => {
+                let (locality, register) = Self::i2c_register(*reg)?;
+                self.write_register(locality, register, data)?;
+            }
+            [i2c::Transfer::Write([reg]), i2c::Transfer::Read(buf)] => {
+                let (locality, register) = Self::i2c_register(*reg)?;
+                buf.copy_from_slice(&self.read_register(locality, register, buf.len())?);
+            }
+            [i2c::Transfer::Read(buf)] => {
+                let reg = self.state.borrow().i2c_register;
+                let (locality, register) = Self::i2c_register(reg)?;
+                buf.copy_from_slice(&self.read_register(locality, register, buf.len())?);
+            }
+            _ => return Err(EmulatorError::BadTransaction.into()),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::app::TransportWrapper;
+use crate::io::gpio::{Edge, GpioMonitoring, GpioPin, PinMode};
+use crate::transport::Capability;
+
+/// Bits of the `INT_ENABLE` and `INT_STATUS` registers.
+pub struct TpmInterrupt;
+
+impl TpmInterrupt {
+    pub const DATA_AVAIL: u32 = 1 << 0;
+    pub const STS_VALID: u32 = 1 << 1;
+    pub const LOCALITY_CHANGE: u32 = 1 << 2;
+    pub const COMMAND_READY: u32 = 1 << 7;
+    /// `typePolarity` field of `INT_ENABLE` selecting a low level interrupt.
+    pub const LOW_LEVEL: u32 = 1 << 3;
+    pub const GLOBAL_ENABLE: u32 = 1 << 31;
+}
+
+/// The interrupt line of a TPM, which allows waiting for the TPM without repeatedly reading its
+/// status register over the bus.
+pub struct TpmIrq {
+    pin: Rc<dyn GpioPin>,
+    monitoring: Option<Rc<dyn GpioMonitoring>>,
+    active_low: bool,
+}
+
+impl TpmIrq {
+    /// Interval between checks of the interrupt line.
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// Uses the GPIO `pin` of `transport` as an active low interrupt line (the `PIRQ#` signal of
+    /// the SPI interface).  Edge events are used to detect the interrupt if the transport
+    /// supports GPIO monitoring, otherwise the level of the pin is read.
+    pub fn new(transport: &TransportWrapper, pin: &str) -> Result<Self> {
+        let pin = transport.gpio_pin(pin)?;
+        pin.set_mode(PinMode::Input)?;
+        let monitoring = if transport
+            .capabilities()?
+            .request(Capability::GPIO_MONITORING)
+            .ok()
+            .is_ok()
+        {
+            Some(transport.gpio_monitoring()?)
+        } else {
+            None
+        };
+        Ok(Self::from_pin(pin, monitoring))
+    }
+
+    /// Uses the input `pin` as an active low interrupt line, detecting the interrupt through
+    /// `monitoring` if given, otherwise by reading the level of the pin.
+    pub fn from_pin(pin: Rc<dyn GpioPin>, monitoring: Option<Rc<dyn GpioMonitoring>>) -> Self {
+        Self {
+            pin,
+            monitoring,
+            active_low: true,
+        }
+    }
+
+    /// The `typePolarity` to be configured in `INT_ENABLE`.
+    pub fn polarity(&self) -> u32 {
+        if self.active_low {
+            TpmInterrupt::LOW_LEVEL
+        } else {
+            0
+        }
+    }
+
+    /// Waits up to `timeout` for the interrupt line to be asserted, returning whether it was.
+    /// As TPM interrupts are level triggered, an interrupt which was asserted before this call
+    /// is also detected.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let pins = [&*self.pin];
+        if let Some(monitoring) = &self.monitoring {
+            let asserting_edge = if self.active_low {
+                Edge::Falling
+            } else {
+                Edge::Rising
+            };
+            let start = monitoring.monitoring_start(&pins)?;
+            let mut asserted = start.initial_levels[0] != self.active_low;
+            while !asserted && Instant::now() <= deadline {
+                thread::sleep(Self::POLL_INTERVAL);
+                let response = monitoring.monitoring_read(&pins, true)?;
+                asserted = response.events.iter().any(|e| e.edge == asserting_edge);
+            }
+            monitoring.monitoring_read(&pins, false)?;
+            return Ok(asserted);
+        }
+        loop {
+            if self.pin.read()? != self.active_low {
+                return Ok(true);
+            }
+            if Instant::now() > deadline {
+                return Ok(false);
+            }
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+}
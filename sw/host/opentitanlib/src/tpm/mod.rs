@@ -3,7 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod access;
+pub mod commands;
 mod driver;
+pub mod emulator;
+mod interrupt;
 mod status;
 
-pub use driver::{Driver, I2cDriver, Register, SpiDriver};
+pub use driver::{run_at_locality, Driver, I2cDriver, Register, SpiDriver, MAX_LOCALITY};
+pub use interrupt::{TpmInterrupt, TpmIrq};
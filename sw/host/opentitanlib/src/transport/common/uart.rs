@@ -119,6 +119,15 @@ impl Uart for SerialPortUart {
         Ok(())
     }
 
+    fn set_break(&self, enable: bool) -> Result<()> {
+        let port = self.port.borrow();
+        match enable {
+            true => port.set_break(),
+            false => port.clear_break(),
+        }
+        .context("setting UART break")
+    }
+
     /// Reads UART receive data into `buf`, returning the number of bytes read.
     /// The `timeout` may be used to specify a duration to wait for data.
     fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
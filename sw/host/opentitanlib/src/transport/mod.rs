@@ -62,7 +62,7 @@ pub struct Capabilities {
 impl Capabilities {
     /// Create a new Capabilities object representing a provider of
     /// capabilities specified by `cap`.
-    fn new(cap: Capability) -> Self {
+    pub fn new(cap: Capability) -> Self {
         Self { capabilities: cap }
     }
 
@@ -0,0 +1,292 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binary-safe captures of console traffic, and their replay.
+//!
+//! A capture file starts with an 8 byte magic value, followed by one record per chunk of data
+//! read from or written to the console.  Each record consists of the time since the start of
+//! the capture in microseconds (64 bit little endian), the direction (0 for data received from
+//! the device, 1 for data sent to it), the length of the data (32 bit little endian) and the
+//! data itself.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::impl_serializable_error;
+use crate::io::console::ConsoleDevice;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum CaptureError {
+    #[error("Not a console capture file")]
+    BadMagic,
+    #[error("Capture file is truncated")]
+    Truncated,
+    #[error("Invalid direction {0} in capture file")]
+    BadDirection(u8),
+    #[error("End of capture")]
+    EndOfCapture,
+}
+impl_serializable_error!(CaptureError);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// Data received from the device.
+    Rx,
+    /// Data sent to the device.
+    Tx,
+}
+
+/// A chunk of console traffic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the start of the capture.
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+const MAGIC: &[u8; 8] = b"OTCAPT\x00\x01";
+
+/// Writes console traffic to a capture file.
+pub struct CaptureWriter {
+    out: Box<dyn Write>,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    pub fn new(mut out: Box<dyn Write>) -> Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::new(Box::new(File::create(path)?))
+    }
+
+    /// Records `data` as transferred in `direction` now.
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> Result<()> {
+        let time = self.start.elapsed().as_micros() as u64;
+        self.out.write_all(&time.to_le_bytes())?;
+        self.out.write_all(&[direction as u8])?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(data)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads all records of a capture file.
+pub fn read_capture(input: &mut dyn Read) -> Result<Vec<CaptureRecord>> {
+    fn read_exact(input: &mut dyn Read, buf: &mut [u8]) -> Result<()> {
+        input.read_exact(buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => CaptureError::Truncated.into(),
+            _ => anyhow::Error::from(e),
+        })
+    }
+
+    let mut magic = [0u8; 8];
+    input
+        .read_exact(&mut magic)
+        .map_err(|_| CaptureError::BadMagic)?;
+    if &magic != MAGIC {
+        return Err(CaptureError::BadMagic.into());
+    }
+    let mut records = Vec::new();
+    loop {
+        let mut time = [0u8; 8];
+        match input.read(&mut time[..1])? {
+            0 => return Ok(records),
+            _ => read_exact(input, &mut time[1..])?,
+        }
+        let mut header = [0u8; 5];
+        read_exact(input, &mut header)?;
+        let direction = match header[0] {
+            0 => Direction::Rx,
+            1 => Direction::Tx,
+            d => return Err(CaptureError::BadDirection(d).into()),
+        };
+        let len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+        // Grow the buffer as data arrives, rather than trusting the length of a corrupt file.
+        let mut data = Vec::new();
+        (&mut *input).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len {
+            return Err(CaptureError::Truncated.into());
+        }
+        records.push(CaptureRecord {
+            time: Duration::from_micros(u64::from_le_bytes(time)),
+            direction,
+            data,
+        });
+    }
+}
+
+pub fn load_capture(path: &Path) -> Result<Vec<CaptureRecord>> {
+    read_capture(&mut BufReader::new(File::open(path)?))
+}
+
+/// A `ConsoleDevice` producing the received data of a capture.  Data written to it is
+/// discarded.  Once all data has been read, reads fail with `CaptureError::EndOfCapture`.
+pub struct Replay {
+    records: RefCell<VecDeque<CaptureRecord>>,
+    /// Whether data is delayed until the time it was captured at.
+    realtime: bool,
+    start: Cell<Option<Instant>>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<CaptureRecord>, realtime: bool) -> Self {
+        Self {
+            records: RefCell::new(
+                records
+                    .into_iter()
+                    .filter(|r| r.direction == Direction::Rx)
+                    .collect(),
+            ),
+            realtime,
+            start: Cell::new(None),
+        }
+    }
+}
+
+impl ConsoleDevice for Replay {
+    fn console_read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let start = self.start.get().unwrap_or_else(Instant::now);
+        self.start.set(Some(start));
+        let mut records = self.records.borrow_mut();
+        let Some(record) = records.front_mut() else {
+            return Err(CaptureError::EndOfCapture.into());
+        };
+        if self.realtime {
+            let due = start + record.time;
+            let now = Instant::now();
+            if due > now + timeout {
+                std::thread::sleep(timeout);
+                return Ok(0);
+            }
+            std::thread::sleep(due.saturating_duration_since(now));
+        }
+        let len = buf.len().min(record.data.len());
+        buf[..len].copy_from_slice(&record.data[..len]);
+        record.data.drain(..len);
+        if record.data.is_empty() {
+            records.pop_front();
+        }
+        Ok(len)
+    }
+
+    fn console_write(&self, _buf: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uart::console::{ExitStatus, UartConsole};
+    use regex::Regex;
+    use std::rc::Rc;
+
+    /// Shares the written bytes with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(chunks: &[(Direction, &[u8])]) -> Result<Vec<u8>> {
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(Box::new(buffer.clone()))?;
+        for (direction, data) in chunks {
+            writer.record(*direction, data)?;
+        }
+        let data = buffer.0.borrow().clone();
+        Ok(data)
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let data = capture(&[
+            (Direction::Rx, b"boot\x00\xff\r\n"),
+            (Direction::Tx, b"help\r"),
+            (Direction::Rx, b""),
+        ])?;
+        let records = read_capture(&mut data.as_slice())?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].data, b"boot\x00\xff\r\n");
+        assert_eq!(records[1].direction, Direction::Tx);
+        assert!(records[1].time >= records[0].time);
+
+        let err = read_capture(&mut &data[..data.len() - 3]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CaptureError>(),
+            Some(CaptureError::Truncated)
+        ));
+        let err = read_capture(&mut &data[1..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CaptureError>(),
+            Some(CaptureError::BadMagic)
+        ));
+
+        // A corrupt length is not trusted beyond the data actually present.
+        let mut data = capture(&[(Direction::Rx, b"abc")])?;
+        data[17..21].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_capture(&mut data.as_slice()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CaptureError>(),
+            Some(CaptureError::Truncated)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let data = capture(&[
+            (Direction::Rx, b"ROM: 1234\r\n"),
+            (Direction::Tx, b"ignored"),
+            (Direction::Rx, b"PASS!\r\n"),
+        ])?;
+        let records = read_capture(&mut data.as_slice())?;
+
+        let mut console = UartConsole {
+            exit_success: Some(Regex::new(r"PASS!")?),
+            ..Default::default()
+        };
+        let replay = Replay::new(records.clone(), true);
+        assert_eq!(
+            console.interact(&replay, None, None)?,
+            ExitStatus::ExitSuccess
+        );
+
+        // Without a match, the console stops at the end of the capture.
+        let mut console = UartConsole {
+            exit_success: Some(Regex::new(r"FAIL")?),
+            ..Default::default()
+        };
+        let replay = Replay::new(records, false);
+        let err = console.interact(&replay, None, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CaptureError>(),
+            Some(CaptureError::EndOfCapture)
+        ));
+        assert_eq!(console.buffer, "ROM: 1234\r\nPASS!\r\n");
+        Ok(())
+    }
+}
@@ -12,7 +12,9 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant, SystemTime};
 
 use crate::io::console::{ConsoleDevice, ConsoleError};
+use crate::uart::capture::{CaptureWriter, Direction};
 use crate::util::file;
+use crate::util::status::StatusAnnotator;
 
 #[derive(Default)]
 pub struct UartConsole {
@@ -24,6 +26,10 @@ pub struct UartConsole {
     pub timestamp: bool,
     pub buffer: String,
     pub newline: bool,
+    /// Explains the statuses in each line of output.
+    pub annotator: Option<StatusAnnotator>,
+    /// Records all traffic of the console, unlike `logfile` which receives only its output.
+    pub capture: Option<CaptureWriter>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -193,6 +199,13 @@ impl UartConsole {
             stdout
                 .as_mut()
                 .map_or(Ok(()), |out| out.write_all(&buf[i..i + 1]))?;
+            if let Some(annotator) = self.annotator.as_mut() {
+                for note in annotator.feed(buf[i]) {
+                    stdout
+                        .as_mut()
+                        .map_or(Ok(()), |out| write!(out, "  ^ {}\r\n", note))?;
+                }
+            }
         }
         stdout.as_mut().map_or(Ok(()), |out| out.flush())?;
 
@@ -200,12 +213,15 @@ impl UartConsole {
         self.logfile
             .as_mut()
             .map_or(Ok(()), |f| f.write_all(&buf[..len]))?;
+        self.capture
+            .as_mut()
+            .map_or(Ok(()), |c| c.record(Direction::Rx, &buf[..len]))?;
         self.append_buffer(&buf[..len]);
         Ok(true)
     }
 
     fn process_input<T>(
-        &self,
+        &mut self,
         device: &T,
         stdin: &mut Option<&mut (dyn ReadAsRawFd)>,
     ) -> Result<ExitStatus>
@@ -221,6 +237,9 @@ impl UartConsole {
                 }
                 if len > 0 {
                     device.console_write(&buf[..len])?;
+                    self.capture
+                        .as_mut()
+                        .map_or(Ok(()), |c| c.record(Direction::Tx, &buf[..len]))?;
                 } else {
                     break;
                 }
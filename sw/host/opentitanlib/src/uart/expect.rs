@@ -0,0 +1,450 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An expect-style engine driving a `ConsoleDevice` through a sequence of steps.
+//!
+//! A script is loaded from HJSON, for example:
+//!
+//! ```hjson
+//! {
+//!   timeout: "10s"
+//!   steps: [
+//!     { reset: {} }
+//!     { expect: { regex: "ROM: (?P<version>[0-9a-f]+)", name: "rom" } }
+//!     { send: "status\r" }
+//!     {
+//!       branch: {
+//!         cases: [
+//!           { regex: "PASS" }
+//!           { regex: "FAIL: (.*)", fail: "test failed" }
+//!           { regex: "RETRY", goto: "again" }
+//!         ]
+//!       }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Text matched by an `expect` step or branch case is consumed, so that subsequent steps only
+//! match output which came after it.  The capture groups of named steps and cases are returned
+//! as the result of the script.
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::app::TransportWrapper;
+use crate::impl_serializable_error;
+use crate::io::console::ConsoleDevice;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum ExpectError {
+    #[error("Timed out in step {0} waiting for {1:?}")]
+    Timeout(usize, String),
+    #[error("Script failed in step {0}: {1}")]
+    Failed(usize, String),
+    #[error("Unknown label {0:?}")]
+    UnknownLabel(String),
+    #[error("Duplicate label {0:?}")]
+    DuplicateLabel(String),
+    #[error("Step {0} requires a transport")]
+    NoTransport(usize),
+}
+impl_serializable_error!(ExpectError);
+
+/// Waits for console output matching a regular expression.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Expect {
+    pub regex: String,
+    /// Name under which the capture groups are reported.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Overrides the default timeout of the script.
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+}
+
+/// One of the alternatives of a `branch` step.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Case {
+    pub regex: String,
+    /// Name under which the capture groups are reported.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Label at which to continue, rather than at the next step.
+    #[serde(default)]
+    pub goto: Option<String>,
+    /// Fails the script with the given message.
+    #[serde(default)]
+    pub fail: Option<String>,
+}
+
+/// Waits for console output matching any of several regular expressions.  The case matching
+/// earliest in the output is taken.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Branch {
+    pub cases: Vec<Case>,
+    /// Overrides the default timeout of the script.
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+}
+
+/// Resets the target via the `RESET` strapping.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Reset {
+    #[serde(default = "Reset::default_delay", with = "humantime_serde")]
+    pub delay: Duration,
+    #[serde(default)]
+    pub clear_uart_rx: bool,
+}
+
+impl Reset {
+    fn default_delay() -> Duration {
+        Duration::from_millis(100)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Writes a string to the console.
+    Send(String),
+    Expect(Expect),
+    Branch(Branch),
+    /// Marks the position of the next step as a target of `goto`.
+    Label(String),
+    Goto(String),
+    /// Fails the script with the given message.
+    Fail(String),
+    /// Drives a GPIO pin to the given level.
+    Gpio {
+        pin: String,
+        value: bool,
+    },
+    /// Applies or removes a pin strapping.
+    Strapping {
+        name: String,
+        apply: bool,
+    },
+    Reset(Reset),
+    Sleep(#[serde(with = "humantime_serde")] Duration),
+}
+
+/// The capture groups of named steps, keyed by step name and then by group name or number.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ExpectResult {
+    pub captures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExpectScript {
+    /// Timeout of each `expect` and `branch` step.
+    #[serde(default = "ExpectScript::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    pub steps: Vec<Step>,
+}
+
+impl ExpectScript {
+    /// Maximum amount of unmatched console output kept for matching.
+    const BUFFER_LEN: usize = 65536;
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// Parses a script from HJSON, checking its regular expressions and labels.
+    pub fn from_hjson(text: &str) -> Result<Self> {
+        let script: Self = deser_hjson::from_str(text)?;
+        script.labels()?;
+        for step in &script.steps {
+            match step {
+                Step::Expect(expect) => {
+                    Regex::new(&expect.regex)?;
+                }
+                Step::Branch(branch) => {
+                    for case in &branch.cases {
+                        Regex::new(&case.regex)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_hjson(&std::fs::read_to_string(path)?)
+    }
+
+    /// Maps labels to the index of the step they mark, checking that all `goto` targets exist.
+    fn labels(&self) -> Result<BTreeMap<&str, usize>> {
+        let mut labels = BTreeMap::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            if let Step::Label(label) = step {
+                if labels.insert(label.as_str(), i).is_some() {
+                    bail!(ExpectError::DuplicateLabel(label.clone()));
+                }
+            }
+        }
+        for step in &self.steps {
+            let targets: Vec<&String> = match step {
+                Step::Goto(label) => vec![label],
+                Step::Branch(branch) => branch
+                    .cases
+                    .iter()
+                    .filter_map(|c| c.goto.as_ref())
+                    .collect(),
+                _ => vec![],
+            };
+            for label in targets {
+                if !labels.contains_key(label.as_str()) {
+                    bail!(ExpectError::UnknownLabel(label.clone()));
+                }
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Runs the script against `device`, echoing the console output to `output`.  Steps acting
+    /// on GPIOs require a `transport`.
+    pub fn run<T>(
+        &self,
+        device: &T,
+        transport: Option<&TransportWrapper>,
+        mut output: Option<&mut dyn Write>,
+    ) -> Result<ExpectResult>
+    where
+        T: ConsoleDevice + ?Sized,
+    {
+        let labels = self.labels()?;
+        let mut result = ExpectResult::default();
+        let mut buffer = String::new();
+        let mut pc = 0;
+        while let Some(step) = self.steps.get(pc) {
+            log::debug!("Step {}: {:?}", pc, step);
+            let index = pc;
+            let transport = move || transport.ok_or(ExpectError::NoTransport(index));
+            pc += 1;
+            match step {
+                Step::Send(s) => device.console_write(s.as_bytes())?,
+                Step::Expect(expect) => {
+                    let regex = Regex::new(&expect.regex)?;
+                    let timeout = expect.timeout.unwrap_or(self.timeout);
+                    let names = [expect.name.as_deref()];
+                    let found = self.wait(
+                        device,
+                        &[&regex],
+                        timeout,
+                        &mut buffer,
+                        &mut output,
+                        &mut result,
+                        &names,
+                    )?;
+                    if found.is_none() {
+                        bail!(ExpectError::Timeout(index, expect.regex.clone()));
+                    }
+                }
+                Step::Branch(branch) => {
+                    let regexes = branch
+                        .cases
+                        .iter()
+                        .map(|c| Regex::new(&c.regex))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let regexes = regexes.iter().collect::<Vec<_>>();
+                    let names = branch
+                        .cases
+                        .iter()
+                        .map(|c| c.name.as_deref())
+                        .collect::<Vec<_>>();
+                    let timeout = branch.timeout.unwrap_or(self.timeout);
+                    let found = self.wait(
+                        device,
+                        &regexes,
+                        timeout,
+                        &mut buffer,
+                        &mut output,
+                        &mut result,
+                        &names,
+                    )?;
+                    let Some(i) = found else {
+                        let alternatives = branch.cases.iter().map(|c| c.regex.as_str());
+                        bail!(ExpectError::Timeout(
+                            index,
+                            alternatives.collect::<Vec<_>>().join("|")
+                        ));
+                    };
+                    let case = &branch.cases[i];
+                    if let Some(message) = &case.fail {
+                        bail!(ExpectError::Failed(index, message.clone()));
+                    }
+                    if let Some(label) = &case.goto {
+                        pc = labels[label.as_str()];
+                    }
+                }
+                Step::Label(_) => (),
+                Step::Goto(label) => pc = labels[label.as_str()],
+                Step::Fail(message) => bail!(ExpectError::Failed(index, message.clone())),
+                Step::Gpio { pin, value } => transport()?.gpio_pin(pin)?.write(*value)?,
+                Step::Strapping { name, apply } => {
+                    let strapping = transport()?.pin_strapping(name)?;
+                    if *apply {
+                        strapping.apply()?;
+                    } else {
+                        strapping.remove()?;
+                    }
+                }
+                Step::Reset(reset) => {
+                    transport()?.reset_target(reset.delay, reset.clear_uart_rx)?;
+                    // Output from before the reset is of no interest.
+                    buffer.clear();
+                }
+                Step::Sleep(duration) => std::thread::sleep(*duration),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Reads from `device` until one of `regexes` matches, returning the index of the regex
+    /// matching earliest, or `None` on timeout.  The output up to the end of the match is
+    /// consumed, and the captures are recorded under the corresponding entry of `names`.
+    #[allow(clippy::too_many_arguments)]
+    fn wait<T>(
+        &self,
+        device: &T,
+        regexes: &[&Regex],
+        timeout: Duration,
+        buffer: &mut String,
+        output: &mut Option<&mut dyn Write>,
+        result: &mut ExpectResult,
+        names: &[Option<&str>],
+    ) -> Result<Option<usize>>
+    where
+        T: ConsoleDevice + ?Sized,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let found = regexes
+                .iter()
+                .enumerate()
+                .filter_map(|(i, rx)| rx.captures(buffer).map(|c| (i, c)))
+                .min_by_key(|(_, c)| c.get(0).unwrap().start());
+            if let Some((i, captures)) = found {
+                if let Some(name) = names[i] {
+                    let groups = regexes[i]
+                        .capture_names()
+                        .enumerate()
+                        .filter_map(|(n, group)| {
+                            let key = group.map_or_else(|| n.to_string(), str::to_string);
+                            captures.get(n).map(|m| (key, m.as_str().to_string()))
+                        })
+                        .collect();
+                    result.captures.insert(name.to_string(), groups);
+                }
+                let end = captures.get(0).unwrap().end();
+                buffer.drain(..end);
+                return Ok(Some(i));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let mut buf = [0u8; 256];
+            let len =
+                device.console_read(&mut buf, (deadline - now).min(Duration::from_millis(100)))?;
+            if let Some(out) = output.as_mut() {
+                out.write_all(&buf[..len])?;
+                out.flush()?;
+            }
+            buffer.push_str(&String::from_utf8_lossy(&buf[..len]));
+            if buffer.len() > Self::BUFFER_LEN {
+                let mut start = buffer.len() - Self::BUFFER_LEN;
+                while !buffer.is_char_boundary(start) {
+                    start += 1;
+                }
+                buffer.drain(..start);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::console::FakeConsole;
+
+    const SCRIPT: &str = r#"{
+        timeout: "50ms"
+        steps: [
+            { expect: { regex: "ROM: (?P<version>[0-9a-f]+)", name: "rom" } }
+            { send: "run\r" }
+            { label: "again" }
+            {
+                branch: {
+                    cases: [
+                        { regex: "PASS (\\d+)", name: "pass" }
+                        { regex: "FAIL", fail: "test failed" }
+                        { regex: "RETRY", goto: "again" }
+                    ]
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_script() -> Result<()> {
+        let script = ExpectScript::from_hjson(SCRIPT)?;
+        let console = FakeConsole::new(&["boot\r\nROM", ": 1a2b\r\n", "RETRY\r\n", "PA", "SS 7"]);
+        let result = script.run(&console, None, None)?;
+        assert_eq!(*console.written.borrow(), "run\r");
+        assert_eq!(result.captures["rom"]["0"], "ROM: 1a2b");
+        assert_eq!(result.captures["rom"]["version"], "1a2b");
+        assert_eq!(result.captures["pass"]["1"], "7");
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_failure() -> Result<()> {
+        let script = ExpectScript::from_hjson(SCRIPT)?;
+
+        // The earliest match is taken, and matched output is consumed.
+        let console = FakeConsole::new(&["ROM: 0 FAIL ROM: 1 PASS 1"]);
+        let err = script.run(&console, None, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExpectError>(),
+            Some(ExpectError::Failed(3, _))
+        ));
+
+        let console = FakeConsole::new(&["ROM: 0\r\n"]);
+        let err = script.run(&console, None, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExpectError>(),
+            Some(ExpectError::Timeout(3, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_labels() {
+        let err = ExpectScript::from_hjson(r#"{ steps: [ { goto: "nowhere" } ] }"#).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExpectError>(),
+            Some(ExpectError::UnknownLabel(_))
+        ));
+
+        // GPIO steps need a transport.
+        let script =
+            ExpectScript::from_hjson(r#"{ steps: [ { gpio: { pin: "RESET", value: false } } ] }"#)
+                .unwrap();
+        let err = script.run(&FakeConsole::default(), None, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExpectError>(),
+            Some(ExpectError::NoTransport(0))
+        ));
+    }
+}
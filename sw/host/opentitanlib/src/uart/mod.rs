@@ -2,4 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod capture;
 pub mod console;
+pub mod expect;
+pub mod multiplex;
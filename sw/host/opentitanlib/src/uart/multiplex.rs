@@ -0,0 +1,479 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interleaves the output of several consoles, and optionally edges of GPIO pins, into a single
+//! stream of timestamped records tagged with their source.
+//!
+//! The sources are polled in turn: each console is read with a short timeout, and then the GPIO
+//! events are fetched.  Lines are therefore timestamped when the host reads them, which may be
+//! up to one round of polling after they arrived.  GPIO events carry the timestamp of the
+//! transport, which is converted to host time if the transport clock is a wall clock.  The
+//! records of each round are ordered by time before they are returned.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::io::console::ConsoleDevice;
+use crate::io::gpio::{ClockNature, Edge, GpioMonitoring, GpioPin};
+
+/// A line of console output, or a GPIO event, along with the host time at which it was seen.
+#[derive(Clone, Debug, Serialize)]
+pub struct Record {
+    /// Host time in RFC 3339 format.
+    pub time: String,
+    /// Transport timestamp of a GPIO event, if the transport clock could not be converted to
+    /// host time.  `time` is then the time at which the event was read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    pub source: String,
+    #[serde(flatten)]
+    pub data: RecordData,
+    /// `time` before formatting, by which records are ordered.
+    #[serde(skip)]
+    host_time: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordData {
+    /// A line of console output, without its line terminator.
+    Line(String),
+    /// Initial level of a monitored GPIO pin.
+    Level(bool),
+    Edge(Edge),
+}
+
+impl Record {
+    fn new(time: SystemTime, source: &str, data: RecordData) -> Self {
+        Record {
+            time: humantime::format_rfc3339_millis(time).to_string(),
+            timestamp: None,
+            source: source.to_string(),
+            data,
+            host_time: time,
+        }
+    }
+}
+
+/// Reads from a console device with a timeout.
+type ReadFn<'a> = Box<dyn Fn(&mut [u8], Duration) -> Result<usize> + 'a>;
+
+/// A console and its partially received line.
+struct Console<'a> {
+    name: String,
+    read: ReadFn<'a>,
+    line: Vec<u8>,
+    /// Time at which the first byte of `line` was received.
+    start: SystemTime,
+}
+
+/// Monitored GPIO pins.
+struct Gpio {
+    monitoring: Rc<dyn GpioMonitoring>,
+    names: Vec<String>,
+    pins: Vec<Rc<dyn GpioPin>>,
+    clock: ClockNature,
+    /// Host time and transport timestamp at which monitoring started.
+    start: (SystemTime, u64),
+}
+
+impl Gpio {
+    /// Converts the transport `timestamp` of an event to host time, if the transport clock is a
+    /// wall clock.  Without a known offset, the time is taken relative to the start of
+    /// monitoring.
+    fn time(&self, timestamp: u64) -> Option<SystemTime> {
+        let ClockNature::Wallclock { resolution, offset } = self.clock else {
+            return None;
+        };
+        if resolution == 0 {
+            return None;
+        }
+        let duration = |ticks: u64| {
+            let nanos = (ticks % resolution) as u128 * 1_000_000_000 / resolution as u128;
+            Duration::from_secs(ticks / resolution) + Duration::from_nanos(nanos as u64)
+        };
+        match offset {
+            Some(offset) => Some(UNIX_EPOCH + duration(timestamp.checked_add(offset)?)),
+            None => {
+                let (time, start) = self.start;
+                if timestamp >= start {
+                    time.checked_add(duration(timestamp - start))
+                } else {
+                    time.checked_sub(duration(start - timestamp))
+                }
+            }
+        }
+    }
+
+    /// Makes the record of `edge` of pin `index`, seen at transport `timestamp`.
+    fn record(&self, index: u8, edge: Edge, timestamp: u64) -> Record {
+        let name = &self.names[index as usize];
+        match self.time(timestamp) {
+            Some(time) => Record::new(time, name, RecordData::Edge(edge)),
+            None => Record {
+                timestamp: Some(timestamp),
+                ..Record::new(SystemTime::now(), name, RecordData::Edge(edge))
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConsoleMux<'a> {
+    consoles: Vec<Console<'a>>,
+    gpio: Option<Gpio>,
+    /// Records which were seen but not yet returned.
+    pending: Vec<Record>,
+}
+
+impl<'a> ConsoleMux<'a> {
+    /// How long each console is waited for during one round of polling.  A round therefore
+    /// takes up to this long for each console without output.
+    const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `device` as a source of console output tagged `name`.
+    pub fn add_console<T>(&mut self, name: &str, device: &'a T)
+    where
+        T: ConsoleDevice + ?Sized,
+    {
+        self.consoles.push(Console {
+            name: name.to_string(),
+            read: Box::new(move |buf, timeout| device.console_read(buf, timeout)),
+            line: Vec::new(),
+            start: SystemTime::now(),
+        });
+    }
+
+    /// Starts monitoring `pins`, tagging their events with the corresponding `names`.  A record
+    /// of the initial level of each pin is produced.
+    pub fn monitor_gpios(
+        &mut self,
+        monitoring: Rc<dyn GpioMonitoring>,
+        names: Vec<String>,
+        pins: Vec<Rc<dyn GpioPin>>,
+    ) -> Result<()> {
+        let clock = monitoring.get_clock_nature()?;
+        let pin_refs = pins.iter().map(Rc::as_ref).collect::<Vec<_>>();
+        let start = monitoring.monitoring_start(&pin_refs)?;
+        let now = SystemTime::now();
+        let gpio = Gpio {
+            monitoring,
+            names,
+            pins,
+            clock,
+            start: (now, start.timestamp),
+        };
+        // The levels are on the same clock as the events which follow them.
+        let time = gpio.time(start.timestamp).unwrap_or(now);
+        for (name, level) in gpio.names.iter().zip(start.initial_levels) {
+            self.pending
+                .push(Record::new(time, name, RecordData::Level(level)));
+        }
+        self.gpio = Some(gpio);
+        Ok(())
+    }
+
+    /// Reads once from each source in turn, returning the completed records ordered by time.
+    /// Each console is waited for up to `READ_TIMEOUT`.
+    pub fn poll(&mut self) -> Result<Vec<Record>> {
+        let mut records = std::mem::take(&mut self.pending);
+        for console in self.consoles.iter_mut() {
+            let mut buf = [0u8; 256];
+            let len = (console.read)(&mut buf, Self::READ_TIMEOUT)?;
+            for &byte in &buf[..len] {
+                if console.line.is_empty() {
+                    console.start = SystemTime::now();
+                }
+                if byte == b'\n' {
+                    records.push(Self::take_line(console));
+                } else {
+                    console.line.push(byte);
+                }
+            }
+        }
+        if let Some(gpio) = &self.gpio {
+            let pins = gpio.pins.iter().map(Rc::as_ref).collect::<Vec<_>>();
+            let response = gpio.monitoring.monitoring_read(&pins, true)?;
+            for event in response.events {
+                records.push(gpio.record(event.signal_index, event.edge, event.timestamp));
+            }
+        }
+        records.sort_by_key(|r| r.host_time);
+        Ok(records)
+    }
+
+    /// Returns the records of any partially received lines ordered by time, and stops monitoring
+    /// GPIOs.
+    pub fn finish(&mut self) -> Result<Vec<Record>> {
+        let mut records = std::mem::take(&mut self.pending);
+        for console in self.consoles.iter_mut() {
+            if !console.line.is_empty() {
+                records.push(Self::take_line(console));
+            }
+        }
+        if let Some(gpio) = self.gpio.take() {
+            let pins = gpio.pins.iter().map(Rc::as_ref).collect::<Vec<_>>();
+            gpio.monitoring.monitoring_read(&pins, false)?;
+        }
+        records.sort_by_key(|r| r.host_time);
+        Ok(records)
+    }
+
+    /// Polls at least once, and then until `deadline` or forever, printing the records to
+    /// `stdout` and logging them to `log` as JSON lines.
+    pub fn run(
+        &mut self,
+        deadline: Option<Instant>,
+        stdout: &mut dyn Write,
+        mut log: Option<&mut dyn Write>,
+    ) -> Result<()> {
+        let mut emit = |records: Vec<Record>| -> Result<()> {
+            for record in records {
+                let text = match &record.data {
+                    RecordData::Line(line) => line.clone(),
+                    RecordData::Level(level) => format!("level {}", *level as u8),
+                    RecordData::Edge(edge) => match record.timestamp {
+                        Some(timestamp) => format!("{:?} at {}", edge, timestamp),
+                        None => format!("{:?}", edge),
+                    },
+                };
+                writeln!(stdout, "[{}  {}] {}", record.time, record.source, text)?;
+                if let Some(log) = log.as_mut() {
+                    serde_json::to_writer(&mut *log, &record)?;
+                    writeln!(log)?;
+                    log.flush()?;
+                }
+            }
+            stdout.flush()?;
+            Ok(())
+        };
+        loop {
+            emit(self.poll()?)?;
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return emit(self.finish()?);
+            }
+        }
+    }
+
+    fn take_line(console: &mut Console) -> Record {
+        let mut line = std::mem::take(&mut console.line);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Record::new(
+            console.start,
+            &console.name,
+            RecordData::Line(String::from_utf8_lossy(&line).into_owned()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::console::FakeConsole;
+    use crate::io::gpio::{
+        MonitoringEvent, MonitoringReadResponse, MonitoringStartResponse, PinMode, PullMode,
+    };
+    use std::cell::RefCell;
+
+    struct FakePin;
+
+    impl GpioPin for FakePin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!()
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            unimplemented!()
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            unimplemented!()
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            unimplemented!()
+        }
+    }
+
+    /// Monitoring which starts at timestamp 1000, and reports `events` on the first read.
+    struct FakeMonitoring {
+        clock: ClockNature,
+        events: RefCell<Vec<(u8, Edge, u64)>>,
+    }
+
+    impl GpioMonitoring for FakeMonitoring {
+        fn get_clock_nature(&self) -> Result<ClockNature> {
+            Ok(self.clock)
+        }
+
+        fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
+            Ok(MonitoringStartResponse {
+                timestamp: 1000,
+                initial_levels: vec![true; pins.len()],
+            })
+        }
+
+        fn monitoring_read(
+            &self,
+            _pins: &[&dyn GpioPin],
+            _continue_monitoring: bool,
+        ) -> Result<MonitoringReadResponse> {
+            let events = std::mem::take(&mut *self.events.borrow_mut());
+            Ok(MonitoringReadResponse {
+                events: events
+                    .into_iter()
+                    .map(|(signal_index, edge, timestamp)| MonitoringEvent {
+                        signal_index,
+                        edge,
+                        timestamp,
+                    })
+                    .collect(),
+                timestamp: 2000,
+            })
+        }
+    }
+
+    fn gpio_mux<'a>(clock: ClockNature, events: Vec<(u8, Edge, u64)>) -> Result<ConsoleMux<'a>> {
+        let monitoring = Rc::new(FakeMonitoring {
+            clock,
+            events: RefCell::new(events),
+        });
+        let mut mux = ConsoleMux::new();
+        mux.monitor_gpios(
+            monitoring,
+            vec!["RESET".to_string(), "IRQ".to_string()],
+            vec![Rc::new(FakePin), Rc::new(FakePin)],
+        )?;
+        Ok(mux)
+    }
+
+    fn lines(records: &[Record]) -> Vec<(&str, RecordData)> {
+        records
+            .iter()
+            .map(|r| (r.source.as_str(), r.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_interleave() -> Result<()> {
+        let uart = FakeConsole::new(&["ROM\r\nboo", "t\r\n", "partial"]);
+        let spi = FakeConsole::new(&["", "spi\n"]);
+        let mut mux = ConsoleMux::new();
+        mux.add_console("uart", &uart);
+        mux.add_console("spi", &spi);
+
+        let line = |s: &str| RecordData::Line(s.to_string());
+        assert_eq!(lines(&mux.poll()?), [("uart", line("ROM"))]);
+        assert_eq!(
+            lines(&mux.poll()?),
+            [("uart", line("boot")), ("spi", line("spi"))]
+        );
+        assert_eq!(lines(&mux.poll()?), []);
+        assert_eq!(lines(&mux.finish()?), [("uart", line("partial"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_lines() -> Result<()> {
+        let uart = FakeConsole::new(&["hello\r\n"]);
+        let mut mux = ConsoleMux::new();
+        mux.add_console("console", &uart);
+        let mut stdout = Vec::new();
+        let mut log = Vec::new();
+        mux.run(Some(Instant::now()), &mut stdout, Some(&mut log))?;
+        assert_eq!(lines(&mux.poll()?), []);
+
+        let log = String::from_utf8(log)?;
+        let record: serde_json::Value = serde_json::from_str(log.trim_end())?;
+        assert_eq!(record["source"], "console");
+        assert_eq!(record["line"], "hello");
+        assert!(record["time"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpio_events() -> Result<()> {
+        let events = vec![(1, Edge::Falling, 1_500_250), (0, Edge::Rising, 2_000_000)];
+        // Microsecond timestamps relative to the Unix epoch.
+        let mut mux = gpio_mux(
+            ClockNature::Wallclock {
+                resolution: 1_000_000,
+                offset: Some(0),
+            },
+            events.clone(),
+        )?;
+        let records = mux.poll()?;
+        assert_eq!(
+            lines(&records),
+            [
+                ("RESET", RecordData::Level(true)),
+                ("IRQ", RecordData::Level(true)),
+                ("IRQ", RecordData::Edge(Edge::Falling)),
+                ("RESET", RecordData::Edge(Edge::Rising)),
+            ]
+        );
+        assert_eq!(records[2].time, "1970-01-01T00:00:01.500Z");
+        assert_eq!(records[2].timestamp, None);
+        assert_eq!(records[3].time, "1970-01-01T00:00:02.000Z");
+
+        // Without an offset, the time is relative to the start of monitoring.
+        let mut mux = gpio_mux(
+            ClockNature::Wallclock {
+                resolution: 1000,
+                offset: None,
+            },
+            vec![(0, Edge::Falling, 3500)],
+        )?;
+        let records = mux.poll()?;
+        let start = humantime::parse_rfc3339(&records[0].time)?;
+        let edge = humantime::parse_rfc3339(&records[2].time)?;
+        assert_eq!(edge.duration_since(start)?, Duration::from_millis(2500));
+
+        // Other clocks are recorded as they are.
+        let mut mux = gpio_mux(ClockNature::Unspecified, events)?;
+        let records = mux.poll()?;
+        assert_eq!(records[2].timestamp, Some(1_500_250));
+        assert_eq!(records[3].timestamp, Some(2_000_000));
+        let json = serde_json::to_value(&records[2])?;
+        assert_eq!(json["timestamp"], 1_500_250);
+        assert_eq!(json["edge"], "Falling");
+        assert!(mux.finish()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_order() -> Result<()> {
+        // Millisecond timestamps relative to the start of monitoring at 1000, one before the
+        // console line is read and one after.
+        let events = vec![(0, Edge::Rising, 3_601_000), (1, Edge::Falling, 500)];
+        let uart = FakeConsole::new(&["hello\n"]);
+        let mut mux = gpio_mux(
+            ClockNature::Wallclock {
+                resolution: 1000,
+                offset: None,
+            },
+            events,
+        )?;
+        mux.add_console("uart", &uart);
+
+        let records = mux.poll()?;
+        assert_eq!(
+            lines(&records),
+            [
+                ("IRQ", RecordData::Edge(Edge::Falling)),
+                ("RESET", RecordData::Level(true)),
+                ("IRQ", RecordData::Level(true)),
+                ("uart", RecordData::Line("hello".to_string())),
+                ("RESET", RecordData::Edge(Edge::Rising)),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -11,6 +11,7 @@ use bindgen::status::{ot_status_create_record_t, status_create, status_err, stat
 use object::{Object, ObjectSection};
 
 use num_enum::TryFromPrimitive;
+use regex::Regex;
 
 use std::convert::TryFrom;
 use std::ffi::CString;
@@ -144,6 +145,41 @@ mod test {
         assert_eq!(decoded_status.code, CODE);
         assert_eq!(decoded_status.arg, ARG);
     }
+
+    #[test]
+    fn status_annotator_test() {
+        let mod_id_val = (b'A' as u32) << 16 | (b'B' as u32) << 21 | (b'C' as u32) << 26;
+        let raw_status = status_create_safe(StatusCode::Internal, mod_id_val, "".to_string(), 42);
+        let records = StatusCreateRecords {
+            records: vec![StatusCreateRecord {
+                module_id: Some(mod_id_val),
+                filename: "sw/device/foo.c".to_string(),
+            }],
+        };
+        let mut annotator = StatusAnnotator::new(Some(records));
+
+        let line = format!("FAIL: 0x{:08x}\r", raw_status.value as u32);
+        let notes = line
+            .bytes()
+            .chain(std::iter::once(b'\n'))
+            .flat_map(|b| annotator.feed(b))
+            .collect::<Vec<_>>();
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].ends_with("Internal in module ABC at line 42 (sw/device/foo.c)"));
+
+        assert_eq!(
+            annotator.annotate(r#"RESP_ERR:{"Internal":["ABC",42]} CRC:1"#),
+            [r#""Internal":["ABC",42]: Internal in module ABC at line 42 (sw/device/foo.c)"#]
+        );
+        assert_eq!(
+            annotator.annotate(r#"- NotFound:["XYZ",7]"#),
+            [r#"NotFound:["XYZ",7]: NotFound in module XYZ at line 7"#]
+        );
+        // Neither ok statuses nor other bracketed values are annotated.
+        assert!(annotator
+            .annotate(r#"0x00001234 Ok:5 Foo:["ABC",1]"#)
+            .is_empty());
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -249,3 +285,84 @@ pub fn load_elf(elf_file: &PathBuf) -> Result<StatusCreateRecords> {
         .collect::<Result<_>>()?;
     Ok(StatusCreateRecords { records })
 }
+
+/// Recognises statuses in lines of console output and explains them.  Raw error statuses (such
+/// as `0x8a0c3107`) and statuses formatted by `%r` (such as `Internal:["ABC",42]`, or
+/// `{"Internal":["ABC",42]}` in `RESP_ERR` responses) are decoded.  Given the status creation
+/// records of the ELF file running on the device, the files with the module ID are named too.
+pub struct StatusAnnotator {
+    records: Option<StatusCreateRecords>,
+    line: Vec<u8>,
+    raw: Regex,
+    text: Regex,
+}
+
+impl StatusAnnotator {
+    pub fn new(records: Option<StatusCreateRecords>) -> Self {
+        StatusAnnotator {
+            records,
+            line: Vec::new(),
+            // Error statuses have the sign bit set.
+            raw: Regex::new(r"\b0x([89a-fA-F][0-9a-fA-F]{7})\b").unwrap(),
+            text: Regex::new(r#""?([A-Za-z]+)"?:\["([^"]{3})",(-?\d+)\]"#).unwrap(),
+        }
+    }
+
+    /// Processes one byte of console output, returning the explanations of the statuses in the
+    /// line it completes.
+    pub fn feed(&mut self, byte: u8) -> Vec<String> {
+        if byte != b'\n' {
+            self.line.push(byte);
+            return Vec::new();
+        }
+        let line = std::mem::take(&mut self.line);
+        self.annotate(&String::from_utf8_lossy(&line))
+    }
+
+    /// Returns an explanation of each status found in `line`.
+    pub fn annotate(&self, line: &str) -> Vec<String> {
+        let mut notes = Vec::new();
+        for cap in self.raw.captures_iter(line) {
+            let value = u32::from_str_radix(&cap[1], 16).unwrap();
+            match Status::from_u32(value) {
+                Ok(status) if status.code != StatusCode::Ok => {
+                    notes.push(self.describe(&cap[0], &status));
+                }
+                _ => (),
+            }
+        }
+        for cap in self.text.captures_iter(line) {
+            // Only names of status codes are of interest.
+            let name = serde_json::Value::String(cap[1].to_string());
+            let (Ok(code), Ok(arg)) = (
+                serde_json::from_value::<StatusCode>(name),
+                cap[3].parse::<i32>(),
+            ) else {
+                continue;
+            };
+            let status = Status {
+                module_id: cap[2].to_string(),
+                arg,
+                code,
+            };
+            notes.push(self.describe(&cap[0], &status));
+        }
+        notes
+    }
+
+    fn describe(&self, text: &str, status: &Status) -> String {
+        let mut note = format!(
+            "{}: {:?} in module {} at line {}",
+            text, status.code, status.module_id, status.arg
+        );
+        let filenames = self
+            .records
+            .as_ref()
+            .map(|records| records.find_module_id(&status.module_id))
+            .unwrap_or_default();
+        if !filenames.is_empty() {
+            note.push_str(&format!(" ({})", filenames.join(", ")));
+        }
+        note
+    }
+}
@@ -10,7 +10,7 @@ use std::path::PathBuf;
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::{StagedProgressBar, TransportWrapper};
-use opentitanlib::bootstrap::{Bootstrap, BootstrapOptions, BootstrapProtocol};
+use opentitanlib::bootstrap::{self, Bootstrap, BootstrapOptions, BootstrapProtocol, TraceReport};
 use opentitanlib::image::image::ImageAssembler;
 use opentitanlib::transport;
 use opentitanlib::util::parse_int::ParseInt;
@@ -34,6 +34,21 @@ pub struct BootstrapCommand {
         help = "Whether or not the assembled image is mirrored (only valid with mutliple FILE arguments)"
     )]
     mirror: bool,
+    #[arg(
+        long,
+        help = "Compute the bootstrap frames without accessing the transport (primitive and legacy only)"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        help = "Write each bootstrap frame and its expected acknowledgement to this directory (implies --dry-run)"
+    )]
+    export_frames: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Validate a captured SPI trace against the expected bootstrap frames (implies --dry-run)"
+    )]
+    check_trace: Option<PathBuf>,
     #[arg(
         name = "FILE",
         required = true,
@@ -43,6 +58,14 @@ pub struct BootstrapCommand {
     filename: Vec<String>,
 }
 
+#[derive(Debug, serde::Serialize)]
+pub struct DryRunResponse {
+    protocol: BootstrapProtocol,
+    payload_len: usize,
+    frames: usize,
+    trace: Option<TraceReport>,
+}
+
 impl BootstrapCommand {
     fn bootstrap_using_direct_emulator_integration(
         &self,
@@ -57,6 +80,27 @@ impl BootstrapCommand {
         })
     }
 
+    fn dry_run(&self, payload: &[u8]) -> Result<Option<Box<dyn Annotate>>> {
+        let protocol = self.bootstrap_options.protocol;
+        let frames = Bootstrap::expected_frames(&self.bootstrap_options, payload)?;
+        if let Some(dir) = &self.export_frames {
+            bootstrap::export_frames(dir, protocol, &frames)?;
+        }
+        let trace = match &self.check_trace {
+            Some(path) => {
+                let trace = bootstrap::parse_trace(&std::fs::read_to_string(path)?)?;
+                Some(bootstrap::validate_trace(&frames, &trace)?)
+            }
+            None => None,
+        };
+        Ok(Some(Box::new(DryRunResponse {
+            protocol,
+            payload_len: payload.len(),
+            frames: frames.len(),
+            trace,
+        })))
+    }
+
     fn payload(&self) -> Result<Vec<u8>> {
         if self.filename.len() > 1 || self.filename[0].contains('@') {
             let mut image = ImageAssembler::with_params(self.size, self.mirror);
@@ -79,8 +123,16 @@ impl CommandDispatch for BootstrapCommand {
         }
 
         let payload = self.payload()?;
+        if self.dry_run || self.export_frames.is_some() || self.check_trace.is_some() {
+            return self.dry_run(&payload);
+        }
         let progress = StagedProgressBar::new();
-        Bootstrap::update_with_progress(transport, &self.bootstrap_options, &payload, &progress)?;
-        Ok(None)
+        let report = Bootstrap::update_with_progress(
+            transport,
+            &self.bootstrap_options,
+            &payload,
+            &progress,
+        )?;
+        Ok(Some(Box::new(report)))
     }
 }
@@ -11,13 +11,19 @@ use serde_annotate::Annotate;
 use std::any::Any;
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
+use opentitanlib::console::spi::SpiConsoleDevice;
 use opentitanlib::io::uart::UartParams;
 use opentitanlib::transport::Capability;
+use opentitanlib::uart::capture::{load_capture, CaptureError, CaptureWriter, Direction, Replay};
 use opentitanlib::uart::console::{ExitStatus, UartConsole};
+use opentitanlib::uart::expect::ExpectScript;
+use opentitanlib::uart::multiplex::ConsoleMux;
+use opentitanlib::util::status::{load_elf, StatusAnnotator};
 
 #[derive(Debug, Args)]
 pub struct Console {
@@ -49,6 +55,115 @@ pub struct Console {
 
     #[arg(long, help = "Exit with failure if the specified regex is matched.")]
     exit_failure: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["exit_success", "exit_failure", "timeout", "logfile", "timestamp"],
+        help = "Run the HJSON expect script non-interactively, reporting its captures."
+    )]
+    script: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["exit_success", "exit_failure", "script", "send"],
+        help = "Interleave several consoles and GPIO edges, logged as JSON lines to --logfile."
+    )]
+    multi: bool,
+
+    #[arg(
+        long = "multi-uart",
+        requires = "multi",
+        help = "UART to include in --multi mode (may be repeated, defaults to --uart)."
+    )]
+    multi_uarts: Vec<String>,
+
+    #[arg(
+        long,
+        requires = "multi",
+        help = "SPI bus of a SPI console to include in --multi mode."
+    )]
+    multi_spi: Option<String>,
+
+    #[arg(
+        long = "multi-gpio",
+        requires = "multi",
+        help = "GPIO pin whose edges to include in --multi mode (may be repeated)."
+    )]
+    multi_gpios: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["script", "multi"],
+        help = "ELF file running on the device, used to explain statuses in the console output."
+    )]
+    elf: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["script", "multi"],
+        help = "Record all console traffic with timestamps to a capture file."
+    )]
+    capture: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["script", "multi", "capture", "send"],
+        help = "Replay the output recorded in a capture file instead of reading the UART."
+    )]
+    replay: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "replay",
+        help = "Replay the capture with its original timing."
+    )]
+    realtime: bool,
+}
+
+impl Console {
+    fn run_multi(&self, transport: &TransportWrapper) -> Result<()> {
+        let uarts = if self.multi_uarts.is_empty() {
+            vec![("console".to_string(), self.params.create(transport)?)]
+        } else {
+            self.multi_uarts
+                .iter()
+                .map(|name| Ok((name.clone(), transport.uart(name)?)))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let spi = self
+            .multi_spi
+            .as_ref()
+            .map(|name| transport.spi(name))
+            .transpose()?;
+        let spi_console = spi.as_ref().map(|spi| SpiConsoleDevice::new(&**spi));
+
+        let mut mux = ConsoleMux::new();
+        for (name, uart) in &uarts {
+            mux.add_console(name, &**uart);
+        }
+        if let (Some(name), Some(spi_console)) = (&self.multi_spi, &spi_console) {
+            mux.add_console(name, spi_console);
+        }
+        if !self.multi_gpios.is_empty() {
+            transport
+                .capabilities()?
+                .request(Capability::GPIO_MONITORING)
+                .ok()?;
+            mux.monitor_gpios(
+                transport.gpio_monitoring()?,
+                self.multi_gpios.clone(),
+                transport.gpio_pins(&self.multi_gpios)?,
+            )?;
+        }
+
+        let mut logfile = self.logfile.as_ref().map(File::create).transpose()?;
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        mux.run(
+            deadline,
+            &mut std::io::stdout(),
+            logfile.as_mut().map(|f| f as &mut dyn std::io::Write),
+        )
+    }
 }
 
 impl CommandDispatch for Console {
@@ -57,11 +172,30 @@ impl CommandDispatch for Console {
         _context: &dyn Any,
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
-        // We need the UART for the console command to operate.
-        transport.capabilities()?.request(Capability::UART).ok()?;
+        // We need the UART for the console command to operate, unless replaying a capture.
+        if self.replay.is_none() {
+            transport.capabilities()?.request(Capability::UART).ok()?;
+        }
         let mut stdout = std::io::stdout();
         let mut stdin = std::io::stdin();
 
+        if self.multi {
+            self.run_multi(transport)?;
+            return Ok(None);
+        }
+
+        if let Some(script) = &self.script {
+            let script = ExpectScript::load(script)?;
+            let uart = self.params.create(transport)?;
+            if let Some(send) = self.send.as_ref() {
+                log::info!("Sending: {:?}", send);
+                uart.write(send.as_bytes())?;
+            }
+            let result = script.run(&*uart, Some(transport), Some(&mut stdout))?;
+            println!();
+            return Ok(Some(Box::new(result)));
+        }
+
         // Set up resources specified by the command line parameters.
         let mut console = UartConsole {
             logfile: self.logfile.as_ref().map(File::create).transpose()?,
@@ -78,6 +212,17 @@ impl CommandDispatch for Console {
                 .transpose()?,
             timestamp: self.timestamp,
             newline: true,
+            annotator: self
+                .elf
+                .as_ref()
+                .map(load_elf)
+                .transpose()?
+                .map(|records| StatusAnnotator::new(Some(records))),
+            capture: self
+                .capture
+                .as_deref()
+                .map(CaptureWriter::create)
+                .transpose()?,
             ..Default::default()
         };
 
@@ -102,12 +247,32 @@ impl CommandDispatch for Console {
             } else {
                 None
             };
-            let uart = self.params.create(transport)?;
-            if let Some(send) = self.send.as_ref() {
-                log::info!("Sending: {:?}", send);
-                uart.write(send.as_bytes())?;
+            let result = if let Some(replay) = &self.replay {
+                let replay = Replay::new(load_capture(replay)?, self.realtime);
+                console.interact(&replay, Some(&mut stdin), Some(&mut stdout))
+            } else {
+                let uart = self.params.create(transport)?;
+                if let Some(send) = self.send.as_ref() {
+                    log::info!("Sending: {:?}", send);
+                    uart.write(send.as_bytes())?;
+                    if let Some(capture) = console.capture.as_mut() {
+                        capture.record(Direction::Tx, send.as_bytes())?;
+                    }
+                }
+                console.interact(&*uart, Some(&mut stdin), Some(&mut stdout))
+            };
+            match result {
+                // Running out of captured output is treated like a timeout.
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<CaptureError>(),
+                        Some(CaptureError::EndOfCapture)
+                    ) =>
+                {
+                    ExitStatus::Timeout
+                }
+                result => result?,
             }
-            console.interact(&*uart, Some(&mut stdin), Some(&mut stdout))?
         };
         if !self.quiet {
             println!("\n\nExiting interactive console.");
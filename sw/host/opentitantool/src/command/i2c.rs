@@ -126,6 +126,13 @@ impl CommandDispatch for I2cRawWriteRead {
 
 #[derive(Debug, Args)]
 pub struct I2cTpm {
+    #[arg(
+        long,
+        default_value = "0",
+        help = "TPM locality at which to run, requested from the TPM if not 0"
+    )]
+    locality: u8,
+
     #[command(subcommand)]
     command: super::tpm::TpmSubCommand,
 }
@@ -139,7 +146,7 @@ impl CommandDispatch for I2cTpm {
         let context = context.downcast_ref::<I2cCommand>().unwrap();
         let tpm_driver = tpm::I2cDriver::new(context.params.create(transport)?, context.addr);
         let bus: Box<dyn tpm::Driver> = Box::new(tpm_driver);
-        self.command.run(&bus, transport)
+        tpm::run_at_locality(&*bus, self.locality, || self.command.run(&bus, transport))
     }
 }
 
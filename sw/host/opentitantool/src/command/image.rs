@@ -22,6 +22,7 @@ use opentitanlib::image::image::{self, ImageAssembler};
 use opentitanlib::image::manifest::ManifestExtSpxSignature;
 use opentitanlib::image::manifest_def::ManifestSpec;
 use opentitanlib::image::manifest_ext::{ManifestExtEntry, ManifestExtId, ManifestExtSpec};
+use opentitanlib::image::signing_request::{SigningPolicy, SigningRequest};
 use opentitanlib::util::file::{FromReader, ToWriter};
 use opentitanlib::util::parse_int::ParseInt;
 
@@ -352,6 +353,99 @@ impl CommandDispatch for SpxMessageCommand {
     }
 }
 
+/// Create a signing request for an image.
+#[derive(Debug, Args)]
+pub struct SigningRequestCreateCommand {
+    #[arg(name = "IMAGE", help = "Filename for the image to be signed")]
+    image: PathBuf,
+    #[arg(short, long, help = "Filename to write the signing request to")]
+    output: PathBuf,
+    #[arg(
+        long,
+        help = "Filename for an output bin file of the digest (little-endian)"
+    )]
+    digest_bin: Option<PathBuf>,
+    #[arg(long, help = "Filename for an output bin file of the SPHINCS+ message")]
+    spx_message_bin: Option<PathBuf>,
+}
+
+impl CommandDispatch for SigningRequestCreateCommand {
+    fn run(
+        &self,
+        _context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let image = image::Image::read_from_file(&self.image)?;
+        let request = SigningRequest::from_image(&image)?;
+        request.write_to_file(&self.output)?;
+        if let Some(bin) = &self.digest_bin {
+            std::fs::write(bin, request.rsa_digest()?.to_le_bytes())?;
+        }
+        if let Some(bin) = &self.spx_message_bin {
+            std::fs::write(bin, &request.spx_message)?;
+        }
+        Ok(Some(Box::new(DigestResponse {
+            digest: request.digest,
+        })))
+    }
+}
+
+/// Verify the signatures collected for a signing request and embed them into the image.
+#[derive(Debug, Args)]
+pub struct SigningRequestFinalizeCommand {
+    #[arg(name = "IMAGE", help = "Filename for the image to be signed")]
+    image: PathBuf,
+    #[arg(short, long, help = "Filename of the signing request")]
+    request: PathBuf,
+    #[arg(short, long, help = "Filename for an HJSON signing policy")]
+    policy: PathBuf,
+    #[arg(long, help = "Filename for a detached RSA signature (may be repeated)")]
+    rsa_signature: Vec<PathBuf>,
+    #[arg(
+        long,
+        help = "Filename for a detached SPHINCS+ signature (may be repeated)"
+    )]
+    spx_signature: Vec<PathBuf>,
+    #[arg(
+        short,
+        long,
+        help = "Filename to write the output to instead of updating the input file"
+    )]
+    output: Option<PathBuf>,
+}
+
+impl CommandDispatch for SigningRequestFinalizeCommand {
+    fn run(
+        &self,
+        _context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let mut image = image::Image::read_from_file(&self.image)?;
+        let request = SigningRequest::read_from_file(&self.request)?;
+        let policy = SigningPolicy::read_from_file(&self.policy)?;
+        let rsa_signatures = self
+            .rsa_signature
+            .iter()
+            .map(|p| RsaSignature::read_from_file(p))
+            .collect::<Result<Vec<_>>>()?;
+        let spx_signatures = self
+            .spx_signature
+            .iter()
+            .map(|p| SpxSignature::read_from_file(p))
+            .collect::<Result<Vec<_>>>()?;
+        let report = policy.finalize(&mut image, &request, &rsa_signatures, &spx_signatures)?;
+        image.write_to_file(self.output.as_ref().unwrap_or(&self.image))?;
+        Ok(Some(Box::new(report)))
+    }
+}
+
+#[derive(Debug, Subcommand, CommandDispatch)]
+/// Multi-party signing commands.
+pub enum SigningRequestCommand {
+    Create(SigningRequestCreateCommand),
+    Finalize(SigningRequestFinalizeCommand),
+}
+
 #[derive(Debug, Subcommand, CommandDispatch)]
 /// Manifest manipulation commands.
 pub enum ManifestCommand {
@@ -368,4 +462,6 @@ pub enum Image {
     Manifest(ManifestCommand),
     Digest(DigestCommand),
     SpxMessage(SpxMessageCommand),
+    #[command(subcommand)]
+    SigningRequest(SigningRequestCommand),
 }
@@ -13,6 +13,7 @@ pub mod i2c;
 pub mod image;
 pub mod load_bitstream;
 pub mod otp;
+pub mod rescue;
 pub mod reset_sam3x;
 pub mod rsa;
 pub mod set_pll;
@@ -0,0 +1,154 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use humantime::parse_duration;
+use serde_annotate::Annotate;
+use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use opentitanlib::app::command::CommandDispatch;
+use opentitanlib::app::{StagedProgressBar, TransportWrapper};
+use opentitanlib::io::uart::UartParams;
+use opentitanlib::rescue::{RescueSerial, XmodemStats};
+
+/// Upload firmware to the ROM_EXT rescue mode.
+#[derive(Debug, Args)]
+pub struct RescueFirmware {
+    #[arg(help = "Firmware image to upload")]
+    filename: PathBuf,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RescueFirmwareResponse {
+    payload_len: usize,
+    stats: XmodemStats,
+}
+
+impl CommandDispatch for RescueFirmware {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let context = context.downcast_ref::<RescueCommand>().unwrap();
+        let payload = fs::read(&self.filename)?;
+        let rescue = context.enter(transport)?;
+        let progress = StagedProgressBar::new();
+        let stats = rescue.update_firmware_with_progress(&payload, &progress)?;
+        context.leave(&rescue)?;
+        Ok(Some(Box::new(RescueFirmwareResponse {
+            payload_len: payload.len(),
+            stats,
+        })))
+    }
+}
+
+/// Read the boot log from the ROM_EXT rescue mode.
+#[derive(Debug, Args)]
+pub struct RescueBootLog {
+    #[arg(short, long, help = "Write the raw boot log to this file")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RescueBootLogResponse {
+    hexdata: String,
+}
+
+impl CommandDispatch for RescueBootLog {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let context = context.downcast_ref::<RescueCommand>().unwrap();
+        let rescue = context.enter(transport)?;
+        let log = rescue.get_boot_log()?;
+        context.leave(&rescue)?;
+        if let Some(output) = &self.output {
+            fs::write(output, &log)?;
+            return Ok(None);
+        }
+        Ok(Some(Box::new(RescueBootLogResponse {
+            hexdata: hex::encode(log),
+        })))
+    }
+}
+
+/// Read the active boot data record from the ROM_EXT rescue mode.
+#[derive(Debug, Args)]
+pub struct RescueBootData {}
+
+impl CommandDispatch for RescueBootData {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let context = context.downcast_ref::<RescueCommand>().unwrap();
+        let rescue = context.enter(transport)?;
+        let boot_data = rescue.get_boot_data()?;
+        context.leave(&rescue)?;
+        Ok(Some(Box::new(boot_data)))
+    }
+}
+
+/// Commands for interacting with the ROM_EXT rescue mode.
+#[derive(Debug, Subcommand, CommandDispatch)]
+pub enum InternalRescueCommand {
+    Firmware(RescueFirmware),
+    BootLog(RescueBootLog),
+    BootData(RescueBootData),
+}
+
+#[derive(Debug, Args)]
+pub struct RescueCommand {
+    #[command(flatten)]
+    params: UartParams,
+
+    #[arg(long, value_parser = parse_duration, default_value = "100ms", help = "Duration of the reset pulse")]
+    reset_delay: Duration,
+
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "Leave rescue mode and reboot the chip when done"
+    )]
+    reboot: bool,
+
+    #[command(subcommand)]
+    command: InternalRescueCommand,
+}
+
+impl RescueCommand {
+    fn enter(&self, transport: &TransportWrapper) -> Result<RescueSerial> {
+        let rescue = RescueSerial::new(self.params.create(transport)?);
+        rescue.enter(transport, self.reset_delay)?;
+        Ok(rescue)
+    }
+
+    fn leave(&self, rescue: &RescueSerial) -> Result<()> {
+        if self.reboot {
+            rescue.reboot()?;
+        }
+        Ok(())
+    }
+}
+
+impl CommandDispatch for RescueCommand {
+    fn run(
+        &self,
+        _context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        // None of the rescue commands care about the prior context, but they do
+        // care about the UART and reset parameters in the current node.
+        self.command.run(self, transport)
+    }
+}
@@ -14,9 +14,9 @@ use std::path::PathBuf;
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
 use opentitanlib::crypto::rsa::{
-    Exponent, Modulus, N0Inv, RsaPrivateKey, RsaPublicKey, Signature, RR,
+    Exponent, HashAlgorithm, Modulus, N0Inv, PaddingScheme, RsaPrivateKey, RsaPublicKey, Signature,
+    RR,
 };
-use opentitanlib::crypto::sha256::Sha256Digest;
 use opentitanlib::util::parse_int::ParseInt;
 
 /// Given the path to a public key, returns the public key. Given
@@ -254,6 +254,24 @@ pub enum RsaKeySubcommands {
     Export(RsaKeyExportCommand),
 }
 
+/// Parses a big-endian hex `digest` and checks its length against `hash`.
+fn parse_digest(hash: HashAlgorithm, digest: &str) -> Result<Vec<u8>> {
+    let digest = digest.trim();
+    let digest = digest
+        .strip_prefix("0x")
+        .or_else(|| digest.strip_prefix("0X"))
+        .unwrap_or(digest);
+    let bytes = hex::decode(digest)?;
+    if bytes.len() != hash.digest_len() {
+        bail!(
+            "{hash:?} digest must be {} bytes, but got {} bytes",
+            hash.digest_len(),
+            bytes.len()
+        );
+    }
+    Ok(bytes)
+}
+
 #[derive(serde::Serialize)]
 pub struct RsaSignResult {
     pub digest: String,
@@ -262,10 +280,29 @@ pub struct RsaSignResult {
 
 #[derive(Debug, Args)]
 pub struct RsaSignCommand {
-    #[arg(short, long, help = "File containing a SHA256 digest")]
+    #[arg(short, long, help = "File containing a digest (little-endian)")]
     input: Option<PathBuf>,
     #[arg(short, long, help = "File name to write the signature to")]
     output: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "sha256",
+        help = "Hash algorithm of the digest"
+    )]
+    hash: HashAlgorithm,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "pkcs1v15",
+        help = "Signature padding scheme"
+    )]
+    padding: PaddingScheme,
+    #[arg(
+        long,
+        help = "PSS salt length in bytes (defaults to the digest length)"
+    )]
+    salt_len: Option<usize>,
 
     #[arg(
         name = "DER_FILE",
@@ -274,12 +311,11 @@ pub struct RsaSignCommand {
     )]
     private_key: RsaPrivateKey,
     #[arg(
-        name = "SHA256_DIGEST",
-        value_parser = Sha256Digest::from_str,
+        name = "DIGEST",
         required_unless_present = "input",
-        help = "SHA256 digest of the message"
+        help = "Digest of the message as a hex string (big-endian), i.e. 0x..."
     )]
-    digest: Option<Sha256Digest>,
+    digest: Option<String>,
 }
 
 impl CommandDispatch for RsaSignCommand {
@@ -289,17 +325,20 @@ impl CommandDispatch for RsaSignCommand {
         _transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
         let digest = if let Some(input) = &self.input {
-            let bytes = std::fs::read(input)?;
-            Sha256Digest::from_le_bytes(bytes)?
+            let mut bytes = std::fs::read(input)?;
+            bytes.reverse();
+            bytes
         } else {
-            self.digest.clone().unwrap()
+            parse_digest(self.hash, self.digest.as_deref().unwrap())?
         };
-        let signature = self.private_key.sign(&digest)?;
+        let signature =
+            self.private_key
+                .sign_digest(self.hash, self.padding, self.salt_len, &digest)?;
         if let Some(output) = &self.output {
             signature.write_to_file(output)?;
         }
         Ok(Some(Box::new(RsaSignResult {
-            digest: digest.to_string(),
+            digest: format!("0x{}", hex::encode(&digest)),
             signature: signature.to_string(),
         })))
     }
@@ -307,11 +346,30 @@ impl CommandDispatch for RsaSignCommand {
 
 #[derive(Debug, Args)]
 pub struct RsaVerifyCommand {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "sha256",
+        help = "Hash algorithm of the digest"
+    )]
+    hash: HashAlgorithm,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "pkcs1v15",
+        help = "Signature padding scheme"
+    )]
+    padding: PaddingScheme,
+    #[arg(
+        long,
+        help = "PSS salt length in bytes (detected from the signature if not given)"
+    )]
+    salt_len: Option<usize>,
     #[arg(name = "KEY", help = "Key file in DER format")]
     der_file: PathBuf,
     #[arg(
-        name = "SHA256_DIGEST",
-        help = "SHA256 digest of the message as a hex string (big-endian), i.e. 0x..."
+        name = "DIGEST",
+        help = "Digest of the message as a hex string (big-endian), i.e. 0x..."
     )]
     digest: String,
     #[arg(
@@ -328,9 +386,9 @@ impl CommandDispatch for RsaVerifyCommand {
         _transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
         let key = RsaPublicKey::from_pkcs1_der_file(&self.der_file)?;
-        let digest = Sha256Digest::from_str(&self.digest)?;
+        let digest = parse_digest(self.hash, &self.digest)?;
         let signature = Signature::from_str(&self.signature)?;
-        key.verify(&digest, &signature)?;
+        key.verify_digest(self.hash, self.padding, self.salt_len, &digest, &signature)?;
         Ok(None)
     }
 }
@@ -4,28 +4,40 @@
 
 use anyhow::Result;
 use clap::{Args, Subcommand};
+use indicatif::{MultiProgress, ProgressBar};
 use serde_annotate::Annotate;
 use std::any::Any;
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::{StagedProgressBar, TransportWrapper};
+use opentitanlib::backend;
 use opentitanlib::io::eeprom::{AddressMode, Transaction, MODE_111};
-use opentitanlib::io::spi::{SpiParams, Transfer};
-use opentitanlib::spiflash::{EraseMode, ReadMode, SpiFlash};
+use opentitanlib::io::spi::{SpiParams, Target, Transfer};
+use opentitanlib::spiflash::protect::{BlockProtect, ProtectedRange, StatusLock};
+use opentitanlib::spiflash::{
+    DumpReport, EraseMode, FlashDump, IncrementalStats, ReadMode, Sfdp, SpiFlash,
+};
 use opentitanlib::tpm;
 use opentitanlib::transport::Capability;
 use opentitanlib::transport::ProgressIndicator;
 
 /// Read and parse an SFDP table.
+///
+/// The SFDP is normally read from the device, but can also be taken from a
+/// binary file (`--input`) or a JSON/HJSON description (`--from-json`).  The
+/// result can be saved as JSON (`--to-json`) or encoded back into its binary
+/// form (`--output`), e.g. to author a custom SFDP for the spi_device.
 #[derive(Debug, Args)]
 pub struct SpiSfdp {
     #[arg(
         short,
         long,
+        conflicts_with_all = ["input", "from_json"],
         help = "Display raw SFDP bytes rather than the parsed struct."
     )]
     raw: Option<usize>,
@@ -36,6 +48,26 @@ pub struct SpiSfdp {
         help = "Start reading SFDP at offset.  Only valid with --raw."
     )]
     offset: Option<u32>,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with = "from_json",
+        help = "Parse the SFDP from a binary file rather than the device."
+    )]
+    input: Option<PathBuf>,
+
+    #[arg(long, help = "Parse the SFDP from a JSON or HJSON description.")]
+    from_json: Option<PathBuf>,
+
+    #[arg(long, help = "Write the parsed SFDP as JSON to a file.")]
+    to_json: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Write the SFDP in its binary form to a file (or hexdump it with `-`)."
+    )]
+    output: Option<PathBuf>,
 }
 
 // Print a hexdump of a buffer to `writer`.
@@ -74,25 +106,47 @@ impl CommandDispatch for SpiSfdp {
         context: &dyn Any,
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
-        transport.capabilities()?.request(Capability::SPI).ok()?;
-        let context = context.downcast_ref::<SpiCommand>().unwrap();
-        let spi = context.params.create(transport, "BOOTSTRAP")?;
-
-        if let Some(length) = self.raw {
-            let offset = self.offset.unwrap_or(0);
-            let mut buffer = vec![0u8; length];
-            spi.run_eeprom_transactions(&mut [Transaction::Read(
-                MODE_111
-                    .dummy_cycles(8)
-                    .cmd_addr(SpiFlash::READ_SFDP, offset, AddressMode::Mode3b),
-                &mut buffer,
-            )])?;
-            hexdump(io::stdout(), &buffer)?;
-            Ok(None)
+        let sfdp = if let Some(path) = &self.from_json {
+            deser_hjson::from_str::<Sfdp>(&fs::read_to_string(path)?)?
+        } else if let Some(path) = &self.input {
+            Sfdp::try_from(&fs::read(path)?[..])?
         } else {
-            let sfdp = SpiFlash::read_sfdp(&*spi)?;
-            Ok(Some(Box::new(sfdp)))
+            transport.capabilities()?.request(Capability::SPI).ok()?;
+            let context = context.downcast_ref::<SpiCommand>().unwrap();
+            let spi = context.params.create(transport, "BOOTSTRAP")?;
+
+            if let Some(length) = self.raw {
+                let offset = self.offset.unwrap_or(0);
+                let mut buffer = vec![0u8; length];
+                spi.run_eeprom_transactions(&mut [Transaction::Read(
+                    MODE_111.dummy_cycles(8).cmd_addr(
+                        SpiFlash::READ_SFDP,
+                        offset,
+                        AddressMode::Mode3b,
+                    ),
+                    &mut buffer,
+                )])?;
+                hexdump(io::stdout(), &buffer)?;
+                return Ok(None);
+            }
+            SpiFlash::read_sfdp(&*spi)?
+        };
+
+        if self.to_json.is_none() && self.output.is_none() {
+            return Ok(Some(Box::new(sfdp)));
+        }
+        if let Some(path) = &self.to_json {
+            fs::write(path, serde_json::to_string_pretty(&sfdp)?)?;
+        }
+        if let Some(path) = &self.output {
+            let bytes = sfdp.to_bytes()?;
+            if path.as_os_str() == "-" {
+                hexdump(io::stdout(), &bytes)?;
+            } else {
+                fs::write(path, &bytes)?;
+            }
         }
+        Ok(None)
     }
 }
 
@@ -150,6 +204,11 @@ pub struct SpiRead {
         help = "Read mode"
     )]
     pub mode: ReadMode,
+    #[arg(
+        long,
+        help = "Set the quad enable bit with a volatile write if a quad read mode needs it."
+    )]
+    enable_quad: bool,
     #[arg(short, long, help = "Hexdump the data.")]
     hexdump: bool,
     #[arg(name = "FILE", default_value = "-")]
@@ -189,6 +248,11 @@ impl CommandDispatch for SpiRead {
         let mut flash = SpiFlash::from_spi(&*spi)?;
         flash.set_address_mode_auto(&*spi)?;
         flash.read_mode = self.mode;
+        flash.enable_quad = self.enable_quad;
+        if self.mode == ReadMode::Auto {
+            let mode = flash.set_read_mode_auto(&*spi, &transport.capabilities()?)?;
+            log::info!("Using read mode {:?}", mode);
+        }
 
         let mut buffer = vec![0u8; self.length];
         let progress = StagedProgressBar::new();
@@ -208,6 +272,88 @@ impl CommandDispatch for SpiRead {
     }
 }
 
+/// Dump the contents of a SPI EEPROM to a file in chunks.  An interrupted dump
+/// is resumed from the `<FILE>.progress` sidecar file when run again.
+#[derive(Debug, Args)]
+pub struct SpiDump {
+    #[arg(short, long, default_value = "0", help = "Start offset.")]
+    start: u32,
+    #[arg(
+        short = 'n',
+        long,
+        help = "Number of bytes to read [default: to the end of the flash]."
+    )]
+    length: Option<usize>,
+    #[arg(long, default_value = "65536", help = "Size of each chunk.")]
+    chunk_size: usize,
+    #[arg(
+        short,
+        long,
+        value_enum,
+        ignore_case = true,
+        default_value = "standard",
+        help = "Read mode"
+    )]
+    pub mode: ReadMode,
+    #[arg(long, help = "Read every chunk twice and compare the CRCs.")]
+    verify: bool,
+    #[arg(
+        long,
+        default_value = "3",
+        help = "Number of re-reads permitted for a chunk whose CRCs disagree."
+    )]
+    retries: usize,
+    #[arg(long, help = "Discard the progress of a previous, interrupted dump.")]
+    restart: bool,
+    #[arg(name = "FILE")]
+    filename: PathBuf,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SpiDumpResponse {
+    report: DumpReport,
+    bytes_per_second: f64,
+}
+
+impl CommandDispatch for SpiDump {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        transport.capabilities()?.request(Capability::SPI).ok()?;
+        let context = context.downcast_ref::<SpiCommand>().unwrap();
+        let spi = context.params.create(transport, "BOOTSTRAP")?;
+        let mut flash = SpiFlash::from_spi(&*spi)?;
+        flash.set_address_mode_auto(&*spi)?;
+        flash.read_mode = self.mode;
+        if self.mode == ReadMode::Auto {
+            let mode = flash.set_read_mode_auto(&*spi, &transport.capabilities()?)?;
+            log::info!("Using read mode {:?}", mode);
+        }
+
+        let progress_path = FlashDump::progress_path(&self.filename);
+        if self.restart && progress_path.exists() {
+            fs::remove_file(&progress_path)?;
+        }
+        let dump = FlashDump {
+            start: self.start,
+            length: self
+                .length
+                .unwrap_or(flash.size.saturating_sub(self.start) as usize),
+            chunk_size: self.chunk_size,
+            verify: self.verify,
+            retries: self.retries,
+        };
+        let progress = StagedProgressBar::new();
+        let report = dump.run(&flash, &*spi, &self.filename, &progress)?;
+        Ok(Some(Box::new(SpiDumpResponse {
+            report,
+            bytes_per_second: progress.bytes_per_second(),
+        })))
+    }
+}
+
 /// Erase sectors of a SPI EEPROM.
 #[derive(Debug, Args)]
 pub struct SpiErase {
@@ -279,10 +425,36 @@ impl CommandDispatch for SpiErase {
 }
 
 /// Program data into a SPI EEPROM.
+///
+/// With `--targets`, the SPI EEPROMs attached to several Dediprog programmers
+/// (given by USB serial number) are programmed concurrently, instead of the
+/// one attached to the current transport.
 #[derive(Debug, Args)]
 pub struct SpiProgram {
     #[arg(short, long, default_value = "0", help = "Start offset.")]
     start: u32,
+    #[arg(
+        long,
+        help = "Only erase and program the blocks which differ from FILE, then verify them."
+    )]
+    incremental: bool,
+    #[arg(
+        long,
+        value_enum,
+        ignore_case = true,
+        default_value = "standard",
+        requires = "incremental",
+        help = "Erase mode used by --incremental"
+    )]
+    erase_mode: EraseMode,
+    #[arg(long, help = "Read back the programmed data and compare it with FILE.")]
+    verify: bool,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "USB serial numbers of Dediprog programmers to program in parallel."
+    )]
+    targets: Vec<String>,
     #[arg(name = "FILE")]
     filename: PathBuf,
 }
@@ -291,6 +463,120 @@ pub struct SpiProgram {
 pub struct SpiProgramResponse {
     length: usize,
     bytes_per_second: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incremental: Option<IncrementalStats>,
+}
+
+/// The outcome of programming the SPI EEPROM attached to one programmer.
+#[derive(Debug, serde::Serialize)]
+pub struct SpiProgramTarget {
+    serial: String,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    seconds: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SpiProgramTargetsResponse {
+    length: usize,
+    passed: usize,
+    failed: usize,
+    targets: Vec<SpiProgramTarget>,
+}
+
+impl SpiProgram {
+    /// Programs (and optionally verifies) `buffer` into the SPI EEPROM attached to `spi`.
+    fn program(
+        &self,
+        spi: &dyn Target,
+        buffer: &[u8],
+        progress: &StagedProgressBar,
+    ) -> Result<Option<IncrementalStats>> {
+        let mut flash = SpiFlash::from_spi(spi)?;
+        flash.set_address_mode_auto(spi)?;
+        flash.erase_mode = self.erase_mode;
+        let incremental = if self.incremental {
+            Some(flash.program_incremental_with_progress(spi, self.start, buffer, progress)?)
+        } else {
+            flash.program_with_progress(spi, self.start, buffer, progress)?;
+            None
+        };
+        if self.verify {
+            flash.verify_with_progress(spi, self.start, buffer, progress)?;
+        }
+        Ok(incremental)
+    }
+
+    /// Programs the file into the SPI EEPROMs attached to the Dediprogs listed in `--targets`,
+    /// each from its own thread.  Each Dediprog is opened according to `backend_opts`, except
+    /// for its serial number, and the SPI options in `params` apply to every target.
+    fn program_targets(
+        &self,
+        params: &SpiParams,
+        backend_opts: &backend::BackendOpts,
+    ) -> Result<SpiProgramTargetsResponse> {
+        let buffer = fs::read(&self.filename)?;
+        let buffer = buffer.as_slice();
+        let multi = MultiProgress::new();
+        let bars = self
+            .targets
+            .iter()
+            .map(|_| multi.add(ProgressBar::new(0)))
+            .collect::<Vec<_>>();
+        let targets = std::thread::scope(|scope| -> Result<Vec<SpiProgramTarget>> {
+            let handles = self
+                .targets
+                .iter()
+                .zip(bars)
+                .map(|(serial, bar)| {
+                    scope.spawn(move || {
+                        let progress = StagedProgressBar::with_bar(bar, serial);
+                        let start = Instant::now();
+                        let result = backend::create_dediprog(backend_opts, serial)
+                            .and_then(|transport| {
+                                transport.capabilities()?.request(Capability::SPI).ok()?;
+                                params.create(&transport, "BOOTSTRAP")
+                            })
+                            .and_then(|spi| self.program(&*spi, buffer, &progress));
+                        let error = result.err().map(|e| format!("{:#}", e));
+                        progress.finish_with_message(match &error {
+                            None => "PASS",
+                            Some(_) => "FAIL",
+                        });
+                        SpiProgramTarget {
+                            serial: serial.clone(),
+                            passed: error.is_none(),
+                            error,
+                            seconds: start.elapsed().as_secs_f64(),
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            // Draws the progress bars until every target has finished.
+            multi.join()?;
+            Ok(handles
+                .into_iter()
+                .zip(&self.targets)
+                .map(|(handle, serial)| {
+                    // A panic only fails the target whose thread it happened in.
+                    handle.join().unwrap_or_else(|_| SpiProgramTarget {
+                        serial: serial.clone(),
+                        passed: false,
+                        error: Some("Programming thread panicked".to_string()),
+                        seconds: 0.0,
+                    })
+                })
+                .collect())
+        })?;
+        let passed = targets.iter().filter(|t| t.passed).count();
+        Ok(SpiProgramTargetsResponse {
+            length: buffer.len(),
+            passed,
+            failed: targets.len() - passed,
+            targets,
+        })
+    }
 }
 
 impl CommandDispatch for SpiProgram {
@@ -299,25 +585,127 @@ impl CommandDispatch for SpiProgram {
         context: &dyn Any,
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
-        transport.capabilities()?.request(Capability::SPI).ok()?;
+        let buffer = fs::read(&self.filename)?;
         let context = context.downcast_ref::<SpiCommand>().unwrap();
+        transport.capabilities()?.request(Capability::SPI).ok()?;
         let spi = context.params.create(transport, "BOOTSTRAP")?;
-        let mut flash = SpiFlash::from_spi(&*spi)?;
-        flash.set_address_mode_auto(&*spi)?;
-
-        let buffer = fs::read(&self.filename)?;
         let progress = StagedProgressBar::new();
-        flash.program_with_progress(&*spi, self.start, &buffer, &progress)?;
+        let incremental = self.program(&*spi, &buffer, &progress)?;
 
         Ok(Some(Box::new(SpiProgramResponse {
             length: buffer.len(),
             bytes_per_second: progress.bytes_per_second(),
+            incremental,
         })))
     }
 }
 
+/// Open the SPI flash selected by `context` and identify its block protection scheme.
+fn block_protect(
+    context: &dyn Any,
+    transport: &TransportWrapper,
+) -> Result<(Rc<dyn Target>, BlockProtect)> {
+    transport.capabilities()?.request(Capability::SPI).ok()?;
+    let context = context.downcast_ref::<SpiCommand>().unwrap();
+    let spi = context.params.create(transport, "BOOTSTRAP")?;
+    let flash = SpiFlash::from_spi(&*spi)?;
+    let protect = BlockProtect::from_spi(&*spi, &flash)?;
+    Ok((spi, protect))
+}
+
+/// Show the block protection state of a SPI EEPROM.
+#[derive(Debug, Args)]
+pub struct SpiProtectShow {}
+
+impl CommandDispatch for SpiProtectShow {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let (spi, protect) = block_protect(context, transport)?;
+        Ok(Some(Box::new(protect.read(&*spi)?)))
+    }
+}
+
+/// Protect a range of a SPI EEPROM.
+#[derive(Debug, Args)]
+pub struct SpiProtectSet {
+    #[arg(short, long, default_value = "0", help = "Start offset.")]
+    start: u32,
+    #[arg(short = 'n', long, help = "Number of bytes to protect.")]
+    length: u32,
+    #[arg(
+        long,
+        value_enum,
+        help = "Also set the status register protection (SRP) bits."
+    )]
+    lock: Option<StatusLock>,
+    #[arg(long, help = "Write the volatile status registers only.")]
+    volatile: bool,
+}
+
+impl CommandDispatch for SpiProtectSet {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let (spi, protect) = block_protect(context, transport)?;
+        let range = ProtectedRange {
+            start: self.start,
+            length: self.length,
+        };
+        Ok(Some(Box::new(protect.set(
+            &*spi,
+            range,
+            self.lock,
+            self.volatile,
+        )?)))
+    }
+}
+
+/// Remove all block protection from a SPI EEPROM.
+#[derive(Debug, Args)]
+pub struct SpiProtectClear {
+    #[arg(long, help = "Write the volatile status registers only.")]
+    volatile: bool,
+}
+
+impl CommandDispatch for SpiProtectClear {
+    fn run(
+        &self,
+        context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let (spi, protect) = block_protect(context, transport)?;
+        Ok(Some(Box::new(protect.clear(&*spi, self.volatile)?)))
+    }
+}
+
+/// Commands for managing SPI EEPROM write protection.
+#[derive(Debug, Subcommand, CommandDispatch)]
+pub enum SpiProtect {
+    Show(SpiProtectShow),
+    Set(SpiProtectSet),
+    Clear(SpiProtectClear),
+}
+
 #[derive(Debug, Args)]
 pub struct SpiTpm {
+    #[arg(
+        long,
+        default_value = "0",
+        help = "TPM locality at which to run, requested from the TPM if not 0"
+    )]
+    locality: u8,
+
+    #[arg(
+        long,
+        help = "GPIO pin of the TPM interrupt line, to wait for instead of polling"
+    )]
+    irq: Option<String>,
+
     #[command(subcommand)]
     command: super::tpm::TpmSubCommand,
 }
@@ -329,10 +717,15 @@ impl CommandDispatch for SpiTpm {
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
         let context = context.downcast_ref::<SpiCommand>().unwrap();
-        let bus: Box<dyn tpm::Driver> = Box::new(tpm::SpiDriver::new(
-            context.params.create(transport, "TPM")?,
-        ));
-        self.command.run(&bus, transport)
+        let mut driver = tpm::SpiDriver::new(context.params.create(transport, "TPM")?);
+        if let Some(irq) = &self.irq {
+            driver = driver.with_interrupt(tpm::TpmIrq::new(transport, irq)?);
+        }
+        let bus: Box<dyn tpm::Driver> = Box::new(driver);
+        tpm::run_at_locality(&*bus, self.locality, || {
+            bus.enable_interrupts()?;
+            self.command.run(&bus, transport)
+        })
     }
 }
 
@@ -462,8 +855,11 @@ pub enum InternalSpiCommand {
     Sfdp(SpiSfdp),
     ReadId(SpiReadId),
     Read(SpiRead),
+    Dump(SpiDump),
     Erase(SpiErase),
     Program(SpiProgram),
+    #[command(subcommand)]
+    Protect(SpiProtect),
     RawRead(SpiRawRead),
     RawWrite(SpiRawWrite),
     RawWriteRead(SpiRawWriteRead),
@@ -483,11 +879,21 @@ pub struct SpiCommand {
 impl CommandDispatch for SpiCommand {
     fn run(
         &self,
-        _context: &dyn Any,
+        context: &dyn Any,
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
         // None of the SPI commands care about the prior context, but they do
-        // care about the `bus` parameter in the current node.
-        self.command.run(self, transport)
+        // care about the `bus` parameter in the current node.  The exception is
+        // `program --targets`, which opens additional Dediprog transports
+        // according to the backend options.
+        match &self.command {
+            InternalSpiCommand::Program(program) if !program.targets.is_empty() => {
+                let opts = context.downcast_ref::<crate::Opts>().unwrap();
+                Ok(Some(Box::new(
+                    program.program_targets(&self.params, &opts.backend_opts)?,
+                )))
+            }
+            command => command.run(self, transport),
+        }
     }
 }
@@ -2,7 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use clap::{Args, Subcommand};
 use serde_annotate::Annotate;
 use std::any::Any;
@@ -10,11 +10,14 @@ use std::path::PathBuf;
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
-use opentitanlib::crypto::spx::{self, SpxKeypair, SpxPublicKeyPart, SpxSignature};
+use opentitanlib::crypto::spx::{
+    self, SpxError, SpxKeypair, SpxParams, SpxPublicKeyPart, SpxSignature,
+};
 use opentitanlib::util::file::{FromReader, PemSerilizable, ToWriter};
 
 #[derive(Annotate, serde::Serialize)]
 pub struct SpxPublicKeyInfo {
+    pub params: String,
     pub public_key_num_bits: usize,
     #[annotate(format=hex,comment="Words in little endian order.")]
     pub public_key: Vec<u32>,
@@ -39,6 +42,7 @@ impl CommandDispatch for SpxKeyShowCommand {
         let key = spx::load_spx_key(&self.key_file)?;
 
         Ok(Some(Box::new(SpxPublicKeyInfo {
+            params: key.params().to_string(),
             public_key_num_bits: key.pk_len() * 8,
             public_key: key
                 .pk_as_bytes()
@@ -49,8 +53,8 @@ impl CommandDispatch for SpxKeyShowCommand {
     }
 }
 
-/// Generate a SPHINCS+-SHAKE256-128s-simple public private key pair. The full keypair will be
-/// written to <OUTPUT_DIR>/<BASENAME>.key and the public key will be written to
+/// Generate a SPHINCS+ public private key pair. The full keypair will be written to
+/// <OUTPUT_DIR>/<BASENAME>.key and the public key will be written to
 /// <OUTPUT_DIR>/<BASENAME>.pub.key.
 #[derive(Debug, Args)]
 pub struct SpxKeyGenerateCommand {
@@ -58,6 +62,8 @@ pub struct SpxKeyGenerateCommand {
     output_dir: PathBuf,
     #[arg(name = "BASENAME", help = "Basename for the generated key pair")]
     basename: String,
+    #[arg(long, value_enum, default_value_t, help = "SPHINCS+ parameter set")]
+    params: SpxParams,
 }
 
 impl CommandDispatch for SpxKeyGenerateCommand {
@@ -66,7 +72,7 @@ impl CommandDispatch for SpxKeyGenerateCommand {
         _context: &dyn Any,
         _transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
-        let private_key = SpxKeypair::generate();
+        let private_key = SpxKeypair::generate_with_params(self.params);
         let mut file = self.output_dir.to_owned();
         file.push(&self.basename);
         file.set_extension("pem");
@@ -131,6 +137,13 @@ pub struct SpxVerifyCommand {
     message: PathBuf,
     #[arg(name = "SIGNATURE", help = "SPHINCS+ signature file to verify")]
     signature: PathBuf,
+    #[arg(
+        long,
+        value_enum,
+        help = "Expected SPHINCS+ parameter set of the key",
+        long_help = "If given, verification fails if the key was not created for this parameter set."
+    )]
+    params: Option<SpxParams>,
 }
 
 impl CommandDispatch for SpxVerifyCommand {
@@ -141,6 +154,12 @@ impl CommandDispatch for SpxVerifyCommand {
     ) -> Result<Option<Box<dyn Annotate>>> {
         let message = std::fs::read(&self.message)?;
         let keypair = spx::load_spx_key(&self.key_file)?;
+        if let Some(params) = self.params {
+            ensure!(
+                keypair.params() == params,
+                SpxError::ParamsMismatch(params, keypair.params())
+            );
+        }
         let signature = SpxSignature::read_from_file(&self.signature)?;
         keypair.verify(&message, &signature)?;
         Ok(None)
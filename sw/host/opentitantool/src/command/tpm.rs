@@ -7,10 +7,14 @@ use clap::{Args, Subcommand};
 use serde::{Deserialize, Serialize};
 use serde_annotate::Annotate;
 use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
 use opentitanlib::tpm;
+use opentitanlib::tpm::commands::{self, HashAlg, Hierarchy, KeyTemplate, NvPublic, PcrSelection};
+use opentitanlib::util::parse_int::ParseInt;
 
 /// Read the value of a given TPM register.
 #[derive(Debug, Args)]
@@ -153,6 +157,426 @@ impl CommandDispatch for TpmExecuteCommand {
     }
 }
 
+/// Initialize the TPM after a reset (TPM2_Startup).
+#[derive(Debug, Args)]
+pub struct TpmStartup {
+    #[arg(value_enum, default_value = "clear", help = "Type of startup")]
+    startup_type: commands::StartupType,
+}
+
+impl CommandDispatch for TpmStartup {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        commands::startup(&**tpm, self.startup_type)?;
+        Ok(None)
+    }
+}
+
+/// Run the TPM self test (TPM2_SelfTest).
+#[derive(Debug, Args)]
+pub struct TpmSelfTest {
+    #[arg(
+        long,
+        help = "Test all functions, rather than only those not yet tested"
+    )]
+    full: bool,
+}
+
+impl CommandDispatch for TpmSelfTest {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        commands::self_test(&**tpm, self.full)?;
+        Ok(None)
+    }
+}
+
+/// Query TPM capabilities (TPM2_GetCapability).
+#[derive(Debug, Args)]
+pub struct TpmGetCapability {
+    #[arg(value_enum, help = "The capability to query")]
+    capability: commands::Capability,
+
+    #[arg(long, value_parser = u32::from_str, default_value = "0", help = "First property to report")]
+    property: u32,
+
+    #[arg(long, value_parser = u32::from_str, default_value = "64", help = "Maximum number of values to report")]
+    count: u32,
+}
+
+impl CommandDispatch for TpmGetCapability {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        Ok(Some(Box::new(commands::get_capability(
+            &**tpm,
+            self.capability,
+            self.property,
+            self.count,
+        )?)))
+    }
+}
+
+/// Read random bytes from the TPM (TPM2_GetRandom).
+#[derive(Debug, Args)]
+pub struct TpmGetRandom {
+    #[arg(long, value_parser = u16::from_str, default_value = "32", help = "Number of bytes to read")]
+    count: u16,
+}
+
+impl CommandDispatch for TpmGetRandom {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let data = commands::get_random(&**tpm, self.count)?;
+        Ok(Some(Box::new(TpmExecuteCommandResponse {
+            hexdata: hex::encode(data),
+        })))
+    }
+}
+
+/// Read PCR values (TPM2_PCR_Read).
+#[derive(Debug, Args)]
+pub struct TpmPcrRead {
+    #[arg(long, value_enum, default_value = "sha256", help = "PCR bank to read")]
+    hash: HashAlg,
+
+    #[arg(
+        value_delimiter = ',',
+        default_value = "0,1,2,3,4,5,6,7",
+        help = "Comma-separated list of PCRs to read"
+    )]
+    pcrs: Vec<u8>,
+}
+
+impl CommandDispatch for TpmPcrRead {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let selection = PcrSelection::new(self.hash, &self.pcrs);
+        Ok(Some(Box::new(commands::pcr_read(&**tpm, &[selection])?)))
+    }
+}
+
+/// Extend a PCR with a digest (TPM2_PCR_Extend).
+#[derive(Debug, Args)]
+pub struct TpmPcrExtend {
+    #[arg(value_parser = u32::from_str, help = "PCR to extend")]
+    pcr: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "sha256",
+        help = "PCR bank to extend"
+    )]
+    hash: HashAlg,
+
+    #[arg(short = 'd', long, help = "Hex encoding of the digest")]
+    digest: String,
+
+    #[arg(long, default_value = "", help = "Password of the PCR")]
+    auth: String,
+}
+
+impl CommandDispatch for TpmPcrExtend {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let digest = hex::decode(&self.digest)?;
+        commands::pcr_extend(
+            &**tpm,
+            self.pcr,
+            self.auth.as_bytes(),
+            &[(self.hash, digest.as_slice())],
+        )?;
+        Ok(None)
+    }
+}
+
+/// Define an NV index (TPM2_NV_DefineSpace).
+#[derive(Debug, Args)]
+pub struct TpmNvDefineSpace {
+    #[arg(value_parser = u32::from_str, help = "Handle of the NV index, e.g. 0x1500000")]
+    index: u32,
+
+    #[arg(long, value_parser = u16::from_str, help = "Size of the NV index in bytes")]
+    size: u16,
+
+    #[arg(long, value_parser = u32::from_str, default_value_t = NvPublic::DEFAULT_ATTRIBUTES, help = "NV index attributes (TPMA_NV)")]
+    attributes: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "owner",
+        help = "Hierarchy in which to define the index"
+    )]
+    hierarchy: Hierarchy,
+
+    #[arg(long, default_value = "", help = "Password of the hierarchy")]
+    auth: String,
+
+    #[arg(long, default_value = "", help = "Password of the new NV index")]
+    index_auth: String,
+}
+
+impl CommandDispatch for TpmNvDefineSpace {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let public = NvPublic {
+            index: self.index,
+            name_alg: commands::alg::SHA256,
+            attributes: self.attributes,
+            auth_policy: Vec::new(),
+            size: self.size,
+        };
+        commands::nv_define_space(
+            &**tpm,
+            self.hierarchy.handle(),
+            self.auth.as_bytes(),
+            self.index_auth.as_bytes(),
+            &public,
+        )?;
+        Ok(None)
+    }
+}
+
+/// Read from an NV index, authorized by its password (TPM2_NV_Read).
+#[derive(Debug, Args)]
+pub struct TpmNvRead {
+    #[arg(value_parser = u32::from_str, help = "Handle of the NV index")]
+    index: u32,
+
+    #[arg(long, value_parser = u16::from_str, help = "Number of bytes to read")]
+    size: u16,
+
+    #[arg(long, value_parser = u16::from_str, default_value = "0", help = "Offset within the NV index")]
+    offset: u16,
+
+    #[arg(long, default_value = "", help = "Password of the NV index")]
+    auth: String,
+
+    #[arg(short, long, help = "Write the data to this file")]
+    output: Option<PathBuf>,
+}
+
+impl CommandDispatch for TpmNvRead {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let data = commands::nv_read(
+            &**tpm,
+            self.index,
+            self.index,
+            self.auth.as_bytes(),
+            self.offset,
+            self.size,
+        )?;
+        if let Some(output) = &self.output {
+            fs::write(output, &data)?;
+            return Ok(None);
+        }
+        Ok(Some(Box::new(TpmExecuteCommandResponse {
+            hexdata: hex::encode(data),
+        })))
+    }
+}
+
+/// Write to an NV index, authorized by its password (TPM2_NV_Write).
+#[derive(Debug, Args)]
+pub struct TpmNvWrite {
+    #[arg(value_parser = u32::from_str, help = "Handle of the NV index")]
+    index: u32,
+
+    #[arg(
+        short = 'd',
+        long,
+        conflicts_with = "filename",
+        help = "Hex encoding of the data to write"
+    )]
+    hexdata: Option<String>,
+
+    #[arg(short, long, help = "File containing the data to write")]
+    filename: Option<PathBuf>,
+
+    #[arg(long, value_parser = u16::from_str, default_value = "0", help = "Offset within the NV index")]
+    offset: u16,
+
+    #[arg(long, default_value = "", help = "Password of the NV index")]
+    auth: String,
+}
+
+impl CommandDispatch for TpmNvWrite {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        let data = match (&self.hexdata, &self.filename) {
+            (Some(hexdata), _) => hex::decode(hexdata)?,
+            (None, Some(filename)) => fs::read(filename)?,
+            (None, None) => return Err(anyhow!("Must specify --hexdata or --filename")),
+        };
+        commands::nv_write(
+            &**tpm,
+            self.index,
+            self.index,
+            self.auth.as_bytes(),
+            self.offset,
+            &data,
+        )?;
+        Ok(None)
+    }
+}
+
+/// Create a primary key from a template (TPM2_CreatePrimary).
+#[derive(Debug, Args)]
+pub struct TpmCreatePrimary {
+    #[arg(long, value_enum, default_value = "rsa-storage", help = "Key template")]
+    template: KeyTemplate,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "owner",
+        help = "Hierarchy in which to create the key"
+    )]
+    hierarchy: Hierarchy,
+
+    #[arg(long, default_value = "", help = "Password of the hierarchy")]
+    auth: String,
+
+    #[arg(long, default_value = "", help = "Password of the new key")]
+    key_auth: String,
+}
+
+impl CommandDispatch for TpmCreatePrimary {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        Ok(Some(Box::new(commands::create_primary(
+            &**tpm,
+            self.hierarchy,
+            self.auth.as_bytes(),
+            &self.template.public(),
+            self.key_auth.as_bytes(),
+        )?)))
+    }
+}
+
+/// Read the public area of an object (TPM2_ReadPublic).
+#[derive(Debug, Args)]
+pub struct TpmReadPublic {
+    #[arg(value_parser = u32::from_str, help = "Handle of the object")]
+    handle: u32,
+}
+
+impl CommandDispatch for TpmReadPublic {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        Ok(Some(Box::new(commands::read_public(&**tpm, self.handle)?)))
+    }
+}
+
+/// Remove a transient object from the TPM (TPM2_FlushContext).
+#[derive(Debug, Args)]
+pub struct TpmFlushContext {
+    #[arg(value_parser = u32::from_str, help = "Handle of the object")]
+    handle: u32,
+}
+
+impl CommandDispatch for TpmFlushContext {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        commands::flush_context(&**tpm, self.handle)?;
+        Ok(None)
+    }
+}
+
+/// Sign a set of PCR values with a signing key (TPM2_Quote).
+#[derive(Debug, Args)]
+pub struct TpmQuote {
+    #[arg(value_parser = u32::from_str, help = "Handle of the signing key")]
+    key: u32,
+
+    #[arg(long, value_enum, default_value = "sha256", help = "PCR bank to quote")]
+    hash: HashAlg,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "0,1,2,3,4,5,6,7",
+        help = "Comma-separated list of PCRs to quote"
+    )]
+    pcrs: Vec<u8>,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Hex encoding of a nonce to include in the quote"
+    )]
+    nonce: String,
+
+    #[arg(long, default_value = "", help = "Password of the signing key")]
+    auth: String,
+}
+
+impl CommandDispatch for TpmQuote {
+    fn run(
+        &self,
+        context: &dyn Any,
+        _transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        let tpm = context.downcast_ref::<Box<dyn tpm::Driver>>().unwrap();
+        Ok(Some(Box::new(commands::quote(
+            &**tpm,
+            self.key,
+            self.auth.as_bytes(),
+            &hex::decode(&self.nonce)?,
+            &[PcrSelection::new(self.hash, &self.pcrs)],
+        )?)))
+    }
+}
+
 /// Commands for interacting with a TPM.  These appear as subcommands of both `opentitantool i2c
 /// tpm` and `opentitantool spi tpm`.
 #[derive(Debug, Subcommand, CommandDispatch)]
@@ -160,4 +584,17 @@ pub enum TpmSubCommand {
     ReadRegister(TpmReadRegister),
     WriteRegister(TpmWriteRegister),
     ExecuteCommand(TpmExecuteCommand),
+    Startup(TpmStartup),
+    SelfTest(TpmSelfTest),
+    GetCapability(TpmGetCapability),
+    GetRandom(TpmGetRandom),
+    PcrRead(TpmPcrRead),
+    PcrExtend(TpmPcrExtend),
+    NvDefineSpace(TpmNvDefineSpace),
+    NvRead(TpmNvRead),
+    NvWrite(TpmNvWrite),
+    CreatePrimary(TpmCreatePrimary),
+    ReadPublic(TpmReadPublic),
+    FlushContext(TpmFlushContext),
+    Quote(TpmQuote),
 }
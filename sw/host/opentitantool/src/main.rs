@@ -43,6 +43,7 @@ enum RootCommandHierarchy {
     NoOp(command::NoOp),
     #[command(subcommand)]
     Otp(command::otp::Otp),
+    Rescue(command::rescue::RescueCommand),
     #[command(subcommand)]
     Rsa(command::rsa::Rsa),
     Spi(command::spi::SpiCommand),
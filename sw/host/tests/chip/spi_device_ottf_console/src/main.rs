@@ -32,6 +32,20 @@ struct Opts {
         help = "Name of the SPI interface to connect to the OTTF console."
     )]
     console_spi: String,
+
+    #[arg(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "10ms",
+        help = "How often to poll the SPI console for data"
+    )]
+    console_poll_interval: Duration,
+
+    #[arg(
+        long,
+        help = "Name of a GPIO pin the device asserts when it has console data to send"
+    )]
+    console_ready_pin: Option<String>,
 }
 
 fn spi_device_console_test(opts: &Opts, transport: &TransportWrapper) -> Result<()> {
@@ -44,7 +58,12 @@ fn spi_device_console_test(opts: &Opts, transport: &TransportWrapper) -> Result<
     };
     let mut stdout = std::io::stdout();
     let spi = transport.spi(&opts.console_spi)?;
-    let spi_console_device = SpiConsoleDevice::new(&*spi);
+    let mut spi_console_device =
+        SpiConsoleDevice::new(&*spi).with_poll_interval(opts.console_poll_interval);
+    if let Some(pin) = &opts.console_ready_pin {
+        spi_console_device = spi_console_device
+            .with_ready_pin(transport.gpio_monitoring()?, transport.gpio_pin(pin)?);
+    }
     let _ = UartConsole::wait_for(&spi_console_device, r"Running [^\r\n]*", opts.timeout)?;
     let result = console.interact(&spi_console_device, None, Some(&mut stdout))?;
     match result {
@@ -338,7 +338,10 @@ fn test_read_flash(opts: &Opts, transport: &TransportWrapper, mode: ReadMode) ->
         ReadMode::Standard => Ok(()),
         ReadMode::Fast => Ok(()),
         ReadMode::Dual => transport.capabilities()?.request(Capability::SPI_DUAL).ok(),
-        ReadMode::Quad => transport.capabilities()?.request(Capability::SPI_QUAD).ok(),
+        ReadMode::Quad | ReadMode::QuadIo | ReadMode::Qpi | ReadMode::QuadDtr => {
+            transport.capabilities()?.request(Capability::SPI_QUAD).ok()
+        }
+        ReadMode::Auto => Ok(()),
     };
     if capability.is_err() {
         log::warn!(
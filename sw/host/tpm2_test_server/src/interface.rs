@@ -9,6 +9,8 @@ use opentitanlib::tpm::Driver;
 use std::io::{Read, Write};
 use std::net::Shutdown;
 
+use crate::platform::Platform;
+
 pub(crate) const CMD_SIZE: usize = std::mem::size_of::<TcpTpmCommands>();
 const SERVER_VERSION: u32 = 1;
 
@@ -50,22 +52,23 @@ const _USES_TBS: u32 = 0x02;
 const _IN_RAW_MODE: u32 = 0x04;
 
 /// Physical presence signals (SignalPPOn/Off) are supported.
-const _SUPPORTS_PP: u32 = 0x08;
+const SUPPORTS_PP: u32 = 0x08;
 
 /// Valid only with PlatformAvailable set.
 /// System and TPM power control signals (SignalPowerOn/Off) are not supported.
 const NO_POWER_CTL: u32 = 0x10;
 
-/// Valid only with tpmPlatformAvailable set.
-/// TPM locality cannot be changed.
-const NO_LOCALITY_CTL: u32 = 0x20;
-
 /// Valid only with tpmPlatformAvailable set.
 /// NV control signals (SignalNvOn/Off) are not supported.
 const NO_NV_CTL: u32 = 0x40;
 
-/// Serve the command port for the TPM, forwarding commands to the bus specified in `opts`.
-pub(crate) fn serve_command(stream: &mut TcpStream, tpm: &dyn Driver) -> Result<bool> {
+/// Serve the command port for the TPM, forwarding commands to the bus specified in `opts`, and
+/// platform signals to `platform`.
+pub(crate) fn serve_command(
+    stream: &mut TcpStream,
+    tpm: &dyn Driver,
+    platform: &Platform,
+) -> Result<bool> {
     let mut data = [0u8; CMD_SIZE];
     let len = stream.read(&mut data)?;
     if len == 0 {
@@ -78,14 +81,28 @@ pub(crate) fn serve_command(stream: &mut TcpStream, tpm: &dyn Driver) -> Result<
         stream.shutdown(Shutdown::Both)?;
         Ok(true)
     } else {
-        handle_cmd(cmd, stream, tpm).map(|_| false)
+        handle_cmd(cmd, stream, tpm, platform).map(|_| false)
     }
 }
 
 /// Handle the requested command and send the reply on `stream`. If this it a TPM command, send it
 /// to `tpm`.
-fn handle_cmd(cmd: TcpTpmCommands, stream: &mut TcpStream, tpm: &dyn Driver) -> Result<()> {
-    const CFG: u32 = PLATFORM_AVAILABLE | NO_POWER_CTL | NO_LOCALITY_CTL | NO_NV_CTL;
+fn handle_cmd(
+    cmd: TcpTpmCommands,
+    stream: &mut TcpStream,
+    tpm: &dyn Driver,
+    platform: &Platform,
+) -> Result<()> {
+    let mut cfg = PLATFORM_AVAILABLE;
+    if !platform.has_power_control() {
+        cfg |= NO_POWER_CTL;
+    }
+    if !platform.has_nv_control() {
+        cfg |= NO_NV_CTL;
+    }
+    if platform.has_physical_presence() {
+        cfg |= SUPPORTS_PP;
+    }
     log::info!("CMD {:?}", cmd);
     match cmd {
         TcpTpmCommands::RemoteHandshake => {
@@ -94,11 +111,26 @@ fn handle_cmd(cmd: TcpTpmCommands, stream: &mut TcpStream, tpm: &dyn Driver) ->
             log::debug!("Client ver {}.", u32::from_be_bytes(ver));
             stream.write_all(&SERVER_VERSION.to_be_bytes())?;
             // TODO Make TpmEndpointInfo a bitfield and send
-            stream.write_all(&CFG.to_be_bytes())?;
+            stream.write_all(&cfg.to_be_bytes())?;
             stream.write_all(&[0u8; 4])?;
             Ok(())
         }
         TcpTpmCommands::SendCommand => handle_send(stream, tpm),
+        TcpTpmCommands::SignalPowerOn | TcpTpmCommands::SignalPowerOff => {
+            platform.set_power(matches!(cmd, TcpTpmCommands::SignalPowerOn), tpm)?;
+            stream.write_all(&[0u8; 4])?;
+            Ok(())
+        }
+        TcpTpmCommands::SignalNvOn | TcpTpmCommands::SignalNvOff => {
+            platform.set_nv(matches!(cmd, TcpTpmCommands::SignalNvOn))?;
+            stream.write_all(&[0u8; 4])?;
+            Ok(())
+        }
+        TcpTpmCommands::SignalPPOn | TcpTpmCommands::SignalPPOff => {
+            platform.set_physical_presence(matches!(cmd, TcpTpmCommands::SignalPPOn))?;
+            stream.write_all(&[0u8; 4])?;
+            Ok(())
+        }
         _ => {
             let _ = stream.write(&[0u8; 4])?;
             Ok(())
@@ -121,8 +153,8 @@ fn handle_send(stream: &mut TcpStream, tpm: &dyn Driver) -> Result<()> {
         return Err(anyhow!("Bad command size."));
     }
 
-    log::debug!("TPM cmd {:02x?}", cmd);
-    if let Ok(res) = tpm.execute_command(&cmd) {
+    log::debug!("TPM cmd at locality {} {:02x?}", locality[0], cmd);
+    if let Ok(res) = tpm.execute_command_at(locality[0], &cmd) {
         stream.write_all(&(res.len() as u32).to_be_bytes())?;
         stream.write_all(&res)?;
         stream.write_all(&[0u8; 4])?;
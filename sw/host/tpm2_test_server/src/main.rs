@@ -3,23 +3,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::interface::serve_command;
+use crate::platform::{Platform, PlatformOpts};
 use clap::{Parser, Subcommand};
 use log::LevelFilter;
 use mio::{Events, Interest, Poll, Token};
 use opentitanlib::backend;
 use opentitanlib::io::i2c::I2cParams;
 use opentitanlib::io::spi::SpiParams;
-use opentitanlib::tpm::{Driver, I2cDriver, SpiDriver};
+use opentitanlib::tpm::{Driver, I2cDriver, SpiDriver, TpmIrq};
 use opentitanlib::util::parse_int::ParseInt;
 use std::net::{SocketAddr, TcpListener};
 
 mod interface;
+mod platform;
 
 #[derive(Debug, Subcommand)]
 pub enum TpmBus {
     Spi {
         #[command(flatten)]
         params: SpiParams,
+        #[arg(long, help = "GPIO pin of the TPM interrupt line.")]
+        irq: Option<String>,
     },
     I2C {
         #[command(flatten)]
@@ -49,6 +53,9 @@ struct Opts {
     #[command(flatten)]
     backend_opts: backend::BackendOpts,
 
+    #[command(flatten)]
+    platform_opts: PlatformOpts,
+
     #[arg(
         short,
         long,
@@ -86,9 +93,13 @@ pub fn main() -> std::io::Result<()> {
 
     let transport = backend::create(&options.backend_opts).unwrap();
     let bus: Box<dyn Driver> = match options.bus {
-        TpmBus::Spi { params } => {
+        TpmBus::Spi { params, irq } => {
             let spi = params.create(&transport, "").unwrap();
-            Box::new(SpiDriver::new(spi))
+            let mut driver = SpiDriver::new(spi);
+            if let Some(irq) = irq {
+                driver = driver.with_interrupt(TpmIrq::new(&transport, &irq).unwrap());
+            }
+            Box::new(driver)
         }
         TpmBus::I2C { params, address } => {
             let i2c = params.create(&transport).unwrap();
@@ -96,6 +107,7 @@ pub fn main() -> std::io::Result<()> {
         }
     };
     bus.init().unwrap();
+    let platform = Platform::new(&transport, options.platform_opts);
 
     loop {
         poll.poll(&mut events, None)?;
@@ -103,12 +115,12 @@ pub fn main() -> std::io::Result<()> {
         for event in events.iter() {
             match event.token() {
                 CMD_TOKEN => {
-                    if serve_command(&mut cmd_stream, &*bus).unwrap() {
+                    if serve_command(&mut cmd_stream, &*bus, &platform).unwrap() {
                         return Ok(());
                     }
                 }
                 PLATFORM_TOKEN => {
-                    if serve_command(&mut platform_stream, &*bus).unwrap() {
+                    if serve_command(&mut platform_stream, &*bus, &platform).unwrap() {
                         return Ok(());
                     }
                 }
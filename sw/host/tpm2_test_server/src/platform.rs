@@ -0,0 +1,179 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, Result};
+use clap::Args;
+use humantime::parse_duration;
+use opentitanlib::app::TransportWrapper;
+use opentitanlib::tpm::Driver;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How a platform signal of the TPM simulator protocol is carried out by the transport.
+///
+/// Written as `pin:NAME` to drive a GPIO pin high while the signal is on, or `strapping:NAME` to
+/// apply a pin strapping while the signal is on.  Prefixing the name with `!` inverts the
+/// signal, e.g. `strapping:!RESET` holds the chip in reset while power is off.  `reset` is
+/// shorthand for `strapping:!RESET`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignalAction {
+    Pin { name: String, inverted: bool },
+    Strapping { name: String, inverted: bool },
+}
+
+impl FromStr for SignalAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "reset" {
+            return Self::from_str("strapping:!RESET");
+        }
+        let Some((kind, name)) = s.split_once(':') else {
+            bail!(
+                "Expected `pin:NAME`, `strapping:NAME` or `reset`, got {:?}",
+                s
+            );
+        };
+        let (name, inverted) = match name.strip_prefix('!') {
+            Some(name) => (name.to_string(), true),
+            None => (name.to_string(), false),
+        };
+        match kind {
+            "pin" => Ok(SignalAction::Pin { name, inverted }),
+            "strapping" => Ok(SignalAction::Strapping { name, inverted }),
+            _ => bail!("Unknown signal action {:?}", kind),
+        }
+    }
+}
+
+impl SignalAction {
+    /// Turns the signal on or off.
+    fn set(&self, transport: &TransportWrapper, on: bool) -> Result<()> {
+        match self {
+            SignalAction::Pin { name, inverted } => {
+                transport.gpio_pin(name)?.write(on != *inverted)?;
+            }
+            SignalAction::Strapping { name, inverted } => {
+                let strapping = transport.pin_strapping(name)?;
+                if on != *inverted {
+                    strapping.apply()?;
+                } else {
+                    strapping.remove()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PlatformOpts {
+    #[arg(long, help = "Action carrying out the power signal, e.g. `reset`.")]
+    power: Option<SignalAction>,
+
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "100ms",
+        help = "Time for the TPM to start up after power on."
+    )]
+    power_on_delay: Duration,
+
+    #[arg(long, help = "Action carrying out the NV available signal.")]
+    nv: Option<SignalAction>,
+
+    #[arg(long, help = "Action carrying out the physical presence signal.")]
+    physical_presence: Option<SignalAction>,
+}
+
+/// Carries out the platform signals of the TPM simulator protocol on the transport.
+pub struct Platform<'a> {
+    transport: &'a TransportWrapper,
+    opts: PlatformOpts,
+}
+
+impl<'a> Platform<'a> {
+    pub fn new(transport: &'a TransportWrapper, opts: PlatformOpts) -> Self {
+        Self { transport, opts }
+    }
+
+    pub fn has_power_control(&self) -> bool {
+        self.opts.power.is_some()
+    }
+
+    pub fn has_nv_control(&self) -> bool {
+        self.opts.nv.is_some()
+    }
+
+    pub fn has_physical_presence(&self) -> bool {
+        self.opts.physical_presence.is_some()
+    }
+
+    /// Powers the TPM on or off.  After power on, the TPM driver is initialized again.
+    pub fn set_power(&self, on: bool, tpm: &dyn Driver) -> Result<()> {
+        let Some(power) = &self.opts.power else {
+            return Ok(());
+        };
+        power.set(self.transport, on)?;
+        if on {
+            std::thread::sleep(self.opts.power_on_delay);
+            tpm.init()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_nv(&self, on: bool) -> Result<()> {
+        match &self.opts.nv {
+            Some(nv) => nv.set(self.transport, on),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_physical_presence(&self, on: bool) -> Result<()> {
+        match &self.opts.physical_presence {
+            Some(pp) => pp.set(self.transport, on),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_action_from_str() -> Result<()> {
+        let action = |name: &str, inverted| SignalAction::Strapping {
+            name: name.to_string(),
+            inverted,
+        };
+        assert_eq!(SignalAction::from_str("reset")?, action("RESET", true));
+        assert_eq!(
+            SignalAction::from_str("strapping:!RESET")?,
+            action("RESET", true)
+        );
+        assert_eq!(
+            SignalAction::from_str("strapping:PWR")?,
+            action("PWR", false)
+        );
+        assert_eq!(
+            SignalAction::from_str("pin:IOA0")?,
+            SignalAction::Pin {
+                name: "IOA0".to_string(),
+                inverted: false,
+            }
+        );
+        assert_eq!(
+            SignalAction::from_str("pin:!IOA0")?,
+            SignalAction::Pin {
+                name: "IOA0".to_string(),
+                inverted: true,
+            }
+        );
+        for invalid in ["RESET", "gpio:IOA0"] {
+            assert!(SignalAction::from_str(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+}